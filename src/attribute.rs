@@ -1,5 +1,11 @@
 use stun_codec::rfc5389::attributes::*;
 use stun_codec::rfc5766::attributes::*;
+use stun_codec::rfc8016::attributes::*;
+use stun_codec::rfc8656::attributes::*;
+
+use crate::rfc6062::attributes::*;
+use crate::rfc7635::attributes::*;
+use crate::rfc8489::attributes::*;
 
 define_attribute_enums!(
     Attribute,
@@ -27,6 +33,21 @@ define_attribute_enums!(
         EvenPort,
         RequestedTransport,
         DontFragment,
-        ReservationToken
+        ReservationToken,
+        // RFC 6062
+        ConnectionId,
+        // RFC 7635
+        ThirdPartyAuthorization,
+        AccessToken,
+        // RFC 8016
+        MobilityTicket,
+        // RFC 8656
+        RequestedAddressFamily,
+        AdditionalAddressFamily,
+        // RFC 8489
+        MessageIntegritySha256,
+        PasswordAlgorithms,
+        PasswordAlgorithm,
+        Userhash
     ]
 );
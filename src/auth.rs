@@ -1,23 +1,44 @@
 use crate::attribute::Attribute;
+use crate::rfc7635::attributes::AccessToken;
+use crate::rfc8489::attributes::{MessageIntegritySha256, PasswordAlgorithms, Userhash};
+use crate::rfc8489::{self, FEATURE_PASSWORD_ALGORITHMS, FEATURE_USERNAME_ANONYMITY};
 use crate::{Error, ErrorKind, Result};
+use bytecodec::DecodeExt;
+use hmac::{Hmac, Mac};
+use md5::Md5;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::fmt;
 use stun_codec::rfc5389;
-use stun_codec::Message;
+use stun_codec::rfc5389::attributes::{MessageIntegrity, MessageIntegrityDecoder};
+use stun_codec::{Message, MessageClass};
+use zeroize::Zeroizing;
+
+pub use crate::rfc8489::PasswordAlgorithm;
 
 #[derive(Debug, Clone)]
 pub struct AuthParams {
     username: rfc5389::attributes::Username,
-    password: String,
+    credential: Credential,
     realm: Option<rfc5389::attributes::Realm>,
     nonce: Option<rfc5389::attributes::Nonce>,
+    password_algorithm: PasswordAlgorithm,
+    password_algorithms: Option<PasswordAlgorithms>,
+    sha256_integrity: bool,
+    userhash: bool,
 }
 impl AuthParams {
     pub fn new(username: &str, password: &str) -> Result<Self> {
         let username = track!(rfc5389::attributes::Username::new(username.to_owned()))?;
         Ok(AuthParams {
             username,
-            password: password.to_owned(),
+            credential: Credential::password(password),
             realm: None,
             nonce: None,
+            password_algorithm: PasswordAlgorithm::Md5,
+            password_algorithms: None,
+            sha256_integrity: false,
+            userhash: false,
         })
     }
 
@@ -32,9 +53,98 @@ impl AuthParams {
         let nonce = track!(rfc5389::attributes::Nonce::new(nonce.to_owned()))?;
         Ok(AuthParams {
             username,
-            password: password.to_owned(),
+            credential: Credential::password(password),
+            realm: Some(realm),
+            nonce: Some(nonce),
+            password_algorithm: PasswordAlgorithm::Md5,
+            password_algorithms: None,
+            sha256_integrity: false,
+            userhash: false,
+        })
+    }
+
+    /// Makes a new `AuthParams` instance that authenticates by a precomputed long-term key
+    /// instead of a password.
+    ///
+    /// `key` must be derived from `username` and `realm`.
+    pub fn with_long_term_key(
+        username: &str,
+        key: LongTermKey,
+        realm: &str,
+        nonce: &str,
+    ) -> Result<Self> {
+        let username = track!(rfc5389::attributes::Username::new(username.to_owned()))?;
+        let realm = track!(rfc5389::attributes::Realm::new(realm.to_owned()))?;
+        let nonce = track!(rfc5389::attributes::Nonce::new(nonce.to_owned()))?;
+        Ok(AuthParams {
+            username,
+            credential: Credential::LongTermKey(key),
             realm: Some(realm),
             nonce: Some(nonce),
+            password_algorithm: PasswordAlgorithm::Md5,
+            password_algorithms: None,
+            sha256_integrity: false,
+            userhash: false,
+        })
+    }
+
+    /// Makes a new `AuthParams` instance that authenticates by an OAuth access token ([RFC 7635]).
+    ///
+    /// `kid` is the key identifier that is sent as the `USERNAME` attribute,
+    /// `access_token` is the encrypted token issued by the authorization server and
+    /// `mac_key` is the session key in the token.
+    ///
+    /// [RFC 7635]: https://tools.ietf.org/html/rfc7635
+    pub fn with_access_token(kid: &str, access_token: Vec<u8>, mac_key: &[u8]) -> Result<Self> {
+        let username = track!(rfc5389::attributes::Username::new(kid.to_owned()))?;
+        Ok(AuthParams {
+            username,
+            credential: Credential::MacKey {
+                key: Zeroizing::new(mac_key.to_owned()),
+                access_token: Some(AccessToken::new(access_token)),
+            },
+            realm: None,
+            nonce: None,
+            password_algorithm: PasswordAlgorithm::Md5,
+            password_algorithms: None,
+            sha256_integrity: false,
+            userhash: false,
+        })
+    }
+
+    /// Returns the server-side parameters for a request authenticated by an access token.
+    pub(crate) fn with_session_key(
+        &self,
+        kid: rfc5389::attributes::Username,
+        mac_key: Vec<u8>,
+    ) -> Self {
+        AuthParams {
+            username: kid,
+            credential: Credential::MacKey {
+                key: Zeroizing::new(mac_key),
+                access_token: None,
+            },
+            realm: self.realm.clone(),
+            nonce: self.nonce.clone(),
+            password_algorithm: self.password_algorithm,
+            password_algorithms: None,
+            sha256_integrity: self.sha256_integrity,
+            userhash: self.userhash,
+        }
+    }
+
+    /// Returns the server-side parameters for a request of another user.
+    pub(crate) fn with_credential(&self, username: &str, credential: Credential) -> Result<Self> {
+        let username = track!(rfc5389::attributes::Username::new(username.to_owned()))?;
+        Ok(AuthParams {
+            username,
+            credential,
+            realm: self.realm.clone(),
+            nonce: self.nonce.clone(),
+            password_algorithm: self.password_algorithm,
+            password_algorithms: None,
+            sha256_integrity: self.sha256_integrity,
+            userhash: self.userhash,
         })
     }
 
@@ -54,6 +164,88 @@ impl AuthParams {
         self.nonce = Some(nonce);
     }
 
+    /// Sets whether the `USERHASH` attribute is sent instead of `USERNAME` ([RFC 8489]).
+    ///
+    /// If `true` is specified, authentication fails unless the server supports username anonymity,
+    /// so that the username is never sent in clear.
+    ///
+    /// The default value is `false`.
+    ///
+    /// [RFC 8489]: https://tools.ietf.org/html/rfc8489#section-14.4
+    pub fn set_userhash(&mut self, enabled: bool) {
+        self.userhash = enabled;
+    }
+
+    /// Returns the username (or the key identifier of the access token).
+    pub(crate) fn username(&self) -> &str {
+        self.username.name()
+    }
+
+    pub(crate) fn clear_realm_and_nonce(&mut self) {
+        self.realm = None;
+        self.nonce = None;
+        self.password_algorithm = PasswordAlgorithm::Md5;
+        self.password_algorithms = None;
+        self.sha256_integrity = false;
+    }
+
+    /// Selects the password algorithm and the type of message integrity (client side).
+    ///
+    /// `algorithms` is the `PASSWORD-ALGORITHMS` attribute of the 401 (Unauthorized) response
+    /// that carried the current nonce ([RFC 8489 -- 9.2.5. Receiving a Response]).
+    ///
+    /// [RFC 8489 -- 9.2.5. Receiving a Response]: https://tools.ietf.org/html/rfc8489#section-9.2.5
+    pub(crate) fn negotiate(&mut self, algorithms: Option<&PasswordAlgorithms>) -> Result<()> {
+        let nonce = track_assert_some!(self.nonce.as_ref(), ErrorKind::Other);
+        let features = rfc8489::nonce_features(nonce.value());
+        if features.is_some_and(|f| f & FEATURE_PASSWORD_ALGORITHMS != 0) {
+            // The attribute is required so that an attacker cannot bid down the algorithm
+            // by stripping it
+            let algorithms = track_assert_some!(
+                algorithms,
+                ErrorKind::InvalidInput,
+                "PASSWORD-ALGORITHMS is missing"
+            );
+            let algorithm = track_assert_some!(
+                algorithms.algorithms().find(|&a| match self.credential {
+                    Credential::LongTermKey(ref key) => key.algorithm() == a,
+                    _ => true,
+                }),
+                ErrorKind::InvalidInput,
+                "No supported password algorithms: {:?}",
+                algorithms
+            );
+            self.password_algorithm = algorithm;
+            self.password_algorithms = Some(algorithms.clone());
+        } else {
+            self.password_algorithm = PasswordAlgorithm::Md5;
+            self.password_algorithms = None;
+        }
+        self.sha256_integrity = features.is_some();
+        if self.userhash {
+            track_assert!(
+                features.is_some_and(|f| f & FEATURE_USERNAME_ANONYMITY != 0),
+                ErrorKind::InvalidInput,
+                "The server does not support USERHASH"
+            );
+        }
+        Ok(())
+    }
+
+    /// Returns the server-side parameters that follow the algorithms selected by `request`.
+    pub(crate) fn for_request(mut self, request: &Message<Attribute>) -> Self {
+        self.password_algorithm = request
+            .get_attribute::<rfc8489::attributes::PasswordAlgorithm>()
+            .and_then(|a| a.algorithm())
+            .unwrap_or(PasswordAlgorithm::Md5);
+        self.sha256_integrity = request.get_attribute::<MessageIntegritySha256>().is_some();
+        self.userhash = request
+            .get_attribute::<rfc5389::attributes::Username>()
+            .is_none()
+            && request.get_attribute::<Userhash>().is_some();
+        self
+    }
+
     pub fn get_realm(&self) -> Option<&rfc5389::attributes::Realm> {
         self.realm.as_ref()
     }
@@ -68,26 +260,265 @@ impl AuthParams {
     {
         let realm = track_assert_some!(self.realm.clone(), ErrorKind::Other);
         let nonce = track_assert_some!(self.nonce.clone(), ErrorKind::Other);
-        message.as_mut().add_attribute(self.username.clone());
-        message.as_mut().add_attribute(realm.clone());
-        message.as_mut().add_attribute(nonce);
-        let mi = track!(
-            rfc5389::attributes::MessageIntegrity::new_long_term_credential(
-                message.as_mut(),
-                &self.username,
-                &realm,
-                &self.password,
-            )
-        )?;
-        message.as_mut().add_attribute(mi);
+        let message = message.as_mut();
+        if self.userhash {
+            message.add_attribute(Userhash::new(self.username.name(), realm.text()));
+        } else {
+            message.add_attribute(self.username.clone());
+        }
+        message.add_attribute(realm.clone());
+        message.add_attribute(nonce);
+        if let Some(ref algorithms) = self.password_algorithms {
+            if message.class() == MessageClass::Request {
+                message.add_attribute(algorithms.clone());
+                message.add_attribute(rfc8489::attributes::PasswordAlgorithm::new(
+                    self.password_algorithm,
+                ));
+            }
+        }
+        if let Credential::MacKey {
+            access_token: Some(ref access_token),
+            ..
+        } = self.credential
+        {
+            message.add_attribute(access_token.clone());
+        }
+        if self.sha256_integrity {
+            let key = track!(self.integrity_key())?;
+            let mi = track!(MessageIntegritySha256::new(message, &key).map_err(Error::from))?;
+            message.add_attribute(mi);
+            return Ok(());
+        }
+
+        let mi = match self.credential {
+            Credential::Password(ref password)
+                if self.password_algorithm == PasswordAlgorithm::Md5 =>
+            {
+                track!(MessageIntegrity::new_long_term_credential(
+                    message,
+                    &self.username,
+                    &realm,
+                    password,
+                ))?
+            }
+            _ => {
+                let key = track!(self.integrity_key())?;
+                let hmac = track!(hmac_sha1(&key, message))?.finalize();
+                track!(MessageIntegrityDecoder::new()
+                    .decode_from_bytes(&hmac.into_bytes())
+                    .map_err(Error::from))?
+            }
+        };
+        message.add_attribute(mi);
+        Ok(())
+    }
+
+    /// Validates the `MESSAGE-INTEGRITY-SHA256` or `MESSAGE-INTEGRITY` attribute of the given message.
+    pub fn validate(&self, message: &Message<Attribute>) -> Result<()> {
+        if let Some(mi) = message.get_attribute::<MessageIntegritySha256>() {
+            let key = track!(self.integrity_key())?;
+            track_assert!(
+                mi.check(&key),
+                ErrorKind::InvalidInput,
+                "MESSAGE-INTEGRITY-SHA256 mismatch"
+            );
+            return Ok(());
+        }
+
+        // Prevents bidding down to `MESSAGE-INTEGRITY` once SHA-256 has been negotiated
+        track_assert!(
+            !self.sha256_integrity,
+            ErrorKind::InvalidInput,
+            "MESSAGE-INTEGRITY-SHA256 is missing"
+        );
+        let mi = track_assert_some!(
+            message.get_attribute::<MessageIntegrity>(),
+            ErrorKind::InvalidInput
+        );
+        match self.credential {
+            Credential::Password(ref password)
+                if self.password_algorithm == PasswordAlgorithm::Md5 =>
+            {
+                let realm = track_assert_some!(self.realm.as_ref(), ErrorKind::Other);
+                track!(mi
+                    .check_long_term_credential(&self.username, realm, password)
+                    .map_err(Error::from))?;
+            }
+            _ => {
+                let key = track!(self.integrity_key())?;
+                track!(check_hmac_sha1(&key, message, mi))?;
+            }
+        }
         Ok(())
     }
 
-    pub fn validate(&self, mi: &rfc5389::attributes::MessageIntegrity) -> Result<()> {
-        let realm = track_assert_some!(self.realm.as_ref(), ErrorKind::Other);
-        track!(mi
-            .check_long_term_credential(&self.username, realm, &self.password)
-            .map_err(Error::from))?;
+    /// Validates the message integrity of a success response (client side).
+    ///
+    /// Once SHA-256 integrity has been negotiated, responses without it are rejected.
+    pub(crate) fn validate_response(&self, message: &Message<Attribute>) -> Result<()> {
+        if self.sha256_integrity || has_message_integrity(message) {
+            track!(self.validate(message))?;
+        }
         Ok(())
     }
+
+    /// Returns the key of the message integrity for the selected password algorithm.
+    fn integrity_key(&self) -> Result<Zeroizing<Vec<u8>>> {
+        match self.credential {
+            Credential::Password(ref password) => {
+                let realm = track_assert_some!(self.realm.as_ref(), ErrorKind::Other);
+                let key = LongTermKey::derive(
+                    self.password_algorithm,
+                    self.username.name(),
+                    realm.text(),
+                    password,
+                );
+                Ok(key.key)
+            }
+            Credential::LongTermKey(ref key) => {
+                track_assert_eq!(
+                    key.algorithm,
+                    self.password_algorithm,
+                    ErrorKind::InvalidInput,
+                    "The long-term key was derived by another algorithm"
+                );
+                Ok(key.key.clone())
+            }
+            Credential::MacKey { ref key, .. } => Ok(key.clone()),
+        }
+    }
+}
+
+/// Returns `true` if `message` has a `MESSAGE-INTEGRITY` or `MESSAGE-INTEGRITY-SHA256` attribute.
+pub(crate) fn has_message_integrity(message: &Message<Attribute>) -> bool {
+    message.get_attribute::<MessageIntegrity>().is_some()
+        || message.get_attribute::<MessageIntegritySha256>().is_some()
+}
+
+/// Checks the `MESSAGE-INTEGRITY` of `message` calculated with `key`.
+fn check_hmac_sha1(key: &[u8], message: &Message<Attribute>, mi: &MessageIntegrity) -> Result<()> {
+    // The preceding bytes of a decoded `MESSAGE-INTEGRITY` are inaccessible,
+    // so they are rebuilt from the attributes.
+    let mut preceding = Message::new(message.class(), message.method(), message.transaction_id());
+    for attr in message.attributes() {
+        if let Attribute::MessageIntegrity(_) = attr {
+            break;
+        }
+        preceding.add_attribute(attr.clone());
+    }
+    let hmac = track!(hmac_sha1(key, &preceding))?;
+    track_assert!(
+        hmac.verify_slice(&mi.hmac_sha1()).is_ok(),
+        ErrorKind::InvalidInput,
+        "MESSAGE-INTEGRITY mismatch"
+    );
+    Ok(())
+}
+
+/// Long-term key derived from a username, a realm and a password.
+///
+/// Servers can be configured with these keys so that they never hold plaintext passwords.
+/// The key material is zeroized on drop and is not printed by `Debug`.
+#[derive(Clone, PartialEq, Eq)]
+pub struct LongTermKey {
+    algorithm: PasswordAlgorithm,
+    key: Zeroizing<Vec<u8>>,
+}
+impl LongTermKey {
+    /// Derives a long-term key from the given credential.
+    pub fn derive(
+        algorithm: PasswordAlgorithm,
+        username: &str,
+        realm: &str,
+        password: &str,
+    ) -> Self {
+        let input = Zeroizing::new(format!("{}:{}:{}", username, realm, password));
+        let key = match algorithm {
+            PasswordAlgorithm::Md5 => Md5::digest(input.as_bytes()).to_vec(),
+            PasswordAlgorithm::Sha256 => Sha256::digest(input.as_bytes()).to_vec(),
+        };
+        LongTermKey {
+            algorithm,
+            key: Zeroizing::new(key),
+        }
+    }
+
+    /// Makes a new `LongTermKey` instance from precomputed key bytes.
+    ///
+    /// The length of `key` must be 16 bytes for MD5 and 32 bytes for SHA-256.
+    pub fn from_bytes(algorithm: PasswordAlgorithm, key: Vec<u8>) -> Result<Self> {
+        let key = Zeroizing::new(key);
+        let expected = match algorithm {
+            PasswordAlgorithm::Md5 => 16,
+            PasswordAlgorithm::Sha256 => 32,
+        };
+        track_assert_eq!(key.len(), expected, ErrorKind::InvalidInput; algorithm);
+        Ok(LongTermKey { algorithm, key })
+    }
+
+    /// Makes a new `LongTermKey` instance from a hex-encoded precomputed key.
+    pub fn from_hex(algorithm: PasswordAlgorithm, hex: &str) -> Result<Self> {
+        track_assert!(
+            hex.len().is_multiple_of(2) && hex.is_ascii(),
+            ErrorKind::InvalidInput,
+            "Invalid hex string"
+        );
+        let key = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+            .collect::<std::result::Result<Vec<_>, _>>();
+        let key = track_assert_some!(key.ok(), ErrorKind::InvalidInput, "Invalid hex string");
+        track!(Self::from_bytes(algorithm, key))
+    }
+
+    /// Returns the algorithm that derived the key.
+    pub fn algorithm(&self) -> PasswordAlgorithm {
+        self.algorithm
+    }
+}
+impl fmt::Debug for LongTermKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("LongTermKey")
+            .field("algorithm", &self.algorithm)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Clone)]
+pub(crate) enum Credential {
+    Password(Zeroizing<String>),
+    LongTermKey(LongTermKey),
+
+    /// Session key of an access token ([RFC 7635]).
+    ///
+    /// [RFC 7635]: https://tools.ietf.org/html/rfc7635
+    MacKey {
+        key: Zeroizing<Vec<u8>>,
+        access_token: Option<AccessToken>,
+    },
+}
+impl Credential {
+    pub(crate) fn password(password: &str) -> Self {
+        Credential::Password(Zeroizing::new(password.to_owned()))
+    }
+}
+impl fmt::Debug for Credential {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Credential::Password(_) => write!(f, "Password(..)"),
+            Credential::LongTermKey(key) => f.debug_tuple("LongTermKey").field(key).finish(),
+            Credential::MacKey { access_token, .. } => f
+                .debug_struct("MacKey")
+                .field("access_token", &access_token.as_ref().map(|_| ".."))
+                .finish_non_exhaustive(),
+        }
+    }
+}
+
+/// Calculates the HMAC of `message` as if a `MESSAGE-INTEGRITY` attribute were appended to it.
+fn hmac_sha1(key: &[u8], message: &Message<Attribute>) -> Result<Hmac<Sha1>> {
+    let bytes = track!(rfc8489::message_into_bytes(message.clone(), 20).map_err(Error::from))?;
+    let mut hmac = Hmac::<Sha1>::new_from_slice(key).expect("never fails");
+    hmac.update(&bytes);
+    Ok(hmac)
 }
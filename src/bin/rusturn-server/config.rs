@@ -0,0 +1,556 @@
+use rusturn::auth::{LongTermKey, PasswordAlgorithm};
+use rusturn::rustls::ServerConfig as TlsConfig;
+use rusturn::server::{self, BanPolicy, IpNetwork, PeerAcl, RateLimit, ServerOptions};
+use rusturn::ErrorKind;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use trackable::error::ErrorKindExt;
+use zeroize::Zeroizing;
+
+/// Configuration of `rusturn-server`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default = "default_log_level")]
+    pub log_level: String,
+
+    #[serde(default)]
+    pub metrics_addr: Option<SocketAddr>,
+
+    pub listeners: Vec<ListenerConfig>,
+
+    #[serde(default)]
+    pub relay: RelayConfig,
+
+    pub auth: AuthConfig,
+
+    #[serde(default)]
+    pub allocation: AllocationConfig,
+
+    #[serde(default)]
+    pub quota: QuotaConfig,
+
+    #[serde(default)]
+    pub acl: AclConfig,
+
+    #[serde(default)]
+    pub limits: LimitsConfig,
+}
+impl Config {
+    /// Loads and validates the configuration file.
+    ///
+    /// Relative paths in the file are resolved from the directory of the file.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| ConfigError::new("", format!("cannot read {:?}: {}", path, e)))?;
+        let mut config: Config =
+            toml::from_str(&text).map_err(|e| ConfigError::new("", e.to_string()))?;
+        if let Some(users_file) = &mut config.auth.users_file {
+            if let Some(dir) = path.parent() {
+                *users_file = dir.join(&*users_file);
+            }
+        }
+        for listener in &mut config.listeners {
+            let files = listener
+                .cert_file
+                .iter_mut()
+                .chain(listener.key_file.iter_mut());
+            for file in files {
+                if let Some(dir) = path.parent() {
+                    *file = dir.join(&*file);
+                }
+            }
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        self.log_level
+            .parse::<log::LevelFilter>()
+            .map_err(|_| ConfigError::new("log_level", "unknown level"))?;
+
+        if self.listeners.is_empty() {
+            return Err(ConfigError::new("listeners", "at least one is required"));
+        }
+        for listener in &self.listeners {
+            if listener.protocol == Protocol::Tls {
+                listener.tls_config()?;
+            } else if listener.cert_file.is_some() || listener.key_file.is_some() {
+                return Err(ConfigError::new(
+                    "listeners",
+                    "`cert_file` and `key_file` are only for TLS listeners",
+                ));
+            }
+        }
+
+        if let Some([min, max]) = self.relay.port_range {
+            if min == 0 || min > max {
+                return Err(ConfigError::new(
+                    "relay.port_range",
+                    "must be `[min, max]` where 0 < min <= max",
+                ));
+            }
+        }
+        if let Some(ip) = self.relay.external_ip {
+            let has_relay = match ip {
+                IpAddr::V4(_) => self.relay.ipv4.is_some(),
+                IpAddr::V6(_) => self.relay.ipv6.is_some(),
+            };
+            if !has_relay {
+                return Err(ConfigError::new(
+                    "relay.external_ip",
+                    "no relay address of the same family is configured",
+                ));
+            }
+        }
+
+        if self.auth.realm.is_empty() {
+            return Err(ConfigError::new("auth.realm", "must not be empty"));
+        }
+        if self.auth.users_file.is_none() && self.auth.shared_secret.is_none() {
+            return Err(ConfigError::new(
+                "auth",
+                "either `users_file` or `shared_secret` is required",
+            ));
+        }
+        self.password_algorithms()?;
+        self.load_users()?;
+
+        let allocation = &self.allocation;
+        if allocation.default_lifetime == 0 {
+            return Err(ConfigError::new(
+                "allocation.default_lifetime",
+                "must be positive",
+            ));
+        }
+        if allocation.max_lifetime < allocation.default_lifetime {
+            return Err(ConfigError::new(
+                "allocation.max_lifetime",
+                "must not be less than `allocation.default_lifetime`",
+            ));
+        }
+
+        if self.quota.max_allocations == Some(0) {
+            return Err(ConfigError::new(
+                "quota.max_allocations",
+                "must be positive",
+            ));
+        }
+        if self.quota.max_allocations_per_user == Some(0) {
+            return Err(ConfigError::new(
+                "quota.max_allocations_per_user",
+                "must be positive",
+            ));
+        }
+
+        let limits = &self.limits;
+        if limits.unauthenticated_rate == Some(0) {
+            return Err(ConfigError::new(
+                "limits.unauthenticated_rate",
+                "must be positive",
+            ));
+        }
+        if limits.unauthenticated_burst.is_some() && limits.unauthenticated_rate.is_none() {
+            return Err(ConfigError::new(
+                "limits.unauthenticated_burst",
+                "requires `limits.unauthenticated_rate`",
+            ));
+        }
+        if limits.unauthenticated_burst == Some(0) {
+            return Err(ConfigError::new(
+                "limits.unauthenticated_burst",
+                "must be positive",
+            ));
+        }
+        if limits.max_auth_failures == Some(0) {
+            return Err(ConfigError::new(
+                "limits.max_auth_failures",
+                "must be positive",
+            ));
+        }
+        if limits.auth_failure_window == 0 {
+            return Err(ConfigError::new(
+                "limits.auth_failure_window",
+                "must be positive",
+            ));
+        }
+        if limits.ban_duration == 0 {
+            return Err(ConfigError::new("limits.ban_duration", "must be positive"));
+        }
+
+        self.peer_acl()?;
+        Ok(())
+    }
+
+    /// Loads the users file.
+    ///
+    /// The file has a `[users]` table that maps usernames to passwords and
+    /// a `[keys]` table that maps usernames to hex-encoded long-term keys
+    /// prefixed by the algorithm (e.g., `"md5:0123..."` or `"sha256:0123..."`).
+    pub fn load_users(&self) -> Result<Users, ConfigError> {
+        #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct UsersFile {
+            #[serde(default)]
+            users: HashMap<String, String>,
+
+            #[serde(default)]
+            keys: HashMap<String, String>,
+        }
+
+        let path = if let Some(path) = &self.auth.users_file {
+            path
+        } else {
+            return Ok(Users::default());
+        };
+        let text = std::fs::read_to_string(path).map_err(|e| {
+            ConfigError::new("auth.users_file", format!("cannot read {:?}: {}", path, e))
+        })?;
+        let text = Zeroizing::new(text);
+        let file: UsersFile = toml::from_str(&text).map_err(|e| {
+            ConfigError::new("auth.users_file", format!("invalid file {:?}: {}", path, e))
+        })?;
+        // Clients select the first advertised algorithm, so keys of the other ones are unusable
+        let algorithm = self
+            .password_algorithms()?
+            .first()
+            .copied()
+            .unwrap_or(PasswordAlgorithm::Md5);
+        let mut keys = HashMap::new();
+        for (username, key) in file.keys {
+            let key = parse_key(&key).ok_or_else(|| {
+                let message = format!("invalid key of {:?} in {:?}", username, path);
+                ConfigError::new("auth.users_file", message)
+            })?;
+            if key.algorithm() != algorithm {
+                let message = format!(
+                    "the key of {:?} in {:?} must be derived by {:?}",
+                    username, path, algorithm
+                );
+                return Err(ConfigError::new("auth.users_file", message));
+            }
+            keys.insert(username, key);
+        }
+        let passwords = file
+            .users
+            .into_iter()
+            .map(|(username, password)| (username, Zeroizing::new(password)))
+            .collect();
+        Ok(Users { passwords, keys })
+    }
+
+    /// Returns the password algorithms in the order of preference (`["sha256", "md5"]` by default).
+    pub fn password_algorithms(&self) -> Result<Vec<PasswordAlgorithm>, ConfigError> {
+        let names = if let Some(names) = &self.auth.password_algorithms {
+            names
+        } else {
+            return Ok(vec![PasswordAlgorithm::Sha256, PasswordAlgorithm::Md5]);
+        };
+        let mut algorithms = Vec::new();
+        for (i, name) in names.iter().enumerate() {
+            let algorithm = parse_algorithm(name).ok_or_else(|| {
+                let key = format!("auth.password_algorithms[{}]", i);
+                ConfigError::new(key, format!("unknown algorithm {:?}", name))
+            })?;
+            if !algorithms.contains(&algorithm) {
+                algorithms.push(algorithm);
+            }
+        }
+        Ok(algorithms)
+    }
+
+    pub fn peer_acl(&self) -> Result<PeerAcl, ConfigError> {
+        let mut acl = PeerAcl::new();
+        for (i, network) in self.acl.allow.iter().enumerate() {
+            let network = parse_network(network, || format!("acl.allow[{}]", i))?;
+            acl.allow(network);
+        }
+        for (i, network) in self.acl.deny.iter().enumerate() {
+            let network = parse_network(network, || format!("acl.deny[{}]", i))?;
+            acl.deny(network);
+        }
+        Ok(acl)
+    }
+
+    /// Makes the server options from the configuration.
+    pub fn server_options(&self) -> Result<ServerOptions, ConfigError> {
+        let users = self.load_users()?;
+        let mut options = ServerOptions::new();
+        options
+            .relay_ipv4(self.relay.ipv4)
+            .relay_ipv6(self.relay.ipv6)
+            .external_ip(self.relay.external_ip)
+            .default_allocation_lifetime(Duration::from_secs(self.allocation.default_lifetime))
+            .max_allocation_lifetime(Duration::from_secs(self.allocation.max_lifetime))
+            .max_allocations(self.quota.max_allocations)
+            .max_allocations_per_user(self.quota.max_allocations_per_user)
+            .peer_acl(self.peer_acl()?)
+            .users(users.passwords)
+            .user_keys(users.keys)
+            .revoke_removed_users(self.auth.revoke_removed_users)
+            .password_algorithms(self.password_algorithms()?);
+        if let Some([min, max]) = self.relay.port_range {
+            options.relay_port_range(min..=max);
+        }
+        if let Some(secret) = &self.auth.shared_secret {
+            options.shared_secret(secret);
+        }
+        if let Some(rate) = self.limits.unauthenticated_rate {
+            let burst = self.limits.unauthenticated_burst.unwrap_or(rate);
+            options.unauthenticated_rate_limit(Some(RateLimit::new(rate, burst)));
+        }
+        if let Some(max_failures) = self.limits.max_auth_failures {
+            options.auth_failure_ban(Some(BanPolicy::new(
+                max_failures,
+                Duration::from_secs(self.limits.auth_failure_window),
+                Duration::from_secs(self.limits.ban_duration),
+            )));
+        }
+        Ok(options)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerConfig {
+    pub protocol: Protocol,
+    pub addr: SocketAddr,
+
+    #[serde(default)]
+    pub cert_file: Option<PathBuf>,
+
+    #[serde(default)]
+    pub key_file: Option<PathBuf>,
+}
+impl ListenerConfig {
+    /// Loads the certificate and the private key of the TLS listener.
+    pub fn tls_config(&self) -> Result<Arc<TlsConfig>, ConfigError> {
+        let (cert_file, key_file) = match (&self.cert_file, &self.key_file) {
+            (Some(cert_file), Some(key_file)) => (cert_file, key_file),
+            _ => {
+                return Err(ConfigError::new(
+                    "listeners",
+                    "`cert_file` and `key_file` are required for TLS listeners",
+                ));
+            }
+        };
+        server::load_tls_config(cert_file, key_file).map_err(|e| {
+            let message = format!("cannot load {:?} and {:?}: {}", cert_file, key_file, e);
+            ConfigError::new("listeners", message)
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    Udp,
+    Tcp,
+    Tls,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RelayConfig {
+    #[serde(default = "default_relay_ipv4")]
+    pub ipv4: Option<Ipv4Addr>,
+
+    #[serde(default)]
+    pub ipv6: Option<Ipv6Addr>,
+
+    #[serde(default)]
+    pub external_ip: Option<IpAddr>,
+
+    #[serde(default)]
+    pub port_range: Option<[u16; 2]>,
+}
+impl Default for RelayConfig {
+    fn default() -> Self {
+        RelayConfig {
+            ipv4: default_relay_ipv4(),
+            ipv6: None,
+            external_ip: None,
+            port_range: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuthConfig {
+    pub realm: String,
+
+    #[serde(default)]
+    pub nonce: Option<String>,
+
+    #[serde(default)]
+    pub users_file: Option<PathBuf>,
+
+    #[serde(default)]
+    pub shared_secret: Option<String>,
+
+    #[serde(default)]
+    pub revoke_removed_users: bool,
+
+    #[serde(default)]
+    pub password_algorithms: Option<Vec<String>>,
+}
+
+/// Lifetimes of allocations in seconds.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AllocationConfig {
+    #[serde(default = "default_lifetime")]
+    pub default_lifetime: u64,
+
+    #[serde(default = "max_lifetime")]
+    pub max_lifetime: u64,
+}
+impl Default for AllocationConfig {
+    fn default() -> Self {
+        AllocationConfig {
+            default_lifetime: default_lifetime(),
+            max_lifetime: max_lifetime(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QuotaConfig {
+    #[serde(default)]
+    pub max_allocations: Option<usize>,
+
+    #[serde(default)]
+    pub max_allocations_per_user: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AclConfig {
+    #[serde(default)]
+    pub allow: Vec<String>,
+
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+/// Limits of unauthenticated traffic (the durations are in seconds).
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LimitsConfig {
+    #[serde(default)]
+    pub unauthenticated_rate: Option<u32>,
+
+    #[serde(default)]
+    pub unauthenticated_burst: Option<u32>,
+
+    #[serde(default)]
+    pub max_auth_failures: Option<u32>,
+
+    #[serde(default = "default_auth_failure_window")]
+    pub auth_failure_window: u64,
+
+    #[serde(default = "default_ban_duration")]
+    pub ban_duration: u64,
+}
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            unauthenticated_rate: None,
+            unauthenticated_burst: None,
+            max_auth_failures: None,
+            auth_failure_window: default_auth_failure_window(),
+            ban_duration: default_ban_duration(),
+        }
+    }
+}
+
+/// Users loaded from the users file (`Debug` is not derived to avoid printing the passwords).
+#[derive(Default)]
+pub struct Users {
+    pub passwords: HashMap<String, Zeroizing<String>>,
+    pub keys: HashMap<String, LongTermKey>,
+}
+
+/// Invalid configuration.
+#[derive(Debug)]
+pub struct ConfigError {
+    key: String,
+    message: String,
+}
+impl ConfigError {
+    fn new<K: Into<String>, M: Into<String>>(key: K, message: M) -> Self {
+        ConfigError {
+            key: key.into(),
+            message: message.into(),
+        }
+    }
+}
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.key.is_empty() {
+            write!(f, "Invalid configuration: {}", self.message)
+        } else {
+            write!(f, "Invalid configuration: `{}`: {}", self.key, self.message)
+        }
+    }
+}
+impl std::error::Error for ConfigError {}
+impl From<ConfigError> for rusturn::Error {
+    fn from(e: ConfigError) -> Self {
+        ErrorKind::InvalidInput.cause(e).into()
+    }
+}
+
+fn parse_network<F>(s: &str, key: F) -> Result<IpNetwork, ConfigError>
+where
+    F: FnOnce() -> String,
+{
+    s.parse()
+        .map_err(|_| ConfigError::new(key(), format!("invalid network {:?}", s)))
+}
+
+fn parse_key(s: &str) -> Option<LongTermKey> {
+    let (algorithm, hex) = s.split_once(':')?;
+    LongTermKey::from_hex(parse_algorithm(algorithm)?, hex).ok()
+}
+
+fn parse_algorithm(s: &str) -> Option<PasswordAlgorithm> {
+    match s {
+        "md5" => Some(PasswordAlgorithm::Md5),
+        "sha256" => Some(PasswordAlgorithm::Sha256),
+        _ => None,
+    }
+}
+
+fn default_log_level() -> String {
+    "info".to_owned()
+}
+
+fn default_relay_ipv4() -> Option<Ipv4Addr> {
+    Some(Ipv4Addr::UNSPECIFIED)
+}
+
+fn default_lifetime() -> u64 {
+    600
+}
+
+fn max_lifetime() -> u64 {
+    3600
+}
+
+fn default_auth_failure_window() -> u64 {
+    60
+}
+
+fn default_ban_duration() -> u64 {
+    600
+}
@@ -0,0 +1,155 @@
+//! TURN server configured by a TOML file.
+//!
+//! ```console
+//! $ rusturn-server /etc/rusturn/rusturn-server.toml
+//! ```
+//!
+//! See `rusturn-server.example.toml` for the available settings.
+#[macro_use]
+extern crate trackable;
+
+use clap::Parser;
+use futures::Future;
+use rusturn::server::{ServerBuilder, ServerHandle};
+use rusturn::Error;
+use signal_hook::consts::SIGHUP;
+use signal_hook::iterator::Signals;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::path::PathBuf;
+use trackable::error::MainError;
+
+use self::config::{Config, Protocol};
+
+mod config;
+
+#[derive(Debug, Parser)]
+#[clap(name = "rusturn-server")]
+struct Opt {
+    /// Configuration file.
+    config: PathBuf,
+}
+
+fn main() -> Result<(), MainError> {
+    let opt = Opt::parse();
+    let config = track!(Config::load(&opt.config).map_err(Error::from))?;
+
+    env_logger::init_from_env(
+        env_logger::Env::default().filter_or(env_logger::DEFAULT_FILTER_ENV, &config.log_level),
+    );
+
+    let options = track!(config.server_options().map_err(Error::from))?;
+    let nonce = config
+        .auth
+        .nonce
+        .clone()
+        .unwrap_or_else(|| format!("{:016x}", rand::random::<u64>()));
+
+    let mut builder = track!(ServerBuilder::without_primary_user(
+        &config.auth.realm,
+        &nonce
+    ))?;
+    builder.options(options);
+    for listener in &config.listeners {
+        match listener.protocol {
+            Protocol::Udp => builder.udp_listener(listener.addr),
+            Protocol::Tcp => builder.tcp_listener(listener.addr),
+            Protocol::Tls => {
+                let tls_config = track!(listener.tls_config().map_err(Error::from))?;
+                builder.tls_listener(listener.addr, tls_config)
+            }
+        };
+    }
+    let server = track!(fibers_global::execute(
+        builder.finish(fibers_global::handle())
+    ))?;
+    for (protocol, addr) in server.local_addrs() {
+        log::info!(protocol:? = protocol, addr:% = addr; "Listening");
+    }
+
+    if let Some(addr) = config.metrics_addr {
+        track!(serve_metrics(addr, server.handle()))?;
+    }
+    track!(reload_on_sighup(opt.config, server.handle()))?;
+
+    track!(fibers_global::execute(server.map(|_| ())))?;
+    Ok(())
+}
+
+/// Reloads the reloadable settings (see `ServerHandle::reload`) whenever SIGHUP is received.
+fn reload_on_sighup(path: PathBuf, handle: ServerHandle) -> Result<(), Error> {
+    let mut signals = track!(Signals::new([SIGHUP]).map_err(Error::from))?;
+    std::thread::spawn(move || {
+        for _ in signals.forever() {
+            let result = Config::load(&path).and_then(|config| config.server_options());
+            match result {
+                Ok(options) => handle.reload(&options),
+                Err(e) => log::error!("Cannot reload the configuration: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Serves the statistics of the server in the Prometheus text format over HTTP.
+fn serve_metrics(addr: SocketAddr, handle: ServerHandle) -> Result<(), Error> {
+    let listener = track!(TcpListener::bind(addr).map_err(Error::from))?;
+    log::info!(addr:% = addr; "Serving metrics");
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    log::warn!("Cannot accept a metrics connection: {}", e);
+                    continue;
+                }
+            };
+
+            // Skips the request (the path is not checked)
+            let mut reader = BufReader::new(&stream);
+            let mut line = String::new();
+            while reader.read_line(&mut line).is_ok_and(|n| n > 2) {
+                line.clear();
+            }
+
+            let body = metrics(&handle);
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            if let Err(e) = stream.write_all(response.as_bytes()) {
+                log::debug!("Cannot write metrics: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+fn metrics(handle: &ServerHandle) -> String {
+    let stats = handle.stats();
+    let mut body = String::new();
+    for (name, kind, value) in [
+        ("active_allocations", "gauge", stats.active_allocations),
+        (
+            "created_allocations_total",
+            "counter",
+            stats.created_allocations,
+        ),
+        ("requests_total", "counter", stats.requests),
+        ("auth_failures_total", "counter", stats.auth_failures),
+        (
+            "rate_limited_requests_total",
+            "counter",
+            stats.rate_limited_requests,
+        ),
+        ("banned_requests_total", "counter", stats.banned_requests),
+        ("bans_total", "counter", stats.bans),
+        ("invalid_messages_total", "counter", stats.invalid_messages),
+        ("bytes_to_peers_total", "counter", stats.bytes_to_peers),
+        ("bytes_from_peers_total", "counter", stats.bytes_from_peers),
+    ] {
+        body += &format!("# TYPE rusturn_{name} {kind}\nrusturn_{name} {value}\n");
+    }
+    body
+}
@@ -1,7 +1,8 @@
 use super::core::ClientCore;
 use super::stun_transaction::StunTransaction;
+use super::RelayAddressFamily;
 use crate::attribute::Attribute;
-use crate::auth::AuthParams;
+use crate::auth::{self, AuthParams};
 use crate::channel_data::ChannelData;
 use crate::{Error, ErrorKind, Result};
 use fibers_transport::Transport;
@@ -9,9 +10,25 @@ use futures::{Async, Future, Poll};
 use rustun::channel::Channel as StunChannel;
 use rustun::message::{Request, Response};
 use rustun::transport::StunTransport;
-use stun_codec::{rfc5389, rfc5766};
+use std::net::SocketAddr;
+use stun_codec::rfc8016::attributes::MobilityTicket;
+use stun_codec::rfc8656::attributes::{
+    AdditionalAddressFamily, AddressFamily, RequestedAddressFamily,
+};
+use stun_codec::{rfc5389, rfc5766, rfc8016};
 
-const TRANSPORT_PROTOCOL_UDP: u8 = 17;
+#[derive(Debug)]
+pub enum Allocated<S, C>
+where
+    S: StunTransport<Attribute, PeerAddr = ()>,
+    C: Transport<PeerAddr = (), SendItem = ChannelData, RecvItem = ChannelData>,
+{
+    Client(Box<ClientCore<S, C>>),
+    Redirected {
+        alternate_server: SocketAddr,
+        auth_params: AuthParams,
+    },
+}
 
 #[derive(Debug)]
 pub struct Allocate<S, C>
@@ -21,6 +38,9 @@ where
     stun_channel: Option<StunChannel<Attribute, S>>,
     channel_data_transporter: Option<C>,
     auth_params: AuthParams,
+    transport_protocol: u8,
+    address_family: RelayAddressFamily,
+    mobility: bool,
     allocate_transaction: Option<StunTransaction>,
 }
 impl<S, C> Allocate<S, C>
@@ -32,22 +52,51 @@ where
         stun_channel: StunChannel<Attribute, S>,
         channel_data_transporter: C,
         auth_params: AuthParams,
+        transport_protocol: u8,
+        address_family: RelayAddressFamily,
     ) -> Self {
         Allocate {
             stun_channel: Some(stun_channel),
             channel_data_transporter: Some(channel_data_transporter),
             auth_params,
+            transport_protocol,
+            address_family,
+            mobility: false,
             allocate_transaction: None,
         }
     }
 
+    /// Requests a mobility ticket ([RFC 8016]) to the server.
+    ///
+    /// If the server does not support mobility, the allocation is made without the ticket.
+    ///
+    /// [RFC 8016]: https://tools.ietf.org/html/rfc8016
+    pub fn with_mobility(mut self) -> Self {
+        self.mobility = true;
+        self
+    }
+
     fn start_allocate(&mut self) -> Result<()> {
         let mut request = Request::new(rfc5766::methods::ALLOCATE);
 
         let requested_transport =
-            rfc5766::attributes::RequestedTransport::new(TRANSPORT_PROTOCOL_UDP).into();
+            rfc5766::attributes::RequestedTransport::new(self.transport_protocol).into();
         request.add_attribute(requested_transport);
 
+        match self.address_family {
+            RelayAddressFamily::Ipv4 => {}
+            RelayAddressFamily::Ipv6 => {
+                request.add_attribute(RequestedAddressFamily::new(AddressFamily::V6).into());
+            }
+            RelayAddressFamily::DualStack => {
+                request.add_attribute(AdditionalAddressFamily::new(AddressFamily::V6).into());
+            }
+        }
+
+        if self.mobility {
+            request.add_attribute(MobilityTicket::empty().into());
+        }
+
         if self.auth_params.has_realm() {
             track!(self.auth_params.add_auth_attributes(&mut request))?;
         }
@@ -63,21 +112,27 @@ where
     fn handle_allocate_response(
         &mut self,
         response: Response<Attribute>,
-    ) -> Result<Option<ClientCore<S, C>>> {
+    ) -> Result<Option<Allocated<S, C>>> {
         match response {
             Ok(response) => {
                 let mut lifetime = None;
-                let mut relay_addr = None;
+                let mut relay_addrs = Vec::new();
+                let mut mapped_addr = None;
+                let mut mobility_ticket = None;
+                track!(self.auth_params.validate_response(response.as_ref()))?;
                 for attr in response.attributes() {
                     match attr {
                         Attribute::Lifetime(a) => {
                             lifetime = Some(a.lifetime());
                         }
-                        Attribute::MessageIntegrity(a) => {
-                            track!(self.auth_params.validate(a))?;
-                        }
                         Attribute::XorRelayAddress(a) => {
-                            relay_addr = Some(a.address());
+                            relay_addrs.push(a.address());
+                        }
+                        Attribute::XorMappedAddress(a) => {
+                            mapped_addr = Some(a.address());
+                        }
+                        Attribute::MobilityTicket(a) => {
+                            mobility_ticket = Some(a.clone());
                         }
                         _ => {}
                     }
@@ -89,11 +144,42 @@ where
                     self.channel_data_transporter.take().expect("never fails"),
                     self.auth_params.clone(),
                     lifetime,
-                    relay_addr,
+                    relay_addrs,
+                    mapped_addr,
+                    mobility_ticket,
                 );
-                Ok(Some(client))
+                Ok(Some(Allocated::Client(Box::new(client))))
             }
             Err(response) => {
+                track!(super::check_unknown_attributes(response.as_ref()))?;
+                let error_code = response
+                    .get_attribute::<rfc5389::attributes::ErrorCode>()
+                    .map(|e| e.code());
+                if self.mobility
+                    && error_code == Some(rfc8016::errors::MobilityForbidden::CODEPOINT)
+                {
+                    self.mobility = false;
+                    track!(self.start_allocate())?;
+                    return Ok(None);
+                }
+                if error_code == Some(rfc5389::errors::TryAlternate::CODEPOINT) {
+                    if auth::has_message_integrity(response.as_ref()) {
+                        track!(self.auth_params.validate(response.as_ref()))?;
+                    }
+                    let alternate_server = track_assert_some!(
+                        response.get_attribute::<rfc5389::attributes::AlternateServer>(),
+                        ErrorKind::Other; response
+                    )
+                    .address();
+
+                    // The realm and the nonce are specific to this server
+                    let mut auth_params = self.auth_params.clone();
+                    auth_params.clear_realm_and_nonce();
+                    return Ok(Some(Allocated::Redirected {
+                        alternate_server,
+                        auth_params,
+                    }));
+                }
                 track_assert!(!self.auth_params.has_realm(), ErrorKind::Other; response);
 
                 for attr in response.attributes() {
@@ -113,6 +199,7 @@ where
                 }
                 track_assert!(self.auth_params.has_realm(), ErrorKind::Other; response);
                 track_assert!(self.auth_params.has_nonce(), ErrorKind::Other; response);
+                track!(self.auth_params.negotiate(response.get_attribute()))?;
 
                 track!(self.start_allocate())?;
                 Ok(None)
@@ -137,7 +224,7 @@ where
     S: StunTransport<Attribute, PeerAddr = ()> + 'static,
     C: Transport<PeerAddr = (), SendItem = ChannelData, RecvItem = ChannelData>,
 {
-    type Item = ClientCore<S, C>;
+    type Item = Allocated<S, C>;
     type Error = Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
@@ -160,12 +247,13 @@ where
             if let Async::Ready(data) = track!(self.channel_data_transporter_mut().poll_recv())? {
                 track_panic!(ErrorKind::Other, "Unexpected data reception: {:?}", data);
             }
+            track!(self.stun_channel_mut().poll_send())?;
             track!(self.channel_data_transporter_mut().poll_send())?;
 
             if let Async::Ready(Some(response)) = track!(self.allocate_transaction.poll())? {
                 did_something = true;
-                if let Some(client) = track!(self.handle_allocate_response(response))? {
-                    return Ok(Async::Ready(client));
+                if let Some(allocated) = track!(self.handle_allocate_response(response))? {
+                    return Ok(Async::Ready(allocated));
                 }
             }
         }
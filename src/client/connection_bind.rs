@@ -0,0 +1,212 @@
+use crate::attribute::Attribute;
+use crate::auth::AuthParams;
+use crate::rfc6062;
+use crate::{Error, ErrorKind, Result};
+use bytecodec::{DecodeExt, EncodeExt};
+use fibers::net::futures::Connect;
+use fibers::net::TcpStream;
+use futures::{Async, Future, Poll};
+use rustun::message::Request;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use stun_codec::{MessageClass, MessageDecoder, MessageEncoder, TransactionId};
+
+const STUN_HEADER_SIZE: usize = 20;
+
+/// A TCP connection with a peer that is relayed by a TURN server ([RFC 6062]).
+///
+/// [RFC 6062]: https://tools.ietf.org/html/rfc6062
+#[derive(Debug)]
+pub struct RelayedTcpStream {
+    stream: TcpStream,
+    peer: SocketAddr,
+}
+impl RelayedTcpStream {
+    /// Returns the address of the peer.
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer
+    }
+
+    /// Returns a reference to the underlying TCP stream connected to the TURN server.
+    pub fn stream_ref(&self) -> &TcpStream {
+        &self.stream
+    }
+}
+impl Read for RelayedTcpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
+impl Write for RelayedTcpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// A future that opens a client data connection and binds it to a peer data connection.
+#[derive(Debug)]
+pub struct ConnectionBind {
+    peer: SocketAddr,
+    auth_params: AuthParams,
+    transaction_id: TransactionId,
+    state: BindState,
+}
+impl ConnectionBind {
+    pub fn new(
+        server_addr: SocketAddr,
+        auth_params: AuthParams,
+        peer: SocketAddr,
+        connection_id: u32,
+    ) -> Result<Self> {
+        let mut request = Request::new(rfc6062::methods::connection_bind());
+        request.add_attribute(rfc6062::attributes::ConnectionId::new(connection_id).into());
+        track!(auth_params.add_auth_attributes(&mut request))?;
+        let transaction_id = request.transaction_id();
+        let bytes = track!(MessageEncoder::<Attribute>::new()
+            .encode_into_bytes(request.into_message())
+            .map_err(Error::from))?;
+        Ok(ConnectionBind {
+            peer,
+            auth_params,
+            transaction_id,
+            state: BindState::Connecting {
+                connect: TcpStream::connect(server_addr),
+                request: bytes,
+            },
+        })
+    }
+
+    fn handle_response(
+        auth_params: &AuthParams,
+        transaction_id: TransactionId,
+        bytes: &[u8],
+    ) -> Result<()> {
+        let response = track!(MessageDecoder::<Attribute>::new()
+            .decode_from_bytes(bytes)
+            .map_err(Error::from))?;
+        let response = track_assert_some!(response.ok(), ErrorKind::Other, "Broken response");
+        track_assert_eq!(response.transaction_id(), transaction_id, ErrorKind::Other);
+        track_assert_eq!(
+            response.method(),
+            rfc6062::methods::connection_bind(),
+            ErrorKind::Other
+        );
+        track!(super::check_unknown_attributes(&response))?;
+        track_assert_eq!(
+            response.class(),
+            MessageClass::SuccessResponse,
+            ErrorKind::Other; response
+        );
+        track!(auth_params.validate_response(&response))?;
+        Ok(())
+    }
+}
+impl Future for ConnectionBind {
+    type Item = RelayedTcpStream;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            let next = match &mut self.state {
+                BindState::Connecting { connect, request } => {
+                    if let Async::Ready(stream) = track!(connect.poll().map_err(Error::from))? {
+                        BindState::Sending {
+                            stream,
+                            request: std::mem::take(request),
+                            offset: 0,
+                        }
+                    } else {
+                        return Ok(Async::NotReady);
+                    }
+                }
+                BindState::Sending {
+                    stream,
+                    request,
+                    offset,
+                } => {
+                    match stream.write(&request[*offset..]) {
+                        Err(e) => {
+                            track_assert_eq!(
+                                e.kind(),
+                                io::ErrorKind::WouldBlock,
+                                ErrorKind::Other,
+                                "I/O Error: {}",
+                                e
+                            );
+                            return Ok(Async::NotReady);
+                        }
+                        Ok(size) => *offset += size,
+                    }
+                    if *offset < request.len() {
+                        continue;
+                    }
+                    BindState::Receiving {
+                        stream: stream.clone(),
+                        response: vec![0; STUN_HEADER_SIZE],
+                        offset: 0,
+                    }
+                }
+                BindState::Receiving {
+                    stream,
+                    response,
+                    offset,
+                } => {
+                    // Reads exactly one STUN message so as not to consume the relayed data following it
+                    match stream.read(&mut response[*offset..]) {
+                        Err(e) => {
+                            track_assert_eq!(
+                                e.kind(),
+                                io::ErrorKind::WouldBlock,
+                                ErrorKind::Other,
+                                "I/O Error: {}",
+                                e
+                            );
+                            return Ok(Async::NotReady);
+                        }
+                        Ok(0) => track_panic!(ErrorKind::Other, "Unexpected EOS"),
+                        Ok(size) => *offset += size,
+                    }
+                    if *offset == STUN_HEADER_SIZE && response.len() == STUN_HEADER_SIZE {
+                        let body_len = u16::from_be_bytes([response[2], response[3]]) as usize;
+                        response.resize(STUN_HEADER_SIZE + body_len, 0);
+                    }
+                    if *offset < response.len() {
+                        continue;
+                    }
+                    track!(Self::handle_response(
+                        &self.auth_params,
+                        self.transaction_id,
+                        response
+                    ))?;
+                    return Ok(Async::Ready(RelayedTcpStream {
+                        stream: stream.clone(),
+                        peer: self.peer,
+                    }));
+                }
+            };
+            self.state = next;
+        }
+    }
+}
+
+#[derive(Debug)]
+enum BindState {
+    Connecting {
+        connect: Connect,
+        request: Vec<u8>,
+    },
+    Sending {
+        stream: TcpStream,
+        request: Vec<u8>,
+        offset: usize,
+    },
+    Receiving {
+        stream: TcpStream,
+        response: Vec<u8>,
+        offset: usize,
+    },
+}
@@ -1,8 +1,10 @@
 use super::allocate::Allocate;
 use super::stun_transaction::StunTransaction;
+use super::RelayAddressFamily;
 use crate::attribute::Attribute;
 use crate::auth::AuthParams;
 use crate::channel_data::ChannelData;
+use crate::rfc6062;
 use crate::{AsyncReply, AsyncResult, Error, ErrorKind, Result};
 use fibers_timeout_queue::TimeoutQueue;
 use fibers_transport::Transport;
@@ -10,14 +12,17 @@ use futures::{Async, Future, Poll};
 use rustun::channel::{Channel as StunChannel, RecvMessage};
 use rustun::message::{ErrorResponse, Indication, Request, Response};
 use rustun::transport::StunTransport;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::net::{IpAddr, SocketAddr};
 use std::time::Duration;
 use stun_codec::rfc5766::attributes::ChannelNumber;
+use stun_codec::rfc8016::attributes::MobilityTicket;
 use stun_codec::{rfc5389, rfc5766};
+use trackable::error::ErrorKindExt;
 
 const PERMISSION_LIFETIME_SECONDS: u64 = 300;
 const CHANNEL_LIFETIME_SECONDS: u64 = PERMISSION_LIFETIME_SECONDS; // FIXME: Use `600` (and refresh permissions)
+const MOBILITY_CHECK_INTERVAL_SECONDS: u64 = 15;
 
 #[derive(Debug)]
 pub struct ClientCore<S, C>
@@ -34,9 +39,15 @@ where
     next_channel_number: ChannelNumber,
     timeout_queue: TimeoutQueue<TimeoutEntry>,
     refresh_transaction: StunTransaction,
-    create_permission_transaction: StunTransaction<(SocketAddr, Response<Attribute>)>,
+    create_permission_transaction: StunTransaction<(Vec<SocketAddr>, Response<Attribute>)>,
     channel_bind_transaction: StunTransaction<(SocketAddr, Response<Attribute>)>,
-    relay_addr: Option<SocketAddr>,
+    connects: HashMap<SocketAddr, AsyncReply<u32>>,
+    connect_transaction: StunTransaction<(SocketAddr, Response<Attribute>)>,
+    connection_attempts: VecDeque<(SocketAddr, u32)>,
+    relay_addrs: Vec<SocketAddr>,
+    mapped_addr: Option<SocketAddr>,
+    mobility_ticket: Option<MobilityTicket>,
+    binding_transaction: StunTransaction,
 }
 impl<S, C> ClientCore<S, C>
 where
@@ -47,11 +58,15 @@ where
         stun_transporter: S,
         channel_data_transporter: C,
         auth_params: AuthParams,
+        transport_protocol: u8,
+        address_family: RelayAddressFamily,
     ) -> Allocate<S, C> {
         Allocate::new(
             StunChannel::new(stun_transporter),
             channel_data_transporter,
             auth_params,
+            transport_protocol,
+            address_family,
         )
     }
 
@@ -60,10 +75,18 @@ where
         channel_data_transporter: C,
         auth_params: AuthParams,
         lifetime: Duration,
-        relay_addr: Option<SocketAddr>,
+        relay_addrs: Vec<SocketAddr>,
+        mapped_addr: Option<SocketAddr>,
+        mobility_ticket: Option<MobilityTicket>,
     ) -> Self {
         let mut timeout_queue = TimeoutQueue::new();
         timeout_queue.push(TimeoutEntry::Refresh, lifetime * 9 / 10);
+        if mobility_ticket.is_some() {
+            timeout_queue.push(
+                TimeoutEntry::MobilityCheck,
+                Duration::from_secs(MOBILITY_CHECK_INTERVAL_SECONDS),
+            );
+        }
         ClientCore {
             stun_channel,
             channel_data_transporter,
@@ -76,7 +99,13 @@ where
             refresh_transaction: StunTransaction::empty(),
             create_permission_transaction: StunTransaction::empty(),
             channel_bind_transaction: StunTransaction::empty(),
-            relay_addr,
+            connects: HashMap::new(),
+            connect_transaction: StunTransaction::empty(),
+            connection_attempts: VecDeque::new(),
+            relay_addrs,
+            mapped_addr,
+            mobility_ticket,
+            binding_transaction: StunTransaction::empty(),
         }
     }
 
@@ -85,7 +114,19 @@ where
     }
 
     pub fn relay_addr(&self) -> Option<SocketAddr> {
-        self.relay_addr
+        self.relay_addrs.first().copied()
+    }
+
+    pub fn relay_addrs(&self) -> &[SocketAddr] {
+        &self.relay_addrs
+    }
+
+    pub fn auth_params(&self) -> &AuthParams {
+        &self.auth_params
+    }
+
+    pub fn pop_connection_attempt(&mut self) -> Option<(SocketAddr, u32)> {
+        self.connection_attempts.pop_front()
     }
 
     fn start_refresh(&mut self) -> Result<()> {
@@ -93,6 +134,10 @@ where
 
         let mut request = Request::new(rfc5766::methods::REFRESH);
         request.add_attribute(lifetime.into());
+        if let Some(ticket) = self.mobility_ticket.clone() {
+            // If the address of this client has changed, the allocation is moved to the new one
+            request.add_attribute(ticket.into());
+        }
         track!(self.auth_params.add_auth_attributes(&mut request))?;
 
         self.refresh_transaction = StunTransaction::new(self.stun_channel.call((), request));
@@ -102,18 +147,23 @@ where
     fn handle_refresh_response(&mut self, response: Response<Attribute>) -> Result<()> {
         match response {
             Err(response) => {
+                let is_allocation_mismatch = response
+                    .get_attribute::<rfc5389::attributes::ErrorCode>()
+                    .is_some_and(|e| e.code() == rfc5766::errors::AllocationMismatch::CODEPOINT);
+                track_assert!(!is_allocation_mismatch, ErrorKind::Other; response);
                 track!(self.handle_error_response(response))?;
                 track!(self.start_refresh())?;
             }
             Ok(response) => {
+                track!(self.auth_params.validate_response(response.as_ref()))?;
                 let mut lifetime = None;
                 for attr in response.attributes() {
                     match attr {
                         Attribute::Lifetime(a) => {
                             lifetime = Some(a.lifetime());
                         }
-                        Attribute::MessageIntegrity(a) => {
-                            track!(self.auth_params.validate(a))?;
+                        Attribute::MobilityTicket(a) => {
+                            self.mobility_ticket = Some(a.clone());
                         }
                         _ => {}
                     }
@@ -127,35 +177,55 @@ where
         Ok(())
     }
 
+    fn handle_binding_response(&mut self, response: Response<Attribute>) -> Result<()> {
+        let response = track_assert_some!(response.ok(), ErrorKind::Other);
+        let mapped_addr = response
+            .get_attribute::<rfc5389::attributes::XorMappedAddress>()
+            .map(|a| a.address());
+        if mapped_addr.is_some() && mapped_addr != self.mapped_addr {
+            self.mapped_addr = mapped_addr;
+            track!(self.start_refresh())?;
+        }
+        Ok(())
+    }
+
     fn handle_create_permission_response(
         &mut self,
-        peer: SocketAddr,
+        peers: Vec<SocketAddr>,
         response: Response<Attribute>,
     ) -> Result<()> {
-        let reply = track_assert_some!(self.permissions.remove(&peer.ip()), ErrorKind::Other);
+        let mut reply = None;
+        for peer in &peers {
+            if let Some(r) = self.permissions.remove(&peer.ip()) {
+                reply = reply.or(r);
+            }
+        }
         match response {
             Err(response) => {
-                track!(self.handle_error_response(response))?;
-                if let Err(e) = track!(self.create_permission_inner(peer)) {
+                if let Err(e) = track!(self.handle_error_response(response)) {
+                    // The request cannot be retried (e.g., 403 (Forbidden))
+                    if let Some(reply) = reply {
+                        reply.send(Err(e));
+                        return Ok(());
+                    }
+                    return Err(e);
+                }
+                if let Err(e) = track!(self.create_permission_inner(peers.clone())) {
                     if let Some(reply) = reply {
                         reply.send(Err(e.clone()));
                     }
                     return Err(e);
                 }
-                self.permissions.insert(peer.ip(), reply);
+                self.insert_permissions(&peers, reply);
             }
             Ok(response) => {
-                for attr in response.attributes() {
-                    if let Attribute::MessageIntegrity(a) = attr {
-                        track!(self.auth_params.validate(a))?;
-                    }
-                }
+                track!(self.auth_params.validate_response(response.as_ref()))?;
                 if let Some(reply) = reply {
                     reply.send(Ok(()));
                 }
-                self.permissions.insert(peer.ip(), None);
+                self.insert_permissions(&peers, None);
                 self.timeout_queue.push(
-                    TimeoutEntry::Permission { peer },
+                    TimeoutEntry::Permission { peers },
                     Duration::from_secs(PERMISSION_LIFETIME_SECONDS * 9 / 10),
                 );
             }
@@ -163,6 +233,13 @@ where
         Ok(())
     }
 
+    /// Registers `peers` as a single pending batch whose completion is notified via `reply`.
+    fn insert_permissions(&mut self, peers: &[SocketAddr], mut reply: Option<AsyncReply<()>>) {
+        for peer in peers {
+            self.permissions.insert(peer.ip(), reply.take());
+        }
+    }
+
     fn handle_channel_bind_response(
         &mut self,
         peer: SocketAddr,
@@ -171,7 +248,14 @@ where
         let state = track_assert_some!(self.channels.remove(&peer), ErrorKind::Other);
         match response {
             Err(response) => {
-                track!(self.handle_error_response(response))?;
+                if let Err(e) = track!(self.handle_error_response(response)) {
+                    // The request cannot be retried (e.g., 403 (Forbidden))
+                    if let ChannelState::Creating { reply, .. } = state {
+                        reply.send(Err(e));
+                        return Ok(());
+                    }
+                    return Err(e);
+                }
                 if let Err(e) = track!(self.channel_bind_inner(peer, state.channel_number())) {
                     if let ChannelState::Creating { reply, .. } = state {
                         reply.send(Err(e.clone()));
@@ -181,11 +265,7 @@ where
                 self.channels.insert(peer, state);
             }
             Ok(response) => {
-                for attr in response.attributes() {
-                    if let Attribute::MessageIntegrity(a) = attr {
-                        track!(self.auth_params.validate(a))?;
-                    }
-                }
+                track!(self.auth_params.validate_response(response.as_ref()))?;
 
                 let number = state.channel_number();
                 if let ChannelState::Creating { reply, .. } = state {
@@ -201,9 +281,46 @@ where
         Ok(())
     }
 
+    fn handle_connect_response(
+        &mut self,
+        peer: SocketAddr,
+        response: Response<Attribute>,
+    ) -> Result<()> {
+        let reply = track_assert_some!(self.connects.remove(&peer), ErrorKind::Other);
+        match response {
+            Err(response) => {
+                if let Err(e) = track!(self.handle_error_response(response))
+                    .and_then(|()| track!(self.connect_inner(peer)))
+                {
+                    reply.send(Err(e));
+                    return Ok(());
+                }
+                self.connects.insert(peer, reply);
+            }
+            Ok(response) => {
+                track!(self.auth_params.validate_response(response.as_ref()))?;
+                let connection_id = response
+                    .get_attribute::<rfc6062::attributes::ConnectionId>()
+                    .map(|a| a.value());
+                let connection_id = track_assert_some!(connection_id, ErrorKind::Other; response);
+                reply.send(Ok(connection_id));
+            }
+        }
+        Ok(())
+    }
+
+    /// Handles an error response of which request can be retried.
     fn handle_error_response(&mut self, response: ErrorResponse<Attribute>) -> Result<()> {
+        track!(super::check_unknown_attributes(response.as_ref()))?;
         let error: &rfc5389::attributes::ErrorCode =
             track_assert_some!(response.get_attribute(), ErrorKind::Other; response);
+        if error.code() == rfc5766::errors::AllocationMismatch::CODEPOINT
+            && self.mobility_ticket.is_some()
+        {
+            // The address of this client seems to have changed
+            track!(self.start_refresh())?;
+            return Ok(());
+        }
         track_assert_eq!(
             error.code(),
             rfc5389::errors::StaleNonce::CODEPOINT,
@@ -213,6 +330,7 @@ where
         let nonce: &rfc5389::attributes::Nonce =
             track_assert_some!(response.get_attribute(), ErrorKind::Other; response);
         self.auth_params.set_nonce(nonce.clone());
+        track!(self.auth_params.negotiate(response.get_attribute()))?;
 
         Ok(())
     }
@@ -220,12 +338,24 @@ where
     fn handle_timeout(&mut self, entry: TimeoutEntry) -> Result<()> {
         match entry {
             TimeoutEntry::Refresh => track!(self.start_refresh())?,
-            TimeoutEntry::Permission { peer } => {
-                if self.permissions.remove(&peer.ip()).is_some() {
-                    track!(self.create_permission_inner(peer))?;
-                    self.permissions.insert(peer.ip(), None);
+            TimeoutEntry::MobilityCheck => {
+                let request = Request::new(rfc5389::methods::BINDING);
+                self.binding_transaction =
+                    StunTransaction::new(self.stun_channel.call((), request));
+                self.timeout_queue.push(
+                    TimeoutEntry::MobilityCheck,
+                    Duration::from_secs(MOBILITY_CHECK_INTERVAL_SECONDS),
+                );
+            }
+            TimeoutEntry::Permission { peers } => {
+                let peers = peers
+                    .into_iter()
+                    .filter(|peer| self.permissions.contains_key(&peer.ip()))
+                    .collect::<Vec<_>>();
+                if !peers.is_empty() {
+                    track!(self.create_permission_inner(peers.clone()))?;
                     self.timeout_queue.push(
-                        TimeoutEntry::Permission { peer },
+                        TimeoutEntry::Permission { peers },
                         Duration::from_secs(PERMISSION_LIFETIME_SECONDS * 9 / 10),
                     );
                 }
@@ -271,6 +401,15 @@ where
                 );
                 Ok(Some((peer.address(), Vec::from(data.data()))))
             }
+            m if m == rfc6062::methods::connection_attempt() => {
+                let peer: &rfc5766::attributes::XorPeerAddress =
+                    track_assert_some!(indication.get_attribute(), ErrorKind::Other; indication);
+                let connection_id: &rfc6062::attributes::ConnectionId =
+                    track_assert_some!(indication.get_attribute(), ErrorKind::Other; indication);
+                self.connection_attempts
+                    .push_back((peer.address(), connection_id.value()));
+                Ok(None)
+            }
             _ => {
                 track_panic!(ErrorKind::Other; indication);
             }
@@ -289,14 +428,18 @@ where
         Ok((peer, data.into_data()))
     }
 
-    fn create_permission_inner(&mut self, peer: SocketAddr) -> Result<()> {
+    fn create_permission_inner(&mut self, peers: Vec<SocketAddr>) -> Result<()> {
+        track_assert!(!peers.is_empty(), ErrorKind::InvalidInput);
+
         // If a permission already exists on the TURN server, it will just be refreshed.
         let mut request = Request::new(rfc5766::methods::CREATE_PERMISSION);
-        request.add_attribute(rfc5766::attributes::XorPeerAddress::new(peer).into());
+        for &peer in &peers {
+            request.add_attribute(rfc5766::attributes::XorPeerAddress::new(peer).into());
+        }
         track!(self.auth_params.add_auth_attributes(&mut request))?;
 
         self.create_permission_transaction =
-            StunTransaction::with_peer(peer, self.stun_channel.call((), request));
+            StunTransaction::with_peer(peers, self.stun_channel.call((), request));
         Ok(())
     }
 
@@ -317,6 +460,16 @@ where
         Ok(())
     }
 
+    fn connect_inner(&mut self, peer: SocketAddr) -> Result<()> {
+        let mut request = Request::new(rfc6062::methods::connect());
+        request.add_attribute(rfc5766::attributes::XorPeerAddress::new(peer).into());
+        track!(self.auth_params.add_auth_attributes(&mut request))?;
+
+        self.connect_transaction =
+            StunTransaction::with_peer(peer, self.stun_channel.call((), request));
+        Ok(())
+    }
+
     fn next_channel_number(&mut self) -> ChannelNumber {
         // FIXME: collision check
         let curr = self.next_channel_number;
@@ -325,13 +478,25 @@ where
     }
 
     pub fn create_permission(&mut self, peer: SocketAddr) -> AsyncResult<()> {
+        self.create_permissions(&[peer])
+    }
+
+    pub fn create_permissions(&mut self, peers: &[SocketAddr]) -> AsyncResult<()> {
         let (result, reply) = AsyncResult::new();
-        match track!(self.create_permission_inner(peer)) {
+
+        // Permissions are installed per IP address, so the ports are irrelevant
+        let mut ips = HashSet::new();
+        let peers = peers
+            .iter()
+            .copied()
+            .filter(|peer| ips.insert(peer.ip()))
+            .collect::<Vec<_>>();
+        match track!(self.create_permission_inner(peers.clone())) {
             Err(e) => {
                 reply.send(Err(e));
             }
             Ok(()) => {
-                self.permissions.insert(peer.ip(), Some(reply));
+                self.insert_permissions(&peers, Some(reply));
             }
         }
         result
@@ -357,6 +522,26 @@ where
         result
     }
 
+    /// Issues a Connect request and returns the identifier of the established peer connection.
+    pub fn connect(&mut self, peer: SocketAddr) -> AsyncResult<u32> {
+        let (result, reply) = AsyncResult::new();
+        if self.connects.contains_key(&peer) {
+            reply.send(Err(track!(ErrorKind::InvalidInput
+                .cause(format!("Connecting to {peer} is already in progress")))
+            .into()));
+            return result;
+        }
+        match track!(self.connect_inner(peer)) {
+            Err(e) => {
+                reply.send(Err(e));
+            }
+            Ok(()) => {
+                self.connects.insert(peer, reply);
+            }
+        }
+        result
+    }
+
     pub fn start_send(&mut self, peer: SocketAddr, data: Vec<u8>) -> Result<()> {
         if let Some(state) = self.channels.get(&peer) {
             let data = track!(ChannelData::new(state.channel_number(), data,))?;
@@ -414,16 +599,25 @@ where
                 did_something = true;
                 track!(self.handle_refresh_response(response))?;
             }
-            if let Async::Ready((peer, response)) =
+            if let Async::Ready(response) = track!(self.binding_transaction.poll())? {
+                did_something = true;
+                track!(self.handle_binding_response(response))?;
+            }
+            if let Async::Ready((peers, response)) =
                 track!(self.create_permission_transaction.poll())?
             {
                 did_something = true;
-                track!(self.handle_create_permission_response(peer, response))?;
+                track!(self.handle_create_permission_response(peers, response))?;
             }
             if let Async::Ready((peer, response)) = track!(self.channel_bind_transaction.poll())? {
                 did_something = true;
                 track!(self.handle_channel_bind_response(peer, response))?;
             }
+            if let Async::Ready((peer, response)) = track!(self.connect_transaction.poll())? {
+                did_something = true;
+                track!(self.handle_connect_response(peer, response))?;
+            }
+            track!(self.stun_channel.poll_send())?;
             track!(self.channel_data_transporter.poll_send())?;
         }
         Ok(Async::NotReady)
@@ -433,7 +627,8 @@ where
 #[derive(Debug)]
 enum TimeoutEntry {
     Refresh,
-    Permission { peer: SocketAddr },
+    MobilityCheck,
+    Permission { peers: Vec<SocketAddr> },
     Channel { peer: SocketAddr },
 }
 
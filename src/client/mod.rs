@@ -1,24 +1,40 @@
+pub use self::connection_bind::RelayedTcpStream;
+
+use self::allocate::Allocated;
+use self::connection_bind::ConnectionBind;
 use self::core::ClientCore;
+use crate::attribute::Attribute;
 use crate::auth::AuthParams;
+use crate::channel_data::ChannelData;
+use crate::rfc6062;
 use std::os::fd::AsRawFd;
 
 use crate::transport::{
     ChannelDataTcpTransporter, ChannelDataUdpTransporter, StunTcpTransporter, StunTransporter,
-    StunUdpTransporter,
+    StunUdpTransporter, TurnUdpTransporter, VirtualNetwork,
 };
 use crate::{AsyncResult, Error, ErrorKind, Result};
+use fibers_transport::Transport;
 use fibers_transport::{
     FixedPeerTransporter, RcTransporter, TcpTransport, TcpTransporter, UdpTransport, UdpTransporter,
 };
 use futures::{Async, Future, Poll};
+use rustun::message::MessageErrorKind;
+use rustun::transport::{StunTransport, StunUdpTransporterBuilder};
 use std::net::SocketAddr;
+use std::time::Duration;
+use stun_codec::{rfc5389, Message};
 
 mod allocate;
+mod connection_bind;
 mod core;
 mod stun_transaction;
 
+const TRANSPORT_PROTOCOL_UDP: u8 = 17;
+
 pub trait Client {
     fn create_permission(&mut self, peer: SocketAddr) -> AsyncResult<()>;
+    fn create_permissions(&mut self, peers: &[SocketAddr]) -> AsyncResult<()>;
     fn channel_bind(&mut self, peer: SocketAddr) -> AsyncResult<()>;
     fn start_send(&mut self, peer: SocketAddr, data: Vec<u8>) -> Result<()>;
     fn poll_send(&mut self) -> Poll<(), Error>;
@@ -27,6 +43,25 @@ pub trait Client {
     fn file_descriptor(&self) -> Option<i32>;
 }
 
+/// Returns an error of the `InvalidMessage(UnknownAttributes(..))` kind if `response` is
+/// a 420 (Unknown Attribute) error, so that callers can know which attributes the server does not understand.
+fn check_unknown_attributes(response: &Message<Attribute>) -> Result<()> {
+    let is_unknown_attribute = response
+        .get_attribute::<rfc5389::attributes::ErrorCode>()
+        .is_some_and(|e| e.code() == rfc5389::errors::UnknownAttribute::CODEPOINT);
+    if is_unknown_attribute {
+        let types = response
+            .get_attribute::<rfc5389::attributes::UnknownAttributes>()
+            .map(|a| a.unknowns().to_vec())
+            .unwrap_or_default();
+        track_panic!(
+            ErrorKind::InvalidMessage(MessageErrorKind::UnknownAttributes(types)),
+            "The server does not understand some attributes"
+        );
+    }
+    Ok(())
+}
+
 pub fn wait<C, FN, FU>(
     mut client: C,
     f: FN,
@@ -80,6 +115,65 @@ impl<T: Client, F: Future> Future for Wait<T, F> {
     }
 }
 
+/// Allocates via `allocate` while following 300 (Try Alternate) redirections.
+fn follow_redirects<F, T, S, C>(
+    server_addr: SocketAddr,
+    auth_params: AuthParams,
+    allocate: F,
+) -> FollowRedirects<F, T>
+where
+    F: Fn(SocketAddr, AuthParams) -> T,
+    T: Future<Item = Allocated<S, C>, Error = Error>,
+    S: StunTransport<Attribute, PeerAddr = ()>,
+    C: Transport<PeerAddr = (), SendItem = ChannelData, RecvItem = ChannelData>,
+{
+    let future = allocate(server_addr, auth_params);
+    FollowRedirects {
+        allocate,
+        future,
+        visited: vec![server_addr],
+    }
+}
+
+#[derive(Debug)]
+struct FollowRedirects<F, T> {
+    allocate: F,
+    future: T,
+    visited: Vec<SocketAddr>,
+}
+impl<F, T, S, C> Future for FollowRedirects<F, T>
+where
+    F: Fn(SocketAddr, AuthParams) -> T,
+    T: Future<Item = Allocated<S, C>, Error = Error>,
+    S: StunTransport<Attribute, PeerAddr = ()>,
+    C: Transport<PeerAddr = (), SendItem = ChannelData, RecvItem = ChannelData>,
+{
+    type Item = ClientCore<S, C>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        while let Async::Ready(allocated) = track!(self.future.poll())? {
+            match allocated {
+                Allocated::Client(client) => return Ok(Async::Ready(*client)),
+                Allocated::Redirected {
+                    alternate_server,
+                    auth_params,
+                } => {
+                    track_assert!(
+                        !self.visited.contains(&alternate_server),
+                        ErrorKind::Other,
+                        "Redirection loop: {:?}",
+                        self.visited
+                    );
+                    self.visited.push(alternate_server);
+                    self.future = (self.allocate)(alternate_server, auth_params);
+                }
+            }
+        }
+        Ok(Async::NotReady)
+    }
+}
+
 #[derive(Debug)]
 pub struct TcpClient(ClientCore<StunTcpTransporter, ChannelDataTcpTransporter>);
 impl TcpClient {
@@ -87,20 +181,133 @@ impl TcpClient {
         server_addr: SocketAddr,
         auth_params: AuthParams,
     ) -> impl Future<Item = Self, Error = Error> {
-        TcpTransporter::connect(server_addr)
-            .map_err(|e| track!(Error::from(e)))
-            .and_then(move |transporter| {
-                let transporter = RcTransporter::new(transporter);
-                let stun = StunTcpTransporter::new(StunTransporter::new(transporter.clone()));
-                let channel_data = ChannelDataTcpTransporter::new(transporter);
-                track_err!(ClientCore::allocate(stun, channel_data, auth_params))
-            })
-            .map(TcpClient)
+        Self::allocate_with_family(server_addr, auth_params, RelayAddressFamily::default())
+    }
+
+    /// Makes an allocation that has relayed transport addresses of the given address family ([RFC 8656]).
+    ///
+    /// [RFC 8656]: https://tools.ietf.org/html/rfc8656
+    pub fn allocate_with_family(
+        server_addr: SocketAddr,
+        auth_params: AuthParams,
+        address_family: RelayAddressFamily,
+    ) -> impl Future<Item = Self, Error = Error> {
+        Self::allocate_with_transport(
+            server_addr,
+            auth_params,
+            TRANSPORT_PROTOCOL_UDP,
+            address_family,
+        )
+    }
+
+    /// Makes a TCP allocation ([RFC 6062]).
+    ///
+    /// Peer data connections of the allocation can be established by `connect` and `accept` methods.
+    ///
+    /// [RFC 6062]: https://tools.ietf.org/html/rfc6062
+    pub fn allocate_tcp(
+        server_addr: SocketAddr,
+        auth_params: AuthParams,
+    ) -> impl Future<Item = Self, Error = Error> {
+        Self::allocate_with_transport(
+            server_addr,
+            auth_params,
+            rfc6062::TRANSPORT_PROTOCOL_TCP,
+            RelayAddressFamily::default(),
+        )
+    }
+
+    fn allocate_with_transport(
+        server_addr: SocketAddr,
+        auth_params: AuthParams,
+        transport_protocol: u8,
+        address_family: RelayAddressFamily,
+    ) -> impl Future<Item = Self, Error = Error> {
+        follow_redirects(server_addr, auth_params, move |server_addr, auth_params| {
+            TcpTransporter::connect(server_addr)
+                .map_err(|e| track!(Error::from(e)))
+                .and_then(move |transporter| {
+                    let transporter = RcTransporter::new(transporter);
+                    let stun = StunTcpTransporter::new(StunTransporter::new(transporter.clone()));
+                    let channel_data = ChannelDataTcpTransporter::new(transporter);
+                    track_err!(ClientCore::allocate(
+                        stun,
+                        channel_data,
+                        auth_params,
+                        transport_protocol,
+                        address_family
+                    ))
+                })
+        })
+        .map(TcpClient)
     }
 
     pub fn relay_addr(&self) -> Option<SocketAddr> {
         self.0.relay_addr()
     }
+
+    pub fn relay_addrs(&self) -> &[SocketAddr] {
+        self.0.relay_addrs()
+    }
+
+    /// Opens a TCP connection to `peer` through the relay of the TCP allocation.
+    ///
+    /// Note that the client needs to be polled (e.g., by using `wait` function) until the Connect transaction completes.
+    pub fn connect(
+        &mut self,
+        peer: SocketAddr,
+    ) -> impl Future<Item = RelayedTcpStream, Error = Error> {
+        let server_addr = self.server_addr();
+        let auth_params = self.0.auth_params().clone();
+        self.0
+            .connect(peer)
+            .and_then(move |connection_id| {
+                track!(ConnectionBind::new(
+                    server_addr,
+                    auth_params,
+                    peer,
+                    connection_id
+                ))
+            })
+            .flatten()
+    }
+
+    /// Polls a TCP connection attempt from a peer to the relayed transport address of the TCP allocation.
+    pub fn poll_connection_attempt(&mut self) -> Poll<ConnectionAttempt, Error> {
+        if let Async::Ready(item) = track!(self.0.poll_recv())? {
+            track_panic!(ErrorKind::Other, "Unexpected reception: {:?}", item);
+        }
+        if let Some((peer, connection_id)) = self.0.pop_connection_attempt() {
+            Ok(Async::Ready(ConnectionAttempt {
+                peer,
+                connection_id,
+            }))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+
+    /// Accepts the given connection attempt.
+    pub fn accept(
+        &self,
+        attempt: ConnectionAttempt,
+    ) -> impl Future<Item = RelayedTcpStream, Error = Error> {
+        futures::future::result(track!(ConnectionBind::new(
+            self.server_addr(),
+            self.0.auth_params().clone(),
+            attempt.peer,
+            attempt.connection_id,
+        )))
+        .flatten()
+    }
+
+    fn server_addr(&self) -> SocketAddr {
+        self.0
+            .stun_channel_ref()
+            .transporter_ref()
+            .inner_ref()
+            .with_inner_ref(|x| x.peer_addr())
+    }
 }
 unsafe impl Send for TcpClient {}
 impl Client for TcpClient {
@@ -108,6 +315,10 @@ impl Client for TcpClient {
         self.0.create_permission(peer)
     }
 
+    fn create_permissions(&mut self, peers: &[SocketAddr]) -> AsyncResult<()> {
+        self.0.create_permissions(peers)
+    }
+
     fn channel_bind(&mut self, peer: SocketAddr) -> AsyncResult<()> {
         self.0.channel_bind(peer)
     }
@@ -137,35 +348,55 @@ impl Client for TcpClient {
 }
 
 #[derive(Debug)]
-pub struct UdpClient(
-    ClientCore<
-        FixedPeerTransporter<StunUdpTransporter, ()>,
-        FixedPeerTransporter<ChannelDataUdpTransporter, ()>,
-    >,
-);
+pub struct UdpClient(ClientCore<UdpStunTransporter, UdpChannelDataTransporter>);
+
+type UdpStunTransporter = FixedPeerTransporter<StunUdpTransporter, ()>;
+
+type UdpChannelDataTransporter = FixedPeerTransporter<ChannelDataUdpTransporter, ()>;
 
 impl UdpClient {
     pub fn allocate(
         server_addr: SocketAddr,
         auth_params: AuthParams,
     ) -> impl Future<Item = Self, Error = Error> {
-        let bind_addr = "0.0.0.0:0".parse().expect("never fails");
-        UdpTransporter::bind(bind_addr)
-            .map_err(|e| track!(Error::from(e)))
-            .and_then(move |transporter| {
-                let transporter = RcTransporter::new(transporter);
-                let stun = StunUdpTransporter::new(StunTransporter::new(transporter.clone()));
-                let stun = FixedPeerTransporter::new((), server_addr, stun);
-                let channel_data = ChannelDataUdpTransporter::new(transporter);
-                let channel_data = FixedPeerTransporter::new((), server_addr, channel_data);
-                track_err!(ClientCore::allocate(stun, channel_data, auth_params))
-            })
-            .map(UdpClient)
+        UdpClientBuilder::new().allocate(server_addr, auth_params)
+    }
+
+    /// Makes an allocation that has relayed transport addresses of the given address family ([RFC 8656]).
+    ///
+    /// [RFC 8656]: https://tools.ietf.org/html/rfc8656
+    pub fn allocate_with_family(
+        server_addr: SocketAddr,
+        auth_params: AuthParams,
+        address_family: RelayAddressFamily,
+    ) -> impl Future<Item = Self, Error = Error> {
+        UdpClientBuilder::new()
+            .address_family(address_family)
+            .allocate(server_addr, auth_params)
+    }
+
+    /// Makes an allocation via a socket of a [`VirtualNetwork`] bound to `bind_addr`
+    /// (e.g., for testing with simulated packet loss or NAT rebinding).
+    ///
+    /// If the server redirects the client, a new socket is bound to `bind_addr` for the alternate server.
+    ///
+    /// [`VirtualNetwork`]: crate::transport::VirtualNetwork
+    pub fn allocate_on(
+        network: &VirtualNetwork,
+        bind_addr: SocketAddr,
+        server_addr: SocketAddr,
+        auth_params: AuthParams,
+    ) -> impl Future<Item = Self, Error = Error> {
+        UdpClientBuilder::new().allocate_on(network, bind_addr, server_addr, auth_params)
     }
 
     pub fn relay_addr(&self) -> Option<SocketAddr> {
         self.0.relay_addr()
     }
+
+    pub fn relay_addrs(&self) -> &[SocketAddr] {
+        self.0.relay_addrs()
+    }
 }
 unsafe impl Send for UdpClient {}
 impl Client for UdpClient {
@@ -177,8 +408,9 @@ impl Client for UdpClient {
             .inner_ref()
             .inner_ref()
             .with_inner_ref(|f| {
-                let socket: &fibers::net::UdpSocket = f.socket_ref();
-                socket.with_inner(|g| my_fd = Some(g.as_raw_fd()));
+                if let Some(socket) = f.socket_ref() {
+                    socket.with_inner(|g| my_fd = Some(g.as_raw_fd()));
+                }
             });
         my_fd
     }
@@ -187,6 +419,10 @@ impl Client for UdpClient {
         self.0.create_permission(peer)
     }
 
+    fn create_permissions(&mut self, peers: &[SocketAddr]) -> AsyncResult<()> {
+        self.0.create_permissions(peers)
+    }
+
     fn channel_bind(&mut self, peer: SocketAddr) -> AsyncResult<()> {
         self.0.channel_bind(peer)
     }
@@ -212,3 +448,164 @@ impl Client for UdpClient {
             .with_inner_ref(|x| x.local_addr())
     }
 }
+
+/// Builder of [`UdpClient`].
+#[derive(Debug, Clone, Default)]
+pub struct UdpClientBuilder {
+    address_family: RelayAddressFamily,
+    mobility: bool,
+}
+impl UdpClientBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the address family of the relayed transport addresses to request ([RFC 8656]).
+    ///
+    /// The default value is `RelayAddressFamily::Ipv4`.
+    ///
+    /// [RFC 8656]: https://tools.ietf.org/html/rfc8656
+    pub fn address_family(&mut self, address_family: RelayAddressFamily) -> &mut Self {
+        self.address_family = address_family;
+        self
+    }
+
+    /// Sets whether the allocation follows the client across address changes ([RFC 8016]).
+    ///
+    /// If `true` is specified and the server supports mobility, the allocation is moved to
+    /// the new transport address of the client automatically when the address changes.
+    ///
+    /// The default value is `false`.
+    ///
+    /// [RFC 8016]: https://tools.ietf.org/html/rfc8016
+    pub fn mobility(&mut self, enabled: bool) -> &mut Self {
+        self.mobility = enabled;
+        self
+    }
+
+    /// Makes an allocation on `server_addr`.
+    pub fn allocate(
+        &self,
+        server_addr: SocketAddr,
+        auth_params: AuthParams,
+    ) -> impl Future<Item = UdpClient, Error = Error> {
+        let builder = self.clone();
+        follow_redirects(server_addr, auth_params, move |server_addr, auth_params| {
+            let bind_addr = if server_addr.is_ipv6() {
+                "[::]:0".parse().expect("never fails")
+            } else {
+                "0.0.0.0:0".parse().expect("never fails")
+            };
+            let builder = builder.clone();
+            UdpTransporter::bind(bind_addr)
+                .map_err(|e| track!(Error::from(e)))
+                .and_then(move |transporter| {
+                    builder.allocate_on_transporter(
+                        TurnUdpTransporter::Socket(transporter),
+                        server_addr,
+                        auth_params,
+                    )
+                })
+        })
+        .map(UdpClient)
+    }
+
+    /// Makes an allocation on `server_addr` via a socket of a [`VirtualNetwork`] bound to `bind_addr`
+    /// (e.g., for testing with simulated packet loss or NAT rebinding).
+    ///
+    /// If the server redirects the client, a new socket is bound to `bind_addr` for the alternate server.
+    ///
+    /// [`VirtualNetwork`]: crate::transport::VirtualNetwork
+    pub fn allocate_on(
+        &self,
+        network: &VirtualNetwork,
+        bind_addr: SocketAddr,
+        server_addr: SocketAddr,
+        auth_params: AuthParams,
+    ) -> impl Future<Item = UdpClient, Error = Error> {
+        let builder = self.clone();
+        let network = network.clone();
+        follow_redirects(server_addr, auth_params, move |server_addr, auth_params| {
+            let builder = builder.clone();
+            futures::future::result(network.bind(bind_addr))
+                .map_err(|e| track!(Error::from(e)))
+                .and_then(move |socket| {
+                    builder.allocate_on_transporter(
+                        TurnUdpTransporter::new_virtual(socket),
+                        server_addr,
+                        auth_params,
+                    )
+                })
+        })
+        .map(UdpClient)
+    }
+
+    fn allocate_on_transporter(
+        &self,
+        transporter: TurnUdpTransporter,
+        server_addr: SocketAddr,
+        auth_params: AuthParams,
+    ) -> impl Future<Item = Allocated<UdpStunTransporter, UdpChannelDataTransporter>, Error = Error>
+    {
+        let transporter = RcTransporter::new(transporter);
+        let mut stun = StunUdpTransporterBuilder::new();
+        if self.mobility {
+            // NOTE: With mobility, the Refresh request for the new address is issued while
+            // other requests are outstanding, and `StunUdpTransporter` silently discards
+            // requests that wait for the minimum transaction interval.
+            stun.min_transaction_interval(Duration::from_millis(0));
+        }
+        let stun =
+            StunUdpTransporter::with_builder(&stun, StunTransporter::new(transporter.clone()));
+        let stun = FixedPeerTransporter::new((), server_addr, stun);
+        let channel_data = ChannelDataUdpTransporter::new(transporter);
+        let channel_data = FixedPeerTransporter::new((), server_addr, channel_data);
+        let mut allocate = ClientCore::allocate(
+            stun,
+            channel_data,
+            auth_params,
+            TRANSPORT_PROTOCOL_UDP,
+            self.address_family,
+        );
+        if self.mobility {
+            allocate = allocate.with_mobility();
+        }
+        track_err!(allocate)
+    }
+}
+
+/// The address family of the relayed transport addresses requested by a client ([RFC 8656]).
+///
+/// [RFC 8656]: https://tools.ietf.org/html/rfc8656
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum RelayAddressFamily {
+    /// An IPv4 relayed transport address.
+    #[default]
+    Ipv4,
+
+    /// An IPv6 relayed transport address.
+    Ipv6,
+
+    /// Both of an IPv4 and an IPv6 relayed transport addresses.
+    ///
+    /// If the server does not support IPv6 relaying, only the IPv4 address is allocated.
+    DualStack,
+}
+
+/// A TCP connection attempt from a peer to the relayed transport address of a TCP allocation.
+#[derive(Debug, Clone)]
+pub struct ConnectionAttempt {
+    peer: SocketAddr,
+    connection_id: u32,
+}
+impl ConnectionAttempt {
+    /// Returns the address of the peer.
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer
+    }
+
+    /// Returns the identifier of the peer data connection.
+    pub fn connection_id(&self) -> u32 {
+        self.connection_id
+    }
+}
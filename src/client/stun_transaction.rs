@@ -2,7 +2,6 @@ use crate::attribute::Attribute;
 use futures::{Future, Poll};
 use rustun::message::{MessageError, Response};
 use std::fmt;
-use std::net::SocketAddr;
 
 pub struct StunTransaction<T = Response<Attribute>>(
     Box<dyn Future<Item = T, Error = MessageError> + Send + 'static>,
@@ -15,8 +14,11 @@ impl StunTransaction<Response<Attribute>> {
         StunTransaction(Box::new(future.fuse()))
     }
 }
-impl StunTransaction<(SocketAddr, Response<Attribute>)> {
-    pub fn with_peer<F>(peer: SocketAddr, future: F) -> Self
+impl<P> StunTransaction<(P, Response<Attribute>)>
+where
+    P: Send + 'static,
+{
+    pub fn with_peer<F>(peer: P, future: F) -> Self
     where
         F: Future<Item = Response<Attribute>, Error = MessageError> + Send + 'static,
     {
@@ -57,8 +57,18 @@
 //! # References
 //!
 //! - [RFC 5766: Traversal Using Relays around NAT (TURN)][RFC 5766]
+//! - [RFC 6062: TURN Extensions for TCP Allocations][RFC 6062]
+//! - [RFC 7635: Session Traversal Utilities for NAT (STUN) Extension for Third-Party Authorization][RFC 7635]
+//! - [RFC 8016: Mobility with Traversal Using Relays around NAT (TURN)][RFC 8016]
+//! - [RFC 8489: Session Traversal Utilities for NAT (STUN)][RFC 8489] (SHA-256 message integrity and password algorithms)
+//! - [RFC 8656: Traversal Using Relays around NAT (TURN)][RFC 8656] (IPv6 and dual-stack allocations)
 //!
 //! [RFC 5766]: https://tools.ietf.org/html/rfc5766
+//! [RFC 6062]: https://tools.ietf.org/html/rfc6062
+//! [RFC 7635]: https://tools.ietf.org/html/rfc7635
+//! [RFC 8016]: https://tools.ietf.org/html/rfc8016
+//! [RFC 8489]: https://tools.ietf.org/html/rfc8489
+//! [RFC 8656]: https://tools.ietf.org/html/rfc8656
 #[macro_use]
 extern crate bytecodec;
 #[cfg(test)]
@@ -68,12 +78,19 @@ extern crate stun_codec;
 #[macro_use]
 extern crate trackable;
 
+pub use rustls;
 pub use rustun::{Error, ErrorKind, Result};
 
+#[macro_use]
+mod macros;
+
 pub mod attribute;
 
 pub mod auth;
 pub mod client;
+pub mod rfc6062;
+pub mod rfc7635;
+pub mod rfc8489;
 pub mod server;
 pub mod transport;
 
@@ -116,13 +133,175 @@ mod tests {
 
     use super::*;
     use auth::AuthParams;
+    use std::net::SocketAddr;
     use transport::UdpOverTurnTransporter;
 
+    /// Helpers for sending hand-crafted STUN requests over a plain UDP socket.
+    mod raw {
+        use super::*;
+        use attribute::Attribute;
+        use bytecodec::{DecodeExt, EncodeExt};
+        use rfc8489::attributes::PasswordAlgorithms;
+        use std::net::UdpSocket;
+        use std::time::Duration;
+
+        /// Sends a request and returns the raw bytes of the response.
+        pub fn call(socket: &UdpSocket, server: SocketAddr, request: &[u8]) -> Vec<u8> {
+            socket.send_to(request, server).unwrap();
+            let mut buf = [0; 1024];
+            let (size, _) = socket.recv_from(&mut buf).unwrap();
+            buf[..size].to_vec()
+        }
+
+        /// Reads a message from a TCP (or TLS) connection and returns its raw bytes.
+        pub fn read_message<R: std::io::Read>(stream: &mut R) -> Vec<u8> {
+            let mut buf = vec![0; 20];
+            stream.read_exact(&mut buf).unwrap();
+            let size = u16::from_be_bytes([buf[2], buf[3]]) as usize;
+            buf.resize(20 + size, 0);
+            stream.read_exact(&mut buf[20..]).unwrap();
+            buf
+        }
+
+        pub fn encode(request: Request<Attribute>) -> Vec<u8> {
+            MessageEncoder::new()
+                .encode_into_bytes(request.into_message())
+                .unwrap()
+        }
+
+        pub fn decode(bytes: &[u8]) -> stun_codec::Message<Attribute> {
+            MessageDecoder::<Attribute>::new()
+                .decode_from_bytes(bytes)
+                .unwrap()
+                .unwrap()
+        }
+
+        pub fn error_code(message: &stun_codec::Message<Attribute>) -> Option<u16> {
+            message
+                .get_attribute::<rfc5389::attributes::ErrorCode>()
+                .map(|e| e.code())
+        }
+
+        pub fn allocate_request() -> Request<Attribute> {
+            let mut request = Request::new(stun_codec::rfc5766::methods::ALLOCATE);
+            request
+                .add_attribute(stun_codec::rfc5766::attributes::RequestedTransport::new(17).into());
+            request
+        }
+
+        /// Returns a new socket and the parameters negotiated with `server` via that socket.
+        pub fn authenticate(
+            server: SocketAddr,
+            username: &str,
+            password: &str,
+        ) -> Result<(UdpSocket, AuthParams)> {
+            let socket = track!(UdpSocket::bind("127.0.0.1:0").map_err(Error::from))?;
+            track!(socket
+                .set_read_timeout(Some(Duration::from_secs(1)))
+                .map_err(Error::from))?;
+            let auth_params = track!(negotiate(&socket, server, username, password))?;
+            Ok((socket, auth_params))
+        }
+
+        /// Returns the parameters negotiated with `server` by an unauthenticated Allocate.
+        pub fn negotiate(
+            socket: &UdpSocket,
+            server: SocketAddr,
+            username: &str,
+            password: &str,
+        ) -> Result<AuthParams> {
+            let response = decode(&call(socket, server, &encode(allocate_request())));
+            assert_eq!(error_code(&response), Some(401));
+            let realm = response.get_attribute::<rfc5389::attributes::Realm>();
+            let nonce = response.get_attribute::<rfc5389::attributes::Nonce>();
+            let mut auth_params = track!(AuthParams::new(username, password))?;
+            auth_params.set_realm(track_assert_some!(realm, ErrorKind::Other).clone());
+            auth_params.set_nonce(track_assert_some!(nonce, ErrorKind::Other).clone());
+            track!(auth_params.negotiate(response.get_attribute::<PasswordAlgorithms>()))?;
+            Ok(auth_params)
+        }
+    }
+
+    /// Spawns a UDP echo server (peer) and returns its address.
+    fn spawn_echo_peer() -> Result<SocketAddr> {
+        let peer = track!(std::net::UdpSocket::bind("127.0.0.1:0").map_err(Error::from))?;
+        let peer_addr = track!(peer.local_addr().map_err(Error::from))?;
+        std::thread::spawn(move || {
+            let mut buf = [0; 1024];
+            while let Ok((size, from)) = peer.recv_from(&mut buf) {
+                let _ = peer.send_to(&buf[..size], from);
+            }
+        });
+        Ok(peer_addr)
+    }
+
+    /// Starts a UDP server on the loopback address for the user `foo` (password `bar`) in the realm `baz`.
+    fn start_server(options: server::ServerOptions) -> Result<(SocketAddr, server::ServerHandle)> {
+        let turn_server = track!(fibers_global::execute(
+            server::UdpServer::start_with_options(
+                "127.0.0.1:0".parse().unwrap(),
+                track!(AuthParams::with_realm_and_nonce("foo", "bar", "baz", "qux"))?,
+                options,
+            )
+        ))?;
+        let turn_server_addr = turn_server.local_addr();
+        let handle = turn_server.handle();
+        fibers_global::spawn(turn_server.map_err(|e| panic!("{}", e)));
+        Ok((turn_server_addr, handle))
+    }
+
+    /// Sends the pending data of `turn_client` and returns the first data relayed from a peer.
+    fn recv_one<C>(turn_client: C) -> Result<(C, SocketAddr, Vec<u8>)>
+    where
+        C: client::Client + Send + 'static,
+    {
+        let mut turn_client = Some(turn_client);
+        track!(fibers_global::execute(futures::future::poll_fn(
+            move || {
+                let client = turn_client.as_mut().expect("never fails");
+                track!(client.poll_send())?;
+                if let futures::Async::Ready(item) = track!(client.poll_recv())? {
+                    let (peer, data) = item.expect("never fails");
+                    let client = turn_client.take().expect("never fails");
+                    return Ok(futures::Async::Ready((client, peer, data)));
+                }
+                Ok::<_, Error>(futures::Async::NotReady)
+            }
+        )))
+    }
+
+    /// Observer that reports the five-tuples of the allocations deleted by the server.
+    struct Teardowns(std::sync::Mutex<std::sync::mpsc::Sender<server::FiveTuple>>);
+    impl Teardowns {
+        fn new() -> (Self, std::sync::mpsc::Receiver<server::FiveTuple>) {
+            let (tx, rx) = std::sync::mpsc::channel();
+            (Teardowns(std::sync::Mutex::new(tx)), rx)
+        }
+
+        fn notify(&self, allocation: &server::AllocationInfo) {
+            let _ = self.0.lock().unwrap().send(allocation.five_tuple());
+        }
+    }
+    impl server::ServerObserver for Teardowns {
+        fn allocation_kicked(&self, allocation: &server::AllocationInfo) {
+            self.notify(allocation);
+        }
+        fn allocation_revoked(&self, allocation: &server::AllocationInfo) {
+            self.notify(allocation);
+        }
+    }
+
+    /// Waits until an allocation is kicked or revoked and returns its five-tuple.
+    fn wait_teardown(
+        teardowns: &std::sync::mpsc::Receiver<server::FiveTuple>,
+    ) -> Result<server::FiveTuple> {
+        let five_tuple = teardowns.recv_timeout(std::time::Duration::from_secs(5));
+        Ok(track_assert_some!(five_tuple.ok(), ErrorKind::Other))
+    }
+
     #[test]
     fn it_works() -> std::result::Result<(), MainError> {
         let client_auth_params = track!(AuthParams::new("foo", "bar"))?;
-        let server_auth_params =
-            track!(AuthParams::with_realm_and_nonce("foo", "bar", "baz", "qux"))?;
 
         // STUN server (peer)
         let stun_server = fibers_global::execute(rustun::server::UdpServer::start(
@@ -134,13 +313,275 @@ mod tests {
         fibers_global::spawn(stun_server.map(|_| ()).map_err(|e| panic!("{}", e)));
 
         // TURN server
-        let turn_server = fibers_global::execute(server::UdpServer::start(
+        let (turn_server_addr, _) = track!(start_server(server::ServerOptions::new()))?;
+
+        // TURN client
+        let turn_client = track!(fibers_global::execute(client::UdpClient::allocate(
+            turn_server_addr,
+            client_auth_params
+        )))?;
+        let transporter =
+            UdpOverTurnTransporter::<_, MessageEncoder<_>, MessageDecoder<_>>::new(turn_client);
+
+        // STUN client (over TURN)
+        let stun_channel = rustun::channel::Channel::new(StunUdpTransporter::new(transporter));
+        let stun_client = rustun::client::Client::new(&fibers_global::handle(), stun_channel);
+
+        // BINDING request
+        let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+        let response = track!(fibers_global::execute(
+            stun_client.call(stun_server_addr, request)
+        ))?;
+        assert!(response.is_ok(), "{:?}", response);
+
+        Ok(())
+    }
+
+    #[test]
+    fn dual_stack_allocation_works() -> std::result::Result<(), MainError> {
+        let client_auth_params = track!(AuthParams::new("foo", "bar"))?;
+
+        // STUN server (IPv6 peer)
+        let stun_server = fibers_global::execute(rustun::server::UdpServer::start(
+            fibers_global::handle(),
+            "[::1]:0".parse().unwrap(),
+            rustun::server::BindingHandler,
+        ))?;
+        let stun_server_addr = stun_server.local_addr();
+        fibers_global::spawn(stun_server.map(|_| ()).map_err(|e| panic!("{}", e)));
+
+        // TURN servers
+        let mut options = server::ServerOptions::new();
+        options
+            .relay_ipv4(Some("127.0.0.1".parse().unwrap()))
+            .relay_ipv6(Some("::1".parse().unwrap()));
+        let (turn_server_addr, _) = track!(start_server(options))?;
+
+        let (ipv4_only_turn_server_addr, _) = track!(start_server(server::ServerOptions::new()))?;
+
+        // IPv6 allocations are rejected by the IPv4-only server
+        let result = fibers_global::execute(client::UdpClient::allocate_with_family(
+            ipv4_only_turn_server_addr,
+            client_auth_params.clone(),
+            client::RelayAddressFamily::Ipv6,
+        ));
+        assert!(result.is_err());
+
+        // TURN client
+        let turn_client = track!(fibers_global::execute(
+            client::UdpClient::allocate_with_family(
+                turn_server_addr,
+                client_auth_params,
+                client::RelayAddressFamily::DualStack,
+            )
+        ))?;
+        assert_eq!(turn_client.relay_addrs().len(), 2);
+        assert!(turn_client.relay_addrs()[0].is_ipv4());
+        assert!(turn_client.relay_addrs()[1].is_ipv6());
+        let transporter =
+            UdpOverTurnTransporter::<_, MessageEncoder<_>, MessageDecoder<_>>::new(turn_client);
+
+        // STUN client (over TURN)
+        let stun_channel = rustun::channel::Channel::new(StunUdpTransporter::new(transporter));
+        let stun_client = rustun::client::Client::new(&fibers_global::handle(), stun_channel);
+
+        // BINDING request
+        let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+        let response = track!(fibers_global::execute(
+            stun_client.call(stun_server_addr, request)
+        ))?;
+        assert!(response.is_ok(), "{:?}", response);
+
+        Ok(())
+    }
+
+    #[test]
+    fn mobility_works() -> std::result::Result<(), MainError> {
+        // TURN server
+        let (turn_server_addr, _) = track!(start_server(server::ServerOptions::new()))?;
+
+        check_mobility(turn_server_addr)
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn sharded_server_works() -> std::result::Result<(), MainError> {
+        use client::Client;
+
+        let server_auth_params =
+            track!(AuthParams::with_realm_and_nonce("foo", "bar", "baz", "qux"))?;
+
+        // UDP echo server (peer)
+        let peer_addr = track!(spawn_echo_peer())?;
+
+        // TURN server
+        let turn_server = fibers_global::execute(server::ShardedUdpServer::start(
+            fibers_global::handle(),
             "127.0.0.1:0".parse().unwrap(),
             server_auth_params,
+            server::ServerOptions::new(),
+            16,
         ))?;
         let turn_server_addr = turn_server.local_addr();
+        let handle = turn_server.handle();
         fibers_global::spawn(turn_server.map_err(|e| panic!("{}", e)));
 
+        // TURN clients
+        for i in 0..4 {
+            let turn_client = track!(fibers_global::execute(client::UdpClient::allocate(
+                turn_server_addr,
+                track!(AuthParams::new("foo", "bar"))?
+            )))?;
+            let (mut turn_client, result) = track!(fibers_global::execute(client::wait(
+                turn_client,
+                move |client| client.create_permission(peer_addr)
+            )))?;
+            track!(result)?;
+            assert_eq!(handle.allocations().len(), i + 1);
+
+            track!(turn_client.start_send(peer_addr, b"hello".to_vec()))?;
+            let (_, from, data) = track!(recv_one(turn_client))?;
+            assert_eq!(from, peer_addr);
+            assert_eq!(data, b"hello");
+        }
+
+        // Allocations are moved between shards
+        check_mobility(turn_server_addr)
+    }
+
+    /// Checks that an allocation survives the change of the client address.
+    fn check_mobility(turn_server_addr: SocketAddr) -> std::result::Result<(), MainError> {
+        use client::Client;
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+
+        let client_auth_params = track!(AuthParams::new("foo", "bar"))?;
+
+        // UDP echo server (peer)
+        let peer_addr = track!(spawn_echo_peer())?;
+
+        // NAT that changes the address of the client when `switched` is set
+        let switched = Arc::new(AtomicBool::new(false));
+        let nat = track!(std::net::UdpSocket::bind("127.0.0.1:0").map_err(Error::from))?;
+        let nat_addr = track!(nat.local_addr().map_err(Error::from))?;
+        {
+            let switched = Arc::clone(&switched);
+            let outer = [
+                std::net::UdpSocket::bind("127.0.0.1:0").unwrap(),
+                std::net::UdpSocket::bind("127.0.0.1:0").unwrap(),
+            ];
+            nat.set_nonblocking(true).unwrap();
+            for socket in &outer {
+                socket.set_nonblocking(true).unwrap();
+            }
+            std::thread::spawn(move || {
+                let mut buf = [0; 2048];
+                let mut client = None;
+                loop {
+                    if let Ok((size, from)) = nat.recv_from(&mut buf) {
+                        client = Some(from);
+                        let i = switched.load(Ordering::SeqCst) as usize;
+                        let _ = outer[i].send_to(&buf[..size], turn_server_addr);
+                    }
+                    for socket in &outer {
+                        if let (Ok((size, _)), Some(client)) = (socket.recv_from(&mut buf), client)
+                        {
+                            let _ = nat.send_to(&buf[..size], client);
+                        }
+                    }
+                    std::thread::sleep(std::time::Duration::from_millis(1));
+                }
+            });
+        }
+
+        // TURN client
+        let turn_client = track!(fibers_global::execute(
+            client::UdpClientBuilder::new()
+                .mobility(true)
+                .allocate(nat_addr, client_auth_params)
+        ))?;
+        let (turn_client, result) = track!(fibers_global::execute(client::wait(
+            turn_client,
+            move |client| client.create_permission(peer_addr)
+        )))?;
+        track!(result)?;
+
+        // Changes the address of the client
+        switched.store(true, Ordering::SeqCst);
+        let other_peer_addr = "127.0.0.1:1".parse().unwrap();
+        let (mut turn_client, result) = track!(fibers_global::execute(client::wait(
+            turn_client,
+            move |client| client.create_permission(other_peer_addr)
+        )))?;
+        track!(result)?;
+
+        // The permission created before the change is still available
+        track!(turn_client.start_send(peer_addr, b"hello".to_vec()))?;
+        let (_, from, data) = track!(recv_one(turn_client))?;
+        assert_eq!(from, peer_addr);
+        assert_eq!(data, b"hello");
+
+        Ok(())
+    }
+
+    #[test]
+    fn redirect_works() -> std::result::Result<(), MainError> {
+        let client_auth_params = track!(AuthParams::new("foo", "bar"))?;
+
+        // TURN servers
+        let (turn_server_addr, _) = track!(start_server(server::ServerOptions::new()))?;
+
+        let mut options = server::ServerOptions::new();
+        options.redirect_policy(move |_| Some(turn_server_addr));
+        let (redirecting_server_addr, _) = track!(start_server(options))?;
+
+        // TURN client
+        let turn_client = track!(fibers_global::execute(client::UdpClient::allocate(
+            redirecting_server_addr,
+            client_auth_params
+        )))?;
+        assert!(turn_client.relay_addr().is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn access_token_works() -> std::result::Result<(), MainError> {
+        let key = vec![7; 16];
+        let mac_key = vec![1; 20];
+        let token = rfc7635::Token::new(mac_key.clone(), std::time::Duration::from_secs(3600));
+        assert!(!format!("{:?}", token).contains("mac_key"));
+        let access_token = track!(token.encrypt(&key, "turn.example.com"))?;
+        assert!(rfc7635::Token::decrypt(&access_token, &key, "other.example.com").is_err());
+        let too_long = std::time::Duration::from_secs(u64::from(u32::MAX) + 1);
+        assert!(rfc7635::Token::new(mac_key.clone(), too_long)
+            .encrypt(&key, "turn.example.com")
+            .is_err());
+
+        let client_auth_params = track!(AuthParams::with_access_token(
+            "kid1",
+            access_token,
+            &mac_key
+        ))?;
+
+        // STUN server (peer)
+        let stun_server = fibers_global::execute(rustun::server::UdpServer::start(
+            fibers_global::handle(),
+            "127.0.0.1:0".parse().unwrap(),
+            rustun::server::BindingHandler,
+        ))?;
+        let stun_server_addr = stun_server.local_addr();
+        fibers_global::spawn(stun_server.map(|_| ()).map_err(|e| panic!("{}", e)));
+
+        // TURN server
+        let mut options = server::ServerOptions::new();
+        options.third_party_authorization(
+            "https://auth.example.com",
+            "turn.example.com",
+            vec![("kid1".to_owned(), key)].into_iter().collect(),
+        );
+        let (turn_server_addr, _) = track!(start_server(options))?;
+
         // TURN client
         let turn_client = track!(fibers_global::execute(client::UdpClient::allocate(
             turn_server_addr,
@@ -162,4 +603,1590 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn multi_listener_server_works() -> std::result::Result<(), MainError> {
+        let server_auth_params =
+            track!(AuthParams::with_realm_and_nonce("foo", "bar", "baz", "qux"))?;
+
+        // TURN server
+        let turn_server = fibers_global::execute(
+            server::ServerBuilder::new(server_auth_params)
+                .udp_listener("127.0.0.1:0".parse().unwrap())
+                .tcp_listener("127.0.0.1:0".parse().unwrap())
+                .finish(fibers_global::handle()),
+        )?;
+        let addrs = turn_server.local_addrs();
+        let handle = turn_server.handle();
+        fibers_global::spawn(turn_server.map_err(|e| panic!("{}", e)));
+
+        // TURN clients
+        let udp_client = track!(fibers_global::execute(client::UdpClient::allocate(
+            addrs[0].1,
+            track!(AuthParams::new("foo", "bar"))?
+        )))?;
+        let tcp_client = track!(fibers_global::execute(client::TcpClient::allocate(
+            addrs[1].1,
+            track!(AuthParams::new("foo", "bar"))?
+        )))?;
+
+        let mut allocations = handle.allocations();
+        allocations.sort_by_key(|a| a.transport() == server::TransportProtocol::Tcp);
+        assert_eq!(allocations.len(), 2);
+        assert_eq!(allocations[0].transport(), server::TransportProtocol::Udp);
+        assert_eq!(allocations[0].relay_addrs(), udp_client.relay_addrs());
+        assert_eq!(allocations[1].transport(), server::TransportProtocol::Tcp);
+        assert_eq!(allocations[1].relay_addrs(), tcp_client.relay_addrs());
+        assert!(allocations.iter().all(|a| a.username() == "foo"));
+        assert_eq!(handle.stats().created_allocations, 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn five_tuple_works() -> std::result::Result<(), MainError> {
+        use raw::{allocate_request, call, decode, encode, error_code};
+
+        let server_auth_params =
+            track!(AuthParams::with_realm_and_nonce("foo", "bar", "baz", "qux"))?;
+
+        // TURN server
+        let (teardowns, teardowns_rx) = Teardowns::new();
+        let mut options = server::ServerOptions::new();
+        options.observer(teardowns);
+        let turn_server = fibers_global::execute(
+            server::ServerBuilder::new(server_auth_params)
+                .options(options)
+                .udp_listener("127.0.0.1:0".parse().unwrap())
+                .udp_listener("127.0.0.1:0".parse().unwrap())
+                .finish(fibers_global::handle()),
+        )?;
+        let addrs = turn_server.local_addrs();
+        let handle = turn_server.handle();
+        fibers_global::spawn(turn_server.map_err(|e| panic!("{}", e)));
+
+        // The same client address allocates on both listeners
+        let (socket, auth_params) = track!(raw::authenticate(addrs[0].1, "foo", "bar"))?;
+        for &(_, server_addr) in &addrs {
+            let mut request = allocate_request();
+            track!(auth_params.add_auth_attributes(&mut request))?;
+            let response = decode(&call(&socket, server_addr, &encode(request)));
+            assert_eq!(error_code(&response), None);
+        }
+
+        let mut allocations = handle.allocations();
+        allocations.sort_by_key(|a| a.server_addr() != addrs[0].1);
+        assert_eq!(allocations.len(), 2);
+        for (allocation, &(transport, server_addr)) in allocations.iter().zip(&addrs) {
+            let five_tuple = allocation.five_tuple();
+            assert_eq!(
+                five_tuple.client_addr(),
+                track!(socket.local_addr().map_err(Error::from))?
+            );
+            assert_eq!(five_tuple.server_addr(), server_addr);
+            assert_eq!(five_tuple.transport(), transport);
+        }
+
+        // Kicking one of them leaves the other
+        assert!(handle.kick(allocations[0].five_tuple()));
+        let kicked = track!(wait_teardown(&teardowns_rx))?;
+        assert_eq!(kicked, allocations[0].five_tuple());
+        let allocations = handle.allocations();
+        assert_eq!(allocations.len(), 1);
+        assert_eq!(allocations[0].server_addr(), addrs[1].1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn server_without_primary_user_works() -> std::result::Result<(), MainError> {
+        // TURN server
+        let mut options = server::ServerOptions::new();
+        options.users(std::iter::once(("alice".to_owned(), "secret".to_owned())).collect());
+        let turn_server = fibers_global::execute(
+            track!(server::ServerBuilder::without_primary_user("baz", "qux"))?
+                .options(options)
+                .udp_listener("127.0.0.1:0".parse().unwrap())
+                .finish(fibers_global::handle()),
+        )?;
+        let turn_server_addr = turn_server.local_addrs()[0].1;
+        fibers_global::spawn(turn_server.map_err(|e| panic!("{}", e)));
+
+        // Only the users in the options can allocate
+        let result = fibers_global::execute(client::UdpClient::allocate(
+            turn_server_addr,
+            track!(AuthParams::new("", ""))?,
+        ));
+        assert!(result.is_err());
+        let turn_client = track!(fibers_global::execute(client::UdpClient::allocate(
+            turn_server_addr,
+            track!(AuthParams::new("alice", "secret"))?
+        )))?;
+        assert!(turn_client.relay_addr().is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn multi_tenant_works() -> std::result::Result<(), MainError> {
+        use raw::{allocate_request, call, decode, encode};
+
+        // TURN server
+        let mut options = server::ServerOptions::new();
+        options.users(std::iter::once(("alice".to_owned(), "secret".to_owned())).collect());
+        let turn_server = fibers_global::execute(
+            server::ServerBuilder::new(track!(AuthParams::with_realm_and_nonce(
+                "foo", "bar", "baz", "qux"
+            ))?)
+            .tenant(
+                track!(AuthParams::with_realm_and_nonce(
+                    "carol", "pw", "tenant", "qux"
+                ))?,
+                options,
+            )
+            .udp_listener("127.0.0.1:0".parse().unwrap())
+            .udp_listener_for_realm("127.0.0.1:0".parse().unwrap(), "tenant")
+            .finish(fibers_global::handle()),
+        )?;
+        let addrs = turn_server.local_addrs();
+        let handle = turn_server.handle();
+        let tenant_handle =
+            track_assert_some!(turn_server.tenant_handle("tenant"), ErrorKind::Other);
+        assert!(turn_server.tenant_handle("unknown").is_none());
+        fibers_global::spawn(turn_server.map_err(|e| panic!("{}", e)));
+
+        // The REALM depends on the listener
+        let socket = track!(std::net::UdpSocket::bind("127.0.0.1:0").map_err(Error::from))?;
+        for (&(_, server_addr), expected) in addrs.iter().zip(["baz", "tenant"]) {
+            let response = decode(&call(&socket, server_addr, &encode(allocate_request())));
+            let realm = response.get_attribute::<rfc5389::attributes::Realm>();
+            assert_eq!(realm.map(|r| r.text()), Some(expected));
+        }
+
+        // The users of a tenant are unknown to the others
+        for (username, password) in [("carol", "pw"), ("alice", "secret")] {
+            track!(fibers_global::execute(client::UdpClient::allocate(
+                addrs[1].1,
+                track!(AuthParams::new(username, password))?
+            )))?;
+            let result = fibers_global::execute(client::UdpClient::allocate(
+                addrs[0].1,
+                track!(AuthParams::new(username, password))?,
+            ));
+            assert!(result.is_err());
+        }
+        track!(fibers_global::execute(client::UdpClient::allocate(
+            addrs[0].1,
+            track!(AuthParams::new("foo", "bar"))?
+        )))?;
+
+        // Allocations and statistics are isolated
+        let allocations = tenant_handle.allocations();
+        assert_eq!(allocations.len(), 2);
+        assert!(allocations.iter().all(|a| a.realm() == "tenant"));
+        assert_eq!(tenant_handle.stats().created_allocations, 2);
+        let allocations = handle.allocations();
+        assert_eq!(allocations.len(), 1);
+        assert_eq!(allocations[0].realm(), "baz");
+        assert_eq!(handle.stats().created_allocations, 1);
+
+        // Listeners cannot refer to unknown realms
+        let result = fibers_global::execute(
+            server::ServerBuilder::new(track!(AuthParams::with_realm_and_nonce(
+                "foo", "bar", "baz", "qux"
+            ))?)
+            .udp_listener_for_realm("127.0.0.1:0".parse().unwrap(), "unknown")
+            .finish(fibers_global::handle()),
+        );
+        assert!(result.is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn tls_listener_works() -> std::result::Result<(), MainError> {
+        use raw::{allocate_request, decode, encode, error_code, read_message};
+        use rfc8489::attributes::PasswordAlgorithms;
+        use std::io::Write;
+        use std::sync::Arc;
+
+        // Certificate for both server names
+        let certified = rcgen::generate_simple_self_signed(vec![
+            "turn.example.com".to_owned(),
+            "tenant.example.com".to_owned(),
+        ])
+        .unwrap();
+        let cert = certified.cert.der().clone();
+        let key = rustls::pki_types::PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der());
+        let tls_config = Arc::new(
+            rustls::ServerConfig::builder()
+                .with_no_client_auth()
+                .with_single_cert(vec![cert.clone()], key.into())
+                .unwrap(),
+        );
+
+        // TURN server
+        let turn_server = fibers_global::execute(
+            server::ServerBuilder::new(track!(AuthParams::with_realm_and_nonce(
+                "foo", "bar", "baz", "qux"
+            ))?)
+            .tenant(
+                track!(AuthParams::with_realm_and_nonce(
+                    "carol", "pw", "tenant", "qux"
+                ))?,
+                server::ServerOptions::new(),
+            )
+            .tls_listener("127.0.0.1:0".parse().unwrap(), tls_config.clone())
+            .tls_server_name("Tenant.Example.com", "tenant")
+            .finish(fibers_global::handle()),
+        )?;
+        let (transport, server_addr) = turn_server.local_addrs()[0];
+        assert_eq!(transport, server::TransportProtocol::Tls);
+        let tenant_handle =
+            track_assert_some!(turn_server.tenant_handle("tenant"), ErrorKind::Other);
+        fibers_global::spawn(turn_server.map_err(|e| panic!("{}", e)));
+
+        // TLS client
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(cert).unwrap();
+        let client_config = Arc::new(
+            rustls::ClientConfig::builder()
+                .with_root_certificates(roots)
+                .with_no_client_auth(),
+        );
+        let connect = |server_name: &'static str| -> Result<_> {
+            let server_name = rustls::pki_types::ServerName::try_from(server_name).unwrap();
+            let connection =
+                rustls::ClientConnection::new(client_config.clone(), server_name).unwrap();
+            let stream = track!(std::net::TcpStream::connect(server_addr).map_err(Error::from))?;
+            track!(stream
+                .set_read_timeout(Some(std::time::Duration::from_secs(5)))
+                .map_err(Error::from))?;
+            Ok(rustls::StreamOwned::new(connection, stream))
+        };
+
+        // The REALM depends on the server name indicated by the client
+        for (server_name, expected) in [
+            ("turn.example.com", "baz"),
+            ("tenant.example.com", "tenant"),
+        ] {
+            let mut stream = track!(connect(server_name))?;
+            track!(stream
+                .write_all(&encode(allocate_request()))
+                .map_err(Error::from))?;
+            let response = decode(&read_message(&mut stream));
+            let realm = response.get_attribute::<rfc5389::attributes::Realm>();
+            assert_eq!(realm.map(|r| r.text()), Some(expected));
+        }
+
+        // Allocation by a user of the tenant
+        let mut stream = track!(connect("tenant.example.com"))?;
+        track!(stream
+            .write_all(&encode(allocate_request()))
+            .map_err(Error::from))?;
+        let response = decode(&read_message(&mut stream));
+        assert_eq!(error_code(&response), Some(401));
+        let mut auth_params = track!(AuthParams::new("carol", "pw"))?;
+        let realm = response.get_attribute::<rfc5389::attributes::Realm>();
+        let nonce = response.get_attribute::<rfc5389::attributes::Nonce>();
+        auth_params.set_realm(track_assert_some!(realm, ErrorKind::Other).clone());
+        auth_params.set_nonce(track_assert_some!(nonce, ErrorKind::Other).clone());
+        track!(auth_params.negotiate(response.get_attribute::<PasswordAlgorithms>()))?;
+
+        let mut request = allocate_request();
+        track!(auth_params.add_auth_attributes(&mut request))?;
+        track!(stream.write_all(&encode(request)).map_err(Error::from))?;
+        let response = decode(&read_message(&mut stream));
+        assert_eq!(error_code(&response), None);
+
+        let allocations = tenant_handle.allocations();
+        assert_eq!(allocations.len(), 1);
+        assert_eq!(allocations[0].transport(), server::TransportProtocol::Tls);
+
+        // Server names cannot refer to unknown realms
+        let result = fibers_global::execute(
+            server::ServerBuilder::new(track!(AuthParams::with_realm_and_nonce(
+                "foo", "bar", "baz", "qux"
+            ))?)
+            .tls_listener("127.0.0.1:0".parse().unwrap(), tls_config)
+            .tls_server_name("tenant.example.com", "unknown")
+            .finish(fibers_global::handle()),
+        );
+        assert!(result.is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn unpermitted_peer_datagrams_are_dropped() -> std::result::Result<(), MainError> {
+        use client::Client;
+
+        // TURN server
+        let (teardowns, teardowns_rx) = Teardowns::new();
+        let mut options = server::ServerOptions::new();
+        options
+            .relay_ipv4(Some(std::net::Ipv4Addr::LOCALHOST))
+            .observer(teardowns);
+        let (turn_server_addr, handle) = track!(start_server(options))?;
+
+        // TURN client (only 127.0.0.1 is permitted)
+        let turn_client = track!(fibers_global::execute(client::UdpClient::allocate(
+            turn_server_addr,
+            track!(AuthParams::new("foo", "bar"))?
+        )))?;
+        let relay_addr = track_assert_some!(turn_client.relay_addr(), ErrorKind::Other);
+        let (turn_client, result) = track!(fibers_global::execute(client::wait(
+            turn_client,
+            |client| client.create_permission("127.0.0.1:0".parse().unwrap())
+        )))?;
+        track!(result)?;
+
+        // The datagram from the unpermitted peer is dropped and the next one is relayed
+        let unpermitted = track!(std::net::UdpSocket::bind("127.0.0.2:0").map_err(Error::from))?;
+        track!(unpermitted
+            .send_to(b"dropped", relay_addr)
+            .map_err(Error::from))?;
+        let permitted = track!(std::net::UdpSocket::bind("127.0.0.1:0").map_err(Error::from))?;
+        track!(permitted
+            .send_to(b"relayed", relay_addr)
+            .map_err(Error::from))?;
+        let (_, peer, data) = track!(recv_one(turn_client))?;
+        assert_eq!(peer, track!(permitted.local_addr().map_err(Error::from))?);
+        assert_eq!(data, b"relayed");
+
+        // Kicks are still processed
+        let five_tuple = handle.allocations()[0].five_tuple();
+        assert!(handle.kick(five_tuple));
+        assert_eq!(track!(wait_teardown(&teardowns_rx))?, five_tuple);
+
+        Ok(())
+    }
+
+    #[test]
+    fn observer_works() -> std::result::Result<(), MainError> {
+        use client::Client;
+        use std::sync::{Arc, Mutex};
+
+        #[derive(Default)]
+        struct Recorder(Mutex<Vec<String>>);
+        impl server::ServerObserver for Arc<Recorder> {
+            fn allocation_created(&self, allocation: &server::AllocationInfo) {
+                let event = format!("created:{}", allocation.username());
+                self.0.lock().unwrap().push(event);
+            }
+            fn permission_installed(&self, _: &server::AllocationInfo, peer: std::net::IpAddr) {
+                self.0.lock().unwrap().push(format!("permission:{peer}"));
+            }
+        }
+
+        // UDP echo server (peer)
+        let peer_addr = track!(spawn_echo_peer())?;
+
+        // TURN server
+        let recorder = Arc::new(Recorder::default());
+        let mut options = server::ServerOptions::new();
+        options.observer(Arc::clone(&recorder));
+        let (turn_server_addr, handle) = track!(start_server(options))?;
+
+        // TURN client
+        let turn_client = track!(fibers_global::execute(client::UdpClient::allocate(
+            turn_server_addr,
+            track!(AuthParams::new("foo", "bar"))?
+        )))?;
+        let (mut turn_client, result) = track!(fibers_global::execute(client::wait(
+            turn_client,
+            move |client| client.create_permission(peer_addr)
+        )))?;
+        track!(result)?;
+
+        track!(turn_client.start_send(peer_addr, b"hello".to_vec()))?;
+        let (_, from, _) = track!(recv_one(turn_client))?;
+        assert_eq!(from, peer_addr);
+
+        let events = recorder.0.lock().unwrap().clone();
+        assert_eq!(events, ["created:foo", "permission:127.0.0.1"]);
+
+        let traffic = handle.allocations()[0].traffic();
+        assert_eq!(traffic.bytes_to_peers, 5);
+        assert_eq!(traffic.packets_to_peers, 1);
+        assert_eq!(traffic.bytes_from_peers, 5);
+        assert_eq!(traffic.packets_from_peers, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn accounting_works() -> std::result::Result<(), MainError> {
+        use client::Client;
+
+        // UDP echo server (peer)
+        let peer_addr = track!(spawn_echo_peer())?;
+
+        // TURN server
+        let path =
+            std::env::temp_dir().join(format!("rusturn-accounting-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let (teardowns, teardowns_rx) = Teardowns::new();
+        let mut options = server::ServerOptions::new();
+        options
+            .accounting_sink(track!(server::JsonLinesSink::open(&path))?)
+            .observer(teardowns);
+        let (turn_server_addr, handle) = track!(start_server(options))?;
+
+        // TURN client
+        let turn_client = track!(fibers_global::execute(client::UdpClient::allocate(
+            turn_server_addr,
+            track!(AuthParams::new("foo", "bar"))?
+        )))?;
+        let (mut turn_client, result) = track!(fibers_global::execute(client::wait(
+            turn_client,
+            move |client| client.create_permission(peer_addr)
+        )))?;
+        track!(result)?;
+
+        track!(turn_client.start_send(peer_addr, b"hello".to_vec()))?;
+        track!(recv_one(turn_client))?;
+
+        // Kicks the allocation
+        // (the record is written before the observer is notified)
+        let five_tuple = handle.allocations()[0].five_tuple();
+        assert!(handle.kick(five_tuple));
+        track!(wait_teardown(&teardowns_rx))?;
+        let content = track!(std::fs::read_to_string(&path).map_err(Error::from))?;
+        let _ = std::fs::remove_file(&path);
+        assert!(handle.allocations().is_empty());
+
+        let record: serde_json::Value = serde_json::from_str(content.trim()).unwrap();
+        assert_eq!(record["username"], "foo");
+        assert_eq!(record["realm"], "baz");
+        assert_eq!(record["client_addr"], five_tuple.client_addr().to_string());
+        assert_eq!(record["server_addr"], turn_server_addr.to_string());
+        assert_eq!(record["reason"], "admin_kicked");
+        assert_eq!(record["peers"][0]["peer"], peer_addr.to_string());
+        assert_eq!(record["peers"][0]["bytes_to_peer"], 5);
+        assert_eq!(record["peers"][0]["bytes_from_peer"], 5);
+
+        Ok(())
+    }
+
+    #[test]
+    fn create_permissions_works() -> std::result::Result<(), MainError> {
+        use client::Client;
+        use std::sync::{Arc, Mutex};
+
+        #[derive(Default)]
+        struct Recorder(Mutex<Vec<std::net::IpAddr>>);
+        impl server::ServerObserver for Arc<Recorder> {
+            fn permission_installed(&self, _: &server::AllocationInfo, peer: std::net::IpAddr) {
+                self.0.lock().unwrap().push(peer);
+            }
+        }
+
+        // TURN server
+        let recorder = Arc::new(Recorder::default());
+        let mut acl = server::PeerAcl::new();
+        acl.deny(track!("127.0.0.3/32".parse())?);
+        let mut options = server::ServerOptions::new();
+        options.observer(Arc::clone(&recorder)).peer_acl(acl);
+        let (turn_server_addr, _) = track!(start_server(options))?;
+
+        // TURN client
+        let turn_client = track!(fibers_global::execute(client::UdpClient::allocate(
+            turn_server_addr,
+            track!(AuthParams::new("foo", "bar"))?
+        )))?;
+        let peers: [SocketAddr; 2] = [
+            "127.0.0.1:1".parse().unwrap(),
+            "127.0.0.2:1".parse().unwrap(),
+        ];
+        let (turn_client, result) = track!(fibers_global::execute(client::wait(
+            turn_client,
+            move |client| client.create_permissions(&peers)
+        )))?;
+        assert!(result.is_ok());
+        let installed = recorder.0.lock().unwrap().clone();
+        assert_eq!(installed, peers.iter().map(|p| p.ip()).collect::<Vec<_>>());
+
+        // Peers that share an IP address need a single permission
+        let peers: [SocketAddr; 2] = [
+            "127.0.0.5:1".parse().unwrap(),
+            "127.0.0.5:2".parse().unwrap(),
+        ];
+        let (turn_client, result) = track!(fibers_global::execute(client::wait(
+            turn_client,
+            move |client| client.create_permissions(&peers)
+        )))?;
+        assert!(result.is_ok());
+        assert_eq!(recorder.0.lock().unwrap().len(), 3);
+
+        // A single forbidden peer rejects the whole batch
+        let peers: [SocketAddr; 2] = [
+            "127.0.0.4:1".parse().unwrap(),
+            "127.0.0.3:1".parse().unwrap(),
+        ];
+        let (_turn_client, result) = track!(fibers_global::execute(client::wait(
+            turn_client,
+            move |client| client.create_permissions(&peers)
+        )))?;
+        assert!(result.is_err());
+        assert_eq!(recorder.0.lock().unwrap().len(), 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn policy_works() -> std::result::Result<(), MainError> {
+        use client::Client;
+        use std::sync::{Arc, Mutex};
+        use stun_codec::rfc5766::errors::Forbidden;
+
+        // UDP echo servers (peers)
+        let mut peer_addrs = Vec::new();
+        for _ in 0..2 {
+            peer_addrs.push(track!(spawn_echo_peer())?);
+        }
+        let denied_peer = peer_addrs[1];
+
+        struct Rules {
+            denied_peer: Mutex<Option<SocketAddr>>,
+            consulted_peers: Mutex<Vec<SocketAddr>>,
+        }
+        impl server::Policy for Arc<Rules> {
+            fn allocate(&self, _: server::FiveTuple, username: &str) -> server::PolicyResult {
+                if username == "alice" {
+                    Err(Forbidden.into())
+                } else {
+                    Ok(())
+                }
+            }
+            fn create_permission(
+                &self,
+                _: &server::AllocationInfo,
+                peer: std::net::IpAddr,
+            ) -> server::PolicyResult {
+                if peer == std::net::IpAddr::from([127, 0, 0, 2]) {
+                    Err(Forbidden.into())
+                } else {
+                    Ok(())
+                }
+            }
+            fn send(&self, _: &server::AllocationInfo, peer: SocketAddr) -> server::PolicyResult {
+                self.consulted_peers.lock().unwrap().push(peer);
+                if Some(peer) == *self.denied_peer.lock().unwrap() {
+                    Err(Forbidden.into())
+                } else {
+                    Ok(())
+                }
+            }
+        }
+
+        // TURN server
+        let rules = Arc::new(Rules {
+            denied_peer: Mutex::new(Some(denied_peer)),
+            consulted_peers: Mutex::default(),
+        });
+        let mut options = server::ServerOptions::new();
+        options
+            .users(std::iter::once(("alice".to_owned(), "secret".to_owned())).collect())
+            .policy(Arc::clone(&rules));
+        let (turn_server_addr, _) = track!(start_server(options))?;
+
+        // Allocate
+        let result = fibers_global::execute(client::UdpClient::allocate(
+            turn_server_addr,
+            track!(AuthParams::new("alice", "secret"))?,
+        ));
+        assert!(result.is_err());
+        let turn_client = track!(fibers_global::execute(client::UdpClient::allocate(
+            turn_server_addr,
+            track!(AuthParams::new("foo", "bar"))?
+        )))?;
+
+        // CreatePermission
+        let peers: [SocketAddr; 2] = [peer_addrs[0], "127.0.0.2:1".parse().unwrap()];
+        let (turn_client, result) = track!(fibers_global::execute(client::wait(
+            turn_client,
+            move |client| client.create_permissions(&peers)
+        )))?;
+        assert!(result.is_err());
+        let (mut turn_client, result) = track!(fibers_global::execute(client::wait(
+            turn_client,
+            |client| client.create_permission("127.0.0.1:0".parse().unwrap())
+        )))?;
+        track!(result)?;
+
+        // Send (the policy is consulted only for the first data to each peer)
+        for _ in 0..2 {
+            track!(turn_client.start_send(denied_peer, b"denied".to_vec()))?;
+            track!(turn_client.start_send(peer_addrs[0], b"allowed".to_vec()))?;
+        }
+        let mut turn_client = turn_client;
+        for _ in 0..2 {
+            let (client, peer, data) = track!(recv_one(turn_client))?;
+            assert_eq!(peer, peer_addrs[0]);
+            assert_eq!(data, b"allowed");
+            turn_client = client;
+        }
+        let consulted_peers = rules.consulted_peers.lock().unwrap().clone();
+        assert_eq!(consulted_peers, [denied_peer, peer_addrs[0]]);
+
+        // The policy is consulted again after the permission is refreshed
+        *rules.denied_peer.lock().unwrap() = None;
+        let (mut turn_client, result) = track!(fibers_global::execute(client::wait(
+            turn_client,
+            |client| client.create_permission("127.0.0.1:0".parse().unwrap())
+        )))?;
+        track!(result)?;
+        track!(turn_client.start_send(denied_peer, b"allowed now".to_vec()))?;
+        let (_turn_client, peer, data) = track!(recv_one(turn_client))?;
+        assert_eq!(peer, denied_peer);
+        assert_eq!(data, b"allowed now");
+
+        Ok(())
+    }
+
+    #[test]
+    fn server_limits_work() -> std::result::Result<(), MainError> {
+        use client::Client;
+
+        // TURN server
+        let mut acl = server::PeerAcl::new();
+        acl.deny(track!("127.0.0.2/32".parse())?);
+        let mut options = server::ServerOptions::new();
+        options
+            .users(std::iter::once(("alice".to_owned(), "secret".to_owned())).collect())
+            .max_allocations_per_user(Some(1))
+            .peer_acl(acl);
+        let (turn_server_addr, _) = track!(start_server(options))?;
+
+        // TURN clients
+        let turn_client = track!(fibers_global::execute(client::UdpClient::allocate(
+            turn_server_addr,
+            track!(AuthParams::new("alice", "secret"))?
+        )))?;
+        let (turn_client, result) = track!(fibers_global::execute(client::wait(
+            turn_client,
+            |client| client.create_permission("127.0.0.1:1".parse().unwrap())
+        )))?;
+        assert!(result.is_ok());
+        let (_turn_client, result) = track!(fibers_global::execute(client::wait(
+            turn_client,
+            |client| client.create_permission("127.0.0.2:1".parse().unwrap())
+        )))?;
+        assert!(result.is_err());
+
+        let result = fibers_global::execute(client::UdpClient::allocate(
+            turn_server_addr,
+            track!(AuthParams::new("alice", "secret"))?,
+        ));
+        assert!(result.is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn reload_works() -> std::result::Result<(), MainError> {
+        use client::Client;
+        use std::sync::{Arc, Mutex};
+
+        #[derive(Clone, Default)]
+        struct Records(Arc<Mutex<Vec<server::AccountingRecord>>>);
+        impl server::AccountingSink for Records {
+            fn write(&self, record: &server::AccountingRecord) -> Result<()> {
+                self.0.lock().unwrap().push(record.clone());
+                Ok(())
+            }
+        }
+
+        // TURN server
+        let records = Records::default();
+        let (teardowns, teardowns_rx) = Teardowns::new();
+        let mut options = server::ServerOptions::new();
+        options
+            .users(std::iter::once(("alice".to_owned(), "secret".to_owned())).collect())
+            .default_allocation_lifetime(std::time::Duration::from_secs(1))
+            .accounting_sink(records.clone())
+            .observer(teardowns);
+        let (turn_server_addr, handle) = track!(start_server(options.clone()))?;
+
+        let mut alice = track!(fibers_global::execute(client::UdpClient::allocate(
+            turn_server_addr,
+            track!(AuthParams::new("alice", "secret"))?
+        )))?;
+        fibers_global::spawn(futures::future::poll_fn(move || {
+            Ok(alice.poll_recv().map_err(|_| ())?.map(|_| ()))
+        }));
+
+        // Replaces alice with bob
+        options
+            .users(std::iter::once(("bob".to_owned(), "secret".to_owned())).collect())
+            .revoke_removed_users(true);
+        handle.reload(&options);
+        let _bob = track!(fibers_global::execute(client::UdpClient::allocate(
+            turn_server_addr,
+            track!(AuthParams::new("bob", "secret"))?
+        )))?;
+
+        // The allocation of alice is revoked at its next refresh
+        track!(wait_teardown(&teardowns_rx))?;
+        let records = records.0.lock().unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].username, "alice");
+        assert_eq!(records[0].reason, server::TeardownReason::Revoked);
+        assert_eq!(handle.allocations().len(), 1);
+        assert_eq!(handle.allocations()[0].username(), "bob");
+
+        Ok(())
+    }
+
+    #[test]
+    fn flood_protection_works() -> std::result::Result<(), MainError> {
+        use std::time::Duration;
+
+        // Sends a BINDING request and returns whether a response arrives
+        fn binding(socket: &std::net::UdpSocket, server: SocketAddr) -> bool {
+            let mut request = vec![0x00, 0x01, 0x00, 0x00, 0x21, 0x12, 0xA4, 0x42];
+            request.extend_from_slice(&rand::random::<[u8; 12]>());
+            socket.send_to(&request, server).unwrap();
+            socket.recv_from(&mut [0; 1024]).is_ok()
+        }
+
+        let socket = track!(std::net::UdpSocket::bind("127.0.0.1:0").map_err(Error::from))?;
+        track!(socket
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .map_err(Error::from))?;
+
+        // Rate limit
+        let mut options = server::ServerOptions::new();
+        options.unauthenticated_rate_limit(Some(server::RateLimit::new(1, 2)));
+        let (turn_server_addr, handle) = track!(start_server(options))?;
+
+        assert!(binding(&socket, turn_server_addr));
+        assert!(binding(&socket, turn_server_addr));
+        assert!(!binding(&socket, turn_server_addr));
+        assert_eq!(handle.stats().rate_limited_requests, 1);
+
+        // Ban
+        let mut options = server::ServerOptions::new();
+        options.auth_failure_ban(Some(server::BanPolicy::new(
+            2,
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+        )));
+        let (turn_server_addr, handle) = track!(start_server(options))?;
+
+        for _ in 0..2 {
+            let result = fibers_global::execute(client::UdpClient::allocate(
+                turn_server_addr,
+                track!(AuthParams::new("foo", "wrong"))?,
+            ));
+            assert!(result.is_err());
+        }
+        assert!(!binding(&socket, turn_server_addr));
+        let stats = handle.stats();
+        assert_eq!(stats.auth_failures, 2);
+        assert_eq!(stats.bans, 1);
+        assert_eq!(stats.banned_requests, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn truncated_message_integrity_sha256_works() -> std::result::Result<(), MainError> {
+        use hmac::{Hmac, Mac};
+        use rfc8489::attributes::MessageIntegritySha256;
+
+        // A request whose `MESSAGE-INTEGRITY-SHA256` is truncated to 16 bytes
+        let key = b"secret";
+        let mut bytes = raw::encode(raw::allocate_request());
+        let len = bytes.len() - 20 + 4 + 16;
+        bytes[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+        let mut hmac = Hmac::<sha2::Sha256>::new_from_slice(key).unwrap();
+        hmac.update(&bytes);
+        bytes.extend_from_slice(&[0x00, 0x1C, 0x00, 0x10]);
+        bytes.extend_from_slice(&hmac.finalize().into_bytes()[..16]);
+
+        let message = raw::decode(&bytes);
+        let integrity = track_assert_some!(
+            message.get_attribute::<MessageIntegritySha256>(),
+            ErrorKind::Other
+        );
+        assert_eq!(integrity.hmac_sha256().len(), 16);
+        assert!(integrity.check(key));
+        assert!(!integrity.check(b"wrong"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn password_algorithms_work() -> std::result::Result<(), MainError> {
+        use attribute::Attribute;
+        use auth::{LongTermKey, PasswordAlgorithm};
+        use bytecodec::{DecodeExt, EncodeExt};
+        use rfc8489::attributes::PasswordAlgorithms;
+        use std::time::Duration;
+
+        // TURN server (SHA-256 is preferred by default)
+        let key = LongTermKey::derive(PasswordAlgorithm::Sha256, "alice", "baz", "secret");
+        let mut options = server::ServerOptions::new();
+        options.user_keys(std::iter::once(("alice".to_owned(), key)).collect());
+        let (turn_server_addr, _) = track!(start_server(options))?;
+
+        for (username, password) in [("foo", "bar"), ("alice", "secret")] {
+            track!(fibers_global::execute(client::UdpClient::allocate(
+                turn_server_addr,
+                track!(AuthParams::new(username, password))?
+            )))?;
+        }
+
+        // Bid-down attempt (the advertised algorithms are replaced with MD5 only)
+        let nonce = rfc8489::nonce_with_features(rfc8489::FEATURE_PASSWORD_ALGORITHMS, "qux");
+        let mut auth_params = track!(AuthParams::with_realm_and_nonce(
+            "foo", "bar", "baz", &nonce
+        ))?;
+        track!(auth_params.negotiate(Some(&PasswordAlgorithms::new(&[PasswordAlgorithm::Md5]))))?;
+        let mut request = Request::<Attribute>::new(stun_codec::rfc5766::methods::ALLOCATE);
+        request.add_attribute(stun_codec::rfc5766::attributes::RequestedTransport::new(17).into());
+        track!(auth_params.add_auth_attributes(&mut request))?;
+        let request = track!(MessageEncoder::new()
+            .encode_into_bytes(request.into_message())
+            .map_err(Error::from))?;
+
+        let socket = track!(std::net::UdpSocket::bind("127.0.0.1:0").map_err(Error::from))?;
+        track!(socket
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .map_err(Error::from))?;
+        track!(socket
+            .send_to(&request, turn_server_addr)
+            .map_err(Error::from))?;
+        let mut buf = [0; 1024];
+        let (size, _) = track!(socket.recv_from(&mut buf).map_err(Error::from))?;
+        let response = track!(MessageDecoder::<Attribute>::new()
+            .decode_from_bytes(&buf[..size])
+            .map_err(Error::from))?;
+        let response = response.expect("broken message");
+        let error = response
+            .get_attribute::<stun_codec::rfc5389::attributes::ErrorCode>()
+            .map(|e| e.code());
+        assert_eq!(error, Some(400));
+
+        // RFC 5389 compatible server
+        let mut options = server::ServerOptions::new();
+        options.password_algorithms(Vec::new());
+        let (turn_server_addr, _) = track!(start_server(options))?;
+        track!(fibers_global::execute(client::UdpClient::allocate(
+            turn_server_addr,
+            track!(AuthParams::new("foo", "bar"))?
+        )))?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn userhash_works() -> std::result::Result<(), MainError> {
+        use client::Client;
+
+        let mut options = server::ServerOptions::new();
+        options.users(std::iter::once(("alice".to_owned(), "secret".to_owned())).collect());
+        let (turn_server_addr, handle) = track!(start_server(options.clone()))?;
+
+        // Users added by reloading can also be resolved by their hashes
+        options.users(
+            [("alice", "secret"), ("bob", "password")]
+                .iter()
+                .map(|&(u, p)| (u.to_owned(), p.to_owned()))
+                .collect(),
+        );
+        handle.reload(&options);
+
+        for (username, password) in [("foo", "bar"), ("alice", "secret"), ("bob", "password")] {
+            let mut auth_params = track!(AuthParams::new(username, password))?;
+            auth_params.set_userhash(true);
+            let turn_client = track!(fibers_global::execute(client::UdpClient::allocate(
+                turn_server_addr,
+                auth_params
+            )))?;
+            let peer_addr = "127.0.0.1:4000".parse().unwrap();
+            let (_, result) = track!(fibers_global::execute(client::wait(
+                turn_client,
+                move |client| client.create_permission(peer_addr)
+            )))?;
+            track!(result)?;
+        }
+        let mut usernames = handle
+            .allocations()
+            .iter()
+            .map(|a| a.username().to_owned())
+            .collect::<Vec<_>>();
+        usernames.sort();
+        assert_eq!(usernames, ["alice", "bob", "foo"]);
+
+        let mut auth_params = track!(AuthParams::new("alice", "wrong"))?;
+        auth_params.set_userhash(true);
+        let result =
+            fibers_global::execute(client::UdpClient::allocate(turn_server_addr, auth_params));
+        assert!(result.is_err());
+
+        // The username is never sent to servers that do not support `USERHASH`
+        let mut options = server::ServerOptions::new();
+        options.password_algorithms(Vec::new());
+        let (turn_server_addr, _) = track!(start_server(options))?;
+        let mut auth_params = track!(AuthParams::new("foo", "bar"))?;
+        auth_params.set_userhash(true);
+        let result =
+            fibers_global::execute(client::UdpClient::allocate(turn_server_addr, auth_params));
+        assert!(result.is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_attributes_work() -> std::result::Result<(), MainError> {
+        use attribute::Attribute;
+        use bytecodec::{DecodeExt, EncodeExt};
+        use rustun::message::{ErrorResponse, MessageErrorKind};
+        use std::time::Duration;
+        use stun_codec::AttributeType;
+
+        // TURN server
+        let (turn_server_addr, _) = track!(start_server(server::ServerOptions::new()))?;
+
+        // ALLOCATE request with an unknown comprehension-required attribute (0x7FFF)
+        let mut request = vec![0x00, 0x03, 0x00, 0x08, 0x21, 0x12, 0xA4, 0x42];
+        request.extend_from_slice(&rand::random::<[u8; 12]>());
+        request.extend_from_slice(&[0x7F, 0xFF, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00]);
+
+        let socket = track!(std::net::UdpSocket::bind("127.0.0.1:0").map_err(Error::from))?;
+        track!(socket
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .map_err(Error::from))?;
+        track!(socket
+            .send_to(&request, turn_server_addr)
+            .map_err(Error::from))?;
+        let mut buf = [0; 1024];
+        let (size, _) = track!(socket.recv_from(&mut buf).map_err(Error::from))?;
+        let response = track!(MessageDecoder::<Attribute>::new()
+            .decode_from_bytes(&buf[..size])
+            .map_err(Error::from))?;
+        let response = response.expect("broken message");
+        let error = response.get_attribute::<rfc5389::attributes::ErrorCode>();
+        assert_eq!(error.map(|e| e.code()), Some(420));
+        let unknowns = response.get_attribute::<rfc5389::attributes::UnknownAttributes>();
+        assert_eq!(
+            unknowns.map(|a| a.unknowns().to_vec()),
+            Some(vec![AttributeType::new(0x7FFF)])
+        );
+
+        // Server that replies 420 to every request
+        let server = track!(std::net::UdpSocket::bind("127.0.0.1:0").map_err(Error::from))?;
+        let server_addr = track!(server.local_addr().map_err(Error::from))?;
+        std::thread::spawn(move || {
+            let mut buf = [0; 1024];
+            let (size, client) = server.recv_from(&mut buf).unwrap();
+            let request = MessageDecoder::<Attribute>::new()
+                .decode_from_bytes(&buf[..size])
+                .unwrap()
+                .unwrap();
+            let request = Request::from_message(request).unwrap();
+            let mut response =
+                ErrorResponse::new(&request, rfc5389::errors::UnknownAttribute.into());
+            response.add_attribute(
+                rfc5389::attributes::UnknownAttributes::new(vec![AttributeType::new(0x7FFF)])
+                    .into(),
+            );
+            let response = MessageEncoder::new()
+                .encode_into_bytes(response.into_message())
+                .unwrap();
+            server.send_to(&response, client).unwrap();
+        });
+
+        let result = fibers_global::execute(client::UdpClient::allocate(
+            server_addr,
+            track!(AuthParams::new("foo", "bar"))?,
+        ));
+        let e = result.expect_err("must fail");
+        if let ErrorKind::InvalidMessage(MessageErrorKind::UnknownAttributes(types)) = e.kind() {
+            assert_eq!(types, &[AttributeType::new(0x7FFF)]);
+        } else {
+            panic!("unexpected error: {}", e);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn retransmissions_work() -> std::result::Result<(), MainError> {
+        use raw::{allocate_request, call, decode, encode, error_code};
+        use std::time::Duration;
+
+        // TURN server
+        let (turn_server_addr, handle) = track!(start_server(server::ServerOptions::new()))?;
+
+        let (socket, auth_params) = track!(raw::authenticate(turn_server_addr, "foo", "bar"))?;
+
+        // A retransmitted Allocate gets the identical response
+        let mut request = allocate_request();
+        track!(auth_params.add_auth_attributes(&mut request))?;
+        let request = encode(request);
+        let response = call(&socket, turn_server_addr, &request);
+        assert_eq!(error_code(&decode(&response)), None);
+        assert_eq!(call(&socket, turn_server_addr, &request), response);
+        assert_eq!(handle.allocations().len(), 1);
+
+        // Another Allocate from the same 5-tuple is rejected
+        let mut request = allocate_request();
+        track!(auth_params.add_auth_attributes(&mut request))?;
+        let response = decode(&call(&socket, turn_server_addr, &encode(request)));
+        assert_eq!(error_code(&response), Some(437));
+
+        // A retransmitted Refresh (lifetime 0) gets the identical response after the deletion
+        let mut request = Request::new(stun_codec::rfc5766::methods::REFRESH);
+        request.add_attribute(
+            track!(stun_codec::rfc5766::attributes::Lifetime::new(
+                Duration::from_secs(0)
+            ))?
+            .into(),
+        );
+        track!(auth_params.add_auth_attributes(&mut request))?;
+        let request = encode(request);
+        let response = call(&socket, turn_server_addr, &request);
+        assert_eq!(error_code(&decode(&response)), None);
+        assert!(handle.allocations().is_empty());
+        assert_eq!(call(&socket, turn_server_addr, &request), response);
+
+        Ok(())
+    }
+
+    #[test]
+    fn retransmission_follows_finished_transaction() -> std::result::Result<(), MainError> {
+        use bytecodec::EncodeExt;
+        use fibers_transport::UdpTransporter;
+        use rustun::transport::StunUdpTransporterBuilder;
+        use std::time::Duration;
+        use transport::StunRetransmitTransporter;
+
+        // STUN server that answers only the requests selected by the test
+        let server = track!(std::net::UdpSocket::bind("127.0.0.1:0").map_err(Error::from))?;
+        let server_addr = track!(server.local_addr().map_err(Error::from))?;
+        track!(server
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .map_err(Error::from))?;
+        let recv_request = || -> Result<_> {
+            let mut buf = [0; 1024];
+            let (size, client) = track!(server.recv_from(&mut buf).map_err(Error::from))?;
+            Ok((client, raw::decode(&buf[..size])))
+        };
+
+        // STUN client
+        let transporter = track!(fibers_global::execute(UdpTransporter::<
+            MessageEncoder<attribute::Attribute>,
+            MessageDecoder<attribute::Attribute>,
+        >::bind(
+            "127.0.0.1:0".parse().unwrap()
+        )))?;
+        let mut builder = StunUdpTransporterBuilder::new();
+        builder.rto(Duration::from_millis(50));
+        let transporter = StunRetransmitTransporter::with_builder(&builder, transporter);
+        let client = rustun::client::Client::new(
+            &fibers_global::handle(),
+            rustun::channel::Channel::new(transporter),
+        );
+
+        // The first transaction finishes before its retransmission timer expires
+        let call = client.call(server_addr, Request::new(rfc5389::methods::BINDING));
+        let response = fibers_global::spawn_monitor(call);
+        let (client_addr, request) = track!(recv_request())?;
+        let response_message = stun_codec::Message::<attribute::Attribute>::new(
+            stun_codec::MessageClass::SuccessResponse,
+            request.method(),
+            request.transaction_id(),
+        );
+        let bytes = track!(MessageEncoder::new()
+            .encode_into_bytes(response_message)
+            .map_err(Error::from))?;
+        track!(server.send_to(&bytes, client_addr).map_err(Error::from))?;
+        assert!(track!(fibers_global::execute(response).map_err(Error::from))?.is_ok());
+
+        // The second request is retransmitted even though the stale timer of the first one expires first
+        let call = client.call(server_addr, Request::new(rfc5389::methods::BINDING));
+        fibers_global::spawn(call.then(|_| Ok(())));
+        let (_, request) = track!(recv_request())?;
+        let (_, retransmitted) = track!(recv_request())?;
+        assert_eq!(retransmitted.transaction_id(), request.transaction_id());
+
+        Ok(())
+    }
+
+    #[test]
+    fn virtual_network_works() -> std::result::Result<(), MainError> {
+        use client::Client;
+        use std::time::Duration;
+        use transport::VirtualNetwork;
+
+        let network = VirtualNetwork::new(0);
+        assert!(network.set_loss_rate(1.5).is_err());
+        assert!(network
+            .set_reordering(f64::NAN, Duration::from_millis(20))
+            .is_err());
+
+        // Delayed datagrams are delivered when the virtual clock reaches their delivery times
+        {
+            use fibers_transport::Transport;
+
+            let mut sender = track!(network.bind("203.0.113.1:0".parse().unwrap()))?;
+            let mut receiver = track!(network.bind("203.0.113.2:0".parse().unwrap()))?;
+            let receiver_addr = receiver.public_addr();
+            let mut recv = || {
+                let item = receiver.poll_recv().expect("never fails");
+                item.map(|x| x.expect("never fails").1)
+            };
+
+            network.set_latency(Duration::from_millis(10));
+            track!(sender.start_send(receiver_addr, b"1".to_vec()))?;
+            track!(network.set_reordering(1.0, Duration::from_millis(20)))?;
+            track!(sender.start_send(receiver_addr, b"2".to_vec()))?;
+            track!(network.set_reordering(0.0, Duration::from_millis(20)))?;
+            track!(sender.start_send(receiver_addr, b"3".to_vec()))?;
+            assert!(recv().is_not_ready());
+
+            network.advance(Duration::from_millis(10));
+            assert_eq!(recv(), futures::Async::Ready(b"1".to_vec()));
+            assert_eq!(recv(), futures::Async::Ready(b"3".to_vec()));
+            assert!(recv().is_not_ready());
+
+            network.advance(Duration::from_millis(20));
+            assert_eq!(recv(), futures::Async::Ready(b"2".to_vec()));
+            assert_eq!(network.elapsed(), Duration::from_millis(30));
+            network.set_latency(Duration::from_secs(0));
+        }
+        track!(network.set_loss_rate(0.2))?;
+
+        // UDP echo server (peer on the real network, because relayed transport addresses are real sockets)
+        let peer_addr = track!(spawn_echo_peer())?;
+
+        // TURN server
+        let turn_server_addr = "192.0.2.1:3478".parse().unwrap();
+        let turn_server = server::UdpServer::start_on(
+            track!(network.bind(turn_server_addr).map_err(Error::from))?,
+            track!(AuthParams::with_realm_and_nonce("foo", "bar", "baz", "qux"))?,
+            server::ServerOptions::new(),
+        );
+        let handle = turn_server.handle();
+        fibers_global::spawn(turn_server.map_err(|e| panic!("{}", e)));
+
+        // Lost requests and responses are retransmitted (by the real-time timers of the client)
+        let turn_client = track!(fibers_global::execute(
+            client::UdpClientBuilder::new().mobility(true).allocate_on(
+                &network,
+                "198.51.100.1:0".parse().unwrap(),
+                turn_server_addr,
+                track!(AuthParams::new("foo", "bar"))?
+            )
+        ))?;
+        let client_addr = turn_client.local_addr();
+        assert_eq!(handle.allocations().len(), 1);
+
+        track!(network.set_loss_rate(0.0))?;
+        let (turn_client, result) = track!(fibers_global::execute(client::wait(
+            turn_client,
+            move |client| client.create_permission(peer_addr)
+        )))?;
+        track!(result)?;
+
+        // The allocation follows the NAT rebinding of the client
+        let public_addr = "198.51.100.2:40000".parse().unwrap();
+        track!(network.rebind(client_addr, public_addr))?;
+        let other_peer_addr = "127.0.0.1:1".parse().unwrap();
+        let (mut turn_client, result) = track!(fibers_global::execute(client::wait(
+            turn_client,
+            move |client| client.create_permission(other_peer_addr)
+        )))?;
+        track!(result)?;
+        let allocations = handle.allocations();
+        assert_eq!(allocations.len(), 1);
+        assert_eq!(allocations[0].five_tuple().client_addr(), public_addr);
+
+        track!(turn_client.start_send(peer_addr, b"hello".to_vec()))?;
+        let (_, from, data) = track!(recv_one(turn_client))?;
+        assert_eq!(from, peer_addr);
+        assert_eq!(data, b"hello");
+
+        Ok(())
+    }
+
+    #[test]
+    fn refresh_works() -> std::result::Result<(), MainError> {
+        use raw::{allocate_request, call, decode, encode, error_code};
+        use std::time::Duration;
+        use stun_codec::rfc5766::{attributes::Lifetime, methods::REFRESH};
+
+        // TURN server
+        let (turn_server_addr, handle) = track!(start_server(server::ServerOptions::new()))?;
+
+        let (socket, auth_params) = track!(raw::authenticate(turn_server_addr, "foo", "bar"))?;
+        let refresh = |lifetime: Option<u64>| -> Result<stun_codec::Message<attribute::Attribute>> {
+            let mut request = Request::new(REFRESH);
+            if let Some(lifetime) = lifetime {
+                let lifetime = track!(Lifetime::new(Duration::from_secs(lifetime)))?;
+                request.add_attribute(lifetime.into());
+            }
+            track!(auth_params.add_auth_attributes(&mut request))?;
+            Ok(decode(&call(&socket, turn_server_addr, &encode(request))))
+        };
+
+        // Refresh without an allocation
+        assert_eq!(error_code(&track!(refresh(None))?), Some(437));
+
+        let mut request = allocate_request();
+        track!(auth_params.add_auth_attributes(&mut request))?;
+        let response = decode(&call(&socket, turn_server_addr, &encode(request)));
+        assert_eq!(error_code(&response), None);
+
+        // Refresh without LIFETIME uses the default lifetime
+        let response = track!(refresh(None))?;
+        assert_eq!(error_code(&response), None);
+        let lifetime = response.get_attribute::<Lifetime>().map(|l| l.lifetime());
+        assert_eq!(lifetime, Some(Duration::from_secs(600)));
+
+        // The requested lifetime is clamped between the default and the maximum
+        for (requested, expected) in [(60, 600), (1800, 1800), (7200, 3600)] {
+            let response = track!(refresh(Some(requested)))?;
+            assert_eq!(error_code(&response), None);
+            let lifetime = response.get_attribute::<Lifetime>().map(|l| l.lifetime());
+            assert_eq!(lifetime, Some(Duration::from_secs(expected)));
+        }
+
+        // Deallocation is answered with a zero LIFETIME
+        let response = track!(refresh(Some(0)))?;
+        assert_eq!(error_code(&response), None);
+        let lifetime = response.get_attribute::<Lifetime>().map(|l| l.lifetime());
+        assert_eq!(lifetime, Some(Duration::from_secs(0)));
+        assert!(handle.allocations().is_empty());
+
+        // The allocation is gone
+        assert_eq!(error_code(&track!(refresh(Some(0)))?), Some(437));
+
+        Ok(())
+    }
+
+    #[test]
+    fn long_term_key_works() -> std::result::Result<(), MainError> {
+        use auth::{LongTermKey, PasswordAlgorithm};
+        use md5::Digest;
+
+        let key = LongTermKey::derive(PasswordAlgorithm::Md5, "foo", "baz", "bar");
+        let server_auth_params = track!(AuthParams::with_long_term_key("foo", key, "baz", "qux"))?;
+        assert!(!format!("{:?}", server_auth_params).contains("bar"));
+        assert!(!format!("{:?}", track!(AuthParams::new("foo", "secret"))?).contains("secret"));
+
+        // TURN server
+        let key = track!(LongTermKey::from_hex(
+            PasswordAlgorithm::Md5,
+            &format!("{:x}", md5::Md5::digest(b"alice:baz:secret"))
+        ))?;
+        let mut options = server::ServerOptions::new();
+        options.user_keys(std::iter::once(("alice".to_owned(), key)).collect());
+        options.password_algorithms(vec![PasswordAlgorithm::Md5]);
+        let (turn_server_addr, _) = track!(start_server(options))?;
+
+        // TURN clients (with passwords)
+        for (username, password) in [("foo", "bar"), ("alice", "secret")] {
+            track!(fibers_global::execute(client::UdpClient::allocate(
+                turn_server_addr,
+                track!(AuthParams::new(username, password))?
+            )))?;
+        }
+        let result = fibers_global::execute(client::UdpClient::allocate(
+            turn_server_addr,
+            track!(AuthParams::new("alice", "wrong"))?,
+        ));
+        assert!(result.is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_username_is_rejected() -> std::result::Result<(), MainError> {
+        use auth::{LongTermKey, PasswordAlgorithm};
+        use rfc8489::attributes::PasswordAlgorithms;
+
+        // TURN server
+        let (turn_server_addr, _) = track!(start_server(server::ServerOptions::new()))?;
+
+        // Signed by the key of the primary user, but sent with other usernames
+        let socket = track!(std::net::UdpSocket::bind("127.0.0.1:0").map_err(Error::from))?;
+        track!(socket
+            .set_read_timeout(Some(std::time::Duration::from_secs(1)))
+            .map_err(Error::from))?;
+        for (username, expected) in [("mallory", Some(401)), ("foo", None)] {
+            let response = raw::decode(&raw::call(
+                &socket,
+                turn_server_addr,
+                &raw::encode(raw::allocate_request()),
+            ));
+            let nonce = track_assert_some!(
+                response.get_attribute::<rfc5389::attributes::Nonce>(),
+                ErrorKind::Other
+            );
+            let key = LongTermKey::derive(PasswordAlgorithm::Md5, "foo", "baz", "bar");
+            let mut auth_params = track!(AuthParams::with_long_term_key(
+                username,
+                key,
+                "baz",
+                nonce.value()
+            ))?;
+            track!(auth_params.negotiate(response.get_attribute::<PasswordAlgorithms>()))?;
+
+            let mut request = raw::allocate_request();
+            track!(auth_params.add_auth_attributes(&mut request))?;
+            let response =
+                raw::decode(&raw::call(&socket, turn_server_addr, &raw::encode(request)));
+            assert_eq!(raw::error_code(&response), expected);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn tcp_connection_errors_are_isolated() -> std::result::Result<(), MainError> {
+        use std::io::{Read, Write};
+
+        let server_auth_params =
+            track!(AuthParams::with_realm_and_nonce("foo", "bar", "baz", "qux"))?;
+
+        // TURN server
+        let turn_server = fibers_global::execute(server::TcpServer::start(
+            fibers_global::handle(),
+            "127.0.0.1:0".parse().unwrap(),
+            server_auth_params,
+        ))?;
+        let turn_server_addr = turn_server.local_addr();
+        fibers_global::spawn(turn_server.map_err(|e| panic!("{}", e)));
+
+        // Broken client: only its connection is closed
+        let mut stream =
+            track!(std::net::TcpStream::connect(turn_server_addr).map_err(Error::from))?;
+        track!(stream.write_all(&[0xFF; 64]).map_err(Error::from))?;
+        track!(stream
+            .set_read_timeout(Some(std::time::Duration::from_secs(5)))
+            .map_err(Error::from))?;
+        let mut buf = [0; 64];
+        assert!(matches!(stream.read(&mut buf), Ok(0) | Err(_)));
+
+        // Well-behaved client
+        let turn_client = track!(fibers_global::execute(client::TcpClient::allocate(
+            turn_server_addr,
+            track!(AuthParams::new("foo", "bar"))?
+        )))?;
+        assert_eq!(turn_client.relay_addrs().len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn tcp_allocation_works() -> std::result::Result<(), MainError> {
+        use std::io::{Read, Write};
+
+        let client_auth_params = track!(AuthParams::new("foo", "bar"))?;
+        let server_auth_params =
+            track!(AuthParams::with_realm_and_nonce("foo", "bar", "baz", "qux"))?;
+
+        // TCP echo server (peer)
+        let echo_server = track!(std::net::TcpListener::bind("127.0.0.1:0").map_err(Error::from))?;
+        let echo_server_addr = track!(echo_server.local_addr().map_err(Error::from))?;
+        std::thread::spawn(move || {
+            let (mut stream, _) = echo_server.accept().unwrap();
+            let mut buf = [0; 1024];
+            loop {
+                let size = stream.read(&mut buf).unwrap();
+                if size == 0 {
+                    break;
+                }
+                stream.write_all(&buf[..size]).unwrap();
+            }
+        });
+
+        // TURN server
+        let turn_server = fibers_global::execute(server::TcpServer::start(
+            fibers_global::handle(),
+            "127.0.0.1:0".parse().unwrap(),
+            server_auth_params,
+        ))?;
+        let turn_server_addr = turn_server.local_addr();
+        fibers_global::spawn(turn_server.map_err(|e| panic!("{}", e)));
+
+        // TURN client
+        let turn_client = track!(fibers_global::execute(client::TcpClient::allocate_tcp(
+            turn_server_addr,
+            client_auth_params
+        )))?;
+        let (_turn_client, result) = track!(fibers_global::execute(client::wait(
+            turn_client,
+            move |client| client.connect(echo_server_addr)
+        )))?;
+        let mut stream = track!(result)?;
+        assert_eq!(stream.peer_addr(), echo_server_addr);
+
+        // Echo over the relayed TCP connection
+        let mut buf = Vec::new();
+        track!(fibers_global::execute(futures::future::poll_fn(
+            move || -> futures::Poll<(), Error> {
+                if buf.is_empty() {
+                    track!(stream.write_all(b"hello").map_err(Error::from))?;
+                    buf.push(0);
+                }
+                let mut tmp = [0; 16];
+                match stream.read(&mut tmp) {
+                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                        return Ok(futures::Async::NotReady);
+                    }
+                    Err(e) => return Err(track!(Error::from(e))),
+                    Ok(size) => buf.extend_from_slice(&tmp[..size]),
+                }
+                if buf.len() < 6 {
+                    return Ok(futures::Async::NotReady);
+                }
+                assert_eq!(&buf[1..], b"hello");
+                Ok(futures::Async::Ready(()))
+            }
+        )))?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn tcp_relay_port_collision_is_retried() -> std::result::Result<(), MainError> {
+        // One port of the relay range is in use
+        let occupied = track!(std::net::TcpListener::bind("127.0.0.1:0").map_err(Error::from))?;
+        let port = track!(occupied.local_addr().map_err(Error::from))?.port();
+        let free_addr = SocketAddr::from(([127, 0, 0, 1], port + 1));
+        drop(track!(
+            std::net::TcpListener::bind(free_addr).map_err(Error::from)
+        )?);
+
+        let mut options = server::ServerOptions::default();
+        options
+            .relay_ipv4(Some(std::net::Ipv4Addr::LOCALHOST))
+            .relay_port_range(port..=port + 1);
+        let turn_server = fibers_global::execute(server::TcpServer::start_with_options(
+            fibers_global::handle(),
+            "127.0.0.1:0".parse().unwrap(),
+            track!(AuthParams::with_realm_and_nonce("foo", "bar", "baz", "qux"))?,
+            options,
+        ))?;
+        let turn_server_addr = turn_server.local_addr();
+        fibers_global::spawn(turn_server.map_err(|e| panic!("{}", e)));
+
+        let turn_client = track!(fibers_global::execute(client::TcpClient::allocate_tcp(
+            turn_server_addr,
+            track!(AuthParams::new("foo", "bar"))?
+        )))?;
+        assert_eq!(turn_client.relay_addr(), Some(free_addr));
+
+        Ok(())
+    }
+
+    #[test]
+    fn connection_bind_keeps_pending_data() -> std::result::Result<(), MainError> {
+        use raw::{decode, encode, error_code, read_message};
+        use std::io::{Read, Write};
+        use std::net::TcpStream;
+        use std::time::Duration;
+
+        // TCP echo server (peer)
+        let echo_server = track!(std::net::TcpListener::bind("127.0.0.1:0").map_err(Error::from))?;
+        let echo_server_addr = track!(echo_server.local_addr().map_err(Error::from))?;
+        std::thread::spawn(move || {
+            let (mut stream, _) = echo_server.accept().unwrap();
+            let mut buf = [0; 1024];
+            while let Ok(size) = stream.read(&mut buf) {
+                if size == 0 || stream.write_all(&buf[..size]).is_err() {
+                    break;
+                }
+            }
+        });
+
+        // TURN server
+        let turn_server = fibers_global::execute(server::TcpServer::start(
+            fibers_global::handle(),
+            "127.0.0.1:0".parse().unwrap(),
+            track!(AuthParams::with_realm_and_nonce("foo", "bar", "baz", "qux"))?,
+        ))?;
+        let turn_server_addr = turn_server.local_addr();
+        fibers_global::spawn(turn_server.map_err(|e| panic!("{}", e)));
+
+        let connect = |request: Request<attribute::Attribute>| -> Result<_> {
+            let mut stream = track!(TcpStream::connect(turn_server_addr).map_err(Error::from))?;
+            track!(stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .map_err(Error::from))?;
+            track!(stream.write_all(&encode(request)).map_err(Error::from))?;
+            Ok(stream)
+        };
+        let allocate_request = || {
+            let mut request = Request::new(stun_codec::rfc5766::methods::ALLOCATE);
+            request.add_attribute(
+                stun_codec::rfc5766::attributes::RequestedTransport::new(
+                    rfc6062::TRANSPORT_PROTOCOL_TCP,
+                )
+                .into(),
+            );
+            request
+        };
+
+        // TCP allocation and Connect on the control connection
+        let mut control = track!(connect(allocate_request()))?;
+        let response = decode(&read_message(&mut control));
+        assert_eq!(error_code(&response), Some(401));
+        let mut auth_params = track!(AuthParams::new("foo", "bar"))?;
+        auth_params.set_realm(
+            track_assert_some!(
+                response.get_attribute::<rfc5389::attributes::Realm>(),
+                ErrorKind::Other
+            )
+            .clone(),
+        );
+        auth_params.set_nonce(
+            track_assert_some!(
+                response.get_attribute::<rfc5389::attributes::Nonce>(),
+                ErrorKind::Other
+            )
+            .clone(),
+        );
+
+        let mut request = allocate_request();
+        track!(auth_params.add_auth_attributes(&mut request))?;
+        track!(control.write_all(&encode(request)).map_err(Error::from))?;
+        assert_eq!(error_code(&decode(&read_message(&mut control))), None);
+
+        let mut request = Request::new(rfc6062::methods::connect());
+        request.add_attribute(
+            stun_codec::rfc5766::attributes::XorPeerAddress::new(echo_server_addr).into(),
+        );
+        track!(auth_params.add_auth_attributes(&mut request))?;
+        track!(control.write_all(&encode(request)).map_err(Error::from))?;
+        let response = decode(&read_message(&mut control));
+        assert_eq!(error_code(&response), None);
+        let connection_id: rfc6062::attributes::ConnectionId =
+            *track_assert_some!(response.get_attribute(), ErrorKind::Other);
+
+        // ConnectionBind and the data for the peer in one write
+        let mut request = Request::new(rfc6062::methods::connection_bind());
+        request.add_attribute(connection_id.into());
+        track!(auth_params.add_auth_attributes(&mut request))?;
+        let mut bytes = encode(request);
+        bytes.extend_from_slice(b"hello");
+        let mut data = track!(TcpStream::connect(turn_server_addr).map_err(Error::from))?;
+        track!(data
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .map_err(Error::from))?;
+        track!(data.write_all(&bytes).map_err(Error::from))?;
+        assert_eq!(error_code(&decode(&read_message(&mut data))), None);
+
+        let mut buf = [0; 5];
+        track!(data.read_exact(&mut buf).map_err(Error::from))?;
+        assert_eq!(&buf, b"hello");
+
+        Ok(())
+    }
 }
@@ -0,0 +1,115 @@
+//! Definitions of [RFC 6062] (TURN extensions for TCP allocations).
+//!
+//! [RFC 6062]: https://tools.ietf.org/html/rfc6062
+
+/// The protocol number of TCP used in `REQUESTED-TRANSPORT` attribute.
+pub const TRANSPORT_PROTOCOL_TCP: u8 = 6;
+
+pub mod methods {
+    //! Methods that are defined in [RFC 6062 -- 6.1. New STUN Methods].
+    //!
+    //! [RFC 6062 -- 6.1. New STUN Methods]: https://tools.ietf.org/html/rfc6062#section-6.1
+    use stun_codec::Method;
+
+    /// Connect method (request/response).
+    pub fn connect() -> Method {
+        Method::new(0x000A).expect("never fails")
+    }
+
+    /// ConnectionBind method (request/response).
+    pub fn connection_bind() -> Method {
+        Method::new(0x000B).expect("never fails")
+    }
+
+    /// ConnectionAttempt method (indication).
+    pub fn connection_attempt() -> Method {
+        Method::new(0x000C).expect("never fails")
+    }
+}
+
+pub mod attributes {
+    //! Attributes that are defined in [RFC 6062 -- 6.2. New STUN Attributes].
+    //!
+    //! [RFC 6062 -- 6.2. New STUN Attributes]: https://tools.ietf.org/html/rfc6062#section-6.2
+    use bytecodec::fixnum::{U32beDecoder, U32beEncoder};
+    use stun_codec::{Attribute, AttributeType};
+
+    /// `CONNECTION-ID` attribute.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct ConnectionId(u32);
+    impl ConnectionId {
+        /// The codepoint of the type of the attribute.
+        pub const CODEPOINT: u16 = 0x002A;
+
+        /// Makes a new `ConnectionId` instance.
+        pub fn new(id: u32) -> Self {
+            ConnectionId(id)
+        }
+
+        /// Returns the identifier of the connection.
+        pub fn value(self) -> u32 {
+            self.0
+        }
+    }
+    impl Attribute for ConnectionId {
+        type Decoder = ConnectionIdDecoder;
+        type Encoder = ConnectionIdEncoder;
+
+        fn get_type(&self) -> AttributeType {
+            AttributeType::new(Self::CODEPOINT)
+        }
+    }
+
+    /// [`ConnectionId`] decoder.
+    #[derive(Debug, Default)]
+    pub struct ConnectionIdDecoder(U32beDecoder);
+    impl_decode!(ConnectionIdDecoder, ConnectionId, |item| Ok(ConnectionId(
+        item
+    )));
+
+    /// [`ConnectionId`] encoder.
+    #[derive(Debug, Default)]
+    pub struct ConnectionIdEncoder(U32beEncoder);
+    impl_encode!(ConnectionIdEncoder, ConnectionId, |item: Self::Item| item.0);
+}
+
+pub mod errors {
+    //! Error codes that are defined in [RFC 6062 -- 6.3. New STUN Error Codes].
+    //!
+    //! [RFC 6062 -- 6.3. New STUN Error Codes]: https://tools.ietf.org/html/rfc6062#section-6.3
+    use stun_codec::rfc5389::attributes::ErrorCode;
+
+    /// `446`: "Connection Already Exists".
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct ConnectionAlreadyExists;
+    impl ConnectionAlreadyExists {
+        /// The codepoint of the error.
+        pub const CODEPOINT: u16 = 446;
+    }
+    impl From<ConnectionAlreadyExists> for ErrorCode {
+        fn from(_: ConnectionAlreadyExists) -> Self {
+            ErrorCode::new(
+                ConnectionAlreadyExists::CODEPOINT,
+                "Connection Already Exists".to_owned(),
+            )
+            .expect("never fails")
+        }
+    }
+
+    /// `447`: "Connection Timeout or Failure".
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct ConnectionTimeoutOrFailure;
+    impl ConnectionTimeoutOrFailure {
+        /// The codepoint of the error.
+        pub const CODEPOINT: u16 = 447;
+    }
+    impl From<ConnectionTimeoutOrFailure> for ErrorCode {
+        fn from(_: ConnectionTimeoutOrFailure) -> Self {
+            ErrorCode::new(
+                ConnectionTimeoutOrFailure::CODEPOINT,
+                "Connection Timeout or Failure".to_owned(),
+            )
+            .expect("never fails")
+        }
+    }
+}
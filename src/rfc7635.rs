@@ -0,0 +1,254 @@
+//! Definitions of [RFC 7635] (Session Traversal Utilities for NAT (STUN) Extension for Third-Party Authorization).
+//!
+//! [RFC 7635]: https://tools.ietf.org/html/rfc7635
+use crate::{ErrorKind, Result};
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes128Gcm, Aes256Gcm, Nonce};
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use zeroize::Zeroizing;
+
+const NONCE_SIZE: usize = 12;
+
+pub mod attributes {
+    //! Attributes that are defined in [RFC 7635 -- 6. STUN Attributes].
+    //!
+    //! [RFC 7635 -- 6. STUN Attributes]: https://tools.ietf.org/html/rfc7635#section-6
+    use bytecodec::bytes::{BytesEncoder, RemainingBytesDecoder, Utf8Decoder, Utf8Encoder};
+    use stun_codec::{Attribute, AttributeType};
+
+    /// `THIRD-PARTY-AUTHORIZATION` attribute.
+    ///
+    /// This holds the name of the authorization server that issues access tokens.
+    #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+    pub struct ThirdPartyAuthorization(String);
+    impl ThirdPartyAuthorization {
+        /// The codepoint of the type of the attribute.
+        pub const CODEPOINT: u16 = 0x802E;
+
+        /// Makes a new `ThirdPartyAuthorization` instance.
+        pub fn new(server_name: String) -> Self {
+            ThirdPartyAuthorization(server_name)
+        }
+
+        /// Returns the name of the authorization server.
+        pub fn server_name(&self) -> &str {
+            &self.0
+        }
+    }
+    impl Attribute for ThirdPartyAuthorization {
+        type Decoder = ThirdPartyAuthorizationDecoder;
+        type Encoder = ThirdPartyAuthorizationEncoder;
+
+        fn get_type(&self) -> AttributeType {
+            AttributeType::new(Self::CODEPOINT)
+        }
+    }
+
+    /// [`ThirdPartyAuthorization`] decoder.
+    #[derive(Debug, Default)]
+    pub struct ThirdPartyAuthorizationDecoder(Utf8Decoder);
+    impl_decode!(
+        ThirdPartyAuthorizationDecoder,
+        ThirdPartyAuthorization,
+        |item| Ok(ThirdPartyAuthorization(item))
+    );
+
+    /// [`ThirdPartyAuthorization`] encoder.
+    #[derive(Debug, Default)]
+    pub struct ThirdPartyAuthorizationEncoder(Utf8Encoder);
+    impl_encode!(
+        ThirdPartyAuthorizationEncoder,
+        ThirdPartyAuthorization,
+        |item: Self::Item| item.0
+    );
+
+    /// `ACCESS-TOKEN` attribute.
+    ///
+    /// This holds an encrypted token (see [`Token`](super::Token)).
+    #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+    pub struct AccessToken(Vec<u8>);
+    impl AccessToken {
+        /// The codepoint of the type of the attribute.
+        pub const CODEPOINT: u16 = 0x001B;
+
+        /// Makes a new `AccessToken` instance.
+        pub fn new(token: Vec<u8>) -> Self {
+            AccessToken(token)
+        }
+
+        /// Returns the encrypted token.
+        pub fn token(&self) -> &[u8] {
+            &self.0
+        }
+    }
+    impl Attribute for AccessToken {
+        type Decoder = AccessTokenDecoder;
+        type Encoder = AccessTokenEncoder;
+
+        fn get_type(&self) -> AttributeType {
+            AttributeType::new(Self::CODEPOINT)
+        }
+    }
+
+    /// [`AccessToken`] decoder.
+    #[derive(Debug, Default)]
+    pub struct AccessTokenDecoder(RemainingBytesDecoder);
+    impl_decode!(AccessTokenDecoder, AccessToken, |item| Ok(AccessToken(
+        item
+    )));
+
+    /// [`AccessToken`] encoder.
+    #[derive(Debug, Default)]
+    pub struct AccessTokenEncoder(BytesEncoder);
+    impl_encode!(AccessTokenEncoder, AccessToken, |item: Self::Item| item.0);
+}
+
+/// The content of an access token ([RFC 7635 -- 6.2. ACCESS-TOKEN]).
+///
+/// A token is encrypted by AEAD_AES_128_GCM or AEAD_AES_256_GCM (depending on the length of the key)
+/// with a key shared by the authorization server and the STUN server.
+/// The name of the STUN server is used as the associated data.
+///
+/// The session key is zeroized on drop and is not printed by `Debug`.
+///
+/// [RFC 7635 -- 6.2. ACCESS-TOKEN]: https://tools.ietf.org/html/rfc7635#section-6.2
+#[derive(Clone, PartialEq, Eq)]
+pub struct Token {
+    mac_key: Zeroizing<Vec<u8>>,
+    timestamp: SystemTime,
+    lifetime: Duration,
+}
+impl Token {
+    /// Makes a new `Token` instance that is valid from now during `lifetime`.
+    ///
+    /// `mac_key` is the session key used for calculating `MESSAGE-INTEGRITY` attributes.
+    pub fn new(mac_key: Vec<u8>, lifetime: Duration) -> Self {
+        Token {
+            mac_key: Zeroizing::new(mac_key),
+            timestamp: SystemTime::now(),
+            lifetime,
+        }
+    }
+
+    /// Returns the session key of the token.
+    pub fn mac_key(&self) -> &[u8] {
+        &self.mac_key
+    }
+
+    /// Returns the time at which the token was issued.
+    pub fn timestamp(&self) -> SystemTime {
+        self.timestamp
+    }
+
+    /// Returns the lifetime of the token.
+    pub fn lifetime(&self) -> Duration {
+        self.lifetime
+    }
+
+    /// Returns `true` if the token has expired.
+    pub fn is_expired(&self) -> bool {
+        self.timestamp + self.lifetime < SystemTime::now()
+    }
+
+    /// Encrypts the token.
+    pub fn encrypt(&self, key: &[u8], server_name: &str) -> Result<Vec<u8>> {
+        track_assert!(self.mac_key.len() <= 0xFFFF, ErrorKind::InvalidInput);
+        track_assert!(
+            self.lifetime.as_secs() <= u64::from(u32::MAX),
+            ErrorKind::InvalidInput
+        );
+        let mut block = Zeroizing::new(Vec::new());
+        block.extend_from_slice(&(self.mac_key.len() as u16).to_be_bytes());
+        block.extend_from_slice(&self.mac_key);
+        block.extend_from_slice(&encode_timestamp(self.timestamp).to_be_bytes());
+        block.extend_from_slice(&(self.lifetime.as_secs() as u32).to_be_bytes());
+
+        let nonce: [u8; NONCE_SIZE] = rand::random();
+        let payload = Payload {
+            msg: &block,
+            aad: server_name.as_bytes(),
+        };
+        let nonce_ref = Nonce::from_slice(&nonce);
+        let encrypted = match key.len() {
+            16 => Aes128Gcm::new_from_slice(key)
+                .expect("never fails")
+                .encrypt(nonce_ref, payload),
+            32 => Aes256Gcm::new_from_slice(key)
+                .expect("never fails")
+                .encrypt(nonce_ref, payload),
+            n => track_panic!(ErrorKind::InvalidInput, "Unsupported key length: {}", n),
+        };
+        let encrypted =
+            track_assert_some!(encrypted.ok(), ErrorKind::Other, "Cannot encrypt a token");
+
+        let mut token = Vec::with_capacity(2 + NONCE_SIZE + encrypted.len());
+        token.extend_from_slice(&(NONCE_SIZE as u16).to_be_bytes());
+        token.extend_from_slice(&nonce);
+        token.extend_from_slice(&encrypted);
+        Ok(token)
+    }
+
+    /// Decrypts the given token.
+    pub fn decrypt(token: &[u8], key: &[u8], server_name: &str) -> Result<Self> {
+        track_assert!(token.len() >= 2, ErrorKind::InvalidInput);
+        let nonce_len = u16::from_be_bytes([token[0], token[1]]) as usize;
+        track_assert_eq!(nonce_len, NONCE_SIZE, ErrorKind::InvalidInput);
+        track_assert!(token.len() >= 2 + nonce_len, ErrorKind::InvalidInput);
+        let nonce = Nonce::from_slice(&token[2..][..nonce_len]);
+        let payload = Payload {
+            msg: &token[2 + nonce_len..],
+            aad: server_name.as_bytes(),
+        };
+        let block = match key.len() {
+            16 => Aes128Gcm::new_from_slice(key)
+                .expect("never fails")
+                .decrypt(nonce, payload),
+            32 => Aes256Gcm::new_from_slice(key)
+                .expect("never fails")
+                .decrypt(nonce, payload),
+            n => track_panic!(ErrorKind::InvalidInput, "Unsupported key length: {}", n),
+        };
+        let block = track_assert_some!(
+            block.ok().map(Zeroizing::new),
+            ErrorKind::InvalidInput,
+            "Cannot decrypt a token"
+        );
+
+        track_assert!(block.len() >= 2, ErrorKind::InvalidInput);
+        let key_len = u16::from_be_bytes([block[0], block[1]]) as usize;
+        track_assert_eq!(block.len(), 2 + key_len + 8 + 4, ErrorKind::InvalidInput);
+        let mac_key = Zeroizing::new(block[2..][..key_len].to_vec());
+        let mut timestamp = [0; 8];
+        timestamp.copy_from_slice(&block[2 + key_len..][..8]);
+        let mut lifetime = [0; 4];
+        lifetime.copy_from_slice(&block[2 + key_len + 8..]);
+        Ok(Token {
+            mac_key,
+            timestamp: decode_timestamp(u64::from_be_bytes(timestamp)),
+            lifetime: Duration::from_secs(u64::from(u32::from_be_bytes(lifetime))),
+        })
+    }
+}
+
+impl fmt::Debug for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Token")
+            .field("timestamp", &self.timestamp)
+            .field("lifetime", &self.lifetime)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The upper 48 bits are seconds since the UNIX epoch and the lower 16 bits are 1/64000 seconds.
+fn encode_timestamp(t: SystemTime) -> u64 {
+    let d = t.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let fraction = u64::from(d.subsec_micros()) * 64 / 1000;
+    (d.as_secs() << 16) | fraction
+}
+
+fn decode_timestamp(t: u64) -> SystemTime {
+    let secs = t >> 16;
+    let micros = (t & 0xFFFF) * 1000 / 64;
+    UNIX_EPOCH + Duration::from_secs(secs) + Duration::from_micros(micros)
+}
@@ -0,0 +1,366 @@
+//! Definitions of [RFC 8489] (Session Traversal Utilities for NAT (STUN)) that are not in [RFC 5389].
+//!
+//! [RFC 5389]: https://tools.ietf.org/html/rfc5389
+//! [RFC 8489]: https://tools.ietf.org/html/rfc8489
+use base64::Engine;
+use bytecodec::EncodeExt;
+use stun_codec::{Attribute, Message, MessageEncoder};
+
+/// The prefix of a `NONCE` that carries the security feature bits ([RFC 8489 -- 9.2. Long-Term Credential Mechanism]).
+///
+/// [RFC 8489 -- 9.2. Long-Term Credential Mechanism]: https://tools.ietf.org/html/rfc8489#section-9.2
+pub const NONCE_COOKIE: &str = "obMatJos2";
+
+/// Security feature bit indicating that the server supports password algorithms.
+pub const FEATURE_PASSWORD_ALGORITHMS: u32 = 1 << 23;
+
+/// Security feature bit indicating that the server supports anonymous usernames (i.e., `USERHASH`).
+pub const FEATURE_USERNAME_ANONYMITY: u32 = 1 << 22;
+
+/// Hash algorithm used to derive a long-term key from a password.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PasswordAlgorithm {
+    /// `MD5(username ":" realm ":" password)` ([RFC 5389]).
+    ///
+    /// [RFC 5389]: https://tools.ietf.org/html/rfc5389#section-15.4
+    Md5,
+
+    /// `SHA-256(username ":" realm ":" password)` ([RFC 8489]).
+    ///
+    /// [RFC 8489]: https://tools.ietf.org/html/rfc8489#section-9.2.2
+    Sha256,
+}
+impl PasswordAlgorithm {
+    /// Returns the codepoint of the algorithm.
+    pub fn code(self) -> u16 {
+        match self {
+            PasswordAlgorithm::Md5 => 0x0001,
+            PasswordAlgorithm::Sha256 => 0x0002,
+        }
+    }
+
+    /// Returns the algorithm identified by `code` if it is known.
+    pub fn from_code(code: u16) -> Option<Self> {
+        match code {
+            0x0001 => Some(PasswordAlgorithm::Md5),
+            0x0002 => Some(PasswordAlgorithm::Sha256),
+            _ => None,
+        }
+    }
+}
+
+/// Makes a `NONCE` value that starts with the cookie and the given security feature bits.
+pub fn nonce_with_features(features: u32, nonce: &str) -> String {
+    let bits = &features.to_be_bytes()[1..];
+    let bits = base64::engine::general_purpose::STANDARD.encode(bits);
+    format!("{}{}{}", NONCE_COOKIE, bits, nonce)
+}
+
+/// Returns the security feature bits of the given `NONCE` value (`None` if there is no cookie).
+pub fn nonce_features(nonce: &str) -> Option<u32> {
+    let bits = nonce.strip_prefix(NONCE_COOKIE)?.get(..4)?;
+    let bits = base64::engine::general_purpose::STANDARD
+        .decode(bits)
+        .ok()?;
+    Some(u32::from_be_bytes([0, bits[0], bits[1], bits[2]]))
+}
+
+/// Encodes the message with the length field adjusted to point to the end of
+/// an integrity attribute (e.g., `MESSAGE-INTEGRITY`) whose value is `mac_len` bytes long.
+///
+/// The result is the input of the HMAC of the integrity attribute appended to the message.
+pub(crate) fn message_into_bytes<A: Attribute>(
+    message: Message<A>,
+    mac_len: usize,
+) -> bytecodec::Result<Vec<u8>> {
+    let mut bytes = track!(MessageEncoder::default().encode_into_bytes(message))?;
+    let adjusted_len = bytes.len() - 20 /*msg header*/ + 4 /*attr header*/ + mac_len;
+    bytes[2..4].copy_from_slice(&(adjusted_len as u16).to_be_bytes());
+    Ok(bytes)
+}
+
+pub mod attributes {
+    //! Attributes that are defined in [RFC 8489 -- 14. STUN Attributes] but not in RFC 5389.
+    //!
+    //! [RFC 8489 -- 14. STUN Attributes]: https://tools.ietf.org/html/rfc8489#section-14
+    use super::message_into_bytes;
+    use super::PasswordAlgorithm as Algorithm;
+    use bytecodec::bytes::{BytesEncoder, RemainingBytesDecoder};
+    use bytecodec::{ErrorKind, Result};
+    use hmac::{Hmac, Mac};
+    use sha2::{Digest, Sha256};
+    use stun_codec::{Attribute, AttributeType, Message};
+
+    /// `MESSAGE-INTEGRITY-SHA256` attribute.
+    ///
+    /// This holds the HMAC-SHA256 of the preceding part of the message.
+    #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+    pub struct MessageIntegritySha256 {
+        hmac_sha256: Vec<u8>,
+        preceding_message_bytes: Vec<u8>,
+    }
+    impl MessageIntegritySha256 {
+        /// The codepoint of the type of the attribute.
+        pub const CODEPOINT: u16 = 0x001C;
+
+        /// Makes a new `MessageIntegritySha256` instance for the message with the given key.
+        pub fn new<A: Attribute>(message: &Message<A>, key: &[u8]) -> Result<Self> {
+            let preceding_message_bytes = track!(message_into_bytes(message.clone(), 32))?;
+            let hmac_sha256 = hmac_sha256(key, &preceding_message_bytes);
+            Ok(MessageIntegritySha256 {
+                hmac_sha256,
+                preceding_message_bytes,
+            })
+        }
+
+        /// Checks whether this has the valid HMAC for `key`.
+        pub fn check(&self, key: &[u8]) -> bool {
+            let mut hmac = Hmac::<Sha256>::new_from_slice(key).expect("never fails");
+            hmac.update(&self.preceding_message_bytes);
+            hmac.verify_truncated_left(&self.hmac_sha256).is_ok()
+        }
+
+        /// Returns the HMAC-SHA256 of this instance.
+        pub fn hmac_sha256(&self) -> &[u8] {
+            &self.hmac_sha256
+        }
+    }
+    impl Attribute for MessageIntegritySha256 {
+        type Decoder = MessageIntegritySha256Decoder;
+        type Encoder = MessageIntegritySha256Encoder;
+
+        fn get_type(&self) -> AttributeType {
+            AttributeType::new(Self::CODEPOINT)
+        }
+
+        fn after_decode<A: Attribute>(&mut self, message: &Message<A>) -> Result<()> {
+            let hmac_len = self.hmac_sha256.len();
+            self.preceding_message_bytes = track!(message_into_bytes(message.clone(), hmac_len))?;
+            Ok(())
+        }
+    }
+
+    /// [`MessageIntegritySha256`] decoder.
+    #[derive(Debug, Default)]
+    pub struct MessageIntegritySha256Decoder(RemainingBytesDecoder);
+    impl_decode!(
+        MessageIntegritySha256Decoder,
+        MessageIntegritySha256,
+        |item: Vec<u8>| {
+            track_assert!(
+                (16..=32).contains(&item.len()) && item.len().is_multiple_of(4),
+                ErrorKind::InvalidInput;
+                item.len()
+            );
+            Ok(MessageIntegritySha256 {
+                hmac_sha256: item,
+                preceding_message_bytes: Vec::new(),
+            })
+        }
+    );
+
+    /// [`MessageIntegritySha256`] encoder.
+    #[derive(Debug, Default)]
+    pub struct MessageIntegritySha256Encoder(BytesEncoder);
+    impl_encode!(
+        MessageIntegritySha256Encoder,
+        MessageIntegritySha256,
+        |item: Self::Item| item.hmac_sha256
+    );
+
+    /// `PASSWORD-ALGORITHMS` attribute.
+    ///
+    /// This holds the password algorithms supported by the server in the order of preference.
+    #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+    pub struct PasswordAlgorithms(Vec<(u16, Vec<u8>)>);
+    impl PasswordAlgorithms {
+        /// The codepoint of the type of the attribute.
+        pub const CODEPOINT: u16 = 0x8002;
+
+        /// Makes a new `PasswordAlgorithms` instance.
+        pub fn new(algorithms: &[Algorithm]) -> Self {
+            PasswordAlgorithms(algorithms.iter().map(|a| (a.code(), Vec::new())).collect())
+        }
+
+        /// Returns the known algorithms in the order of preference.
+        pub fn algorithms(&self) -> impl Iterator<Item = Algorithm> + '_ {
+            self.0
+                .iter()
+                .filter(|(_, params)| params.is_empty())
+                .filter_map(|&(code, _)| Algorithm::from_code(code))
+        }
+
+        /// Returns `true` if the attribute contains `algorithm`.
+        pub fn contains(&self, algorithm: Algorithm) -> bool {
+            self.algorithms().any(|a| a == algorithm)
+        }
+    }
+    impl Attribute for PasswordAlgorithms {
+        type Decoder = PasswordAlgorithmsDecoder;
+        type Encoder = PasswordAlgorithmsEncoder;
+
+        fn get_type(&self) -> AttributeType {
+            AttributeType::new(Self::CODEPOINT)
+        }
+    }
+
+    /// [`PasswordAlgorithms`] decoder.
+    #[derive(Debug, Default)]
+    pub struct PasswordAlgorithmsDecoder(RemainingBytesDecoder);
+    impl_decode!(
+        PasswordAlgorithmsDecoder,
+        PasswordAlgorithms,
+        |item: Vec<u8>| {
+            let mut algorithms = Vec::new();
+            let mut rest = &item[..];
+            while !rest.is_empty() {
+                let (entry, size) = track!(decode_algorithm(rest))?;
+                algorithms.push(entry);
+                rest = &rest[size..];
+            }
+            Ok(PasswordAlgorithms(algorithms))
+        }
+    );
+
+    /// [`PasswordAlgorithms`] encoder.
+    #[derive(Debug, Default)]
+    pub struct PasswordAlgorithmsEncoder(BytesEncoder);
+    impl_encode!(
+        PasswordAlgorithmsEncoder,
+        PasswordAlgorithms,
+        |item: Self::Item| {
+            let mut bytes = Vec::new();
+            for (code, params) in &item.0 {
+                encode_algorithm(*code, params, &mut bytes);
+            }
+            bytes
+        }
+    );
+
+    /// `PASSWORD-ALGORITHM` attribute.
+    ///
+    /// This holds the password algorithm selected by the client.
+    #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+    pub struct PasswordAlgorithm {
+        code: u16,
+        params: Vec<u8>,
+    }
+    impl PasswordAlgorithm {
+        /// The codepoint of the type of the attribute.
+        pub const CODEPOINT: u16 = 0x001D;
+
+        /// Makes a new `PasswordAlgorithm` instance.
+        pub fn new(algorithm: Algorithm) -> Self {
+            PasswordAlgorithm {
+                code: algorithm.code(),
+                params: Vec::new(),
+            }
+        }
+
+        /// Returns the algorithm (`None` if it is unknown).
+        pub fn algorithm(&self) -> Option<Algorithm> {
+            if self.params.is_empty() {
+                Algorithm::from_code(self.code)
+            } else {
+                None
+            }
+        }
+    }
+    impl Attribute for PasswordAlgorithm {
+        type Decoder = PasswordAlgorithmDecoder;
+        type Encoder = PasswordAlgorithmEncoder;
+
+        fn get_type(&self) -> AttributeType {
+            AttributeType::new(Self::CODEPOINT)
+        }
+    }
+
+    /// [`PasswordAlgorithm`] decoder.
+    #[derive(Debug, Default)]
+    pub struct PasswordAlgorithmDecoder(RemainingBytesDecoder);
+    impl_decode!(PasswordAlgorithmDecoder, PasswordAlgorithm, |item: Vec<
+        u8,
+    >| {
+        let ((code, params), size) = track!(decode_algorithm(&item))?;
+        track_assert_eq!(size, item.len(), ErrorKind::InvalidInput);
+        Ok(PasswordAlgorithm { code, params })
+    });
+
+    /// [`PasswordAlgorithm`] encoder.
+    #[derive(Debug, Default)]
+    pub struct PasswordAlgorithmEncoder(BytesEncoder);
+    impl_encode!(
+        PasswordAlgorithmEncoder,
+        PasswordAlgorithm,
+        |item: Self::Item| {
+            let mut bytes = Vec::new();
+            encode_algorithm(item.code, &item.params, &mut bytes);
+            bytes
+        }
+    );
+
+    /// `USERHASH` attribute.
+    ///
+    /// This is sent instead of `USERNAME` so that passive observers cannot learn the username.
+    #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+    pub struct Userhash(Vec<u8>);
+    impl Userhash {
+        /// The codepoint of the type of the attribute.
+        pub const CODEPOINT: u16 = 0x001E;
+
+        /// Makes a new `Userhash` instance, i.e., `SHA-256(username ":" realm)`.
+        pub fn new(username: &str, realm: &str) -> Self {
+            let input = format!("{}:{}", username, realm);
+            Userhash(Sha256::digest(input.as_bytes()).to_vec())
+        }
+
+        /// Returns the hash value.
+        pub fn value(&self) -> &[u8] {
+            &self.0
+        }
+    }
+    impl Attribute for Userhash {
+        type Decoder = UserhashDecoder;
+        type Encoder = UserhashEncoder;
+
+        fn get_type(&self) -> AttributeType {
+            AttributeType::new(Self::CODEPOINT)
+        }
+    }
+
+    /// [`Userhash`] decoder.
+    #[derive(Debug, Default)]
+    pub struct UserhashDecoder(RemainingBytesDecoder);
+    impl_decode!(UserhashDecoder, Userhash, |item: Vec<u8>| {
+        track_assert_eq!(item.len(), 32, ErrorKind::InvalidInput);
+        Ok(Userhash(item))
+    });
+
+    /// [`Userhash`] encoder.
+    #[derive(Debug, Default)]
+    pub struct UserhashEncoder(BytesEncoder);
+    impl_encode!(UserhashEncoder, Userhash, |item: Self::Item| item.0);
+
+    /// Decodes an algorithm entry and returns it with the size of the entry (including the padding).
+    fn decode_algorithm(bytes: &[u8]) -> Result<((u16, Vec<u8>), usize)> {
+        track_assert!(bytes.len() >= 4, ErrorKind::InvalidInput);
+        let code = u16::from_be_bytes([bytes[0], bytes[1]]);
+        let len = usize::from(u16::from_be_bytes([bytes[2], bytes[3]]));
+        let padded_len = len.div_ceil(4) * 4;
+        track_assert!(bytes.len() >= 4 + padded_len, ErrorKind::InvalidInput);
+        Ok(((code, bytes[4..][..len].to_vec()), 4 + padded_len))
+    }
+
+    fn encode_algorithm(code: u16, params: &[u8], bytes: &mut Vec<u8>) {
+        bytes.extend_from_slice(&code.to_be_bytes());
+        bytes.extend_from_slice(&(params.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(params);
+        bytes.resize(bytes.len().div_ceil(4) * 4, 0);
+    }
+
+    fn hmac_sha256(key: &[u8], bytes: &[u8]) -> Vec<u8> {
+        let mut hmac = Hmac::<Sha256>::new_from_slice(key).expect("never fails");
+        hmac.update(bytes);
+        hmac.finalize().into_bytes().to_vec()
+    }
+}
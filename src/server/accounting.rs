@@ -0,0 +1,165 @@
+use super::shared::{AllocationInfo, TrafficCounters, TransportProtocol};
+use crate::{Error, Result};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Reason why an allocation has been torn down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TeardownReason {
+    /// The lifetime of the allocation has expired.
+    Expired,
+
+    /// The client has deleted the allocation
+    /// (i.e., by a Refresh request with zero lifetime or by closing the TCP connection).
+    Deallocated,
+
+    /// The allocation has been removed by [`ServerHandle::kick`](super::ServerHandle::kick).
+    AdminKicked,
+
+    /// The user of the allocation has been removed by
+    /// [`ServerHandle::reload`](super::ServerHandle::reload).
+    Revoked,
+}
+impl TeardownReason {
+    fn as_str(self) -> &'static str {
+        match self {
+            TeardownReason::Expired => "expired",
+            TeardownReason::Deallocated => "deallocated",
+            TeardownReason::AdminKicked => "admin_kicked",
+            TeardownReason::Revoked => "revoked",
+        }
+    }
+}
+
+/// Accounting record of an allocation that is written when the allocation is torn down.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccountingRecord {
+    /// Username that created the allocation.
+    pub username: String,
+
+    /// Realm in which the username was authenticated.
+    pub realm: String,
+
+    /// Transport protocol between the client and the server.
+    pub transport: TransportProtocol,
+
+    /// Transport address of the client.
+    pub client_addr: SocketAddr,
+
+    /// Transport address of the server.
+    pub server_addr: SocketAddr,
+
+    /// Relayed transport addresses of the allocation.
+    pub relay_addrs: Vec<SocketAddr>,
+
+    /// Time at which the allocation was created.
+    pub started_at: SystemTime,
+
+    /// Time at which the allocation was torn down.
+    pub ended_at: SystemTime,
+
+    /// Reason why the allocation was torn down.
+    pub reason: TeardownReason,
+
+    /// Amount of the data relayed between the client and each peer.
+    pub peers: Vec<(SocketAddr, TrafficCounters)>,
+}
+impl AccountingRecord {
+    pub(crate) fn new(allocation: &AllocationInfo, reason: TeardownReason) -> Self {
+        AccountingRecord {
+            username: allocation.username().to_owned(),
+            realm: allocation.realm().to_owned(),
+            transport: allocation.transport(),
+            client_addr: allocation.client_addr(),
+            server_addr: allocation.server_addr(),
+            relay_addrs: allocation.relay_addrs().to_owned(),
+            started_at: allocation.created_at(),
+            ended_at: SystemTime::now(),
+            reason,
+            peers: allocation.peer_traffic(),
+        }
+    }
+
+    /// Returns the JSON representation of the record.
+    ///
+    /// Times are represented as milliseconds since the UNIX epoch.
+    pub fn to_json(&self) -> String {
+        let peers = self
+            .peers
+            .iter()
+            .map(|(peer, traffic)| {
+                serde_json::json!({
+                    "peer": peer.to_string(),
+                    "bytes_to_peer": traffic.bytes_to_peers,
+                    "packets_to_peer": traffic.packets_to_peers,
+                    "bytes_from_peer": traffic.bytes_from_peers,
+                    "packets_from_peer": traffic.packets_from_peers,
+                })
+            })
+            .collect::<Vec<_>>();
+        let transport = match self.transport {
+            TransportProtocol::Udp => "udp",
+            TransportProtocol::Tcp => "tcp",
+            TransportProtocol::Tls => "tls",
+        };
+        serde_json::json!({
+            "username": self.username,
+            "realm": self.realm,
+            "transport": transport,
+            "client_addr": self.client_addr.to_string(),
+            "server_addr": self.server_addr.to_string(),
+            "relay_addrs": self.relay_addrs.iter().map(|a| a.to_string()).collect::<Vec<_>>(),
+            "started_at": unix_millis(self.started_at),
+            "ended_at": unix_millis(self.ended_at),
+            "reason": self.reason.as_str(),
+            "peers": peers,
+        })
+        .to_string()
+    }
+}
+
+/// Destination of accounting records.
+pub trait AccountingSink: Send + Sync + 'static {
+    /// Writes the given record.
+    ///
+    /// This is called synchronously by the task that handles the client, so it should return quickly.
+    fn write(&self, record: &AccountingRecord) -> Result<()>;
+}
+
+/// [`AccountingSink`] that appends records to a file in the [JSON Lines] format.
+///
+/// [JSON Lines]: https://jsonlines.org/
+#[derive(Debug)]
+pub struct JsonLinesSink {
+    file: Mutex<File>,
+}
+impl JsonLinesSink {
+    /// Opens the given file in the append mode (the file is created if it does not exist).
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = track!(OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(Error::from))?;
+        Ok(JsonLinesSink {
+            file: Mutex::new(file),
+        })
+    }
+}
+impl AccountingSink for JsonLinesSink {
+    fn write(&self, record: &AccountingRecord) -> Result<()> {
+        let mut line = record.to_json();
+        line.push('\n');
+        let mut file = self.file.lock().expect("never fails");
+        track!(file.write_all(line.as_bytes()).map_err(Error::from))?;
+        Ok(())
+    }
+}
+
+fn unix_millis(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
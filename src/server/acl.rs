@@ -0,0 +1,117 @@
+use crate::{Error, ErrorKind, Result};
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// Range of IP addresses expressed in the CIDR notation (e.g., `10.0.0.0/8`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct IpNetwork {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+impl IpNetwork {
+    /// Makes a new `IpNetwork` instance.
+    ///
+    /// The bits of `addr` beyond `prefix_len` are ignored.
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Result<Self> {
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        track_assert!(
+            prefix_len <= max,
+            ErrorKind::InvalidInput,
+            "Too long prefix: {}",
+            prefix_len
+        );
+        Ok(IpNetwork { addr, prefix_len })
+    }
+
+    /// Returns `true` if the network contains `addr`.
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, addr) {
+            (IpAddr::V4(a), IpAddr::V4(b)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix_len));
+                let mask = mask.unwrap_or(0);
+                u32::from(a) & mask == u32::from(b) & mask
+            }
+            (IpAddr::V6(a), IpAddr::V6(b)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(self.prefix_len));
+                let mask = mask.unwrap_or(0);
+                u128::from(a) & mask == u128::from(b) & mask
+            }
+            _ => false,
+        }
+    }
+}
+impl FromStr for IpNetwork {
+    type Err = Error;
+
+    /// Parses a string such as `192.168.0.0/16`.
+    ///
+    /// If the prefix length is omitted, the network consists of the single address.
+    fn from_str(s: &str) -> Result<Self> {
+        let (addr, prefix_len) = match s.find('/') {
+            Some(i) => (&s[..i], Some(&s[i + 1..])),
+            None => (s, None),
+        };
+        let addr: IpAddr = track_assert_some!(
+            addr.parse().ok(),
+            ErrorKind::InvalidInput,
+            "Invalid IP address: {:?}",
+            s
+        );
+        let prefix_len = match prefix_len {
+            Some(n) => track_assert_some!(
+                n.parse().ok(),
+                ErrorKind::InvalidInput,
+                "Invalid prefix length: {:?}",
+                s
+            ),
+            None if addr.is_ipv4() => 32,
+            None => 128,
+        };
+        track!(Self::new(addr, prefix_len))
+    }
+}
+impl fmt::Display for IpNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/// Access control list that decides which peers clients are allowed to communicate with.
+///
+/// A peer is denied if it matches any of the denied networks.
+/// Otherwise, it is allowed if the allowed networks are empty or it matches any of them.
+///
+/// Requests for permissions (or channels, connections) to denied peers are rejected with
+/// 403 (Forbidden) errors.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PeerAcl {
+    allow: Vec<IpNetwork>,
+    deny: Vec<IpNetwork>,
+}
+impl PeerAcl {
+    /// Makes a new `PeerAcl` instance that allows all peers.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a network to the allowed networks.
+    pub fn allow(&mut self, network: IpNetwork) -> &mut Self {
+        self.allow.push(network);
+        self
+    }
+
+    /// Adds a network to the denied networks.
+    pub fn deny(&mut self, network: IpNetwork) -> &mut Self {
+        self.deny.push(network);
+        self
+    }
+
+    /// Returns `true` if clients are allowed to communicate with `peer`.
+    pub fn is_allowed(&self, peer: IpAddr) -> bool {
+        if self.deny.iter().any(|n| n.contains(peer)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|n| n.contains(peer))
+    }
+}
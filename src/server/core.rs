@@ -1,23 +1,50 @@
+use super::accounting::TeardownReason;
+use super::mobility::TicketIssuer;
+use super::options::ServerOptions;
+use super::policy::{Policy, PolicyResult};
+use super::shared::{AllocationInfo, FiveTuple, SharedState, TransportProtocol};
+use super::tcp_relay::ConnectionRegistry;
 use crate::attribute::Attribute;
-use crate::auth::AuthParams;
+use crate::auth::{self, AuthParams};
 use crate::channel_data::ChannelData;
+use crate::rfc6062;
+use crate::rfc7635::attributes::{AccessToken, ThirdPartyAuthorization};
+use crate::rfc8489::attributes::{PasswordAlgorithm, PasswordAlgorithms, Userhash};
+use crate::rfc8489::{self, FEATURE_PASSWORD_ALGORITHMS, FEATURE_USERNAME_ANONYMITY};
 use crate::{Error, ErrorKind, Result};
+use fibers::net::futures::{Connect, Connected, TcpListenerBind};
+use fibers::net::streams::Incoming;
+use fibers::net::{TcpListener, TcpStream};
 use fibers_timeout_queue::TimeoutQueue;
 use fibers_transport::Transport;
-use futures::{Async, Future, Poll};
+use futures::{Async, Future, Poll, Stream};
+use rand::Rng;
 use rustun::channel::{Channel as StunChannel, RecvMessage};
-use rustun::message::{ErrorResponse, Indication, Request, SuccessResponse};
+use rustun::message::{
+    ErrorResponse, Indication, InvalidMessage, MessageErrorKind, Request, SuccessResponse,
+};
 use rustun::transport::StunTransport;
 use std::collections::HashMap;
+use std::fmt;
 use std::net::UdpSocket as StdUdpSocket;
 use std::net::{IpAddr, SocketAddr};
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use std::vec;
+use stun_codec::rfc5389::attributes::ErrorCode;
 use stun_codec::rfc5766::attributes::ChannelNumber;
-use stun_codec::{rfc5389, rfc5766};
+use stun_codec::rfc8016::attributes::MobilityTicket;
+use stun_codec::rfc8656::attributes::AddressFamily;
+use stun_codec::{
+    rfc5389, rfc5766, rfc8016, rfc8656, AttributeType, Message, MessageClass, Method, TransactionId,
+};
 
-const ALLOCATION_LIEFTIME_SECONDS: u64 = 600;
 const PERMISSION_LIFETIME_SECONDS: u64 = 300;
 const CHANNEL_LIEFTIME_SECONDS: u64 = 600;
+const CONNECTION_TIMEOUT_SECONDS: u64 = 30;
+const RESPONSE_CACHE_SECONDS: u64 = 40;
+const MAX_RELAY_PORT_TRIALS: usize = 64;
+
+const TRANSPORT_PROTOCOL_UDP: u8 = 17;
 
 #[derive(Debug)]
 pub struct ServerCore<S, C>
@@ -27,33 +54,104 @@ where
 {
     stun_channel: StunChannel<Attribute, S>,
     channel_data_transporter: C,
-    allocations: HashMap<SocketAddr, AllocationState>,
+    allocations: HashMap<FiveTuple, AllocationState>,
     seqno: u64,
-    auth_params: AuthParams,
+    server_addr: SocketAddr,
+    options: ServerOptions,
+    shared: SharedState,
+    transport: TransportProtocol,
+    ticket_issuer: Option<TicketIssuer>,
     timeout_queue: TimeoutQueue<TimeoutEntry>,
+    tcp_connections: Option<ConnectionRegistry>,
+    pending_tcp_allocations: Vec<PendingTcpAllocation>,
+    pending_connects: Vec<PendingConnect>,
+    bound_connection: Option<(u32, TcpStream)>,
+    responses: HashMap<(FiveTuple, TransactionId), SuccessResponse<Attribute>>,
 }
 impl<S, C> ServerCore<S, C>
 where
     S: StunTransport<Attribute, PeerAddr = SocketAddr>,
     C: Transport<PeerAddr = SocketAddr, SendItem = ChannelData, RecvItem = ChannelData>,
 {
-    pub fn new(stun_transporter: S, channel_data_transporter: C, auth_params: AuthParams) -> Self {
+    pub fn new(
+        stun_transporter: S,
+        channel_data_transporter: C,
+        server_addr: SocketAddr,
+        options: ServerOptions,
+        shared: SharedState,
+    ) -> Self {
         let mut timeout_queue = TimeoutQueue::new();
         timeout_queue.push(TimeoutEntry::PollRecv, Duration::from_millis(100));
+        timeout_queue.push(TimeoutEntry::SweepKicked, Duration::from_millis(100));
+        let ticket_issuer = if options.is_mobility_enabled() {
+            Some(shared.ticket_issuer().clone())
+        } else {
+            None
+        };
         ServerCore {
             stun_channel: StunChannel::new(stun_transporter),
             channel_data_transporter,
             allocations: HashMap::new(),
             seqno: 0,
-            auth_params,
+            server_addr,
+            options,
+            shared,
+            transport: TransportProtocol::Udp,
+            ticket_issuer,
             timeout_queue,
+            tcp_connections: None,
+            pending_tcp_allocations: Vec::new(),
+            pending_connects: Vec::new(),
+            bound_connection: None,
+            responses: HashMap::new(),
         }
     }
 
+    /// Makes a new `ServerCore` instance that serves a TCP (or TLS, depending on `transport`) client connection.
+    ///
+    /// Such instance can handle TCP allocations ([RFC 6062]) of which
+    /// peer data connections are shared via `shared`.
+    /// Note that mobility ([RFC 8016]) is not supported over TCP.
+    ///
+    /// [RFC 6062]: https://tools.ietf.org/html/rfc6062
+    /// [RFC 8016]: https://tools.ietf.org/html/rfc8016
+    pub fn with_tcp_connections(
+        stun_transporter: S,
+        channel_data_transporter: C,
+        server_addr: SocketAddr,
+        transport: TransportProtocol,
+        options: ServerOptions,
+        shared: SharedState,
+    ) -> Self {
+        let mut this = Self::new(
+            stun_transporter,
+            channel_data_transporter,
+            server_addr,
+            options,
+            shared,
+        );
+        this.tcp_connections = Some(this.shared.tcp_connections().clone());
+        this.transport = transport;
+        this.ticket_issuer = None;
+        this
+    }
+
     pub fn stun_transporter_ref(&self) -> &S {
         self.stun_channel.transporter_ref()
     }
 
+    /// Takes the peer data connection bound to this client connection by a ConnectionBind request.
+    ///
+    /// After that, this client connection is no longer used for STUN messages.
+    pub fn take_bound_connection(&mut self) -> Option<(u32, TcpStream)> {
+        self.bound_connection.take()
+    }
+
+    /// Returns the 5-tuple between `client` and this server.
+    fn five_tuple(&self, client: SocketAddr) -> FiveTuple {
+        FiveTuple::new(client, self.server_addr, self.transport)
+    }
+
     fn handle_stun_message(
         &mut self,
         client: SocketAddr,
@@ -61,44 +159,370 @@ where
     ) -> Result<()> {
         match message {
             RecvMessage::Request(m) => {
-                track!(self.handle_stun_request(client, m))?;
+                if !self.check_source_limits(client, auth::has_message_integrity(m.as_ref())) {
+                    return Ok(());
+                }
+                let method = m.method();
+                let transaction_id = m.transaction_id();
+                if let Some(response) = self
+                    .responses
+                    .get(&(self.five_tuple(client), transaction_id))
+                {
+                    // Retransmission
+                    let response = response.clone();
+                    track!(self.stun_channel.reply(client, Ok(response)))?;
+                    return Ok(());
+                }
+                let username = self.request_username(&m).unwrap_or_default();
+                log::debug!(
+                    client:% = client,
+                    transaction_id:% = Hex(transaction_id.as_bytes()),
+                    method = method_name(method),
+                    username = username.as_str();
+                    "STUN request received"
+                );
+                if let Err(e) = track!(self.handle_stun_request(client, m)) {
+                    log::warn!(
+                        client:% = client,
+                        transaction_id:% = Hex(transaction_id.as_bytes()),
+                        method = method_name(method),
+                        username = username.as_str(),
+                        allocation:? = self.relay_addrs(client);
+                        "Cannot handle a STUN request: {}", e
+                    );
+
+                    // Answers the request on a best-effort basis (the client may retry it)
+                    let error = if let ErrorKind::InvalidInput = e.kind() {
+                        rfc5389::errors::BadRequest.into()
+                    } else {
+                        rfc5389::errors::ServerError.into()
+                    };
+                    let mut message =
+                        Message::new(MessageClass::ErrorResponse, method, transaction_id);
+                    message.add_attribute(Attribute::ErrorCode(error));
+                    if let Ok(response) = ErrorResponse::from_message(message) {
+                        let _ = self.stun_channel.reply(client, Err(response));
+                    }
+                }
             }
             RecvMessage::Indication(m) => {
-                track!(self.handle_stun_indication(client, m))?;
+                let method = m.method();
+                let transaction_id = m.transaction_id();
+                if let Err(e) = track!(self.handle_stun_indication(client, m)) {
+                    log::debug!(
+                        client:% = client,
+                        transaction_id:% = Hex(transaction_id.as_bytes()),
+                        method = method_name(method),
+                        allocation:? = self.relay_addrs(client);
+                        "Cannot handle a STUN indication: {}", e
+                    );
+                }
             }
             RecvMessage::Invalid(m) => {
-                #[allow(clippy::print_literal)]
-                {
-                    eprintln!("[ERROR:{}:{}] Invalid message: {:?}", file!(), line!(), m);
+                // Logged at the debug level because anyone can send them in bulk
+                self.shared.increment_invalid_messages();
+                log::debug!(
+                    client:% = client,
+                    transaction_id:% = Hex(m.transaction_id().as_bytes()),
+                    method = method_name(m.method()),
+                    class:% = m.class();
+                    "Invalid STUN message: {}", m.error()
+                );
+                if let MessageErrorKind::UnknownAttributes(ref types) = *m.error().kind() {
+                    if m.class() == MessageClass::Request && self.check_source_limits(client, false)
+                    {
+                        track!(self.reply_unknown_attributes(client, &m, types))?;
+                    }
                 }
             }
         }
         Ok(())
     }
 
+    /// Returns `false` if a request should be dropped because its source is banned or
+    /// exceeds the rate limit of unauthenticated requests.
+    ///
+    /// `has_integrity` indicates whether the request has a message integrity attribute.
+    fn check_source_limits(&self, client: SocketAddr, has_integrity: bool) -> bool {
+        let now = Instant::now();
+        if self.options.get_auth_failure_ban().is_some()
+            && self.shared.limiter().is_banned(client.ip(), now)
+        {
+            self.shared.increment_banned_requests();
+            return false;
+        }
+        if !has_integrity {
+            if let Some(limit) = self.options.get_unauthenticated_rate_limit() {
+                if !self.shared.limiter().acquire(client.ip(), limit, now) {
+                    self.shared.increment_rate_limited_requests();
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    /// Replies a 420 (Unknown Attribute) response that lists the comprehension-required
+    /// attributes that the server does not understand.
+    fn reply_unknown_attributes(
+        &mut self,
+        client: SocketAddr,
+        request: &InvalidMessage,
+        types: &[AttributeType],
+    ) -> Result<()> {
+        let mut message = Message::new(
+            MessageClass::ErrorResponse,
+            request.method(),
+            request.transaction_id(),
+        );
+        message.add_attribute(Attribute::ErrorCode(
+            rfc5389::errors::UnknownAttribute.into(),
+        ));
+        message.add_attribute(Attribute::UnknownAttributes(
+            rfc5389::attributes::UnknownAttributes::new(types.to_vec()),
+        ));
+        let response = track!(ErrorResponse::from_message(message).map_err(Error::from))?;
+        track!(self.stun_channel.reply(client, Err(response)))?;
+        Ok(())
+    }
+
+    /// Returns the relayed transport addresses of the allocation of `client` (for logging).
+    fn relay_addrs(&self, client: SocketAddr) -> Option<&[SocketAddr]> {
+        self.allocations
+            .get(&self.five_tuple(client))
+            .map(|a| a.info.relay_addrs())
+    }
+
     fn handle_stun_request(
         &mut self,
         client: SocketAddr,
         request: Request<Attribute>,
     ) -> Result<()> {
+        self.shared.increment_requests();
         match request.method() {
+            rfc5389::methods::BINDING => track!(self.handle_binding(client, request))?,
             rfc5766::methods::ALLOCATE => track!(self.handle_allocate(client, request))?,
             rfc5766::methods::REFRESH => track!(self.handle_refresh(client, request))?,
             rfc5766::methods::CREATE_PERMISSION => {
                 track!(self.handle_create_permission(client, request))?
             }
             rfc5766::methods::CHANNEL_BIND => track!(self.handle_channel_bind(client, request))?,
+            m if m == rfc6062::methods::connect() => track!(self.handle_connect(client, request))?,
+            m if m == rfc6062::methods::connection_bind() => {
+                track!(self.handle_connection_bind(client, request))?
+            }
             _ => track!(self.reply_bad_request(client, request))?,
         }
         Ok(())
     }
 
-    fn auth_validate(&self, request: &Request<Attribute>) -> Result<()> {
-        let mi = track_assert_some!(
-            request.get_attribute::<rfc5389::attributes::MessageIntegrity>(),
+    /// Validates the `MESSAGE-INTEGRITY` of `request`.
+    ///
+    /// If the validation fails, this method replies a 401 (Unauthorized) response
+    /// (unless the rate limit is exceeded) and returns `false`.
+    fn auth_validate(&mut self, client: SocketAddr, request: &Request<Attribute>) -> Result<bool> {
+        let username = self.request_username(request);
+        if let Err(e) = track!(self.check_password_algorithm(request)) {
+            log::info!(
+                client:% = client,
+                transaction_id:% = Hex(request.transaction_id().as_bytes()),
+                method = method_name(request.method()),
+                username = username.as_deref().unwrap_or("");
+                "Password algorithm negotiation failed: {}", e
+            );
+            track!(self.reply_error(client, request, rfc5389::errors::BadRequest.into()))?;
+            return Ok(false);
+        }
+
+        let result = track!(self.request_auth_params(request))
+            .and_then(|auth_params| track!(auth_params.validate(request.as_ref())));
+        if let Err(e) = result {
+            log::info!(
+                client:% = client,
+                transaction_id:% = Hex(request.transaction_id().as_bytes()),
+                method = method_name(request.method()),
+                username = username.as_deref().unwrap_or("");
+                "Authentication failed: {}", e
+            );
+            self.shared.increment_auth_failures();
+            self.options.notify(|o| {
+                o.auth_failed(
+                    client,
+                    username.as_deref(),
+                    self.allocations
+                        .get(&self.five_tuple(client))
+                        .map(|a| &a.info),
+                )
+            });
+
+            let now = Instant::now();
+            if let Some(policy) = self.options.get_auth_failure_ban() {
+                if self
+                    .shared
+                    .limiter()
+                    .record_auth_failure(client.ip(), policy, now)
+                {
+                    log::warn!(
+                        client:% = client;
+                        "Source banned due to repeated authentication failures"
+                    );
+                    self.shared.increment_bans();
+                }
+            }
+            if let Some(limit) = self.options.get_unauthenticated_rate_limit() {
+                if !self.shared.limiter().acquire(client.ip(), limit, now) {
+                    self.shared.increment_rate_limited_requests();
+                    return Ok(false);
+                }
+            }
+            track!(self.reply_unauthorized(client, request))?;
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    /// Checks the `PASSWORD-ALGORITHMS` and `PASSWORD-ALGORITHM` attributes of `request`
+    /// so that an attacker cannot bid down the algorithm ([RFC 8489 -- 9.2.4]).
+    ///
+    /// [RFC 8489 -- 9.2.4]: https://tools.ietf.org/html/rfc8489#section-9.2.4
+    fn check_password_algorithm(&self, request: &Request<Attribute>) -> Result<()> {
+        let supported = self.options.get_password_algorithms();
+        let algorithms = request.get_attribute::<PasswordAlgorithms>();
+        let algorithm = request.get_attribute::<PasswordAlgorithm>();
+        match (algorithms, algorithm) {
+            (None, None) => {
+                track_assert!(
+                    supported.is_empty() || supported.contains(&auth::PasswordAlgorithm::Md5),
+                    ErrorKind::InvalidInput,
+                    "MD5 is not allowed"
+                );
+            }
+            (Some(algorithms), Some(algorithm)) => {
+                track_assert_eq!(
+                    algorithms,
+                    &PasswordAlgorithms::new(supported),
+                    ErrorKind::InvalidInput,
+                    "PASSWORD-ALGORITHMS mismatch"
+                );
+                let algorithm = track_assert_some!(
+                    algorithm.algorithm(),
+                    ErrorKind::InvalidInput,
+                    "Unknown password algorithm"
+                );
+                track_assert!(
+                    supported.contains(&algorithm),
+                    ErrorKind::InvalidInput,
+                    "Unsupported password algorithm: {:?}",
+                    algorithm
+                );
+            }
+            _ => track_panic!(
+                ErrorKind::InvalidInput,
+                "Either PASSWORD-ALGORITHMS or PASSWORD-ALGORITHM is missing"
+            ),
+        }
+        Ok(())
+    }
+
+    /// Replies a 401 (Unauthorized) response that has the realm and the nonce of the server.
+    ///
+    /// If password algorithms are enabled, the nonce is prefixed with the [RFC 8489] cookie
+    /// (that also advertises the support of `USERHASH`) and the response has
+    /// a `PASSWORD-ALGORITHMS` attribute.
+    ///
+    /// [RFC 8489]: https://tools.ietf.org/html/rfc8489#section-9.2
+    fn reply_unauthorized(
+        &mut self,
+        client: SocketAddr,
+        request: &Request<Attribute>,
+    ) -> Result<()> {
+        let auth_params = self.shared.auth_params();
+        let realm = track_assert_some!(auth_params.get_realm(), ErrorKind::InvalidInput);
+        let nonce = track_assert_some!(auth_params.get_nonce(), ErrorKind::InvalidInput);
+
+        let mut response = ErrorResponse::new(request, rfc5389::errors::Unauthorized.into());
+        response.add_attribute(realm.clone().into());
+        let algorithms = self.options.get_password_algorithms();
+        if algorithms.is_empty() {
+            response.add_attribute(nonce.clone().into());
+        } else {
+            let features = FEATURE_PASSWORD_ALGORITHMS | FEATURE_USERNAME_ANONYMITY;
+            let nonce = rfc8489::nonce_with_features(features, nonce.value());
+            let nonce = track!(rfc5389::attributes::Nonce::new(nonce).map_err(Error::from))?;
+            response.add_attribute(nonce.into());
+            response.add_attribute(PasswordAlgorithms::new(algorithms).into());
+        }
+        if let Some(server) = self.options.authorization_server() {
+            response.add_attribute(ThirdPartyAuthorization::new(server.to_owned()).into());
+        }
+        track!(self.stun_channel.reply(client, Err(response)))?;
+        Ok(())
+    }
+
+    /// Returns the username of `request` (a `USERHASH` attribute is resolved to the username).
+    fn request_username(&self, request: &Request<Attribute>) -> Option<String> {
+        if let Some(a) = request.get_attribute::<rfc5389::attributes::Username>() {
+            return Some(a.name().to_owned());
+        }
+        let userhash = request.get_attribute::<Userhash>()?;
+        self.shared
+            .access()
+            .resolve_userhash(userhash)
+            .map(str::to_owned)
+    }
+
+    /// Returns the parameters for authenticating `request`.
+    ///
+    /// The responses to the request are protected by the same algorithms as the request.
+    fn request_auth_params(&self, request: &Request<Attribute>) -> Result<AuthParams> {
+        let auth_params = track!(self.credential_auth_params(request))?;
+        Ok(auth_params.for_request(request.as_ref()))
+    }
+
+    /// Returns the parameters that have the credential of the user of `request`.
+    ///
+    /// If the request has an `ACCESS-TOKEN` attribute ([RFC 7635]),
+    /// the session key in the token is used instead of the password.
+    ///
+    /// [RFC 7635]: https://tools.ietf.org/html/rfc7635
+    fn credential_auth_params(&self, request: &Request<Attribute>) -> Result<AuthParams> {
+        let access_token = if let Some(a) = request.get_attribute::<AccessToken>() {
+            a
+        } else {
+            let auth_params = self.shared.auth_params();
+            let username = track_assert_some!(
+                self.request_username(request),
+                ErrorKind::InvalidInput,
+                "No username"
+            );
+            if let Some(credential) = track!(self.shared.access().credential(&username))? {
+                return track!(auth_params.with_credential(&username, credential));
+            }
+            track_assert!(
+                self.shared.is_primary_user(&username),
+                ErrorKind::InvalidInput,
+                "Unknown user: {:?}",
+                username
+            );
+            return Ok(auth_params);
+        };
+        let kid = track_assert_some!(
+            request.get_attribute::<rfc5389::attributes::Username>(),
             ErrorKind::InvalidInput
         );
-        track!(self.auth_params.validate(mi))?;
+        let token = track!(self
+            .options
+            .decrypt_access_token(kid.name(), access_token.token()))?;
+        Ok(self
+            .shared
+            .auth_params()
+            .with_session_key(kid.clone(), token.mac_key().to_owned()))
+    }
+
+    fn handle_binding(&mut self, client: SocketAddr, request: Request<Attribute>) -> Result<()> {
+        let mut response = SuccessResponse::new(&request);
+        response.add_attribute(rfc5389::attributes::XorMappedAddress::new(client).into());
+        track!(self.stun_channel.reply(client, Ok(response)))?;
         Ok(())
     }
 
@@ -107,35 +531,62 @@ where
         client: SocketAddr,
         request: Request<Attribute>,
     ) -> Result<()> {
-        track!(self.auth_validate(&request))?;
-        let peer = track_assert_some!(
-            request.get_attribute::<rfc5766::attributes::XorPeerAddress>(),
-            ErrorKind::InvalidInput
-        )
-        .address();
+        if !track!(self.auth_validate(client, &request))? {
+            return Ok(());
+        }
+        let peers = request
+            .attributes()
+            .filter_map(|a| match a {
+                Attribute::XorPeerAddress(a) => Some(a.address()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        track_assert!(!peers.is_empty(), ErrorKind::InvalidInput);
+        if !self.allocations.contains_key(&self.five_tuple(client)) {
+            let error = rfc5766::errors::AllocationMismatch.into();
+            return track!(self.reply_error(client, &request, error));
+        }
 
-        let seqno = self.next_seqno();
-        let allocation =
-            track_assert_some!(self.allocations.get_mut(&client), ErrorKind::InvalidInput);
+        // Either all of the permissions are installed or none of them
+        for &peer in &peers {
+            if !track!(self.check_peer_address_family(client, &request, peer))? {
+                return Ok(());
+            }
+            if !track!(self.check_peer_acl(client, &request, peer))? {
+                return Ok(());
+            }
+            let ip = peer.ip();
+            if !track!(self.check_policy(client, &request, |p, a| p.create_permission(a, ip)))? {
+                return Ok(());
+            }
+        }
 
-        allocation
-            .permissions
-            .entry(peer.ip())
-            .or_insert_with(|| PermissionState { seqno })
-            .seqno = seqno;
+        let seqno = self.next_seqno();
+        let allocation = track_assert_some!(
+            self.allocations.get_mut(&self.five_tuple(client)),
+            ErrorKind::InvalidInput
+        );
+        for peer in &peers {
+            allocation.install_permission(peer.ip(), seqno);
+            self.options
+                .notify(|o| o.permission_installed(&allocation.info, peer.ip()));
+        }
 
         let mut response = SuccessResponse::new(&request);
-        track!(self.auth_params.add_auth_attributes(&mut response))?;
-        track!(self.stun_channel.reply(client, Ok(response)))?;
+        let auth_params = track!(self.request_auth_params(&request))?;
+        track!(auth_params.add_auth_attributes(&mut response))?;
+        track!(self.reply_success(client, response))?;
 
-        self.timeout_queue.push(
-            TimeoutEntry::Permission {
-                client,
-                peer: peer.ip(),
-                seqno,
-            },
-            Duration::from_secs(PERMISSION_LIFETIME_SECONDS),
-        );
+        for peer in peers {
+            self.timeout_queue.push(
+                TimeoutEntry::Permission {
+                    five_tuple: self.five_tuple(client),
+                    peer: peer.ip(),
+                    seqno,
+                },
+                Duration::from_secs(PERMISSION_LIFETIME_SECONDS),
+            );
+        }
 
         Ok(())
     }
@@ -145,7 +596,9 @@ where
         client: SocketAddr,
         request: Request<Attribute>,
     ) -> Result<()> {
-        track!(self.auth_validate(&request))?;
+        if !track!(self.auth_validate(client, &request))? {
+            return Ok(());
+        }
         let peer = track_assert_some!(
             request.get_attribute::<rfc5766::attributes::XorPeerAddress>(),
             ErrorKind::InvalidInput
@@ -155,24 +608,43 @@ where
             request.get_attribute::<ChannelNumber>(),
             ErrorKind::InvalidInput
         );
+        if !self.allocations.contains_key(&self.five_tuple(client)) {
+            let error = rfc5766::errors::AllocationMismatch.into();
+            return track!(self.reply_error(client, &request, error));
+        }
+        if !track!(self.check_peer_address_family(client, &request, peer))? {
+            return Ok(());
+        }
+        if !track!(self.check_peer_acl(client, &request, peer))? {
+            return Ok(());
+        }
+        let number = channel_number.value();
+        if !track!(self.check_policy(client, &request, |p, a| p.channel_bind(a, number, peer)))? {
+            return Ok(());
+        }
 
         let seqno = self.next_seqno();
-        let allocation =
-            track_assert_some!(self.allocations.get_mut(&client), ErrorKind::InvalidInput);
+        let allocation = track_assert_some!(
+            self.allocations.get_mut(&self.five_tuple(client)),
+            ErrorKind::InvalidInput
+        );
 
         allocation
             .channels
             .entry(channel_number)
             .or_insert_with(|| ChannelState::new(peer, seqno))
             .seqno = seqno;
+        self.options
+            .notify(|o| o.channel_bound(&allocation.info, channel_number.value(), peer));
 
         let mut response = SuccessResponse::new(&request);
-        track!(self.auth_params.add_auth_attributes(&mut response))?;
-        track!(self.stun_channel.reply(client, Ok(response)))?;
+        let auth_params = track!(self.request_auth_params(&request))?;
+        track!(auth_params.add_auth_attributes(&mut response))?;
+        track!(self.reply_success(client, response))?;
 
         self.timeout_queue.push(
             TimeoutEntry::Channel {
-                client,
+                five_tuple: self.five_tuple(client),
                 channel_number,
                 seqno,
             },
@@ -183,58 +655,573 @@ where
     }
 
     fn handle_refresh(&mut self, client: SocketAddr, request: Request<Attribute>) -> Result<()> {
-        track!(self.auth_validate(&request))?;
-        let lifetime = track_assert_some!(
-            request.get_attribute::<rfc5766::attributes::Lifetime>(),
-            ErrorKind::InvalidInput
-        );
+        let revoked = self
+            .allocations
+            .get(&self.five_tuple(client))
+            .filter(|a| a.info.is_revoked())
+            .map(|a| a.credential.clone().for_request(request.as_ref()));
+        if let Some(credential) = revoked {
+            // The user has been removed by `ServerHandle::reload`,
+            // so the request is authenticated by the credential with which the allocation was created
+            if track!(credential.validate(request.as_ref())).is_ok() {
+                track!(self.reply_unauthorized(client, &request))?;
+                self.remove_allocation(self.five_tuple(client), TeardownReason::Revoked);
+                return Ok(());
+            }
+        }
+        if !track!(self.auth_validate(client, &request))? {
+            return Ok(());
+        }
+        let requested_lifetime = request
+            .get_attribute::<rfc5766::attributes::Lifetime>()
+            .map(|a| a.lifetime());
+        let lifetime = if requested_lifetime == Some(Duration::from_secs(0)) {
+            // Deallocation
+            Duration::from_secs(0)
+        } else {
+            self.options.allocation_lifetime(requested_lifetime)
+        };
+        let lifetime = track!(rfc5766::attributes::Lifetime::new(lifetime))?;
+        let ticket = request.get_attribute::<MobilityTicket>();
+        if ticket.is_some() && self.ticket_issuer.is_none() {
+            let error = rfc8016::errors::MobilityForbidden.into();
+            return track!(self.reply_error(client, &request, error));
+        }
+        if let Some(ticket) = ticket.filter(|t| !t.data().is_empty()) {
+            if !track!(self.migrate_allocation(client, &request, ticket.data()))? {
+                return Ok(());
+            }
+        }
+        if !self.allocations.contains_key(&self.five_tuple(client)) {
+            let error = rfc5766::errors::AllocationMismatch.into();
+            return track!(self.reply_error(client, &request, error));
+        }
+
         if lifetime.lifetime().as_secs() == 0 {
-            self.allocations.remove(&client);
+            self.remove_allocation(self.five_tuple(client), TeardownReason::Deallocated);
+
+            let mut response = SuccessResponse::new(&request);
+            response.add_attribute(lifetime.into());
+            let auth_params = track!(self.request_auth_params(&request))?;
+            track!(auth_params.add_auth_attributes(&mut response))?;
+            track!(self.reply_success(client, response))?;
         } else {
+            let lifetime_value = lifetime.lifetime();
+            if !track!(self.check_policy(client, &request, |p, a| p.refresh(a, lifetime_value)))? {
+                return Ok(());
+            }
+
             let seqno = self.next_seqno();
-            let allocation =
-                track_assert_some!(self.allocations.get_mut(&client), ErrorKind::InvalidInput);
+            let allocation = self
+                .allocations
+                .get_mut(&self.five_tuple(client))
+                .expect("never fails");
             allocation.seqno = seqno;
+            self.options
+                .notify(|o| o.allocation_refreshed(&allocation.info, lifetime.lifetime()));
             self.timeout_queue.push(
-                TimeoutEntry::Allocation { client, seqno },
+                TimeoutEntry::Allocation {
+                    five_tuple: self.five_tuple(client),
+                    seqno,
+                },
                 lifetime.lifetime(),
             );
 
             let mut response = SuccessResponse::new(&request);
-            response.add_attribute(lifetime.clone().into());
-            track!(self.auth_params.add_auth_attributes(&mut response))?;
-            track!(self.stun_channel.reply(client, Ok(response)))?;
+            response.add_attribute(lifetime.into());
+            if ticket.is_some() {
+                response
+                    .add_attribute(track!(self.issue_mobility_ticket(client, &request))?.into());
+            }
+            let auth_params = track!(self.request_auth_params(&request))?;
+            track!(auth_params.add_auth_attributes(&mut response))?;
+            track!(self.reply_success(client, response))?;
         }
         Ok(())
     }
 
+    /// Moves the allocation identified by the mobility ticket to the new 5-tuple of `client` ([RFC 8016]).
+    ///
+    /// If the migration fails, this method replies an error response and returns `false`.
+    ///
+    /// [RFC 8016]: https://tools.ietf.org/html/rfc8016
+    fn migrate_allocation(
+        &mut self,
+        client: SocketAddr,
+        request: &Request<Attribute>,
+        ticket: &[u8],
+    ) -> Result<bool> {
+        let issuer = track_assert_some!(self.ticket_issuer.as_ref(), ErrorKind::Other);
+        let username = self.request_username(request);
+        let old_client = match issuer.open(ticket) {
+            Some((addr, ticket_username)) if Some(&ticket_username) == username.as_ref() => addr,
+            _ => {
+                let error = rfc5389::errors::BadRequest.into();
+                track!(self.reply_error(client, request, error))?;
+                return Ok(false);
+            }
+        };
+        if old_client == client {
+            return Ok(true);
+        }
+        let five_tuple = self.five_tuple(client);
+        let old_five_tuple = self.five_tuple(old_client);
+        if self.allocations.contains_key(&five_tuple)
+            || !self.allocations.contains_key(&old_five_tuple)
+        {
+            let error = rfc5766::errors::AllocationMismatch.into();
+            track!(self.reply_error(client, request, error))?;
+            return Ok(false);
+        }
+
+        let mut allocation = self
+            .allocations
+            .remove(&old_five_tuple)
+            .expect("never fails");
+
+        // The timers of the old 5-tuple are invalidated, so those of the permissions and
+        // the channels are restarted (the allocation timer is restarted by the refresh itself).
+        for (peer, permission) in &mut allocation.permissions {
+            permission.seqno = self.next_seqno();
+            self.timeout_queue.push(
+                TimeoutEntry::Permission {
+                    five_tuple: self.five_tuple(client),
+                    peer: *peer,
+                    seqno: permission.seqno,
+                },
+                Duration::from_secs(PERMISSION_LIFETIME_SECONDS),
+            );
+        }
+        for (channel_number, channel) in &mut allocation.channels {
+            channel.seqno = self.next_seqno();
+            self.timeout_queue.push(
+                TimeoutEntry::Channel {
+                    five_tuple: self.five_tuple(client),
+                    channel_number: *channel_number,
+                    seqno: channel.seqno,
+                },
+                Duration::from_secs(CHANNEL_LIEFTIME_SECONDS),
+            );
+        }
+        allocation.info.set_client_addr(client);
+        self.allocations.insert(five_tuple, allocation);
+        self.shared.move_allocation(old_five_tuple, five_tuple);
+        Ok(true)
+    }
+
+    fn issue_mobility_ticket(
+        &self,
+        client: SocketAddr,
+        request: &Request<Attribute>,
+    ) -> Result<MobilityTicket> {
+        let issuer = track_assert_some!(self.ticket_issuer.as_ref(), ErrorKind::Other);
+        let username = track_assert_some!(self.request_username(request), ErrorKind::InvalidInput);
+        let ticket = issuer.issue(client, &username);
+        track!(MobilityTicket::new(ticket).map_err(Error::from))
+    }
+
     fn handle_allocate(&mut self, client: SocketAddr, request: Request<Attribute>) -> Result<()> {
-        if let Some(mi) = request.get_attribute::<rfc5389::attributes::MessageIntegrity>() {
-            track!(self.auth_params.validate(mi))?;
+        if auth::has_message_integrity(request.as_ref()) {
+            if !track!(self.auth_validate(client, &request))? {
+                return Ok(());
+            }
+            if self.pending_tcp_allocations.iter().any(|a| {
+                a.client == client && a.request.transaction_id() == request.transaction_id()
+            }) {
+                // Retransmission (the response will be sent once the allocation is completed)
+                return Ok(());
+            }
+            if self.allocations.contains_key(&self.five_tuple(client))
+                || self
+                    .pending_tcp_allocations
+                    .iter()
+                    .any(|a| a.client == client)
+            {
+                let error = rfc5766::errors::AllocationMismatch.into();
+                return track!(self.reply_error(client, &request, error));
+            }
+            if let Some(alternate_server) = self.options.alternate_server(client) {
+                let mut response =
+                    ErrorResponse::new(&request, rfc5389::errors::TryAlternate.into());
+                response.add_attribute(
+                    rfc5389::attributes::AlternateServer::new(alternate_server).into(),
+                );
+                let auth_params = track!(self.request_auth_params(&request))?;
+                track!(auth_params.add_auth_attributes(&mut response))?;
+                track!(self.stun_channel.reply(client, Err(response)))?;
+                return Ok(());
+            }
+            if request.get_attribute::<MobilityTicket>().is_some() && self.ticket_issuer.is_none() {
+                let error = rfc8016::errors::MobilityForbidden.into();
+                return track!(self.reply_error(client, &request, error));
+            }
 
-            // FIXME: Add existence check
-            let seqno = self.next_seqno();
-            let state = track!(AllocationState::new(seqno))?;
-            let relay_addr = track!(state.socket.local_addr().map_err(Error::from))?;
-            self.allocations.insert(client, state);
+            if let Some(error) = self.check_allocation_quota(&request) {
+                return track!(self.reply_error(client, &request, error));
+            }
+            let username = self.request_username(&request).unwrap_or_default();
+            let five_tuple = self.five_tuple(client);
+            if let Err(error) = self
+                .options
+                .authorize(|p| p.allocate(five_tuple, &username))
+            {
+                log::info!(
+                    client:% = client,
+                    username = username.as_str();
+                    "Allocate request denied by the policy"
+                );
+                return track!(self.reply_error(client, &request, error));
+            }
+
+            let protocol = request
+                .get_attribute::<rfc5766::attributes::RequestedTransport>()
+                .map(|a| a.protocol());
+            let (family, dual_stack) = if let Some(x) = requested_address_families(&request) {
+                x
+            } else {
+                return track!(self.reply_bad_request(client, request));
+            };
+            let relay_ip = if let Some(ip) = self.options.relay_ip(family) {
+                ip
+            } else {
+                let error = rfc8656::errors::AddressFamilyNotSupported.into();
+                return track!(self.reply_error(client, &request, error));
+            };
+            match protocol {
+                None => track!(self.reply_bad_request(client, request))?,
+                Some(TRANSPORT_PROTOCOL_UDP) => {
+                    let mut relay_ips = vec![relay_ip];
+                    if dual_stack {
+                        // If no IPv6 relay address is configured, only the IPv4 one is allocated
+                        relay_ips.extend(self.options.relay_ip(AddressFamily::V6));
+                    }
 
-            let lifetime = Duration::from_secs(ALLOCATION_LIEFTIME_SECONDS);
-            self.timeout_queue
-                .push(TimeoutEntry::Allocation { client, seqno }, lifetime);
+                    let mut sockets = Vec::new();
+                    for ip in relay_ips {
+                        if let Some(socket) = track!(self.bind_relay_socket(ip))? {
+                            sockets.push(socket);
+                        } else {
+                            let error = rfc5766::errors::InsufficientCapacity.into();
+                            return track!(self.reply_error(client, &request, error));
+                        }
+                    }
+                    track!(self.complete_allocate(client, request, Relay::Udp(sockets)))?;
+                }
+                Some(rfc6062::TRANSPORT_PROTOCOL_TCP) if self.tcp_connections.is_some() => {
+                    let mut ports = self.relay_port_candidates();
+                    let port = ports.next().unwrap_or(0);
+                    self.pending_tcp_allocations.push(PendingTcpAllocation {
+                        client,
+                        request,
+                        relay_ip,
+                        ports,
+                        bind: TcpListener::bind(SocketAddr::new(relay_ip, port)),
+                    });
+                }
+                Some(_) => {
+                    let error = rfc5766::errors::UnsupportedTransportProtocol.into();
+                    track!(self.reply_error(client, &request, error))?;
+                }
+            }
+        } else {
+            track!(self.reply_unauthorized(client, &request))?;
+        }
+        Ok(())
+    }
 
-            let mut response = SuccessResponse::new(&request);
-            response.add_attribute(track!(rfc5766::attributes::Lifetime::new(lifetime))?.into());
+    /// Returns the error to reply if the allocation quota is exceeded.
+    fn check_allocation_quota(&self, request: &Request<Attribute>) -> Option<ErrorCode> {
+        if let Some(max) = self.shared.access().get_max_allocations() {
+            if self.shared.allocation_count(None) >= max {
+                return Some(rfc5766::errors::InsufficientCapacity.into());
+            }
+        }
+        if let Some(max) = self.shared.access().get_max_allocations_per_user() {
+            let username = self.request_username(request).unwrap_or_default();
+            if self.shared.allocation_count(Some(&username)) >= max {
+                return Some(rfc5766::errors::AllocationQuotaReached.into());
+            }
+        }
+        None
+    }
+
+    /// Returns the ports to try, in order, when binding a relayed transport address.
+    ///
+    /// `0` (an ephemeral port) is the only candidate if no port range is configured.
+    fn relay_port_candidates(&self) -> vec::IntoIter<u16> {
+        let ports = if let Some(ports) = self.options.get_relay_port_range() {
+            let mut rng = rand::thread_rng();
+            (0..MAX_RELAY_PORT_TRIALS)
+                .map(|_| rng.gen_range(ports.clone()))
+                .collect()
+        } else {
+            vec![0]
+        };
+        ports.into_iter()
+    }
+
+    /// Binds a relayed UDP socket to a port in the configured range.
+    ///
+    /// `None` means that no ports are available.
+    fn bind_relay_socket(&self, ip: IpAddr) -> Result<Option<StdUdpSocket>> {
+        // FIXME: Make asynchronous
+        let socket = self
+            .relay_port_candidates()
+            .find_map(|port| StdUdpSocket::bind(SocketAddr::new(ip, port)).ok());
+        let socket = if let Some(socket) = socket {
+            socket
+        } else {
+            log::warn!(relay_ip:% = ip; "No relay ports are available");
+            return Ok(None);
+        };
+        track!(socket.set_nonblocking(true).map_err(Error::from))?;
+        Ok(Some(socket))
+    }
+
+    /// Replies 403 (Forbidden) and returns `false` if `peer` is denied by the access control list.
+    fn check_peer_acl(
+        &mut self,
+        client: SocketAddr,
+        request: &Request<Attribute>,
+        peer: SocketAddr,
+    ) -> Result<bool> {
+        let is_allowed = self.shared.access().is_peer_allowed(peer.ip());
+        if !is_allowed {
+            log::info!(client:% = client, peer:% = peer; "Peer denied by the ACL");
+            let error = rfc5766::errors::Forbidden.into();
+            track!(self.reply_error(client, request, error))?;
+        }
+        Ok(is_allowed)
+    }
+
+    /// Consults the policy about an operation on the allocation of `client`.
+    ///
+    /// If the operation is denied, this method replies the error response and returns `false`.
+    fn check_policy<F>(
+        &mut self,
+        client: SocketAddr,
+        request: &Request<Attribute>,
+        f: F,
+    ) -> Result<bool>
+    where
+        F: FnOnce(&dyn Policy, &AllocationInfo) -> PolicyResult,
+    {
+        let result = match self.allocations.get(&self.five_tuple(client)) {
+            Some(allocation) => self.options.authorize(|p| f(p, &allocation.info)),
+            None => Ok(()),
+        };
+        if let Err(error) = result {
+            log::info!(
+                client:% = client,
+                method = method_name(request.method()),
+                code = error.code();
+                "Request denied by the policy"
+            );
+            track!(self.reply_error(client, request, error))?;
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    fn complete_allocate(
+        &mut self,
+        client: SocketAddr,
+        request: Request<Attribute>,
+        relay: Relay,
+    ) -> Result<()> {
+        let seqno = self.next_seqno();
+        let relay_addrs = track!(relay.local_addrs())?;
+        let lifetime = self.options.allocation_lifetime(
+            request
+                .get_attribute::<rfc5766::attributes::Lifetime>()
+                .map(|a| a.lifetime()),
+        );
+        let username = self.request_username(&request).unwrap_or_default();
+        let username = username.as_str();
+        let auth_params = self.shared.auth_params();
+        let realm = auth_params.get_realm().map_or("", |r| r.text());
+        let five_tuple = self.five_tuple(client);
+        let info = AllocationInfo::new(five_tuple, username, realm, relay_addrs.clone());
+        let credential = track!(self.credential_auth_params(&request))?;
+        log::info!(
+            five_tuple:% = five_tuple,
+            username = username,
+            allocation:? = relay_addrs;
+            "Allocation created"
+        );
+        self.shared.register_allocation(info.clone());
+        self.options.notify(|o| o.allocation_created(&info));
+        self.allocations.insert(
+            five_tuple,
+            AllocationState::new(seqno, relay, info, credential),
+        );
+
+        self.timeout_queue
+            .push(TimeoutEntry::Allocation { five_tuple, seqno }, lifetime);
+
+        let mut response = SuccessResponse::new(&request);
+        response.add_attribute(track!(rfc5766::attributes::Lifetime::new(lifetime))?.into());
+        for relay_addr in relay_addrs {
+            let relay_addr = self.options.advertised_relay_addr(relay_addr);
             response.add_attribute(rfc5766::attributes::XorRelayAddress::new(relay_addr).into());
-            track!(self.auth_params.add_auth_attributes(&mut response))?;
-            track!(self.stun_channel.reply(client, Ok(response)))?;
+        }
+        response.add_attribute(rfc5389::attributes::XorMappedAddress::new(client).into());
+        if request.get_attribute::<MobilityTicket>().is_some() {
+            response.add_attribute(track!(self.issue_mobility_ticket(client, &request))?.into());
+        }
+        let auth_params = track!(self.request_auth_params(&request))?;
+        track!(auth_params.add_auth_attributes(&mut response))?;
+        track!(self.reply_success(client, response))?;
+        Ok(())
+    }
+
+    /// Replies 443 (Peer Address Family Mismatch) and returns `false`
+    /// if the allocation has no relayed transport address of the same family as `peer`.
+    fn check_peer_address_family(
+        &mut self,
+        client: SocketAddr,
+        request: &Request<Attribute>,
+        peer: SocketAddr,
+    ) -> Result<bool> {
+        let is_mismatch = self
+            .allocations
+            .get(&self.five_tuple(client))
+            .is_some_and(|a| !a.relay.supports(peer));
+        if is_mismatch {
+            let error = rfc8656::errors::PeerAddressFamilyMismatch.into();
+            track!(self.reply_error(client, request, error))?;
+        }
+        Ok(!is_mismatch)
+    }
+
+    fn handle_connect(&mut self, client: SocketAddr, request: Request<Attribute>) -> Result<()> {
+        if !track!(self.auth_validate(client, &request))? {
+            return Ok(());
+        }
+        let peer = track_assert_some!(
+            request.get_attribute::<rfc5766::attributes::XorPeerAddress>(),
+            ErrorKind::InvalidInput
+        )
+        .address();
+        if !track!(self.check_peer_address_family(client, &request, peer))? {
+            return Ok(());
+        }
+        if !track!(self.check_peer_acl(client, &request, peer))? {
+            return Ok(());
+        }
+
+        let tcp_connections = if let Some(x) = self.tcp_connections.clone() {
+            x
         } else {
-            let realm = track_assert_some!(self.auth_params.get_realm(), ErrorKind::InvalidInput);
-            let nonce = track_assert_some!(self.auth_params.get_nonce(), ErrorKind::InvalidInput);
+            return track!(self.reply_bad_request(client, request));
+        };
+        match self.allocations.get(&self.five_tuple(client)) {
+            None => {
+                let error = rfc5766::errors::AllocationMismatch.into();
+                return track!(self.reply_error(client, &request, error));
+            }
+            Some(allocation) if !allocation.relay.is_tcp() => {
+                return track!(self.reply_bad_request(client, request));
+            }
+            Some(_) => {}
+        }
+        let exists = tcp_connections.contains(self.five_tuple(client), peer)
+            || self
+                .pending_connects
+                .iter()
+                .any(|x| x.client == client && x.peer == peer);
+        if exists {
+            let error = rfc6062::errors::ConnectionAlreadyExists.into();
+            return track!(self.reply_error(client, &request, error));
+        }
 
-            let mut response = ErrorResponse::new(&request, rfc5389::errors::Unauthorized.into());
-            response.add_attribute(realm.clone().into());
-            response.add_attribute(nonce.clone().into());
-            track!(self.stun_channel.reply(client, Err(response)))?;
+        let seqno = self.next_seqno();
+        self.pending_connects.push(PendingConnect {
+            seqno,
+            client,
+            peer,
+            request,
+            connect: TcpStream::connect(peer),
+        });
+        self.timeout_queue.push(
+            TimeoutEntry::Connect { seqno },
+            Duration::from_secs(CONNECTION_TIMEOUT_SECONDS),
+        );
+        Ok(())
+    }
+
+    fn complete_connect(&mut self, connect: PendingConnect, stream: TcpStream) -> Result<()> {
+        let PendingConnect {
+            client,
+            peer,
+            request,
+            ..
+        } = connect;
+        let tcp_connections = track_assert_some!(self.tcp_connections.clone(), ErrorKind::Other);
+
+        let seqno = self.next_seqno();
+        let allocation = if let Some(x) = self.allocations.get_mut(&self.five_tuple(client)) {
+            x
+        } else {
+            let error = rfc5766::errors::AllocationMismatch.into();
+            return track!(self.reply_error(client, &request, error));
+        };
+        allocation.install_permission(peer.ip(), seqno);
+        self.options
+            .notify(|o| o.permission_installed(&allocation.info, peer.ip()));
+        self.timeout_queue.push(
+            TimeoutEntry::Permission {
+                five_tuple: self.five_tuple(client),
+                peer: peer.ip(),
+                seqno,
+            },
+            Duration::from_secs(PERMISSION_LIFETIME_SECONDS),
+        );
+
+        let connection_id = tcp_connections.register(self.five_tuple(client), peer, stream);
+        self.timeout_queue.push(
+            TimeoutEntry::PeerConnection { connection_id },
+            Duration::from_secs(CONNECTION_TIMEOUT_SECONDS),
+        );
+
+        let mut response = SuccessResponse::new(&request);
+        response.add_attribute(rfc6062::attributes::ConnectionId::new(connection_id).into());
+        let auth_params = track!(self.request_auth_params(&request))?;
+        track!(auth_params.add_auth_attributes(&mut response))?;
+        track!(self.reply_success(client, response))?;
+        Ok(())
+    }
+
+    fn handle_connection_bind(
+        &mut self,
+        client: SocketAddr,
+        request: Request<Attribute>,
+    ) -> Result<()> {
+        if !track!(self.auth_validate(client, &request))? {
+            return Ok(());
+        }
+        let connection_id = track_assert_some!(
+            request.get_attribute::<rfc6062::attributes::ConnectionId>(),
+            ErrorKind::InvalidInput
+        )
+        .value();
+
+        let bound = self
+            .tcp_connections
+            .as_ref()
+            .filter(|_| {
+                self.bound_connection.is_none()
+                    && !self.allocations.contains_key(&self.five_tuple(client))
+            })
+            .and_then(|x| x.bind(connection_id));
+        if let Some((_peer, stream)) = bound {
+            let mut response = SuccessResponse::new(&request);
+            let auth_params = track!(self.request_auth_params(&request))?;
+            track!(auth_params.add_auth_attributes(&mut response))?;
+            track!(self.reply_success(client, response))?;
+            self.bound_connection = Some((connection_id, stream));
+        } else {
+            track!(self.reply_bad_request(client, request))?;
         }
         Ok(())
     }
@@ -248,24 +1235,23 @@ where
             rfc5766::methods::SEND => {
                 track!(self.handle_send(client, indication))?;
             }
-            _ => {
-                #[allow(clippy::print_literal)]
-                {
-                    eprintln!(
-                        "[WARN:{}:{}] Unknown STUN indication: {:?}",
-                        file!(),
-                        line!(),
-                        indication
-                    );
-                }
+            method => {
+                log::debug!(
+                    client:% = client,
+                    transaction_id:% = Hex(indication.transaction_id().as_bytes()),
+                    method = method_name(method);
+                    "Unknown STUN indication"
+                );
             }
         }
         Ok(())
     }
 
     fn handle_send(&mut self, client: SocketAddr, indication: Indication<Attribute>) -> Result<()> {
-        let allocation =
-            track_assert_some!(self.allocations.get_mut(&client), ErrorKind::InvalidInput);
+        let allocation = track_assert_some!(
+            self.allocations.get_mut(&self.five_tuple(client)),
+            ErrorKind::InvalidInput
+        );
         let peer = track_assert_some!(
             indication.get_attribute::<rfc5766::attributes::XorPeerAddress>(),
             ErrorKind::InvalidInput
@@ -279,31 +1265,128 @@ where
             allocation.permissions.contains_key(&peer.ip()),
             ErrorKind::InvalidInput
         );
-        track!(allocation
-            .socket
-            .send_to(data.data(), peer)
-            .map_err(Error::from))?;
+        track_assert!(
+            allocation.is_send_allowed(&self.options, peer),
+            ErrorKind::InvalidInput,
+            "Denied by the policy"
+        );
+        track!(allocation.relay.send_to(data.data(), peer))?;
+        allocation.info.record_to_peer(peer, data.data().len());
+        self.shared.add_bytes_to_peers(data.data().len());
 
         Ok(())
     }
 
     fn reply_bad_request(&mut self, client: SocketAddr, request: Request<Attribute>) -> Result<()> {
-        let response = ErrorResponse::new(&request, rfc5389::errors::BadRequest.into());
+        track!(self.reply_error(client, &request, rfc5389::errors::BadRequest.into()))
+    }
+
+    /// Replies a success response and caches it so that retransmissions of the request
+    /// get the identical response ([RFC 5766 -- 7.2. Receiving an Allocate Request]).
+    ///
+    /// Only these responses to authenticated requests are cached,
+    /// so unauthenticated requests cannot fill the cache.
+    ///
+    /// [RFC 5766 -- 7.2. Receiving an Allocate Request]: https://tools.ietf.org/html/rfc5766#section-7.2
+    fn reply_success(
+        &mut self,
+        client: SocketAddr,
+        response: SuccessResponse<Attribute>,
+    ) -> Result<()> {
+        let five_tuple = self.five_tuple(client);
+        let transaction_id = response.transaction_id();
+        self.responses
+            .insert((five_tuple, transaction_id), response.clone());
+        self.timeout_queue.push(
+            TimeoutEntry::Response {
+                five_tuple,
+                transaction_id,
+            },
+            Duration::from_secs(RESPONSE_CACHE_SECONDS),
+        );
+        track!(self.stun_channel.reply(client, Ok(response)))?;
+        Ok(())
+    }
+
+    fn reply_error(
+        &mut self,
+        client: SocketAddr,
+        request: &Request<Attribute>,
+        error: ErrorCode,
+    ) -> Result<()> {
+        let username = self.request_username(request);
+        self.options.notify(|o| {
+            o.request_rejected(
+                client,
+                username.as_deref(),
+                self.allocations
+                    .get(&self.five_tuple(client))
+                    .map(|a| &a.info),
+                request.method(),
+                &error,
+            )
+        });
+        let response = ErrorResponse::new(request, error);
         track!(self.stun_channel.reply(client, Err(response)))?;
         Ok(())
     }
 
+    fn remove_allocation(&mut self, five_tuple: FiveTuple, reason: TeardownReason) {
+        if let Some(allocation) = self.allocations.remove(&five_tuple) {
+            log::info!(
+                five_tuple:% = five_tuple,
+                username = allocation.info.username(),
+                allocation:? = allocation.info.relay_addrs(),
+                reason:? = reason;
+                "Allocation removed"
+            );
+            self.shared.unregister_allocation(five_tuple);
+            self.options
+                .write_accounting_record(&allocation.info, reason);
+            self.options.notify(|o| match reason {
+                TeardownReason::Expired => o.allocation_expired(&allocation.info),
+                TeardownReason::Deallocated => o.allocation_deleted(&allocation.info),
+                TeardownReason::AdminKicked => o.allocation_kicked(&allocation.info),
+                TeardownReason::Revoked => o.allocation_revoked(&allocation.info),
+            });
+            if let Some(tcp_connections) = &self.tcp_connections {
+                tcp_connections.remove_pending_of(five_tuple);
+            }
+        }
+    }
+
     fn handle_channel_data(&mut self, client: SocketAddr, data: ChannelData) -> Result<()> {
-        let allocation =
-            track_assert_some!(self.allocations.get_mut(&client), ErrorKind::InvalidInput);
-        let channel = track_assert_some!(
-            allocation.channels.get_mut(&data.channel_number()),
+        let channel_number = data.channel_number();
+        if let Err(e) = track!(self.relay_channel_data(client, data)) {
+            log::debug!(
+                client:% = client,
+                channel_number = channel_number.value(),
+                allocation:? = self.relay_addrs(client);
+                "Cannot relay a ChannelData message: {}", e
+            );
+        }
+        Ok(())
+    }
+
+    fn relay_channel_data(&mut self, client: SocketAddr, data: ChannelData) -> Result<()> {
+        let allocation = track_assert_some!(
+            self.allocations.get_mut(&self.five_tuple(client)),
             ErrorKind::InvalidInput
         );
-        track!(allocation
-            .socket
-            .send_to(&data.into_data(), channel.peer_addr)
-            .map_err(Error::from))?;
+        let peer = track_assert_some!(
+            allocation.channels.get(&data.channel_number()),
+            ErrorKind::InvalidInput
+        )
+        .peer_addr;
+        track_assert!(
+            allocation.is_send_allowed(&self.options, peer),
+            ErrorKind::InvalidInput,
+            "Denied by the policy"
+        );
+        let data = data.into_data();
+        track!(allocation.relay.send_to(&data, peer))?;
+        allocation.info.record_to_peer(peer, data.len());
+        self.shared.add_bytes_to_peers(data.len());
         Ok(())
     }
 
@@ -315,98 +1398,316 @@ where
 
     fn handle_timeout(&mut self, entry: TimeoutEntry) -> Result<()> {
         match entry {
-            TimeoutEntry::Allocation { client, seqno } => {
+            TimeoutEntry::Allocation { five_tuple, seqno } => {
                 let do_delete = self
                     .allocations
-                    .get(&client)
+                    .get(&five_tuple)
                     .is_some_and(|s| s.seqno == seqno);
                 if do_delete {
-                    self.allocations.remove(&client);
+                    self.remove_allocation(five_tuple, TeardownReason::Expired);
                 }
             }
             TimeoutEntry::Permission {
-                client,
+                five_tuple,
                 peer,
                 seqno,
             } => {
-                if let Some(allocation) = self.allocations.get_mut(&client) {
+                if let Some(allocation) = self.allocations.get_mut(&five_tuple) {
                     let do_delete = allocation
                         .permissions
                         .get_mut(&peer)
                         .is_some_and(|s| s.seqno == seqno);
                     if do_delete {
                         allocation.permissions.remove(&peer);
+                        self.options
+                            .notify(|o| o.permission_expired(&allocation.info, peer));
                     }
                 }
             }
             TimeoutEntry::Channel {
-                client,
+                five_tuple,
                 channel_number,
                 seqno,
             } => {
                 // FIXME: Check permission lifetime
-                if let Some(allocation) = self.allocations.get_mut(&client) {
+                if let Some(allocation) = self.allocations.get_mut(&five_tuple) {
                     let do_delete = allocation
                         .channels
                         .get_mut(&channel_number)
                         .is_some_and(|s| s.seqno == seqno);
                     if do_delete {
-                        allocation.channels.remove(&channel_number);
+                        let channel = allocation.channels.remove(&channel_number);
+                        let peer = channel.expect("never fails").peer_addr;
+                        self.options.notify(|o| {
+                            o.channel_expired(&allocation.info, channel_number.value(), peer)
+                        });
                     }
                 }
             }
+            TimeoutEntry::Connect { seqno } => {
+                if let Some(i) = self.pending_connects.iter().position(|x| x.seqno == seqno) {
+                    let connect = self.pending_connects.swap_remove(i);
+                    let error = rfc6062::errors::ConnectionTimeoutOrFailure.into();
+                    track!(self.reply_error(connect.client, &connect.request, error))?;
+                }
+            }
+            TimeoutEntry::PeerConnection { connection_id } => {
+                if let Some(tcp_connections) = &self.tcp_connections {
+                    tcp_connections.remove_if_pending(connection_id);
+                }
+            }
+            TimeoutEntry::Response {
+                five_tuple,
+                transaction_id,
+            } => {
+                self.responses.remove(&(five_tuple, transaction_id));
+            }
             TimeoutEntry::PollRecv => {
                 // FIXME: Use asynchronous UDP socket
-                track!(self.poll_peer_recv())?;
                 self.timeout_queue
                     .push(TimeoutEntry::PollRecv, Duration::from_millis(100));
+                track!(self.poll_peer_recv())?;
+            }
+            TimeoutEntry::SweepKicked => {
+                self.timeout_queue
+                    .push(TimeoutEntry::SweepKicked, Duration::from_millis(100));
+                self.remove_kicked_allocations();
             }
         }
         Ok(())
     }
 
+    fn remove_kicked_allocations(&mut self) {
+        let kicked = self
+            .allocations
+            .iter()
+            .filter(|(_, a)| a.info.is_kicked())
+            .map(|(five_tuple, _)| *five_tuple)
+            .collect::<Vec<_>>();
+        for five_tuple in kicked {
+            self.remove_allocation(five_tuple, TeardownReason::AdminKicked);
+        }
+    }
+
     fn poll_peer_recv(&mut self) -> Result<()> {
         let mut buf = [0; 4096];
-        for (client, allocation) in &mut self.allocations {
-            match allocation.socket.recv_from(&mut buf) {
+        for (five_tuple, allocation) in &mut self.allocations {
+            let client = five_tuple.client_addr();
+            let sockets = if let Relay::Udp(sockets) = &allocation.relay {
+                sockets
+            } else {
+                continue;
+            };
+            for socket in sockets {
+                match socket.recv_from(&mut buf) {
+                    Err(e) => {
+                        use std::io;
+                        track_assert!(
+                            e.kind() == io::ErrorKind::WouldBlock,
+                            ErrorKind::Other,
+                            "I/O Error: {}",
+                            e
+                        );
+                    }
+                    Ok((size, peer)) => {
+                        let data = Vec::from(&buf[..size]);
+
+                        // FIXME: optimize
+                        if let Some(&channel_number) = allocation
+                            .channels
+                            .iter()
+                            .find(|(_, s)| s.peer_addr == peer)
+                            .map(|x| x.0)
+                        {
+                            let data = track!(ChannelData::new(channel_number, data))?;
+                            track!(self.channel_data_transporter.start_send(client, data))?;
+                        } else {
+                            if !allocation.permissions.contains_key(&peer.ip()) {
+                                log::debug!(
+                                    client:% = client, peer:% = peer;
+                                    "Dropped a datagram from a peer without permission"
+                                );
+                                continue;
+                            }
+
+                            let mut indication = Indication::new(rfc5766::methods::DATA);
+                            indication.add_attribute(
+                                rfc5766::attributes::XorPeerAddress::new(peer).into(),
+                            );
+                            indication.add_attribute(
+                                track!(rfc5766::attributes::Data::new(data))?.into(),
+                            );
+                            track!(self.stun_channel.cast(client, indication))?;
+                        }
+                        allocation.info.record_from_peer(peer, size);
+                        self.shared.add_bytes_from_peers(size);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn poll_pending_tcp_allocations(&mut self) -> Result<bool> {
+        let mut did_something = false;
+        let mut i = 0;
+        while i < self.pending_tcp_allocations.len() {
+            match self.pending_tcp_allocations[i].bind.poll() {
+                Ok(Async::NotReady) => {
+                    i += 1;
+                }
+                Ok(Async::Ready(listener)) => {
+                    did_something = true;
+                    let pending = self.pending_tcp_allocations.swap_remove(i);
+                    let local_addr = track!(listener.local_addr().map_err(Error::from))?;
+                    let relay = Relay::Tcp {
+                        local_addr,
+                        incoming: listener.incoming(),
+                        accepting: Vec::new(),
+                    };
+                    track!(self.complete_allocate(pending.client, pending.request, relay))?;
+                }
                 Err(e) => {
-                    use std::io;
-                    track_assert!(
-                        e.kind() == io::ErrorKind::WouldBlock,
-                        ErrorKind::Other,
-                        "I/O Error: {}",
-                        e
+                    did_something = true;
+                    let pending = &mut self.pending_tcp_allocations[i];
+                    if let Some(port) = pending.ports.next() {
+                        // The port is probably in use; try another one in the range
+                        pending.bind = TcpListener::bind(SocketAddr::new(pending.relay_ip, port));
+                        continue;
+                    }
+                    let pending = self.pending_tcp_allocations.swap_remove(i);
+                    log::warn!(
+                        client:% = pending.client;
+                        "Cannot bind a relayed TCP listener: {}", e
                     );
+                    let error = rfc5766::errors::InsufficientCapacity.into();
+                    track!(self.reply_error(pending.client, &pending.request, error))?;
                 }
-                Ok((size, peer)) => {
-                    let data = Vec::from(&buf[..size]);
+            }
+        }
+        Ok(did_something)
+    }
 
-                    // FIXME: optimize
-                    if let Some(&channel_number) = allocation
-                        .channels
-                        .iter()
-                        .find(|(_, s)| s.peer_addr == peer)
-                        .map(|x| x.0)
-                    {
-                        let data = track!(ChannelData::new(channel_number, data))?;
-                        track!(self.channel_data_transporter.start_send(*client, data))?;
-                    } else {
-                        track_assert!(
-                            allocation.permissions.contains_key(&peer.ip()),
-                            ErrorKind::InvalidInput
+    fn poll_pending_connects(&mut self) -> Result<bool> {
+        let mut did_something = false;
+        let mut i = 0;
+        while i < self.pending_connects.len() {
+            match self.pending_connects[i].connect.poll() {
+                Ok(Async::NotReady) => {
+                    i += 1;
+                }
+                Ok(Async::Ready(stream)) => {
+                    did_something = true;
+                    let connect = self.pending_connects.swap_remove(i);
+                    track!(self.complete_connect(connect, stream))?;
+                }
+                Err(e) => {
+                    did_something = true;
+                    let connect = self.pending_connects.swap_remove(i);
+                    log::debug!(
+                        client:% = connect.client,
+                        peer:% = connect.peer;
+                        "Cannot connect to a TCP peer: {}", e
+                    );
+                    let error = rfc6062::errors::ConnectionTimeoutOrFailure.into();
+                    track!(self.reply_error(connect.client, &connect.request, error))?;
+                }
+            }
+        }
+        Ok(did_something)
+    }
+
+    fn poll_peer_accept(&mut self) -> Result<bool> {
+        let tcp_connections = if let Some(x) = &self.tcp_connections {
+            x
+        } else {
+            return Ok(false);
+        };
+
+        let mut did_something = false;
+        for (five_tuple, allocation) in &mut self.allocations {
+            let client = five_tuple.client_addr();
+            let (incoming, accepting) = if let Relay::Tcp {
+                incoming,
+                accepting,
+                ..
+            } = &mut allocation.relay
+            {
+                (incoming, accepting)
+            } else {
+                continue;
+            };
+
+            while let Async::Ready(item) = track!(incoming.poll().map_err(Error::from))? {
+                did_something = true;
+                if let Some((connected, peer)) = item {
+                    accepting.push((connected, peer));
+                } else {
+                    break;
+                }
+            }
+
+            let mut i = 0;
+            while i < accepting.len() {
+                match accepting[i].0.poll() {
+                    Ok(Async::NotReady) => {
+                        i += 1;
+                        continue;
+                    }
+                    Ok(Async::Ready(stream)) => {
+                        let peer = accepting.swap_remove(i).1;
+                        if !allocation.permissions.contains_key(&peer.ip()) {
+                            log::debug!(
+                                client:% = client,
+                                peer:% = peer;
+                                "No permission for a TCP peer"
+                            );
+                            continue;
+                        }
+
+                        let connection_id = tcp_connections.register(*five_tuple, peer, stream);
+                        self.timeout_queue.push(
+                            TimeoutEntry::PeerConnection { connection_id },
+                            Duration::from_secs(CONNECTION_TIMEOUT_SECONDS),
                         );
 
-                        let mut indication = Indication::new(rfc5766::methods::DATA);
+                        let mut indication =
+                            Indication::new(rfc6062::methods::connection_attempt());
                         indication
                             .add_attribute(rfc5766::attributes::XorPeerAddress::new(peer).into());
-                        indication
-                            .add_attribute(track!(rfc5766::attributes::Data::new(data))?.into());
-                        track!(self.stun_channel.cast(*client, indication))?;
+                        indication.add_attribute(
+                            rfc6062::attributes::ConnectionId::new(connection_id).into(),
+                        );
+                        track!(self.stun_channel.cast(client, indication))?;
+                    }
+                    Err(e) => {
+                        let peer = accepting.swap_remove(i).1;
+                        log::debug!(
+                            client:% = client,
+                            peer:% = peer;
+                            "Cannot accept a TCP connection: {}", e
+                        );
                     }
                 }
+                did_something = true;
             }
         }
-        Ok(())
+        Ok(did_something)
+    }
+}
+impl<S, C> Drop for ServerCore<S, C>
+where
+    S: StunTransport<Attribute, PeerAddr = SocketAddr>,
+    C: Transport<PeerAddr = SocketAddr, SendItem = ChannelData, RecvItem = ChannelData>,
+{
+    fn drop(&mut self) {
+        for (five_tuple, allocation) in &self.allocations {
+            self.shared.unregister_allocation(*five_tuple);
+            self.options
+                .write_accounting_record(&allocation.info, TeardownReason::Deallocated);
+            self.options
+                .notify(|o| o.allocation_deleted(&allocation.info));
+        }
     }
 }
 unsafe impl<S, C> Send for ServerCore<S, C>
@@ -428,7 +1729,14 @@ where
         while did_something {
             did_something = false;
 
-            while let Async::Ready(message) = track!(self.stun_channel.poll_recv())? {
+            // The bytes following a ConnectionBind request are relayed to the peer as they are
+            while self.bound_connection.is_none() {
+                let message = if let Async::Ready(message) = track!(self.stun_channel.poll_recv())?
+                {
+                    message
+                } else {
+                    break;
+                };
                 did_something = true;
                 if let Some((client, message)) = message {
                     track!(self.handle_stun_message(client, message))?;
@@ -436,7 +1744,14 @@ where
                     return Ok(Async::Ready(()));
                 }
             }
-            while let Async::Ready(data) = track!(self.channel_data_transporter.poll_recv())? {
+            while self.bound_connection.is_none() {
+                let data = if let Async::Ready(data) =
+                    track!(self.channel_data_transporter.poll_recv())?
+                {
+                    data
+                } else {
+                    break;
+                };
                 did_something = true;
                 if let Some((client, data)) = data {
                     track!(self.handle_channel_data(client, data))?;
@@ -444,7 +1759,14 @@ where
                     return Ok(Async::Ready(()));
                 }
             }
-            track!(self.stun_channel.poll_send())?;
+            did_something |= track!(self.poll_pending_tcp_allocations())?;
+            did_something |= track!(self.poll_pending_connects())?;
+            did_something |= track!(self.poll_peer_accept())?;
+
+            let sent = track!(self.stun_channel.poll_send())?.is_ready();
+            if sent && self.bound_connection.is_some() {
+                return Ok(Async::Ready(()));
+            }
             track!(self.channel_data_transporter.poll_send())?;
             while let Some(entry) = self.timeout_queue.pop() {
                 did_something = true;
@@ -455,27 +1777,155 @@ where
     }
 }
 
+/// Returns the address family of the relayed transport address requested by the client
+/// and whether an additional IPv6 address is requested (i.e., dual-stack allocation).
+///
+/// `None` means that the request is malformed.
+fn requested_address_families(request: &Request<Attribute>) -> Option<(AddressFamily, bool)> {
+    let requested = request.get_attribute::<rfc8656::attributes::RequestedAddressFamily>();
+    let additional = request.get_attribute::<rfc8656::attributes::AdditionalAddressFamily>();
+    match (requested, additional) {
+        (None, None) => Some((AddressFamily::V4, false)),
+        (Some(a), None) => Some((a.address_family(), false)),
+        (None, Some(a)) if a.address_family() == AddressFamily::V6 => {
+            Some((AddressFamily::V4, true))
+        }
+        _ => None,
+    }
+}
+
 #[derive(Debug)]
 struct AllocationState {
     seqno: u64,
-    socket: StdUdpSocket,
+    relay: Relay,
+    info: AllocationInfo,
     permissions: HashMap<IpAddr, PermissionState>,
     channels: HashMap<ChannelNumber, ChannelState>,
+
+    // The credential with which the allocation was created
+    credential: AuthParams,
 }
 impl AllocationState {
-    fn new(seqno: u64) -> Result<Self> {
-        // FIXME: Make asynchronous
-        let socket = track!(StdUdpSocket::bind("0.0.0.0:0").map_err(Error::from))?;
-        track!(socket.set_nonblocking(true).map_err(Error::from))?;
-        Ok(AllocationState {
+    fn new(seqno: u64, relay: Relay, info: AllocationInfo, credential: AuthParams) -> Self {
+        AllocationState {
             seqno,
-            socket,
+            relay,
+            info,
+            credential,
             permissions: HashMap::new(),
             channels: HashMap::new(),
-        })
+        }
+    }
+
+    /// Installs or refreshes the permission for `peer`.
+    ///
+    /// Refreshing a permission discards the decisions of the policy for sending data to the peer.
+    fn install_permission(&mut self, peer: IpAddr, seqno: u64) {
+        let permission = PermissionState {
+            seqno,
+            send_decisions: HashMap::new(),
+        };
+        self.permissions.insert(peer, permission);
+    }
+
+    /// Returns whether the policy allows sending data to `peer`.
+    ///
+    /// The decision is cached in the permission for the peer,
+    /// and the policy is consulted again only after the decision expires or the permission is refreshed.
+    fn is_send_allowed(&mut self, options: &ServerOptions, peer: SocketAddr) -> bool {
+        let now = Instant::now();
+        let lifetime = options.get_send_decision_lifetime();
+        let decisions = self
+            .permissions
+            .get_mut(&peer.ip())
+            .map(|p| &mut p.send_decisions);
+        if let Some(&(allowed, decided_at)) = decisions.as_ref().and_then(|d| d.get(&peer)) {
+            if now < decided_at + lifetime {
+                return allowed;
+            }
+        }
+        let allowed = options.authorize(|p| p.send(&self.info, peer)).is_ok();
+        if let Some(decisions) = decisions {
+            decisions.retain(|_, &mut (_, decided_at)| now < decided_at + lifetime);
+            decisions.insert(peer, (allowed, now));
+        }
+        allowed
+    }
+}
+
+#[derive(Debug)]
+enum Relay {
+    Udp(Vec<StdUdpSocket>),
+    Tcp {
+        local_addr: SocketAddr,
+        incoming: Incoming,
+        accepting: Vec<(Connected, SocketAddr)>,
+    },
+}
+impl Relay {
+    fn is_tcp(&self) -> bool {
+        matches!(self, Relay::Tcp { .. })
+    }
+
+    fn local_addrs(&self) -> Result<Vec<SocketAddr>> {
+        match self {
+            Relay::Udp(sockets) => sockets
+                .iter()
+                .map(|s| track!(s.local_addr().map_err(Error::from)))
+                .collect(),
+            Relay::Tcp { local_addr, .. } => Ok(vec![*local_addr]),
+        }
+    }
+
+    /// Returns `true` if the relay has a transport address of the same family as `peer`.
+    fn supports(&self, peer: SocketAddr) -> bool {
+        match self {
+            Relay::Udp(sockets) => sockets
+                .iter()
+                .any(|s| s.local_addr().is_ok_and(|a| a.is_ipv4() == peer.is_ipv4())),
+            Relay::Tcp { local_addr, .. } => local_addr.is_ipv4() == peer.is_ipv4(),
+        }
+    }
+
+    fn send_to(&self, data: &[u8], peer: SocketAddr) -> Result<()> {
+        match self {
+            Relay::Udp(sockets) => {
+                let socket = track_assert_some!(
+                    sockets
+                        .iter()
+                        .find(|s| s.local_addr().is_ok_and(|a| a.is_ipv4() == peer.is_ipv4())),
+                    ErrorKind::InvalidInput,
+                    "Peer address family mismatch: {}",
+                    peer
+                );
+                track!(socket.send_to(data, peer).map_err(Error::from))?;
+            }
+            Relay::Tcp { .. } => {
+                track_panic!(ErrorKind::InvalidInput, "Not a UDP allocation");
+            }
+        }
+        Ok(())
     }
 }
 
+#[derive(Debug)]
+struct PendingTcpAllocation {
+    client: SocketAddr,
+    request: Request<Attribute>,
+    relay_ip: IpAddr,
+    ports: vec::IntoIter<u16>,
+    bind: TcpListenerBind,
+}
+
+#[derive(Debug)]
+struct PendingConnect {
+    seqno: u64,
+    client: SocketAddr,
+    peer: SocketAddr,
+    request: Request<Attribute>,
+    connect: Connect,
+}
+
 #[derive(Debug)]
 struct ChannelState {
     peer_addr: SocketAddr,
@@ -490,23 +1940,62 @@ impl ChannelState {
 #[derive(Debug)]
 struct PermissionState {
     seqno: u64,
+    send_decisions: HashMap<SocketAddr, (bool, Instant)>,
 }
 
 #[derive(Debug)]
 enum TimeoutEntry {
     Allocation {
-        client: SocketAddr,
+        five_tuple: FiveTuple,
         seqno: u64,
     },
     Permission {
-        client: SocketAddr,
+        five_tuple: FiveTuple,
         peer: IpAddr,
         seqno: u64,
     },
     Channel {
-        client: SocketAddr,
+        five_tuple: FiveTuple,
         channel_number: ChannelNumber,
         seqno: u64,
     },
+    Connect {
+        seqno: u64,
+    },
+    PeerConnection {
+        connection_id: u32,
+    },
+    Response {
+        five_tuple: FiveTuple,
+        transaction_id: TransactionId,
+    },
     PollRecv,
+    SweepKicked,
+}
+
+fn method_name(method: Method) -> &'static str {
+    match method {
+        rfc5389::methods::BINDING => "binding",
+        rfc5766::methods::ALLOCATE => "allocate",
+        rfc5766::methods::REFRESH => "refresh",
+        rfc5766::methods::SEND => "send",
+        rfc5766::methods::DATA => "data",
+        rfc5766::methods::CREATE_PERMISSION => "create_permission",
+        rfc5766::methods::CHANNEL_BIND => "channel_bind",
+        m if m == rfc6062::methods::connect() => "connect",
+        m if m == rfc6062::methods::connection_bind() => "connection_bind",
+        m if m == rfc6062::methods::connection_attempt() => "connection_attempt",
+        _ => "unknown",
+    }
+}
+
+/// Hexadecimal representation of bytes (e.g., transaction IDs).
+struct Hex<'a>(&'a [u8]);
+impl fmt::Display for Hex<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for b in self.0 {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
 }
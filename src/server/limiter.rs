@@ -0,0 +1,130 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const SWEEP_INTERVAL_SECONDS: u64 = 10;
+
+/// Per-source rate limit of requests (i.e., token bucket).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    rate: f64,
+    burst: f64,
+}
+impl RateLimit {
+    /// Makes a new `RateLimit` instance that allows `rate` requests per second on average
+    /// and at most `burst` requests at once.
+    pub fn new(rate: u32, burst: u32) -> Self {
+        RateLimit {
+            rate: f64::from(rate),
+            burst: f64::from(burst.max(1)),
+        }
+    }
+}
+
+/// Policy for temporarily banning sources that repeatedly fail to be authenticated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BanPolicy {
+    max_failures: u32,
+    window: Duration,
+    duration: Duration,
+}
+impl BanPolicy {
+    /// Makes a new `BanPolicy` instance that bans a source for `duration`
+    /// once `max_failures` authentication failures occur within `window`.
+    pub fn new(max_failures: u32, window: Duration, duration: Duration) -> Self {
+        BanPolicy {
+            max_failures: max_failures.max(1),
+            window,
+            duration,
+        }
+    }
+}
+
+/// Per-source-IP state of the rate limits and the bans.
+#[derive(Debug, Default)]
+pub(crate) struct SourceLimiter(Mutex<LimiterInner>);
+impl SourceLimiter {
+    /// Returns `true` if `ip` is banned at the moment.
+    pub fn is_banned(&self, ip: IpAddr, now: Instant) -> bool {
+        let inner = self.0.lock().expect("never fails");
+        inner
+            .sources
+            .get(&ip)
+            .and_then(|s| s.banned_until)
+            .is_some_and(|t| now < t)
+    }
+
+    /// Consumes a token of `ip` and returns `false` if there are no tokens left.
+    pub fn acquire(&self, ip: IpAddr, limit: &RateLimit, now: Instant) -> bool {
+        let mut inner = self.0.lock().expect("never fails");
+        let source = inner.source(ip, now);
+        let elapsed = now.saturating_duration_since(source.updated_at);
+        source.tokens = (source.tokens + elapsed.as_secs_f64() * limit.rate).min(limit.burst);
+        source.updated_at = now;
+        if source.tokens < 1.0 {
+            return false;
+        }
+        source.tokens -= 1.0;
+        true
+    }
+
+    /// Records an authentication failure of `ip` and returns `true` if it has just been banned.
+    pub fn record_auth_failure(&self, ip: IpAddr, policy: &BanPolicy, now: Instant) -> bool {
+        let mut inner = self.0.lock().expect("never fails");
+        let source = inner.source(ip, now);
+        if source.failures_until <= now {
+            source.failures = 0;
+            source.failures_until = now + policy.window;
+        }
+        source.failures += 1;
+        if source.failures < policy.max_failures {
+            return false;
+        }
+        source.failures = 0;
+        source.banned_until = Some(now + policy.duration);
+        true
+    }
+}
+
+#[derive(Debug, Default)]
+struct LimiterInner {
+    sources: HashMap<IpAddr, Source>,
+    last_sweep: Option<Instant>,
+}
+impl LimiterInner {
+    fn source(&mut self, ip: IpAddr, now: Instant) -> &mut Source {
+        self.sweep(now);
+        self.sources.entry(ip).or_insert_with(|| Source {
+            tokens: f64::INFINITY, // Capped to the burst size when used
+            updated_at: now,
+            failures: 0,
+            failures_until: now,
+            banned_until: None,
+        })
+    }
+
+    /// Removes the sources that are neither banned, counting failures nor refilling tokens
+    /// (the tokens are regarded as refilled after `SWEEP_INTERVAL_SECONDS`).
+    fn sweep(&mut self, now: Instant) {
+        let interval = Duration::from_secs(SWEEP_INTERVAL_SECONDS);
+        if self.last_sweep.is_some_and(|t| now < t + interval) {
+            return;
+        }
+        self.last_sweep = Some(now);
+        self.sources.retain(|_, s| {
+            s.banned_until.is_some_and(|t| now < t)
+                || now.saturating_duration_since(s.updated_at) < interval
+                || now < s.failures_until
+        });
+    }
+}
+
+#[derive(Debug)]
+struct Source {
+    tokens: f64,
+    updated_at: Instant,
+    failures: u32,
+    failures_until: Instant,
+    banned_until: Option<Instant>,
+}
@@ -0,0 +1,86 @@
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes128Gcm, Nonce};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+const NONCE_SIZE: usize = 12;
+
+/// Issuer of mobility tickets ([RFC 8016]).
+///
+/// A ticket holds the transport address of the client and the username of the allocation,
+/// encrypted by a key that is known only to the server.
+///
+/// [RFC 8016]: https://tools.ietf.org/html/rfc8016
+#[derive(Clone)]
+pub struct TicketIssuer {
+    cipher: Aes128Gcm,
+}
+impl TicketIssuer {
+    pub fn new() -> Self {
+        let key: [u8; 16] = rand::random();
+        TicketIssuer {
+            cipher: Aes128Gcm::new(&key.into()),
+        }
+    }
+
+    pub fn issue(&self, client: SocketAddr, username: &str) -> Vec<u8> {
+        let mut plaintext = Vec::new();
+        match client.ip() {
+            IpAddr::V4(ip) => {
+                plaintext.push(4);
+                plaintext.extend_from_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                plaintext.push(6);
+                plaintext.extend_from_slice(&ip.octets());
+            }
+        }
+        plaintext.extend_from_slice(&client.port().to_be_bytes());
+        plaintext.extend_from_slice(username.as_bytes());
+
+        let nonce: [u8; NONCE_SIZE] = rand::random();
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce.into(), &plaintext[..])
+            .expect("never fails");
+        let mut ticket = nonce.to_vec();
+        ticket.extend_from_slice(&ciphertext);
+        ticket
+    }
+
+    /// Decrypts the given ticket and returns the client address and the username in it.
+    ///
+    /// `None` means that the ticket is broken or has not been issued by this server.
+    pub fn open(&self, ticket: &[u8]) -> Option<(SocketAddr, String)> {
+        if ticket.len() < NONCE_SIZE {
+            return None;
+        }
+        let (nonce, ciphertext) = ticket.split_at(NONCE_SIZE);
+        let plaintext = self
+            .cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .ok()?;
+
+        let (ip, rest): (IpAddr, _) = match plaintext.first()? {
+            4 if plaintext.len() >= 7 => {
+                let mut octets = [0; 4];
+                octets.copy_from_slice(&plaintext[1..5]);
+                (Ipv4Addr::from(octets).into(), &plaintext[5..])
+            }
+            6 if plaintext.len() >= 19 => {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(&plaintext[1..17]);
+                (Ipv6Addr::from(octets).into(), &plaintext[17..])
+            }
+            _ => return None,
+        };
+        let port = u16::from_be_bytes([rest[0], rest[1]]);
+        let username = String::from_utf8(rest[2..].to_vec()).ok()?;
+        Some((SocketAddr::new(ip, port), username))
+    }
+}
+impl fmt::Debug for TicketIssuer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "TicketIssuer {{ .. }}")
+    }
+}
@@ -1,48 +1,127 @@
+pub use self::accounting::{AccountingRecord, AccountingSink, JsonLinesSink, TeardownReason};
+pub use self::acl::{IpNetwork, PeerAcl};
+pub use self::limiter::{BanPolicy, RateLimit};
+pub use self::observer::ServerObserver;
+pub use self::options::ServerOptions;
+pub use self::policy::{Policy, PolicyResult};
+pub use self::redirect::RedirectPolicy;
+#[cfg(unix)]
+pub use self::sharded::ShardedUdpServer;
+pub use self::shared::{
+    AllocationInfo, FiveTuple, ServerHandle, ServerStats, TrafficCounters, TransportProtocol,
+};
+
 use self::core::ServerCore;
+use self::shared::SharedState;
+use self::tcp_relay::{ConnectionRegistry, RelayStream, TcpRelay};
 use crate::auth::AuthParams;
 use crate::transport::{
-    ChannelDataTcpTransporter, ChannelDataUdpTransporter, StunTcpTransporter, StunTransporter,
-    StunUdpTransporter,
+    ChannelDataTransporter, ChannelDataUdpTransporter, StunTransporter, StunUdpTransporter,
+    TlsStream, TurnTcpTransporter, TurnTlsTransporter, TurnUdpTransporter, VirtualSocket,
 };
-use crate::turn_message::{TurnMessageDecoder, TurnMessageEncoder};
-use crate::Error;
+use crate::turn_message::{TurnMessage, TurnMessageDecoder, TurnMessageEncoder};
+use crate::{Error, ErrorKind, Result};
 use factory::DefaultFactory;
+use fibers::net::futures::Connected;
+use fibers::net::streams::Incoming;
 use fibers::{BoxSpawn, Spawn};
 use fibers_transport::{
     FixedPeerTransporter, RcTransporter, TcpListener, TcpTransport, UdpTransport, UdpTransporter,
 };
+use futures::future::Either;
 use futures::{Async, Future, Poll, Stream};
+use rustls::ServerConfig as TlsConfig;
+use std::collections::HashMap;
+use std::fmt;
 use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use trackable::error::ErrorKindExt;
 
+mod accounting;
+mod acl;
 mod core;
+mod limiter;
+mod mobility;
+mod observer;
+mod options;
+mod policy;
+mod redirect;
+#[cfg(unix)]
+mod sharded;
+mod shared;
+mod tcp_relay;
 
 #[derive(Debug)]
 #[must_use = "future do nothing unless polled"]
 pub struct UdpServer {
     core: ServerCore<StunUdpTransporter, ChannelDataUdpTransporter>,
+    shared: SharedState,
 }
 impl UdpServer {
     pub fn start(
         bind_addr: SocketAddr,
         auth_params: AuthParams,
+    ) -> impl Future<Item = Self, Error = Error> {
+        Self::start_with_options(bind_addr, auth_params, ServerOptions::default())
+    }
+
+    pub fn start_with_options(
+        bind_addr: SocketAddr,
+        auth_params: AuthParams,
+        options: ServerOptions,
+    ) -> impl Future<Item = Self, Error = Error> {
+        let shared = SharedState::new(auth_params, &options);
+        Self::start_with_shared_state(bind_addr, options, shared)
+    }
+
+    fn start_with_shared_state(
+        bind_addr: SocketAddr,
+        options: ServerOptions,
+        shared: SharedState,
     ) -> impl Future<Item = Self, Error = Error> {
         UdpTransporter::bind(bind_addr)
             .map_err(|e| track!(Error::from(e)))
             .map(move |transporter| {
-                let transporter = RcTransporter::new(transporter);
-                let stun = StunUdpTransporter::new(StunTransporter::new(transporter.clone()));
-                let channel_data = ChannelDataUdpTransporter::new(transporter);
-                let core = ServerCore::new(stun, channel_data, auth_params);
-                UdpServer { core }
+                Self::new(TurnUdpTransporter::Socket(transporter), options, shared)
             })
     }
 
+    /// Starts a server on a socket of a [`VirtualNetwork`] (e.g., for testing with simulated packet loss).
+    ///
+    /// Only the traffic between the server and its clients goes through the virtual network.
+    /// The relayed transport addresses are still bound to real UDP sockets.
+    ///
+    /// [`VirtualNetwork`]: crate::transport::VirtualNetwork
+    pub fn start_on(
+        socket: VirtualSocket,
+        auth_params: AuthParams,
+        options: ServerOptions,
+    ) -> Self {
+        let shared = SharedState::new(auth_params, &options);
+        Self::new(TurnUdpTransporter::new_virtual(socket), options, shared)
+    }
+
+    fn new(transporter: TurnUdpTransporter, options: ServerOptions, shared: SharedState) -> Self {
+        let local_addr = transporter.local_addr();
+        let transporter = RcTransporter::new(transporter);
+        let stun = StunUdpTransporter::new(StunTransporter::new(transporter.clone()));
+        let channel_data = ChannelDataUdpTransporter::new(transporter);
+        let core = ServerCore::new(stun, channel_data, local_addr, options, shared.clone());
+        UdpServer { core, shared }
+    }
+
     pub fn local_addr(&self) -> SocketAddr {
         self.core
             .stun_transporter_ref()
             .inner_ref()
             .with_inner_ref(|x| x.local_addr())
     }
+
+    /// Returns a handle for inspecting the allocations and the statistics of the server.
+    pub fn handle(&self) -> ServerHandle {
+        self.shared.handle()
+    }
 }
 impl Future for UdpServer {
     type Item = ();
@@ -51,7 +130,7 @@ impl Future for UdpServer {
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         match track!(self.core.poll()) {
             Err(e) => {
-                log::warn!("{}", e);
+                log::error!(listener:% = self.local_addr(); "UDP server error: {}", e);
                 Ok(Async::NotReady)
             }
             other => other,
@@ -64,7 +143,8 @@ impl Future for UdpServer {
 pub struct TcpServer {
     listener: TcpListener<DefaultFactory<TurnMessageEncoder>, DefaultFactory<TurnMessageDecoder>>,
     spawner: BoxSpawn,
-    auth_params: AuthParams,
+    options: ServerOptions,
+    shared: SharedState,
 }
 impl TcpServer {
     pub fn start<S>(
@@ -72,6 +152,31 @@ impl TcpServer {
         bind_addr: SocketAddr,
         auth_params: AuthParams,
     ) -> impl Future<Item = Self, Error = Error>
+    where
+        S: Spawn + Send + 'static,
+    {
+        Self::start_with_options(spawner, bind_addr, auth_params, ServerOptions::default())
+    }
+
+    pub fn start_with_options<S>(
+        spawner: S,
+        bind_addr: SocketAddr,
+        auth_params: AuthParams,
+        options: ServerOptions,
+    ) -> impl Future<Item = Self, Error = Error>
+    where
+        S: Spawn + Send + 'static,
+    {
+        let shared = SharedState::new(auth_params, &options);
+        Self::start_with_shared_state(spawner, bind_addr, options, shared)
+    }
+
+    fn start_with_shared_state<S>(
+        spawner: S,
+        bind_addr: SocketAddr,
+        options: ServerOptions,
+        shared: SharedState,
+    ) -> impl Future<Item = Self, Error = Error>
     where
         S: Spawn + Send + 'static,
     {
@@ -80,13 +185,19 @@ impl TcpServer {
             .map(move |listener| TcpServer {
                 listener,
                 spawner: spawner.boxed(),
-                auth_params,
+                options,
+                shared,
             })
     }
 
     pub fn local_addr(&self) -> SocketAddr {
         self.listener.local_addr()
     }
+
+    /// Returns a handle for inspecting the allocations and the statistics of the server.
+    pub fn handle(&self) -> ServerHandle {
+        self.shared.handle()
+    }
 }
 impl Future for TcpServer {
     type Item = ();
@@ -96,15 +207,159 @@ impl Future for TcpServer {
         while let Async::Ready(transporter) = track!(self.listener.poll())? {
             if let Some(transporter) = transporter {
                 let peer = transporter.peer_addr();
-                let transporter = RcTransporter::new(transporter);
-                let stun = StunTcpTransporter::new(StunTransporter::new(transporter.clone()));
-                let stun = FixedPeerTransporter::new(peer, (), stun);
-                let channel_data = ChannelDataTcpTransporter::new(transporter);
-                let channel_data = FixedPeerTransporter::new(peer, (), channel_data);
-                let auth_params = self.auth_params.clone();
-                self.spawner.spawn(
-                    ServerCore::new(stun, channel_data, auth_params).map_err(|e| panic!("{}", e)),
+                let connection = TcpConnection::new(
+                    transporter,
+                    self.listener.local_addr(),
+                    self.options.clone(),
+                    self.shared.clone(),
                 );
+                // Errors only close the connection (and remove its allocation)
+                let listener = self.listener.local_addr();
+                self.spawner.spawn(connection.map_err(move |e| {
+                    log::warn!(
+                        client:% = peer,
+                        listener:% = listener;
+                        "TCP connection closed due to an error: {}", e
+                    );
+                }));
+            } else {
+                return Ok(Async::Ready(()));
+            }
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+/// TURN server that serves TLS connections.
+#[must_use = "future do nothing unless polled"]
+pub struct TlsServer {
+    incoming: Incoming,
+    local_addr: SocketAddr,
+    tls_config: Arc<TlsConfig>,
+    spawner: BoxSpawn,
+    options: ServerOptions,
+    shared: SharedState,
+    server_names: Arc<Tenants>,
+}
+impl TlsServer {
+    pub fn start<S>(
+        spawner: S,
+        bind_addr: SocketAddr,
+        auth_params: AuthParams,
+        tls_config: Arc<TlsConfig>,
+    ) -> impl Future<Item = Self, Error = Error>
+    where
+        S: Spawn + Send + 'static,
+    {
+        Self::start_with_options(
+            spawner,
+            bind_addr,
+            auth_params,
+            tls_config,
+            ServerOptions::default(),
+        )
+    }
+
+    pub fn start_with_options<S>(
+        spawner: S,
+        bind_addr: SocketAddr,
+        auth_params: AuthParams,
+        tls_config: Arc<TlsConfig>,
+        options: ServerOptions,
+    ) -> impl Future<Item = Self, Error = Error>
+    where
+        S: Spawn + Send + 'static,
+    {
+        let shared = SharedState::new(auth_params, &options);
+        Self::start_with_shared_state(
+            spawner,
+            bind_addr,
+            tls_config,
+            options,
+            shared,
+            Arc::default(),
+        )
+    }
+
+    /// Starts a server of which clients are served by the tenants of `server_names`
+    /// if they indicate those names (SNI), and by the tenant of `shared` otherwise.
+    fn start_with_shared_state<S>(
+        spawner: S,
+        bind_addr: SocketAddr,
+        tls_config: Arc<TlsConfig>,
+        options: ServerOptions,
+        shared: SharedState,
+        server_names: Arc<Tenants>,
+    ) -> impl Future<Item = Self, Error = Error>
+    where
+        S: Spawn + Send + 'static,
+    {
+        fibers::net::TcpListener::bind(bind_addr)
+            .map_err(|e| track!(Error::from(e)))
+            .and_then(move |listener| {
+                let local_addr = track!(listener.local_addr().map_err(Error::from))?;
+                Ok(TlsServer {
+                    incoming: listener.incoming(),
+                    local_addr,
+                    tls_config,
+                    spawner: spawner.boxed(),
+                    options,
+                    shared,
+                    server_names,
+                })
+            })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Returns a handle for inspecting the allocations and the statistics of the server.
+    pub fn handle(&self) -> ServerHandle {
+        self.shared.handle()
+    }
+
+    fn spawn_connection(&self, connected: Connected, client: SocketAddr) {
+        let tls_config = Arc::clone(&self.tls_config);
+        let listener = self.local_addr;
+        let server_names = Arc::clone(&self.server_names);
+        let default_tenant = (self.options.clone(), self.shared.clone());
+        let future = connected
+            .map_err(|e| track!(Error::from(e)))
+            .and_then(move |stream| track!(TlsStream::new(stream, tls_config)))
+            .and_then(|mut stream| {
+                futures::future::poll_fn(move || {
+                    futures::try_ready!(track!(stream.poll_handshake()));
+                    Ok(Async::Ready(stream.clone()))
+                })
+            })
+            .and_then(move |stream| {
+                let tenant = stream
+                    .server_name()
+                    .and_then(|name| server_names.get(&name.to_ascii_lowercase()));
+                let (options, shared) = tenant.cloned().unwrap_or(default_tenant);
+                let transporter = track!(TurnTlsTransporter::new(stream))?;
+                Ok(TcpConnection::new(transporter, listener, options, shared))
+            })
+            .flatten();
+        // Errors only close the connection (and remove its allocation)
+        self.spawner.spawn(future.map_err(move |e| {
+            log::warn!(
+                client:% = client,
+                listener:% = listener;
+                "TLS connection closed due to an error: {}", e
+            );
+        }));
+    }
+}
+impl Future for TlsServer {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        while let Async::Ready(item) = track!(self.incoming.poll().map_err(Error::from))? {
+            if let Some((connected, client)) = item {
+                self.spawn_connection(connected, client);
             } else {
                 return Ok(Async::Ready(()));
             }
@@ -112,3 +367,459 @@ impl Future for TcpServer {
         Ok(Async::NotReady)
     }
 }
+impl fmt::Debug for TlsServer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TlsServer")
+            .field("local_addr", &self.local_addr)
+            .field("options", &self.options)
+            .field("shared", &self.shared)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Loads a TLS server configuration from PEM files of a certificate chain and its private key.
+pub fn load_tls_config<P, Q>(cert_file: P, key_file: Q) -> Result<Arc<TlsConfig>>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let pem = track!(std::fs::read(cert_file).map_err(Error::from))?;
+    let certs = track!(rustls_pemfile::certs(&mut &pem[..])
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(Error::from))?;
+    track_assert!(
+        !certs.is_empty(),
+        ErrorKind::InvalidInput,
+        "No certificates"
+    );
+
+    let pem = zeroize::Zeroizing::new(track!(std::fs::read(key_file).map_err(Error::from))?);
+    let key = track!(rustls_pemfile::private_key(&mut &pem[..]).map_err(Error::from))?;
+    let key = track_assert_some!(key, ErrorKind::InvalidInput, "No private keys");
+
+    let config = track!(TlsConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| Error::from(ErrorKind::InvalidInput.cause(e))))?;
+    Ok(Arc::new(config))
+}
+
+/// Transporter of a client connection over TCP or TLS.
+trait ConnectionTransporter: TcpTransport<SendItem = TurnMessage, RecvItem = TurnMessage> {
+    const TRANSPORT: TransportProtocol;
+
+    type Stream: RelayStream + Clone + fmt::Debug;
+
+    /// Returns the stream of the connection (that shares the underlying connection).
+    fn stream(&self) -> Self::Stream;
+
+    fn decoder_mut(&mut self) -> &mut TurnMessageDecoder;
+}
+impl ConnectionTransporter for TurnTcpTransporter {
+    const TRANSPORT: TransportProtocol = TransportProtocol::Tcp;
+
+    type Stream = fibers::net::TcpStream;
+
+    fn stream(&self) -> Self::Stream {
+        self.stream_ref().clone()
+    }
+
+    fn decoder_mut(&mut self) -> &mut TurnMessageDecoder {
+        TurnTcpTransporter::decoder_mut(self)
+    }
+}
+impl ConnectionTransporter for TurnTlsTransporter {
+    const TRANSPORT: TransportProtocol = TransportProtocol::Tls;
+
+    type Stream = TlsStream;
+
+    fn stream(&self) -> Self::Stream {
+        self.stream_ref().clone()
+    }
+
+    fn decoder_mut(&mut self) -> &mut TurnMessageDecoder {
+        TurnTlsTransporter::decoder_mut(self)
+    }
+}
+
+type TcpServerCore<T> = ServerCore<
+    FixedPeerTransporter<rustun::transport::StunTcpTransporter<StunTransporter<T>>, SocketAddr>,
+    FixedPeerTransporter<ChannelDataTransporter<T>, SocketAddr>,
+>;
+
+#[derive(Debug)]
+struct TcpConnection<T: ConnectionTransporter> {
+    core: TcpServerCore<T>,
+    transporter: RcTransporter<T>,
+    stream: T::Stream,
+    relay: Option<TcpRelay<T::Stream>>,
+    tcp_connections: ConnectionRegistry,
+}
+impl<T: ConnectionTransporter> TcpConnection<T> {
+    fn new(
+        transporter: T,
+        server_addr: SocketAddr,
+        options: ServerOptions,
+        shared: SharedState,
+    ) -> Self {
+        let peer = transporter.peer_addr();
+        let stream = transporter.stream();
+        let transporter = RcTransporter::new(transporter);
+        let stun =
+            rustun::transport::StunTcpTransporter::new(StunTransporter::new(transporter.clone()));
+        let stun = FixedPeerTransporter::new(peer, (), stun);
+        let channel_data = ChannelDataTransporter::new(transporter.clone());
+        let channel_data = FixedPeerTransporter::new(peer, (), channel_data);
+        let tcp_connections = shared.tcp_connections().clone();
+        let core = ServerCore::with_tcp_connections(
+            stun,
+            channel_data,
+            server_addr,
+            T::TRANSPORT,
+            options,
+            shared,
+        );
+        TcpConnection {
+            core,
+            transporter,
+            stream,
+            relay: None,
+            tcp_connections,
+        }
+    }
+}
+impl<T: ConnectionTransporter> Future for TcpConnection<T> {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if self.relay.is_none() {
+            if let Async::NotReady = track!(self.core.poll())? {
+                return Ok(Async::NotReady);
+            }
+            if let Some((connection_id, peer)) = self.core.take_bound_connection() {
+                // Takes the bytes that have been read after the ConnectionBind request
+                let pending = track!(self.transporter.with_inner_mut(|t| {
+                    t.decoder_mut().start_raw();
+                    let _ = track!(t.poll_recv())?;
+                    Ok::<_, Error>(t.decoder_mut().take_raw_bytes())
+                }))?;
+                self.relay = Some(TcpRelay::new(
+                    self.stream.clone(),
+                    pending,
+                    peer,
+                    self.tcp_connections.clone(),
+                    connection_id,
+                ));
+            } else {
+                return Ok(Async::Ready(()));
+            }
+        }
+        track!(self.relay.as_mut().expect("never fails").poll())
+    }
+}
+unsafe impl<T: ConnectionTransporter> Send for TcpConnection<T> {}
+
+/// Builder of [`Server`].
+///
+/// The credentials and the options given to the builder are those of the default tenant.
+/// Additional tenants (realms) can be added by [`ServerBuilder::tenant`].
+///
+/// The tenant of a client is selected by the listener that accepts it
+/// (see [`ServerBuilder::udp_listener_for_realm`], [`ServerBuilder::tcp_listener_for_realm`]
+/// and [`ServerBuilder::tls_listener_for_realm`]), or by the server name that it indicates in the TLS handshake (see [`ServerBuilder::tls_server_name`]).
+#[derive(Debug)]
+pub struct ServerBuilder {
+    auth_params: AuthParams,
+    primary_user: bool,
+    options: ServerOptions,
+    tenants: Vec<(AuthParams, ServerOptions)>,
+    listeners: Vec<(ListenerKind, SocketAddr, Option<String>)>,
+    server_names: Vec<(String, String)>,
+}
+impl ServerBuilder {
+    /// Makes a new `ServerBuilder` instance.
+    pub fn new(auth_params: AuthParams) -> Self {
+        ServerBuilder {
+            auth_params,
+            primary_user: true,
+            options: ServerOptions::default(),
+            tenants: Vec::new(),
+            listeners: Vec::new(),
+            server_names: Vec::new(),
+        }
+    }
+
+    /// Makes a new `ServerBuilder` instance that challenges clients with `realm` and `nonce`
+    /// but has no primary user.
+    ///
+    /// The users of the default tenant are given only by its options (e.g., [`ServerOptions::users`]).
+    pub fn without_primary_user(realm: &str, nonce: &str) -> Result<Self> {
+        let auth_params = track!(AuthParams::with_realm_and_nonce("", "", realm, nonce))?;
+        let mut builder = Self::new(auth_params);
+        builder.primary_user = false;
+        Ok(builder)
+    }
+
+    /// Adds a tenant identified by the realm of `auth_params`.
+    ///
+    /// Each tenant has its own users, quotas, peer ACL, allocations and statistics (see `options`),
+    /// and the clients of the listeners of the tenant are challenged with its realm.
+    pub fn tenant(&mut self, auth_params: AuthParams, options: ServerOptions) -> &mut Self {
+        self.tenants.push((auth_params, options));
+        self
+    }
+
+    /// Sets the options of the server.
+    pub fn options(&mut self, options: ServerOptions) -> &mut Self {
+        self.options = options;
+        self
+    }
+
+    /// Adds a UDP listener bound to `bind_addr`.
+    pub fn udp_listener(&mut self, bind_addr: SocketAddr) -> &mut Self {
+        self.listeners.push((ListenerKind::Udp, bind_addr, None));
+        self
+    }
+
+    /// Adds a TCP listener bound to `bind_addr`.
+    pub fn tcp_listener(&mut self, bind_addr: SocketAddr) -> &mut Self {
+        self.listeners.push((ListenerKind::Tcp, bind_addr, None));
+        self
+    }
+
+    /// Adds a UDP listener bound to `bind_addr` that serves the tenant of `realm`.
+    pub fn udp_listener_for_realm(&mut self, bind_addr: SocketAddr, realm: &str) -> &mut Self {
+        let realm = Some(realm.to_owned());
+        self.listeners.push((ListenerKind::Udp, bind_addr, realm));
+        self
+    }
+
+    /// Adds a TCP listener bound to `bind_addr` that serves the tenant of `realm`.
+    pub fn tcp_listener_for_realm(&mut self, bind_addr: SocketAddr, realm: &str) -> &mut Self {
+        let realm = Some(realm.to_owned());
+        self.listeners.push((ListenerKind::Tcp, bind_addr, realm));
+        self
+    }
+
+    /// Adds a TLS listener bound to `bind_addr`.
+    ///
+    /// The clients that indicate the server names given by [`ServerBuilder::tls_server_name`]
+    /// are served by the tenants of those names, and the others by the default tenant.
+    pub fn tls_listener(&mut self, bind_addr: SocketAddr, tls_config: Arc<TlsConfig>) -> &mut Self {
+        self.listeners
+            .push((ListenerKind::Tls(tls_config), bind_addr, None));
+        self
+    }
+
+    /// Adds a TLS listener bound to `bind_addr` that serves the tenant of `realm`
+    /// unless clients indicate the server names given by [`ServerBuilder::tls_server_name`].
+    pub fn tls_listener_for_realm(
+        &mut self,
+        bind_addr: SocketAddr,
+        tls_config: Arc<TlsConfig>,
+        realm: &str,
+    ) -> &mut Self {
+        let realm = Some(realm.to_owned());
+        self.listeners
+            .push((ListenerKind::Tls(tls_config), bind_addr, realm));
+        self
+    }
+
+    /// Makes the TLS listeners serve the clients that indicate `server_name` (SNI) by the tenant of `realm`.
+    ///
+    /// Server names are compared case-insensitively.
+    /// The certificates for the names are selected by the TLS configurations of the listeners
+    /// (e.g., by `rustls::server::ResolvesServerCertUsingSni`).
+    pub fn tls_server_name(&mut self, server_name: &str, realm: &str) -> &mut Self {
+        self.server_names
+            .push((server_name.to_ascii_lowercase(), realm.to_owned()));
+        self
+    }
+
+    /// Starts the listeners and builds a [`Server`] instance.
+    ///
+    /// This fails if a listener or a server name refers to a realm that is not added by [`ServerBuilder::tenant`].
+    pub fn finish<S>(&self, spawner: S) -> impl Future<Item = Server, Error = Error>
+    where
+        S: Spawn + Clone + Send + 'static,
+    {
+        let (shared, tenants) = match track!(self.tenant_states()) {
+            Err(e) => return Either::A(futures::future::err(e)),
+            Ok(x) => x,
+        };
+        let server_names: Arc<Tenants> = Arc::new(
+            self.server_names
+                .iter()
+                .map(|(name, realm)| (name.clone(), tenants[realm].clone()))
+                .collect(),
+        );
+        let listeners = self.listeners.iter().map(|(kind, bind_addr, realm)| {
+            let (options, shared) = match realm {
+                None => (self.options.clone(), shared.clone()),
+                Some(realm) => tenants[realm].clone(),
+            };
+            let future: Box<dyn Future<Item = Listener, Error = Error> + Send> = match kind {
+                ListenerKind::Udp => Box::new(
+                    UdpServer::start_with_shared_state(*bind_addr, options, shared)
+                        .map(|x| Listener::Udp(Box::new(x))),
+                ),
+                ListenerKind::Tcp => Box::new(
+                    TcpServer::start_with_shared_state(
+                        spawner.clone(),
+                        *bind_addr,
+                        options,
+                        shared,
+                    )
+                    .map(|x| Listener::Tcp(Box::new(x))),
+                ),
+                ListenerKind::Tls(tls_config) => Box::new(
+                    TlsServer::start_with_shared_state(
+                        spawner.clone(),
+                        *bind_addr,
+                        Arc::clone(tls_config),
+                        options,
+                        shared,
+                        Arc::clone(&server_names),
+                    )
+                    .map(|x| Listener::Tls(Box::new(x))),
+                ),
+            };
+            future
+        });
+        let listeners = listeners.collect::<Vec<_>>();
+        let tenants = tenants
+            .into_iter()
+            .map(|(realm, (_, shared))| (realm, shared))
+            .collect();
+        Either::B(
+            futures::future::join_all(listeners).map(move |listeners| Server {
+                listeners,
+                shared,
+                tenants,
+            }),
+        )
+    }
+
+    /// Makes the shared states of the default tenant and the additional tenants (keyed by their realms).
+    fn tenant_states(&self) -> Result<(SharedState, Tenants)> {
+        let shared = if self.primary_user {
+            SharedState::new(self.auth_params.clone(), &self.options)
+        } else {
+            SharedState::without_primary_user(self.auth_params.clone(), &self.options)
+        };
+        let mut tenants = HashMap::new();
+        for (auth_params, options) in &self.tenants {
+            let realm = track_assert_some!(auth_params.get_realm(), ErrorKind::InvalidInput);
+            let realm = realm.text().to_owned();
+            let state = (
+                options.clone(),
+                SharedState::new(auth_params.clone(), options),
+            );
+            track_assert!(
+                tenants.insert(realm.clone(), state).is_none(),
+                ErrorKind::InvalidInput,
+                "Duplicate tenant: {:?}",
+                realm
+            );
+        }
+        for (_, bind_addr, realm) in &self.listeners {
+            if let Some(realm) = realm {
+                track_assert!(
+                    tenants.contains_key(realm),
+                    ErrorKind::InvalidInput,
+                    "Unknown realm of the listener {}: {:?}",
+                    bind_addr,
+                    realm
+                );
+            }
+        }
+        for (server_name, realm) in &self.server_names {
+            track_assert!(
+                tenants.contains_key(realm),
+                ErrorKind::InvalidInput,
+                "Unknown realm of the server name {:?}: {:?}",
+                server_name,
+                realm
+            );
+        }
+        Ok((shared, tenants))
+    }
+}
+
+/// Options and shared states of tenants keyed by their realms.
+type Tenants = HashMap<String, (ServerOptions, SharedState)>;
+
+/// TURN server that serves multiple UDP, TCP and TLS listeners.
+///
+/// All the listeners of a tenant share the allocation registry, the credentials (and the nonce)
+/// and the statistics, and those of different tenants are isolated from each other.
+#[derive(Debug)]
+#[must_use = "future do nothing unless polled"]
+pub struct Server {
+    listeners: Vec<Listener>,
+    shared: SharedState,
+    tenants: HashMap<String, SharedState>,
+}
+impl Server {
+    /// Returns the transport protocols and the addresses of the listeners.
+    pub fn local_addrs(&self) -> Vec<(TransportProtocol, SocketAddr)> {
+        self.listeners
+            .iter()
+            .map(|l| match l {
+                Listener::Udp(x) => (TransportProtocol::Udp, x.local_addr()),
+                Listener::Tcp(x) => (TransportProtocol::Tcp, x.local_addr()),
+                Listener::Tls(x) => (TransportProtocol::Tls, x.local_addr()),
+            })
+            .collect()
+    }
+
+    /// Returns a handle for inspecting the allocations and the statistics of the default tenant.
+    pub fn handle(&self) -> ServerHandle {
+        self.shared.handle()
+    }
+
+    /// Returns a handle for inspecting the allocations and the statistics of the tenant of `realm`.
+    pub fn tenant_handle(&self, realm: &str) -> Option<ServerHandle> {
+        self.tenants.get(realm).map(|x| x.handle())
+    }
+}
+impl Future for Server {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let mut i = 0;
+        while i < self.listeners.len() {
+            let ready = match &mut self.listeners[i] {
+                Listener::Udp(x) => track!(x.poll())?.is_ready(),
+                Listener::Tcp(x) => track!(x.poll())?.is_ready(),
+                Listener::Tls(x) => track!(x.poll())?.is_ready(),
+            };
+            if ready {
+                self.listeners.swap_remove(i);
+            } else {
+                i += 1;
+            }
+        }
+        if self.listeners.is_empty() {
+            Ok(Async::Ready(()))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Listener {
+    Udp(Box<UdpServer>),
+    Tcp(Box<TcpServer>),
+    Tls(Box<TlsServer>),
+}
+
+#[derive(Debug)]
+enum ListenerKind {
+    Udp,
+    Tcp,
+    Tls(Arc<TlsConfig>),
+}
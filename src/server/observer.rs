@@ -0,0 +1,68 @@
+use super::shared::AllocationInfo;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use stun_codec::rfc5389::attributes::ErrorCode;
+use stun_codec::Method;
+
+/// Observer of the activities of a server (e.g., for billing, audit logging or session management).
+///
+/// All the methods do nothing by default.
+/// They are called synchronously by the task that handles the client, so they should return quickly.
+#[allow(unused_variables)]
+pub trait ServerObserver: Send + Sync + 'static {
+    /// Called when an allocation is created.
+    fn allocation_created(&self, allocation: &AllocationInfo) {}
+
+    /// Called when an allocation is refreshed with the new lifetime.
+    fn allocation_refreshed(&self, allocation: &AllocationInfo, lifetime: Duration) {}
+
+    /// Called when an allocation is deleted because its lifetime has expired.
+    fn allocation_expired(&self, allocation: &AllocationInfo) {}
+
+    /// Called when an allocation is deleted by the client
+    /// (i.e., by a Refresh request with zero lifetime or by closing the TCP connection).
+    fn allocation_deleted(&self, allocation: &AllocationInfo) {}
+
+    /// Called when an allocation is deleted by [`ServerHandle::kick`](super::ServerHandle::kick).
+    fn allocation_kicked(&self, allocation: &AllocationInfo) {}
+
+    /// Called when an allocation is deleted because its user has been removed by
+    /// [`ServerHandle::reload`](super::ServerHandle::reload).
+    fn allocation_revoked(&self, allocation: &AllocationInfo) {}
+
+    /// Called when a permission for `peer` is installed or refreshed.
+    fn permission_installed(&self, allocation: &AllocationInfo, peer: IpAddr) {}
+
+    /// Called when a permission for `peer` has expired.
+    fn permission_expired(&self, allocation: &AllocationInfo, peer: IpAddr) {}
+
+    /// Called when a channel to `peer` is bound or refreshed.
+    fn channel_bound(&self, allocation: &AllocationInfo, channel_number: u16, peer: SocketAddr) {}
+
+    /// Called when a channel to `peer` has expired.
+    fn channel_expired(&self, allocation: &AllocationInfo, channel_number: u16, peer: SocketAddr) {}
+
+    /// Called when a request from `client` fails to be authenticated.
+    ///
+    /// `allocation` is the current allocation of the client if it exists.
+    fn auth_failed(
+        &self,
+        client: SocketAddr,
+        username: Option<&str>,
+        allocation: Option<&AllocationInfo>,
+    ) {
+    }
+
+    /// Called when a request from `client` is rejected with an error response.
+    ///
+    /// `allocation` is the current allocation of the client if it exists.
+    fn request_rejected(
+        &self,
+        client: SocketAddr,
+        username: Option<&str>,
+        allocation: Option<&AllocationInfo>,
+        method: Method,
+        error: &ErrorCode,
+    ) {
+    }
+}
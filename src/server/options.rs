@@ -0,0 +1,615 @@
+use super::accounting::{AccountingRecord, AccountingSink, TeardownReason};
+use super::acl::PeerAcl;
+use super::limiter::{BanPolicy, RateLimit};
+use super::observer::ServerObserver;
+use super::policy::{Policy, PolicyResult};
+use super::redirect::RedirectPolicy;
+use super::shared::AllocationInfo;
+use crate::auth::{Credential, LongTermKey, PasswordAlgorithm};
+use crate::rfc7635::Token;
+use crate::rfc8489::attributes::Userhash;
+use crate::{ErrorKind, Result};
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use stun_codec::rfc8656::attributes::AddressFamily;
+use zeroize::Zeroizing;
+
+/// Options of TURN servers.
+#[derive(Clone)]
+pub struct ServerOptions {
+    relay_ipv4: Option<Ipv4Addr>,
+    relay_ipv6: Option<Ipv6Addr>,
+    mobility: bool,
+    redirect_policy: Option<Arc<dyn RedirectPolicy>>,
+    third_party_authorization: Option<Arc<ThirdPartyAuthorization>>,
+    observer: Option<Arc<dyn ServerObserver>>,
+    policy: Option<Arc<dyn Policy>>,
+    send_decision_lifetime: Duration,
+    accounting_sink: Option<Arc<dyn AccountingSink>>,
+    relay_port_range: Option<RangeInclusive<u16>>,
+    external_ip: Option<IpAddr>,
+    default_allocation_lifetime: Duration,
+    max_allocation_lifetime: Duration,
+    unauthenticated_rate_limit: Option<RateLimit>,
+    auth_failure_ban: Option<BanPolicy>,
+    password_algorithms: Vec<PasswordAlgorithm>,
+    access: AccessSettings,
+}
+impl ServerOptions {
+    /// Makes a new `ServerOptions` instance with the default settings.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the IPv4 address to which relayed transport addresses are bound.
+    ///
+    /// If `None` is specified, the server rejects requests for IPv4 allocations
+    /// with 440 (Address Family not Supported) errors.
+    ///
+    /// The default value is `Some(0.0.0.0)`.
+    pub fn relay_ipv4(&mut self, addr: Option<Ipv4Addr>) -> &mut Self {
+        self.relay_ipv4 = addr;
+        self
+    }
+
+    /// Sets the IPv6 address to which relayed transport addresses are bound.
+    ///
+    /// If `None` is specified, the server rejects requests for IPv6 allocations
+    /// with 440 (Address Family not Supported) errors.
+    ///
+    /// The default value is `None`.
+    pub fn relay_ipv6(&mut self, addr: Option<Ipv6Addr>) -> &mut Self {
+        self.relay_ipv6 = addr;
+        self
+    }
+
+    /// Sets whether the server supports mobility of UDP allocations ([RFC 8016]).
+    ///
+    /// If `false` is specified, the server rejects requests that have `MOBILITY-TICKET` attributes
+    /// with 405 (Mobility Forbidden) errors.
+    ///
+    /// The default value is `true`.
+    ///
+    /// [RFC 8016]: https://tools.ietf.org/html/rfc8016
+    pub fn mobility(&mut self, enabled: bool) -> &mut Self {
+        self.mobility = enabled;
+        self
+    }
+
+    /// Sets the policy for redirecting Allocate requests to alternate servers.
+    ///
+    /// By default, no requests are redirected.
+    pub fn redirect_policy<P: RedirectPolicy>(&mut self, policy: P) -> &mut Self {
+        self.redirect_policy = Some(Arc::new(policy));
+        self
+    }
+
+    /// Enables third-party authorization by OAuth access tokens ([RFC 7635]).
+    ///
+    /// `authorization_server` is notified to clients by `THIRD-PARTY-AUTHORIZATION` attributes of
+    /// 401 (Unauthorized) responses.
+    /// `server_name` is the name of this server used as the associated data of encrypted tokens.
+    /// `keys` maps key identifiers (kid) to the keys shared with the authorization server,
+    /// each of which must be 16 bytes (AES-128-GCM) or 32 bytes (AES-256-GCM) long.
+    ///
+    /// Requests that have no `ACCESS-TOKEN` attributes are still authenticated by the password.
+    ///
+    /// [RFC 7635]: https://tools.ietf.org/html/rfc7635
+    pub fn third_party_authorization(
+        &mut self,
+        authorization_server: &str,
+        server_name: &str,
+        keys: HashMap<String, Vec<u8>>,
+    ) -> &mut Self {
+        self.third_party_authorization = Some(Arc::new(ThirdPartyAuthorization {
+            authorization_server: authorization_server.to_owned(),
+            server_name: server_name.to_owned(),
+            keys: keys
+                .into_iter()
+                .map(|(kid, key)| (kid, Zeroizing::new(key)))
+                .collect(),
+        }));
+        self
+    }
+
+    /// Sets the observer that is notified of the activities of the server.
+    ///
+    /// By default, no observer is set.
+    pub fn observer<O: ServerObserver>(&mut self, observer: O) -> &mut Self {
+        self.observer = Some(Arc::new(observer));
+        self
+    }
+
+    /// Sets the policy that authorizes the operations of authenticated clients.
+    ///
+    /// By default, all the operations are allowed.
+    pub fn policy<P: Policy>(&mut self, policy: P) -> &mut Self {
+        self.policy = Some(Arc::new(policy));
+        self
+    }
+
+    /// Sets how long the decision of [`Policy::send`] for a peer is reused.
+    ///
+    /// After that, the policy is consulted again by the next data to the peer,
+    /// so that changes of the policy apply to the existing allocations.
+    /// The decisions are also discarded when the permission for the peer is refreshed or expires.
+    ///
+    /// The default value is 1 minute.
+    pub fn send_decision_lifetime(&mut self, lifetime: Duration) -> &mut Self {
+        self.send_decision_lifetime = lifetime;
+        self
+    }
+
+    /// Sets the sink to which an accounting record is written when an allocation is torn down.
+    ///
+    /// By default, no records are written.
+    pub fn accounting_sink<S: AccountingSink>(&mut self, sink: S) -> &mut Self {
+        self.accounting_sink = Some(Arc::new(sink));
+        self
+    }
+
+    /// Sets the range of the ports to which relayed transport addresses are bound.
+    ///
+    /// If all the ports in the range are in use, Allocate requests are rejected
+    /// with 508 (Insufficient Capacity) errors.
+    ///
+    /// By default, ephemeral ports assigned by the OS are used.
+    pub fn relay_port_range(&mut self, ports: RangeInclusive<u16>) -> &mut Self {
+        self.relay_port_range = Some(ports);
+        self
+    }
+
+    /// Sets the IP address advertised to clients instead of the IP address of relayed transport addresses
+    /// (e.g., the public address of a server behind 1:1 NAT).
+    ///
+    /// Only the relayed transport addresses of the same address family are affected.
+    ///
+    /// The default value is `None`.
+    pub fn external_ip(&mut self, ip: Option<IpAddr>) -> &mut Self {
+        self.external_ip = ip;
+        self
+    }
+
+    /// Sets the long-term credentials (pairs of a username and a password) of additional users.
+    ///
+    /// The user of the `AuthParams` given to the server is always accepted.
+    pub fn users<P>(&mut self, users: HashMap<String, P>) -> &mut Self
+    where
+        P: Into<Zeroizing<String>>,
+    {
+        let users = users
+            .into_iter()
+            .map(|(username, password)| (username, password.into()))
+            .collect();
+        self.access.users = Arc::new(users);
+        self
+    }
+
+    /// Sets the precomputed long-term keys of additional users.
+    ///
+    /// Unlike [`users`](Self::users), this does not require the server to hold plaintext passwords.
+    /// Each key must be derived from the username and the realm of the server.
+    /// If a username is also given to `users`, the key takes precedence.
+    pub fn user_keys(&mut self, keys: HashMap<String, LongTermKey>) -> &mut Self {
+        self.access.user_keys = Arc::new(keys);
+        self
+    }
+
+    /// Enables time-limited credentials derived from the given secret
+    /// (i.e., [A REST API For Access To TURN Services]).
+    ///
+    /// Such username has the form of `$EXPIRATION_TIMESTAMP[:$USER]` and
+    /// the password is `base64(HMAC-SHA1($SECRET, $USERNAME))`.
+    ///
+    /// [A REST API For Access To TURN Services]: https://tools.ietf.org/html/draft-uberti-behave-turn-rest-00
+    pub fn shared_secret(&mut self, secret: &str) -> &mut Self {
+        self.access.shared_secret = Some(Arc::new(Zeroizing::new(secret.to_owned())));
+        self
+    }
+
+    /// Sets the lifetime of allocations that is used if clients do not request a specific lifetime.
+    ///
+    /// The default value is 10 minutes.
+    pub fn default_allocation_lifetime(&mut self, lifetime: Duration) -> &mut Self {
+        self.default_allocation_lifetime = lifetime;
+        self
+    }
+
+    /// Sets the maximum lifetime of allocations.
+    ///
+    /// Lifetimes longer than this requested by clients are reduced to this value.
+    ///
+    /// The default value is 1 hour.
+    pub fn max_allocation_lifetime(&mut self, lifetime: Duration) -> &mut Self {
+        self.max_allocation_lifetime = lifetime;
+        self
+    }
+
+    /// Sets the maximum number of allocations of the server.
+    ///
+    /// Allocate requests exceeding the limit are rejected with 508 (Insufficient Capacity) errors.
+    ///
+    /// The default value is `None` (unlimited).
+    pub fn max_allocations(&mut self, n: Option<usize>) -> &mut Self {
+        self.access.max_allocations = n;
+        self
+    }
+
+    /// Sets the maximum number of allocations per username.
+    ///
+    /// Allocate requests exceeding the limit are rejected with 486 (Allocation Quota Reached) errors.
+    ///
+    /// The default value is `None` (unlimited).
+    pub fn max_allocations_per_user(&mut self, n: Option<usize>) -> &mut Self {
+        self.access.max_allocations_per_user = n;
+        self
+    }
+
+    /// Sets the access control list of peers.
+    ///
+    /// By default, all peers are allowed.
+    pub fn peer_acl(&mut self, acl: PeerAcl) -> &mut Self {
+        self.access.peer_acl = Arc::new(acl);
+        self
+    }
+
+    /// Sets the per-source-IP rate limit of unauthenticated requests and of authentication failures.
+    ///
+    /// Requests exceeding the limit are silently dropped
+    /// (i.e., no 401 (Unauthorized) responses are sent to them).
+    ///
+    /// The default value is `None` (unlimited).
+    pub fn unauthenticated_rate_limit(&mut self, limit: Option<RateLimit>) -> &mut Self {
+        self.unauthenticated_rate_limit = limit;
+        self
+    }
+
+    /// Sets the policy for temporarily banning source IPs that repeatedly fail to be authenticated.
+    ///
+    /// All the requests from banned IPs are silently dropped.
+    ///
+    /// The default value is `None` (no bans).
+    pub fn auth_failure_ban(&mut self, policy: Option<BanPolicy>) -> &mut Self {
+        self.auth_failure_ban = policy;
+        self
+    }
+
+    /// Sets whether the allocations of the users removed by
+    /// [`ServerHandle::reload`](super::ServerHandle::reload) are revoked.
+    ///
+    /// If `true` is specified, the next Refresh request of such an allocation is rejected
+    /// with a 401 (Unauthorized) error and the allocation is deleted.
+    /// Otherwise, the allocation lives until it expires.
+    ///
+    /// The default value is `false`.
+    pub fn revoke_removed_users(&mut self, enabled: bool) -> &mut Self {
+        self.access.revoke_removed_users = enabled;
+        self
+    }
+
+    /// Sets the password algorithms advertised to clients in the order of preference ([RFC 8489]).
+    ///
+    /// Clients select the first algorithm that they support,
+    /// so the keys given by [`user_keys`](Self::user_keys) should be derived by it.
+    /// If an empty list is specified, the server behaves as an [RFC 5389] server
+    /// (i.e., MD5 keys, `MESSAGE-INTEGRITY` and `USERNAME` only).
+    ///
+    /// The default value is `[Sha256, Md5]`.
+    ///
+    /// [RFC 5389]: https://tools.ietf.org/html/rfc5389
+    /// [RFC 8489]: https://tools.ietf.org/html/rfc8489#section-9.2
+    pub fn password_algorithms(&mut self, algorithms: Vec<PasswordAlgorithm>) -> &mut Self {
+        self.password_algorithms = algorithms;
+        self
+    }
+
+    pub(crate) fn notify<F>(&self, f: F)
+    where
+        F: FnOnce(&dyn ServerObserver),
+    {
+        if let Some(observer) = self.observer.as_deref() {
+            f(observer);
+        }
+    }
+
+    pub(crate) fn authorize<F>(&self, f: F) -> PolicyResult
+    where
+        F: FnOnce(&dyn Policy) -> PolicyResult,
+    {
+        self.policy.as_deref().map_or(Ok(()), f)
+    }
+
+    pub(crate) fn get_send_decision_lifetime(&self) -> Duration {
+        self.send_decision_lifetime
+    }
+
+    pub(crate) fn write_accounting_record(
+        &self,
+        allocation: &AllocationInfo,
+        reason: TeardownReason,
+    ) {
+        if let Some(sink) = &self.accounting_sink {
+            let record = AccountingRecord::new(allocation, reason);
+            if let Err(e) = track!(sink.write(&record)) {
+                log::warn!(
+                    client:% = allocation.client_addr(),
+                    username = allocation.username();
+                    "Cannot write an accounting record: {}", e
+                );
+            }
+        }
+    }
+
+    pub(crate) fn authorization_server(&self) -> Option<&str> {
+        self.third_party_authorization
+            .as_ref()
+            .map(|a| a.authorization_server.as_str())
+    }
+
+    /// Decrypts the given access token with the key identified by `kid`.
+    pub(crate) fn decrypt_access_token(&self, kid: &str, token: &[u8]) -> Result<Token> {
+        let config = track_assert_some!(
+            self.third_party_authorization.as_ref(),
+            ErrorKind::InvalidInput,
+            "Third-party authorization is disabled"
+        );
+        let key = track_assert_some!(
+            config.keys.get(kid),
+            ErrorKind::InvalidInput,
+            "Unknown key identifier: {:?}",
+            kid
+        );
+        let token = track!(Token::decrypt(token, key, &config.server_name))?;
+        track_assert!(
+            !token.is_expired(),
+            ErrorKind::InvalidInput,
+            "Expired token"
+        );
+        Ok(token)
+    }
+
+    pub(crate) fn get_unauthenticated_rate_limit(&self) -> Option<&RateLimit> {
+        self.unauthenticated_rate_limit.as_ref()
+    }
+
+    pub(crate) fn get_auth_failure_ban(&self) -> Option<&BanPolicy> {
+        self.auth_failure_ban.as_ref()
+    }
+
+    pub(crate) fn get_password_algorithms(&self) -> &[PasswordAlgorithm] {
+        &self.password_algorithms
+    }
+
+    pub(crate) fn access_settings(&self) -> AccessSettings {
+        self.access.clone()
+    }
+
+    pub(crate) fn get_relay_port_range(&self) -> Option<RangeInclusive<u16>> {
+        self.relay_port_range.clone()
+    }
+
+    /// Returns the relayed transport address advertised to clients.
+    pub(crate) fn advertised_relay_addr(&self, addr: SocketAddr) -> SocketAddr {
+        match self.external_ip {
+            Some(ip) if ip.is_ipv4() == addr.is_ipv4() => SocketAddr::new(ip, addr.port()),
+            _ => addr,
+        }
+    }
+
+    /// Returns the lifetime of an allocation for the lifetime requested by the client.
+    ///
+    /// The requested lifetime is clamped to the range between the default and the maximum
+    /// ([RFC 5766 -- 6.2. Receiving an Allocate Request]).
+    ///
+    /// [RFC 5766 -- 6.2. Receiving an Allocate Request]: https://tools.ietf.org/html/rfc5766#section-6.2
+    pub(crate) fn allocation_lifetime(&self, requested: Option<Duration>) -> Duration {
+        requested
+            .unwrap_or(self.default_allocation_lifetime)
+            .max(self.default_allocation_lifetime)
+            .min(self.max_allocation_lifetime)
+    }
+
+    pub(crate) fn alternate_server(&self, client: SocketAddr) -> Option<SocketAddr> {
+        self.redirect_policy
+            .as_ref()
+            .and_then(|p| p.alternate_server(client))
+    }
+
+    pub(crate) fn is_mobility_enabled(&self) -> bool {
+        self.mobility
+    }
+
+    pub(crate) fn relay_ip(&self, family: AddressFamily) -> Option<IpAddr> {
+        match family {
+            AddressFamily::V4 => self.relay_ipv4.map(IpAddr::V4),
+            AddressFamily::V6 => self.relay_ipv6.map(IpAddr::V6),
+        }
+    }
+}
+impl Default for ServerOptions {
+    fn default() -> Self {
+        ServerOptions {
+            relay_ipv4: Some(Ipv4Addr::UNSPECIFIED),
+            relay_ipv6: None,
+            mobility: true,
+            redirect_policy: None,
+            third_party_authorization: None,
+            observer: None,
+            policy: None,
+            send_decision_lifetime: Duration::from_secs(60),
+            accounting_sink: None,
+            relay_port_range: None,
+            external_ip: None,
+            default_allocation_lifetime: Duration::from_secs(600),
+            max_allocation_lifetime: Duration::from_secs(3600),
+            unauthenticated_rate_limit: None,
+            auth_failure_ban: None,
+            password_algorithms: vec![PasswordAlgorithm::Sha256, PasswordAlgorithm::Md5],
+            access: AccessSettings::default(),
+        }
+    }
+}
+impl fmt::Debug for ServerOptions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ServerOptions")
+            .field("relay_ipv4", &self.relay_ipv4)
+            .field("relay_ipv6", &self.relay_ipv6)
+            .field("mobility", &self.mobility)
+            .field(
+                "redirect_policy",
+                &self.redirect_policy.as_ref().map(|_| ".."),
+            )
+            .field(
+                "third_party_authorization",
+                &self
+                    .third_party_authorization
+                    .as_ref()
+                    .map(|a| (&a.authorization_server, &a.server_name)),
+            )
+            .field("observer", &self.observer.as_ref().map(|_| ".."))
+            .field("policy", &self.policy.as_ref().map(|_| ".."))
+            .field("send_decision_lifetime", &self.send_decision_lifetime)
+            .field(
+                "accounting_sink",
+                &self.accounting_sink.as_ref().map(|_| ".."),
+            )
+            .field("relay_port_range", &self.relay_port_range)
+            .field("external_ip", &self.external_ip)
+            .field(
+                "default_allocation_lifetime",
+                &self.default_allocation_lifetime,
+            )
+            .field("max_allocation_lifetime", &self.max_allocation_lifetime)
+            .field(
+                "unauthenticated_rate_limit",
+                &self.unauthenticated_rate_limit,
+            )
+            .field("auth_failure_ban", &self.auth_failure_ban)
+            .field("password_algorithms", &self.password_algorithms)
+            .field("access", &self.access)
+            .finish()
+    }
+}
+
+struct ThirdPartyAuthorization {
+    authorization_server: String,
+    server_name: String,
+    keys: HashMap<String, Zeroizing<Vec<u8>>>,
+}
+
+/// Part of the options that can be replaced while the server is running
+/// (see [`ServerHandle::reload`](super::ServerHandle::reload)).
+#[derive(Clone, Default)]
+pub(crate) struct AccessSettings {
+    users: Arc<HashMap<String, Zeroizing<String>>>,
+    user_keys: Arc<HashMap<String, LongTermKey>>,
+    shared_secret: Option<Arc<Zeroizing<String>>>,
+    max_allocations: Option<usize>,
+    max_allocations_per_user: Option<usize>,
+    peer_acl: Arc<PeerAcl>,
+    revoke_removed_users: bool,
+    userhashes: Arc<HashMap<Userhash, String>>,
+}
+impl AccessSettings {
+    /// Indexes the `USERHASH` values of the users (including `primary_user` if any) in `realm`.
+    ///
+    /// The users of the shared secret cannot be resolved by their hashes.
+    pub(crate) fn with_userhashes(mut self, realm: &str, primary_user: Option<&str>) -> Self {
+        let userhashes = self
+            .usernames()
+            .chain(primary_user)
+            .map(|u| (Userhash::new(u, realm), u.to_owned()))
+            .collect();
+        self.userhashes = Arc::new(userhashes);
+        self
+    }
+
+    /// Returns the username that has the given `USERHASH`.
+    pub(crate) fn resolve_userhash(&self, userhash: &Userhash) -> Option<&str> {
+        self.userhashes.get(userhash).map(String::as_str)
+    }
+
+    /// Returns the credential of `username` if it is a user other than the one of the server's `AuthParams`.
+    pub(crate) fn credential(&self, username: &str) -> Result<Option<Credential>> {
+        if let Some(key) = self.user_keys.get(username) {
+            return Ok(Some(Credential::LongTermKey(key.clone())));
+        }
+        if let Some(password) = self.users.get(username) {
+            return Ok(Some(Credential::Password(password.clone())));
+        }
+        let secret = if let Some(secret) = &self.shared_secret {
+            secret
+        } else {
+            return Ok(None);
+        };
+        let expiry = if let Some(Ok(expiry)) = username.split(':').next().map(str::parse::<u64>) {
+            expiry
+        } else {
+            return Ok(None);
+        };
+        track_assert!(
+            UNIX_EPOCH + Duration::from_secs(expiry) > SystemTime::now(),
+            ErrorKind::InvalidInput,
+            "Expired username: {:?}",
+            username
+        );
+        let mut hmac = Hmac::<Sha1>::new_from_slice(secret.as_bytes()).expect("never fails");
+        hmac.update(username.as_bytes());
+        let password = Zeroizing::new(
+            base64::engine::general_purpose::STANDARD.encode(hmac.finalize().into_bytes()),
+        );
+        Ok(Some(Credential::Password(password)))
+    }
+
+    pub(crate) fn get_max_allocations(&self) -> Option<usize> {
+        self.max_allocations
+    }
+
+    pub(crate) fn get_max_allocations_per_user(&self) -> Option<usize> {
+        self.max_allocations_per_user
+    }
+
+    pub(crate) fn is_peer_allowed(&self, peer: IpAddr) -> bool {
+        self.peer_acl.is_allowed(peer)
+    }
+
+    /// Returns the users that exist in `self` but not in `new`.
+    pub(crate) fn removed_users<'a>(&'a self, new: &'a Self) -> impl Iterator<Item = &'a str> {
+        self.usernames()
+            .filter(move |u| !new.user_keys.contains_key(*u) && !new.users.contains_key(*u))
+    }
+
+    fn usernames(&self) -> impl Iterator<Item = &str> {
+        self.user_keys
+            .keys()
+            .chain(
+                self.users
+                    .keys()
+                    .filter(|u| !self.user_keys.contains_key(*u)),
+            )
+            .map(String::as_str)
+    }
+
+    pub(crate) fn is_revoke_removed_users_enabled(&self) -> bool {
+        self.revoke_removed_users
+    }
+}
+impl fmt::Debug for AccessSettings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AccessSettings")
+            .field("users", &self.usernames().collect::<Vec<_>>())
+            .field("shared_secret", &self.shared_secret.as_ref().map(|_| ".."))
+            .field("max_allocations", &self.max_allocations)
+            .field("max_allocations_per_user", &self.max_allocations_per_user)
+            .field("peer_acl", &self.peer_acl)
+            .field("revoke_removed_users", &self.revoke_removed_users)
+            .finish()
+    }
+}
@@ -0,0 +1,56 @@
+use super::shared::{AllocationInfo, FiveTuple};
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use stun_codec::rfc5389::attributes::ErrorCode;
+
+/// Result of a decision of a [`Policy`].
+///
+/// `Err` rejects the operation with the error code.
+pub type PolicyResult = std::result::Result<(), ErrorCode>;
+
+/// Policy that authorizes the operations of authenticated clients
+/// (e.g., for per-user peer allowlists or time-of-day rules).
+///
+/// All the methods allow everything by default.
+/// They are called synchronously by the task that handles the client, so they should return quickly.
+#[allow(unused_variables)]
+pub trait Policy: Send + Sync + 'static {
+    /// Decides whether `username` may create an allocation for `five_tuple`.
+    fn allocate(&self, five_tuple: FiveTuple, username: &str) -> PolicyResult {
+        Ok(())
+    }
+
+    /// Decides whether `allocation` may be refreshed with the new lifetime.
+    ///
+    /// Deleting an allocation (i.e., a refresh with zero lifetime) is always allowed.
+    fn refresh(&self, allocation: &AllocationInfo, lifetime: Duration) -> PolicyResult {
+        Ok(())
+    }
+
+    /// Decides whether a permission for `peer` may be installed or refreshed.
+    ///
+    /// If a CreatePermission request has multiple peers, it is rejected unless all of them are allowed.
+    fn create_permission(&self, allocation: &AllocationInfo, peer: IpAddr) -> PolicyResult {
+        Ok(())
+    }
+
+    /// Decides whether a channel to `peer` may be bound or refreshed.
+    fn channel_bind(
+        &self,
+        allocation: &AllocationInfo,
+        channel_number: u16,
+        peer: SocketAddr,
+    ) -> PolicyResult {
+        Ok(())
+    }
+
+    /// Decides whether `allocation` may send data to `peer`.
+    ///
+    /// This is called for the first Send indication or ChannelData message to each peer,
+    /// and the decision applies to the subsequent data to the peer for a while
+    /// (see [`ServerOptions::send_decision_lifetime`](super::ServerOptions::send_decision_lifetime)).
+    /// Since neither of them has a response, the data to a rejected peer are silently discarded.
+    fn send(&self, allocation: &AllocationInfo, peer: SocketAddr) -> PolicyResult {
+        Ok(())
+    }
+}
@@ -0,0 +1,21 @@
+use std::net::SocketAddr;
+
+/// Policy that decides whether Allocate requests are redirected to other servers.
+///
+/// If `alternate_server` returns an address, the server answers the Allocate request
+/// with 300 (Try Alternate) and an `ALTERNATE-SERVER` attribute holding the address
+/// (e.g., for choosing the least-loaded node from a list of servers).
+pub trait RedirectPolicy: Send + Sync + 'static {
+    /// Returns the address of the server to which the Allocate request from `client` is redirected.
+    ///
+    /// `None` means that the request is handled by this server.
+    fn alternate_server(&self, client: SocketAddr) -> Option<SocketAddr>;
+}
+impl<F> RedirectPolicy for F
+where
+    F: Fn(SocketAddr) -> Option<SocketAddr> + Send + Sync + 'static,
+{
+    fn alternate_server(&self, client: SocketAddr) -> Option<SocketAddr> {
+        self(client)
+    }
+}
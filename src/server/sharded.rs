@@ -0,0 +1,442 @@
+use super::core::ServerCore;
+use super::options::ServerOptions;
+use super::shared::{FiveTuple, ServerHandle, SharedState, TransportProtocol};
+use crate::attribute::Attribute;
+use crate::auth::AuthParams;
+use crate::transport::{ChannelDataTransporter, StunTransporter};
+use crate::turn_message::{TurnMessage, TurnMessageDecoder, TurnMessageEncoder};
+use crate::{Error, ErrorKind, Result};
+use bytecodec::{DecodeExt, EncodeExt};
+use fibers::fiber::with_current_context;
+use fibers::io::poll::{EventedHandle, Interest};
+use fibers::sync::{mpsc, oneshot};
+use fibers::{BoxSpawn, Spawn};
+use fibers_transport::{PollRecv, PollSend, RcTransporter, Transport, UdpTransport};
+use futures::{Async, Future, Poll, Stream};
+use mio::net::UdpSocket as MioUdpSocket;
+use net2::unix::UnixUdpBuilderExt;
+use net2::UdpBuilder;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use stun_codec::rfc5766::methods::ALLOCATE;
+use stun_codec::rfc8016::attributes::MobilityTicket;
+use stun_codec::{MessageClass, MessageDecoder};
+use trackable::error::ErrorKindExt;
+
+const RECV_BUF_SIZE: usize = 4096;
+
+const MIN_OWNERS_TO_PRUNE: usize = 1024;
+
+type ShardStunTransporter =
+    rustun::transport::StunUdpTransporter<Attribute, StunTransporter<ShardTransporter>>;
+
+type ShardChannelDataTransporter = ChannelDataTransporter<ShardTransporter>;
+
+/// UDP TURN server that spreads client traffic across multiple `ServerCore` instances (shards).
+///
+/// Each shard has its own socket bound to the same address with `SO_REUSEPORT`,
+/// so the kernel distributes the datagrams across the shards by the transport address of the sender,
+/// and both receiving and handling datagrams run in parallel if the spawner is a multi-threaded executor
+/// (e.g., `fibers_global`).
+///
+/// All the shards share the allocation registry, the credentials and the statistics,
+/// and mobility tickets ([RFC 8016]) are valid across the shards:
+/// the datagrams from the new address of a client are forwarded to the shard that has its allocation.
+///
+/// Note that other sockets of the same user can also bind to the address with `SO_REUSEPORT`
+/// and steal a part of the datagrams.
+/// Because of `SO_REUSEPORT`, this server is available only on Unix-like platforms.
+///
+/// [RFC 8016]: https://tools.ietf.org/html/rfc8016
+#[derive(Debug)]
+#[must_use = "future do nothing unless polled"]
+pub struct ShardedUdpServer {
+    local_addr: SocketAddr,
+    shards: Vec<oneshot::Monitor<(), ()>>,
+    shared: SharedState,
+}
+impl ShardedUdpServer {
+    /// Starts a server that has `shards` shards.
+    pub fn start<S>(
+        spawner: S,
+        bind_addr: SocketAddr,
+        auth_params: AuthParams,
+        options: ServerOptions,
+        shards: usize,
+    ) -> impl Future<Item = Self, Error = Error>
+    where
+        S: Spawn + Send + 'static,
+    {
+        let shared = SharedState::new(auth_params, &options);
+        futures::future::result(bind_sockets(bind_addr, shards.max(1)))
+            .and_then(|sockets| {
+                let registers = sockets
+                    .into_iter()
+                    .map(|socket| {
+                        let register = with_current_context(|mut c| c.poller().register(socket));
+                        let register = track_assert_some!(
+                            register,
+                            ErrorKind::Other,
+                            "Must be started in a fiber"
+                        );
+                        Ok(register.map_err(|e| track!(Error::from(ErrorKind::Other.cause(e)))))
+                    })
+                    .collect::<Result<Vec<_>>>();
+                futures::future::result(registers).and_then(futures::future::join_all)
+            })
+            .and_then(move |sockets| {
+                let local_addr = track!(sockets[0].inner().local_addr().map_err(Error::from))?;
+                let (senders, receivers): (Vec<_>, Vec<_>) =
+                    sockets.iter().map(|_| mpsc::channel()).unzip();
+                let router = Arc::new(ShardRouter::new(senders, local_addr, shared.clone()));
+                let spawner = spawner.boxed();
+                let shards = sockets
+                    .into_iter()
+                    .zip(receivers)
+                    .enumerate()
+                    .map(|(index, (socket, forwarded))| {
+                        let transporter = ShardTransporter::new(
+                            index,
+                            socket,
+                            local_addr,
+                            forwarded,
+                            router.clone(),
+                        );
+                        spawn_shard(
+                            &spawner,
+                            index,
+                            transporter,
+                            local_addr,
+                            options.clone(),
+                            shared.clone(),
+                        )
+                    })
+                    .collect();
+                Ok(ShardedUdpServer {
+                    local_addr,
+                    shards,
+                    shared,
+                })
+            })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Returns a handle for inspecting the allocations and the statistics of the server.
+    pub fn handle(&self) -> ServerHandle {
+        self.shared.handle()
+    }
+}
+impl Future for ShardedUdpServer {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let mut i = 0;
+        while i < self.shards.len() {
+            match self.shards[i].poll() {
+                Err(_) => track_panic!(ErrorKind::Other, "A shard has aborted"),
+                Ok(Async::NotReady) => i += 1,
+                Ok(Async::Ready(())) => {
+                    self.shards.swap_remove(i);
+                }
+            }
+        }
+        if self.shards.is_empty() {
+            Ok(Async::Ready(()))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+/// Binds `n` sockets to `bind_addr` with `SO_REUSEPORT`.
+fn bind_sockets(bind_addr: SocketAddr, n: usize) -> Result<Vec<MioUdpSocket>> {
+    let mut addr = bind_addr;
+    let mut sockets = Vec::with_capacity(n);
+    for _ in 0..n {
+        let builder = if addr.is_ipv4() {
+            UdpBuilder::new_v4()
+        } else {
+            UdpBuilder::new_v6()
+        };
+        let builder = track!(builder.map_err(Error::from))?;
+        track!(builder.reuse_port(true).map_err(Error::from))?;
+        let socket = track!(builder.bind(addr).map_err(Error::from); addr)?;
+
+        // The subsequent sockets are bound to the port assigned to the first one
+        addr = track!(socket.local_addr().map_err(Error::from))?;
+        sockets.push(track!(
+            MioUdpSocket::from_socket(socket).map_err(Error::from)
+        )?);
+    }
+    Ok(sockets)
+}
+
+fn spawn_shard(
+    spawner: &BoxSpawn,
+    index: usize,
+    transporter: ShardTransporter,
+    local_addr: SocketAddr,
+    options: ServerOptions,
+    shared: SharedState,
+) -> oneshot::Monitor<(), ()> {
+    let transporter = RcTransporter::new(transporter);
+    let stun = ShardStunTransporter::new(StunTransporter::new(transporter.clone()));
+    let channel_data = ChannelDataTransporter::new(transporter);
+    let core = ServerCore::new(stun, channel_data, local_addr, options, shared);
+    spawner.spawn_monitor(Shard { index, core })
+}
+
+#[derive(Debug)]
+struct Shard {
+    index: usize,
+    core: ServerCore<ShardStunTransporter, ShardChannelDataTransporter>,
+}
+impl Future for Shard {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match track!(self.core.poll()) {
+            Err(e) => {
+                log::error!(shard = self.index; "Shard error: {}", e);
+                Ok(Async::NotReady)
+            }
+            Ok(x) => Ok(x),
+        }
+    }
+}
+
+/// Router that keeps track of the shards that have the allocations of clients.
+///
+/// The kernel always delivers the datagrams from a transport address to the same shard,
+/// but the allocation of a client that has moved to a new address ([RFC 8016]) stays in
+/// the shard that created it, so the datagrams from the new address have to be forwarded.
+///
+/// [RFC 8016]: https://tools.ietf.org/html/rfc8016
+#[derive(Debug)]
+struct ShardRouter {
+    shards: Vec<mpsc::Sender<(SocketAddr, Vec<u8>)>>,
+    local_addr: SocketAddr,
+    shared: SharedState,
+    owners: RwLock<Owners>,
+}
+impl ShardRouter {
+    fn new(
+        shards: Vec<mpsc::Sender<(SocketAddr, Vec<u8>)>>,
+        local_addr: SocketAddr,
+        shared: SharedState,
+    ) -> Self {
+        ShardRouter {
+            shards,
+            local_addr,
+            shared,
+            owners: RwLock::new(Owners {
+                shards: HashMap::new(),
+                prune_threshold: MIN_OWNERS_TO_PRUNE,
+            }),
+        }
+    }
+
+    /// Returns the index of the shard that should handle `data` from `client`.
+    fn route(&self, shard: usize, client: SocketAddr, data: &[u8]) -> usize {
+        if let Some(old_client) = self.migration_source(data) {
+            // The allocation of `old_client` is going to be moved to `client`,
+            // so the datagrams from `client` have to be handled by the same shard as `old_client`.
+            let owner = self.owner(old_client).unwrap_or(shard);
+            self.set_owner(client, owner);
+            owner
+        } else {
+            self.owner(client).unwrap_or(shard)
+        }
+    }
+
+    fn forward(&self, shard: usize, client: SocketAddr, data: Vec<u8>) {
+        if self.shards[shard].send((client, data)).is_err() {
+            log::warn!(client:% = client, shard = shard; "Shard has terminated");
+        }
+    }
+
+    fn owner(&self, client: SocketAddr) -> Option<usize> {
+        let owners = self.owners.read().expect("never fails");
+        owners.shards.get(&client).copied()
+    }
+
+    fn set_owner(&self, client: SocketAddr, shard: usize) {
+        let mut owners = self.owners.write().expect("never fails");
+        owners.shards.insert(client, shard);
+        if owners.shards.len() >= owners.prune_threshold {
+            let shared = &self.shared;
+            let server = self.local_addr;
+            owners.shards.retain(|c, _| {
+                shared.has_allocation(FiveTuple::new(*c, server, TransportProtocol::Udp))
+            });
+            owners.prune_threshold = (owners.shards.len() * 2).max(MIN_OWNERS_TO_PRUNE);
+        }
+    }
+
+    /// Returns the client address in the mobility ticket if `data` is a Refresh request that has it.
+    fn migration_source(&self, data: &[u8]) -> Option<SocketAddr> {
+        const REFRESH_REQUEST: [u8; 2] = [0x00, 0x04];
+        if data.get(..2) != Some(&REFRESH_REQUEST[..]) {
+            return None;
+        }
+        let message = MessageDecoder::<Attribute>::new()
+            .decode_from_bytes(data)
+            .ok()?
+            .ok()?;
+        let ticket = message.get_attribute::<MobilityTicket>()?;
+        let (old_client, _) = self.shared.ticket_issuer().open(ticket.data())?;
+        Some(old_client)
+    }
+}
+
+#[derive(Debug)]
+struct Owners {
+    shards: HashMap<SocketAddr, usize>,
+
+    // The entries of the removed allocations are pruned when the number of entries reaches this
+    prune_threshold: usize,
+}
+
+/// Transport of a shard.
+///
+/// This receives datagrams via the socket of the shard and those forwarded by the other shards.
+#[derive(Debug)]
+struct ShardTransporter {
+    index: usize,
+    socket: Arc<EventedHandle<MioUdpSocket>>,
+    local_addr: SocketAddr,
+    forwarded: mpsc::Receiver<(SocketAddr, Vec<u8>)>,
+    router: Arc<ShardRouter>,
+    recv_buf: Vec<u8>,
+    read_monitor: Option<oneshot::Monitor<(), io::Error>>,
+    write_monitor: Option<oneshot::Monitor<(), io::Error>>,
+    outgoing: VecDeque<(SocketAddr, Vec<u8>)>,
+    encoder: TurnMessageEncoder,
+    decoder: TurnMessageDecoder,
+}
+impl ShardTransporter {
+    fn new(
+        index: usize,
+        socket: Arc<EventedHandle<MioUdpSocket>>,
+        local_addr: SocketAddr,
+        forwarded: mpsc::Receiver<(SocketAddr, Vec<u8>)>,
+        router: Arc<ShardRouter>,
+    ) -> Self {
+        ShardTransporter {
+            index,
+            socket,
+            local_addr,
+            forwarded,
+            router,
+            recv_buf: vec![0; RECV_BUF_SIZE],
+            read_monitor: None,
+            write_monitor: None,
+            outgoing: VecDeque::new(),
+            encoder: TurnMessageEncoder::default(),
+            decoder: TurnMessageDecoder::default(),
+        }
+    }
+
+    fn decode(&mut self, peer: SocketAddr, data: &[u8]) -> PollRecv<(SocketAddr, TurnMessage)> {
+        let item = track!(self.decoder.decode_from_bytes(data); peer)?;
+        Ok(Async::Ready(Some((peer, item))))
+    }
+}
+impl Transport for ShardTransporter {
+    type PeerAddr = SocketAddr;
+    type SendItem = TurnMessage;
+    type RecvItem = TurnMessage;
+
+    fn start_send(&mut self, peer: SocketAddr, item: TurnMessage) -> fibers_transport::Result<()> {
+        if let TurnMessage::Stun(message) = &item {
+            if message.method() == ALLOCATE && message.class() == MessageClass::SuccessResponse {
+                self.router.set_owner(peer, self.index);
+            }
+        }
+        let bytes = track!(self.encoder.encode_into_bytes(item))?;
+        self.outgoing.push_back((peer, bytes));
+        track!(self.poll_send())?;
+        Ok(())
+    }
+
+    fn poll_send(&mut self) -> PollSend {
+        loop {
+            if let Some(monitor) = self.write_monitor.as_mut() {
+                if track!(poll_monitor(monitor))?.is_not_ready() {
+                    return Ok(Async::NotReady);
+                }
+                self.write_monitor = None;
+            }
+            let (peer, bytes) = if let Some(x) = self.outgoing.front() {
+                x
+            } else {
+                return Ok(Async::Ready(()));
+            };
+            let result = self.socket.inner().send_to(bytes, peer);
+            match result {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    self.write_monitor = Some(self.socket.monitor(Interest::Write));
+                }
+                Err(e) => {
+                    self.outgoing.pop_front();
+                    return Err(track!(fibers_transport::Error::from(e)));
+                }
+                Ok(_) => {
+                    self.outgoing.pop_front();
+                }
+            }
+        }
+    }
+
+    fn poll_recv(&mut self) -> PollRecv<(SocketAddr, TurnMessage)> {
+        if let Async::Ready(Some((peer, data))) = self.forwarded.poll().expect("never fails") {
+            return track!(self.decode(peer, &data));
+        }
+        loop {
+            if let Some(monitor) = self.read_monitor.as_mut() {
+                if track!(poll_monitor(monitor))?.is_not_ready() {
+                    return Ok(Async::NotReady);
+                }
+                self.read_monitor = None;
+            }
+            let result = self.socket.inner().recv_from(&mut self.recv_buf);
+            match result {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    self.read_monitor = Some(self.socket.monitor(Interest::Read));
+                }
+                Err(e) => {
+                    log::warn!(listener:% = self.local_addr; "Cannot receive a datagram: {}", e);
+                }
+                Ok((size, peer)) => {
+                    let data = self.recv_buf[..size].to_vec();
+                    let shard = self.router.route(self.index, peer, &data);
+                    if shard == self.index {
+                        return track!(self.decode(peer, &data));
+                    }
+                    self.router.forward(shard, peer, data);
+                }
+            }
+        }
+    }
+}
+impl UdpTransport for ShardTransporter {
+    fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+fn poll_monitor(
+    monitor: &mut oneshot::Monitor<(), io::Error>,
+) -> fibers_transport::Result<Async<()>> {
+    monitor.poll().map_err(|e| {
+        let e = e.unwrap_or_else(|| io::Error::other("Monitor aborted"));
+        track!(fibers_transport::Error::from(e))
+    })
+}
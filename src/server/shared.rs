@@ -0,0 +1,531 @@
+use super::limiter::SourceLimiter;
+use super::mobility::TicketIssuer;
+use super::options::{AccessSettings, ServerOptions};
+use super::tcp_relay::ConnectionRegistry;
+use crate::auth::AuthParams;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
+
+/// Transport protocol between a client and a server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TransportProtocol {
+    Udp,
+    Tcp,
+
+    /// TLS over TCP.
+    Tls,
+}
+
+/// 5-tuple (the client address, the server address and the transport protocol) of an allocation.
+///
+/// Allocations received on different listeners never share a 5-tuple even if their client addresses are the same.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FiveTuple {
+    client: SocketAddr,
+    server: SocketAddr,
+    transport: TransportProtocol,
+}
+impl FiveTuple {
+    /// Makes a new `FiveTuple` instance.
+    pub fn new(client: SocketAddr, server: SocketAddr, transport: TransportProtocol) -> Self {
+        FiveTuple {
+            client,
+            server,
+            transport,
+        }
+    }
+
+    /// Returns the transport address of the client.
+    pub fn client_addr(&self) -> SocketAddr {
+        self.client
+    }
+
+    /// Returns the transport address of the server.
+    pub fn server_addr(&self) -> SocketAddr {
+        self.server
+    }
+
+    /// Returns the transport protocol between the client and the server.
+    pub fn transport(&self) -> TransportProtocol {
+        self.transport
+    }
+
+    /// Returns the 5-tuple of which the client address is replaced with `client`.
+    pub(crate) fn with_client_addr(self, client: SocketAddr) -> Self {
+        FiveTuple { client, ..self }
+    }
+}
+impl fmt::Display for FiveTuple {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let transport = match self.transport {
+            TransportProtocol::Udp => "udp",
+            TransportProtocol::Tcp => "tcp",
+            TransportProtocol::Tls => "tls",
+        };
+        write!(f, "{}:{}->{}", transport, self.client, self.server)
+    }
+}
+
+/// Information about an allocation.
+///
+/// The traffic counters are shared by all the clones of an instance,
+/// so they always reflect the latest values.
+#[derive(Debug, Clone)]
+pub struct AllocationInfo {
+    five_tuple: FiveTuple,
+    username: String,
+    realm: String,
+    relay_addrs: Vec<SocketAddr>,
+    created_at: SystemTime,
+    traffic: Arc<TrafficCells>,
+}
+impl AllocationInfo {
+    pub(crate) fn new(
+        five_tuple: FiveTuple,
+        username: &str,
+        realm: &str,
+        relay_addrs: Vec<SocketAddr>,
+    ) -> Self {
+        AllocationInfo {
+            five_tuple,
+            username: username.to_owned(),
+            realm: realm.to_owned(),
+            relay_addrs,
+            created_at: SystemTime::now(),
+            traffic: Arc::default(),
+        }
+    }
+
+    /// Returns the 5-tuple that identifies the allocation.
+    pub fn five_tuple(&self) -> FiveTuple {
+        self.five_tuple
+    }
+
+    /// Returns the transport protocol between the client and the server.
+    pub fn transport(&self) -> TransportProtocol {
+        self.five_tuple.transport
+    }
+
+    /// Returns the transport address of the client.
+    pub fn client_addr(&self) -> SocketAddr {
+        self.five_tuple.client
+    }
+
+    /// Returns the transport address of the server that the client sends requests to.
+    pub fn server_addr(&self) -> SocketAddr {
+        self.five_tuple.server
+    }
+
+    /// Returns the username that created the allocation.
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    /// Returns the realm in which the username was authenticated.
+    pub fn realm(&self) -> &str {
+        &self.realm
+    }
+
+    /// Returns the relayed transport addresses of the allocation.
+    pub fn relay_addrs(&self) -> &[SocketAddr] {
+        &self.relay_addrs
+    }
+
+    /// Returns the time at which the allocation was created.
+    pub fn created_at(&self) -> SystemTime {
+        self.created_at
+    }
+
+    /// Returns the amount of the data relayed by the allocation.
+    pub fn traffic(&self) -> TrafficCounters {
+        TrafficCounters {
+            bytes_to_peers: self.traffic.bytes_to_peers.load(Ordering::Relaxed),
+            packets_to_peers: self.traffic.packets_to_peers.load(Ordering::Relaxed),
+            bytes_from_peers: self.traffic.bytes_from_peers.load(Ordering::Relaxed),
+            packets_from_peers: self.traffic.packets_from_peers.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Returns the amount of the data relayed by the allocation for each peer.
+    pub fn peer_traffic(&self) -> Vec<(SocketAddr, TrafficCounters)> {
+        let peers = self.traffic.peers.lock().expect("never fails");
+        let mut peers = peers.iter().map(|(k, v)| (*k, *v)).collect::<Vec<_>>();
+        peers.sort_by_key(|x| x.0);
+        peers
+    }
+
+    pub(crate) fn set_client_addr(&mut self, client: SocketAddr) {
+        self.five_tuple = self.five_tuple.with_client_addr(client);
+    }
+
+    pub(crate) fn record_to_peer(&self, peer: SocketAddr, bytes: usize) {
+        self.traffic
+            .bytes_to_peers
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+        self.traffic
+            .packets_to_peers
+            .fetch_add(1, Ordering::Relaxed);
+
+        let mut peers = self.traffic.peers.lock().expect("never fails");
+        let counters = peers.entry(peer).or_default();
+        counters.bytes_to_peers += bytes as u64;
+        counters.packets_to_peers += 1;
+    }
+
+    pub(crate) fn record_from_peer(&self, peer: SocketAddr, bytes: usize) {
+        self.traffic
+            .bytes_from_peers
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+        self.traffic
+            .packets_from_peers
+            .fetch_add(1, Ordering::Relaxed);
+
+        let mut peers = self.traffic.peers.lock().expect("never fails");
+        let counters = peers.entry(peer).or_default();
+        counters.bytes_from_peers += bytes as u64;
+        counters.packets_from_peers += 1;
+    }
+
+    pub(crate) fn is_kicked(&self) -> bool {
+        self.traffic.kicked.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn is_revoked(&self) -> bool {
+        self.traffic.revoked.load(Ordering::Relaxed)
+    }
+}
+
+/// Amount of the data relayed by an allocation.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TrafficCounters {
+    /// Number of the bytes relayed from the client to peers.
+    pub bytes_to_peers: u64,
+
+    /// Number of the packets relayed from the client to peers.
+    pub packets_to_peers: u64,
+
+    /// Number of the bytes relayed from peers to the client.
+    pub bytes_from_peers: u64,
+
+    /// Number of the packets relayed from peers to the client.
+    pub packets_from_peers: u64,
+}
+
+#[derive(Debug, Default)]
+struct TrafficCells {
+    bytes_to_peers: AtomicU64,
+    packets_to_peers: AtomicU64,
+    bytes_from_peers: AtomicU64,
+    packets_from_peers: AtomicU64,
+    peers: Mutex<HashMap<SocketAddr, TrafficCounters>>,
+    kicked: AtomicBool,
+    revoked: AtomicBool,
+}
+
+/// Snapshot of the statistics of a server.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ServerStats {
+    /// Number of the allocations that are currently active.
+    pub active_allocations: u64,
+
+    /// Total number of the allocations that have been created.
+    pub created_allocations: u64,
+
+    /// Total number of the STUN requests that have been received.
+    pub requests: u64,
+
+    /// Total number of the requests that failed to be authenticated.
+    pub auth_failures: u64,
+
+    /// Total number of the requests dropped by the rate limit of unauthenticated requests.
+    pub rate_limited_requests: u64,
+
+    /// Total number of the requests dropped because their source IPs were banned.
+    pub banned_requests: u64,
+
+    /// Total number of the bans of source IPs.
+    pub bans: u64,
+
+    /// Total number of the invalid STUN messages that have been received.
+    pub invalid_messages: u64,
+
+    /// Total number of the bytes relayed from clients to peers.
+    pub bytes_to_peers: u64,
+
+    /// Total number of the bytes relayed from peers to clients.
+    pub bytes_from_peers: u64,
+}
+
+/// Handle for inspecting a running server.
+///
+/// All the listeners of a server share the state observed by this handle.
+#[derive(Debug, Clone)]
+pub struct ServerHandle {
+    shared: SharedState,
+}
+impl ServerHandle {
+    /// Returns the allocations that are currently active.
+    pub fn allocations(&self) -> Vec<AllocationInfo> {
+        let allocations = self.shared.0.allocations.lock().expect("never fails");
+        allocations.values().cloned().collect()
+    }
+
+    /// Removes the allocation identified by `five_tuple`.
+    ///
+    /// The allocation is torn down asynchronously by the task that handles the client.
+    /// Returns `false` if there is no such allocation.
+    pub fn kick(&self, five_tuple: FiveTuple) -> bool {
+        let allocations = self.shared.0.allocations.lock().expect("never fails");
+        if let Some(info) = allocations.get(&five_tuple) {
+            info.traffic.kicked.store(true, Ordering::Relaxed);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Replaces the users, the shared secret, the quotas and the peer ACL of the server
+    /// with those of `options` (the other options are ignored).
+    ///
+    /// The new settings apply to subsequent requests and the existing allocations are kept.
+    /// If [`ServerOptions::revoke_removed_users`] is enabled in `options`,
+    /// the allocations of the users that no longer exist are revoked at their next refresh.
+    pub fn reload(&self, options: &ServerOptions) {
+        let new = Arc::new(access_settings(
+            &self.shared.auth_params(),
+            self.shared.0.primary_user,
+            options,
+        ));
+        let old = std::mem::replace(
+            &mut *self.shared.0.access.write().expect("never fails"),
+            Arc::clone(&new),
+        );
+        if new.is_revoke_removed_users_enabled() {
+            let allocations = self.shared.0.allocations.lock().expect("never fails");
+            for username in old.removed_users(&new) {
+                for info in allocations.values().filter(|a| a.username == username) {
+                    info.traffic.revoked.store(true, Ordering::Relaxed);
+                }
+            }
+        }
+        log::info!(settings:? = new; "Reloaded the access settings");
+    }
+
+    /// Returns the statistics of the server.
+    pub fn stats(&self) -> ServerStats {
+        let counters = &self.shared.0.counters;
+        let active_allocations = self.shared.0.allocations.lock().expect("never fails").len();
+        ServerStats {
+            active_allocations: active_allocations as u64,
+            created_allocations: counters.created_allocations.load(Ordering::Relaxed),
+            requests: counters.requests.load(Ordering::Relaxed),
+            auth_failures: counters.auth_failures.load(Ordering::Relaxed),
+            rate_limited_requests: counters.rate_limited_requests.load(Ordering::Relaxed),
+            banned_requests: counters.banned_requests.load(Ordering::Relaxed),
+            bans: counters.bans.load(Ordering::Relaxed),
+            invalid_messages: counters.invalid_messages.load(Ordering::Relaxed),
+            bytes_to_peers: counters.bytes_to_peers.load(Ordering::Relaxed),
+            bytes_from_peers: counters.bytes_from_peers.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// State shared by all the listeners (and the `ServerCore` instances) of a server.
+///
+/// This consists of the credentials (including the nonce), the reloadable access settings,
+/// the allocation registry, the peer data connections of TCP allocations,
+/// the mobility ticket issuer, the per-source rate limits and the statistics.
+#[derive(Debug, Clone)]
+pub(crate) struct SharedState(Arc<SharedInner>);
+impl SharedState {
+    pub fn new(auth_params: AuthParams, options: &ServerOptions) -> Self {
+        Self::with_primary_user(auth_params, true, options)
+    }
+
+    /// Makes a state in which only the realm and the nonce of `auth_params` are used,
+    /// so that the users are given only by `options`.
+    pub fn without_primary_user(auth_params: AuthParams, options: &ServerOptions) -> Self {
+        Self::with_primary_user(auth_params, false, options)
+    }
+
+    fn with_primary_user(
+        auth_params: AuthParams,
+        primary_user: bool,
+        options: &ServerOptions,
+    ) -> Self {
+        let access = access_settings(&auth_params, primary_user, options);
+        SharedState(Arc::new(SharedInner {
+            auth_params: Mutex::new(auth_params),
+            primary_user,
+            access: RwLock::new(Arc::new(access)),
+            allocations: Mutex::new(HashMap::new()),
+            tcp_connections: ConnectionRegistry::new(),
+            ticket_issuer: TicketIssuer::new(),
+            limiter: SourceLimiter::default(),
+            counters: Counters::default(),
+        }))
+    }
+
+    pub fn handle(&self) -> ServerHandle {
+        ServerHandle {
+            shared: self.clone(),
+        }
+    }
+
+    pub fn auth_params(&self) -> AuthParams {
+        self.0.auth_params.lock().expect("never fails").clone()
+    }
+
+    /// Returns whether `username` is the user of the server's `AuthParams`.
+    pub fn is_primary_user(&self, username: &str) -> bool {
+        self.0.primary_user && self.auth_params().username() == username
+    }
+
+    pub fn access(&self) -> Arc<AccessSettings> {
+        Arc::clone(&self.0.access.read().expect("never fails"))
+    }
+
+    pub fn tcp_connections(&self) -> &ConnectionRegistry {
+        &self.0.tcp_connections
+    }
+
+    pub fn ticket_issuer(&self) -> &TicketIssuer {
+        &self.0.ticket_issuer
+    }
+
+    pub fn limiter(&self) -> &SourceLimiter {
+        &self.0.limiter
+    }
+
+    #[cfg(unix)]
+    pub fn has_allocation(&self, five_tuple: FiveTuple) -> bool {
+        let allocations = self.0.allocations.lock().expect("never fails");
+        allocations.contains_key(&five_tuple)
+    }
+
+    /// Returns the number of the allocations (of `username` if specified).
+    pub fn allocation_count(&self, username: Option<&str>) -> usize {
+        let allocations = self.0.allocations.lock().expect("never fails");
+        match username {
+            None => allocations.len(),
+            Some(username) => allocations
+                .values()
+                .filter(|a| a.username == username)
+                .count(),
+        }
+    }
+
+    pub fn register_allocation(&self, info: AllocationInfo) {
+        let mut allocations = self.0.allocations.lock().expect("never fails");
+        allocations.insert(info.five_tuple, info);
+        self.0
+            .counters
+            .created_allocations
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn move_allocation(&self, from: FiveTuple, to: FiveTuple) {
+        let mut allocations = self.0.allocations.lock().expect("never fails");
+        if let Some(mut info) = allocations.remove(&from) {
+            info.set_client_addr(to.client);
+            allocations.insert(to, info);
+        }
+    }
+
+    pub fn unregister_allocation(&self, five_tuple: FiveTuple) {
+        let mut allocations = self.0.allocations.lock().expect("never fails");
+        allocations.remove(&five_tuple);
+    }
+
+    pub fn increment_requests(&self) {
+        self.0.counters.requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn increment_auth_failures(&self) {
+        self.0
+            .counters
+            .auth_failures
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn increment_rate_limited_requests(&self) {
+        self.0
+            .counters
+            .rate_limited_requests
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn increment_banned_requests(&self) {
+        self.0
+            .counters
+            .banned_requests
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn increment_bans(&self) {
+        self.0.counters.bans.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn increment_invalid_messages(&self) {
+        self.0
+            .counters
+            .invalid_messages
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_bytes_to_peers(&self, n: usize) {
+        self.0
+            .counters
+            .bytes_to_peers
+            .fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_bytes_from_peers(&self, n: usize) {
+        self.0
+            .counters
+            .bytes_from_peers
+            .fetch_add(n as u64, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug)]
+struct SharedInner {
+    auth_params: Mutex<AuthParams>,
+    primary_user: bool,
+    access: RwLock<Arc<AccessSettings>>,
+    allocations: Mutex<HashMap<FiveTuple, AllocationInfo>>,
+    tcp_connections: ConnectionRegistry,
+    ticket_issuer: TicketIssuer,
+    limiter: SourceLimiter,
+    counters: Counters,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    created_allocations: AtomicU64,
+    requests: AtomicU64,
+    auth_failures: AtomicU64,
+    rate_limited_requests: AtomicU64,
+    banned_requests: AtomicU64,
+    bans: AtomicU64,
+    invalid_messages: AtomicU64,
+    bytes_to_peers: AtomicU64,
+    bytes_from_peers: AtomicU64,
+}
+
+/// Returns the access settings of `options` that can resolve the `USERHASH` of the users.
+fn access_settings(
+    auth_params: &AuthParams,
+    primary_user: bool,
+    options: &ServerOptions,
+) -> AccessSettings {
+    let realm = auth_params.get_realm().map_or("", |r| r.text());
+    let primary_user = Some(auth_params.username()).filter(|_| primary_user);
+    options
+        .access_settings()
+        .with_userhashes(realm, primary_user)
+}
@@ -0,0 +1,242 @@
+use super::shared::FiveTuple;
+use crate::transport::TlsStream;
+use crate::{Error, ErrorKind, Result};
+use fibers::net::TcpStream;
+use futures::{Async, Future, Poll};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::sync::{Arc, Mutex};
+
+/// Peer data connections of TCP allocations ([RFC 6062]).
+///
+/// A connection is registered when the server has established it with a peer
+/// (by a Connect request or an incoming connection to a relayed transport address),
+/// and it is taken by the client data connection that sends the corresponding ConnectionBind request.
+///
+/// [RFC 6062]: https://tools.ietf.org/html/rfc6062
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionRegistry(Arc<Mutex<RegistryInner>>);
+impl ConnectionRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&self, client: FiveTuple, peer: SocketAddr, stream: TcpStream) -> u32 {
+        let mut inner = self.0.lock().expect("never fails");
+        loop {
+            let id = inner.next_id;
+            inner.next_id = inner.next_id.wrapping_add(1);
+            if let std::collections::hash_map::Entry::Vacant(e) = inner.connections.entry(id) {
+                e.insert(PeerConnection {
+                    client,
+                    peer,
+                    stream: Some(stream),
+                });
+                return id;
+            }
+        }
+    }
+
+    pub fn contains(&self, client: FiveTuple, peer: SocketAddr) -> bool {
+        let inner = self.0.lock().expect("never fails");
+        inner
+            .connections
+            .values()
+            .any(|c| c.client == client && c.peer == peer)
+    }
+
+    /// Takes the peer stream of the pending connection identified by `id`.
+    pub fn bind(&self, id: u32) -> Option<(SocketAddr, TcpStream)> {
+        let mut inner = self.0.lock().expect("never fails");
+        let connection = inner.connections.get_mut(&id)?;
+        let stream = connection.stream.take()?;
+        Some((connection.peer, stream))
+    }
+
+    /// Removes the connection identified by `id` if it has not been bound yet.
+    pub fn remove_if_pending(&self, id: u32) {
+        let mut inner = self.0.lock().expect("never fails");
+        if inner
+            .connections
+            .get(&id)
+            .is_some_and(|c| c.stream.is_some())
+        {
+            inner.connections.remove(&id);
+        }
+    }
+
+    /// Removes the pending connections that belong to the allocation of `client`.
+    pub fn remove_pending_of(&self, client: FiveTuple) {
+        let mut inner = self.0.lock().expect("never fails");
+        inner
+            .connections
+            .retain(|_, c| !(c.client == client && c.stream.is_some()));
+    }
+
+    fn remove(&self, id: u32) {
+        let mut inner = self.0.lock().expect("never fails");
+        inner.connections.remove(&id);
+    }
+}
+
+#[derive(Debug, Default)]
+struct RegistryInner {
+    next_id: u32,
+    connections: HashMap<u32, PeerConnection>,
+}
+
+#[derive(Debug)]
+struct PeerConnection {
+    client: FiveTuple,
+    peer: SocketAddr,
+    stream: Option<TcpStream>,
+}
+
+/// Stream of a data connection that [`TcpRelay`] relays bytes to and from.
+pub trait RelayStream: Read + Write {
+    /// Shuts down the writing half of the connection.
+    fn shutdown_write(&mut self);
+}
+impl RelayStream for TcpStream {
+    fn shutdown_write(&mut self) {
+        let _ = self.with_inner(|s| s.shutdown(Shutdown::Write));
+    }
+}
+impl RelayStream for TlsStream {
+    fn shutdown_write(&mut self) {
+        self.close();
+    }
+}
+
+/// A future that relays bytes between a client data connection and a peer data connection.
+///
+/// The client data connection is either a TCP connection or a TLS connection.
+#[derive(Debug)]
+pub struct TcpRelay<S = TcpStream> {
+    client: S,
+    peer: TcpStream,
+    client_to_peer: Pipe,
+    peer_to_client: Pipe,
+    registry: ConnectionRegistry,
+    connection_id: u32,
+}
+impl<S: RelayStream> TcpRelay<S> {
+    /// Makes a new `TcpRelay` instance.
+    ///
+    /// `pending` is the data that has already been read from the client data connection.
+    pub fn new(
+        client: S,
+        pending: Vec<u8>,
+        peer: TcpStream,
+        registry: ConnectionRegistry,
+        connection_id: u32,
+    ) -> Self {
+        TcpRelay {
+            client,
+            peer,
+            client_to_peer: Pipe::with_pending(pending),
+            peer_to_client: Pipe::with_pending(Vec::new()),
+            registry,
+            connection_id,
+        }
+    }
+}
+impl<S: RelayStream> Future for TcpRelay<S> {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let mut did_something = true;
+        while did_something {
+            did_something = false;
+            did_something |= track!(self.client_to_peer.poll(&mut self.client, &mut self.peer))?;
+            did_something |= track!(self.peer_to_client.poll(&mut self.peer, &mut self.client))?;
+        }
+        if self.client_to_peer.is_closed() && self.peer_to_client.is_closed() {
+            Ok(Async::Ready(()))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+impl<S> Drop for TcpRelay<S> {
+    fn drop(&mut self) {
+        self.registry.remove(self.connection_id);
+    }
+}
+
+#[derive(Debug)]
+struct Pipe {
+    buf: Vec<u8>,
+    start: usize,
+    end: usize,
+    eos: bool,
+    closed: bool,
+}
+impl Pipe {
+    fn with_pending(mut buf: Vec<u8>) -> Self {
+        let end = buf.len();
+        if buf.len() < 4096 {
+            buf.resize(4096, 0);
+        }
+        Pipe {
+            buf,
+            start: 0,
+            end,
+            eos: false,
+            closed: false,
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    fn poll<R, W>(&mut self, reader: &mut R, writer: &mut W) -> Result<bool>
+    where
+        R: Read,
+        W: RelayStream,
+    {
+        let mut did_something = false;
+        if self.start == self.end && !self.eos {
+            match reader.read(&mut self.buf) {
+                Err(e) => track!(Self::check_would_block(e))?,
+                Ok(0) => {
+                    self.eos = true;
+                    did_something = true;
+                }
+                Ok(size) => {
+                    self.start = 0;
+                    self.end = size;
+                    did_something = true;
+                }
+            }
+        }
+        if self.start < self.end {
+            match writer.write(&self.buf[self.start..self.end]) {
+                Err(e) => track!(Self::check_would_block(e))?,
+                Ok(size) => {
+                    self.start += size;
+                    did_something = true;
+                }
+            }
+        }
+        if self.eos && self.start == self.end && !self.closed {
+            self.closed = true;
+            writer.shutdown_write();
+        }
+        Ok(did_something)
+    }
+
+    fn check_would_block(e: io::Error) -> Result<()> {
+        track_assert_eq!(
+            e.kind(),
+            io::ErrorKind::WouldBlock,
+            ErrorKind::Other,
+            "I/O Error: {}",
+            e
+        );
+        Ok(())
+    }
+}
@@ -1,26 +1,30 @@
-use crate::attribute::Attribute;
 use crate::turn_message::{TurnMessageDecoder, TurnMessageEncoder};
-use fibers_transport::{TcpTransporter, UdpTransporter};
+use fibers_transport::TcpTransporter;
 
 pub(crate) use self::channel_data::ChannelDataTransporter;
 pub(crate) use self::stun::StunTransporter;
+pub(crate) use self::stun_retransmit::StunRetransmitTransporter;
+pub(crate) use self::tls::{TlsStream, TurnTlsTransporter};
+pub(crate) use self::udp::TurnUdpTransporter;
 
 pub use self::udp_over_turn::UdpOverTurnTransporter;
+pub use self::virtual_network::{VirtualNetwork, VirtualSocket};
 
 mod channel_data;
 mod stun;
+mod stun_retransmit;
+mod tls;
+mod udp;
 mod udp_over_turn;
+mod virtual_network;
 
 pub(crate) type StunTcpTransporter =
     rustun::transport::StunTcpTransporter<StunTransporter<TurnTcpTransporter>>;
 
-pub(crate) type StunUdpTransporter =
-    rustun::transport::StunUdpTransporter<Attribute, StunTransporter<TurnUdpTransporter>>;
+pub(crate) type StunUdpTransporter = StunRetransmitTransporter<StunTransporter<TurnUdpTransporter>>;
 
 pub(crate) type ChannelDataTcpTransporter = ChannelDataTransporter<TurnTcpTransporter>;
 
 pub(crate) type ChannelDataUdpTransporter = ChannelDataTransporter<TurnUdpTransporter>;
 
-pub(crate) type TurnUdpTransporter = UdpTransporter<TurnMessageEncoder, TurnMessageDecoder>;
-
 pub(crate) type TurnTcpTransporter = TcpTransporter<TurnMessageEncoder, TurnMessageDecoder>;
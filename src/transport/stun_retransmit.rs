@@ -0,0 +1,97 @@
+use crate::attribute::Attribute;
+use fibers::time::timer::{self, Timeout};
+use fibers_transport::{PollRecv, PollSend, Result, Transport, UdpTransport};
+use futures::{Async, Future};
+use rustun::transport::{StunTransport, StunUdpTransporter, StunUdpTransporterBuilder};
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::time::Duration;
+use stun_codec::{DecodedMessage, Message, MessageClass, TransactionId};
+
+/// The interval of the wakeups while there are outstanding transactions.
+const WAKEUP_INTERVAL_MS: u64 = 50;
+
+/// `rustun`'s UDP transporter for STUN that does not miss the timeouts of retransmissions.
+///
+/// The timeout queue of `rustun` replaces an expired timer without polling the new one,
+/// so the task is not woken up by the next retransmission of a request if the expired timer belonged
+/// to a finished transaction. This wakes up the task periodically while there are outstanding
+/// transactions, so that their retransmissions (and timeouts) are handled on time.
+#[derive(Debug)]
+pub struct StunRetransmitTransporter<T> {
+    inner: StunUdpTransporter<Attribute, T>,
+    transactions: HashSet<(SocketAddr, TransactionId)>,
+    wakeup: Option<Timeout>,
+}
+impl<T> StunRetransmitTransporter<T>
+where
+    T: UdpTransport<SendItem = Message<Attribute>, RecvItem = DecodedMessage<Attribute>>,
+{
+    pub fn new(inner: T) -> Self {
+        Self::with_builder(&StunUdpTransporterBuilder::new(), inner)
+    }
+
+    pub fn with_builder(builder: &StunUdpTransporterBuilder, inner: T) -> Self {
+        StunRetransmitTransporter {
+            inner: builder.finish(inner),
+            transactions: HashSet::new(),
+            wakeup: None,
+        }
+    }
+
+    pub fn inner_ref(&self) -> &T {
+        self.inner.inner_ref()
+    }
+
+    fn poll_wakeup(&mut self) {
+        if self.transactions.is_empty() {
+            self.wakeup = None;
+            return;
+        }
+        while let Ok(Async::Ready(())) = self
+            .wakeup
+            .get_or_insert_with(|| timer::timeout(Duration::from_millis(WAKEUP_INTERVAL_MS)))
+            .poll()
+        {
+            self.wakeup = None;
+        }
+    }
+}
+impl<T> Transport for StunRetransmitTransporter<T>
+where
+    T: UdpTransport<SendItem = Message<Attribute>, RecvItem = DecodedMessage<Attribute>>,
+{
+    type PeerAddr = SocketAddr;
+    type SendItem = Message<Attribute>;
+    type RecvItem = DecodedMessage<Attribute>;
+
+    fn start_send(&mut self, peer: SocketAddr, item: Self::SendItem) -> Result<()> {
+        if item.class() == MessageClass::Request {
+            self.transactions.insert((peer, item.transaction_id()));
+        }
+        track!(self.inner.start_send(peer, item))
+    }
+
+    fn poll_send(&mut self) -> PollSend {
+        let result = track!(self.inner.poll_send());
+        self.poll_wakeup();
+        result
+    }
+
+    fn poll_recv(&mut self) -> PollRecv<(SocketAddr, Self::RecvItem)> {
+        track!(self.inner.poll_recv())
+    }
+}
+impl<T> StunTransport<Attribute> for StunRetransmitTransporter<T>
+where
+    T: UdpTransport<SendItem = Message<Attribute>, RecvItem = DecodedMessage<Attribute>>,
+{
+    fn finish_transaction(
+        &mut self,
+        peer: &SocketAddr,
+        transaction_id: TransactionId,
+    ) -> Result<()> {
+        self.transactions.remove(&(*peer, transaction_id));
+        track!(self.inner.finish_transaction(peer, transaction_id))
+    }
+}
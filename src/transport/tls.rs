@@ -0,0 +1,236 @@
+use crate::turn_message::{TurnMessage, TurnMessageDecoder, TurnMessageEncoder};
+use crate::{Error, ErrorKind, Result};
+use bytecodec::io::{BufferedIo, IoDecodeExt, IoEncodeExt};
+use bytecodec::{Decode, Encode};
+use fibers::net::TcpStream;
+use fibers_transport::{PollRecv, PollSend, TcpTransport, Transport};
+use futures::{Async, Poll};
+use rustls::{ServerConfig, ServerConnection};
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use trackable::error::ErrorKindExt;
+
+const BUF_SIZE: usize = 8192;
+
+/// Server side TLS session over a TCP connection.
+///
+/// Clones share the same connection (like `TcpStream`), so that a client data connection
+/// can be handed to a relay after the ConnectionBind request has been read from it.
+#[derive(Debug, Clone)]
+pub(crate) struct TlsStream(Arc<Mutex<TlsSession>>);
+impl TlsStream {
+    pub(crate) fn new(stream: TcpStream, config: Arc<ServerConfig>) -> Result<Self> {
+        let connection = track!(ServerConnection::new(config)
+            .map_err(|e| Error::from(ErrorKind::InvalidInput.cause(e))))?;
+        Ok(TlsStream(Arc::new(Mutex::new(TlsSession {
+            stream,
+            connection,
+        }))))
+    }
+
+    /// Proceeds with the handshake, and returns `Async::Ready` once it has been completed.
+    pub(crate) fn poll_handshake(&mut self) -> Poll<(), Error> {
+        let mut session = self.0.lock().expect("never fails");
+        while session.connection.is_handshaking() {
+            track!(session.write_records().map_err(Error::from))?;
+            match session.read_records() {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+                Err(e) => return Err(track!(Error::from(e))),
+                Ok(0) => track_panic!(ErrorKind::Other, "Closed during the TLS handshake"),
+                Ok(_) => {}
+            }
+        }
+        track!(session.write_records().map_err(Error::from))?;
+        Ok(Async::Ready(()))
+    }
+
+    /// Returns the server name that the client has indicated (SNI).
+    pub(crate) fn server_name(&self) -> Option<String> {
+        let session = self.0.lock().expect("never fails");
+        session.connection.server_name().map(|x| x.to_owned())
+    }
+
+    pub(crate) fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.0.lock().expect("never fails").stream.peer_addr()
+    }
+
+    pub(crate) fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.0.lock().expect("never fails").stream.local_addr()
+    }
+
+    /// Sends a `close_notify` alert to the client.
+    pub(crate) fn close(&mut self) {
+        let mut session = self.0.lock().expect("never fails");
+        session.connection.send_close_notify();
+        let _ = session.write_records();
+    }
+}
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut session = self.0.lock().expect("never fails");
+        loop {
+            // Records may be pending because the previous write would have blocked
+            session.write_records()?;
+            match session.connection.reader().read(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                result => return result,
+            }
+            if session.read_records()? == 0 {
+                return Ok(0);
+            }
+        }
+    }
+}
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut session = self.0.lock().expect("never fails");
+        session.write_records()?;
+        let size = session.connection.writer().write(buf)?;
+        session.write_records()?;
+        if size == 0 && !buf.is_empty() {
+            // The buffer of the session is full until the socket becomes writable
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut session = self.0.lock().expect("never fails");
+        session.write_records()?;
+        if session.connection.wants_write() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct TlsSession {
+    stream: TcpStream,
+    connection: ServerConnection,
+}
+impl TlsSession {
+    /// Reads and processes TLS records, and returns the number of the bytes read (`0` at the end of the stream).
+    fn read_records(&mut self) -> io::Result<usize> {
+        let size = self.connection.read_tls(&mut self.stream)?;
+        if let Err(e) = self.connection.process_new_packets() {
+            // Sends the alert describing the error
+            let _ = self.write_records();
+            return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+        }
+        Ok(size)
+    }
+
+    /// Writes the pending TLS records as much as the socket accepts without blocking.
+    fn write_records(&mut self) -> io::Result<()> {
+        while self.connection.wants_write() {
+            match self.connection.write_tls(&mut self.stream) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+                Ok(0) => break,
+                Ok(_) => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Transporter for TURN messages over a TLS connection.
+///
+/// This is the TLS counterpart of `fibers_transport::TcpTransporter`.
+#[derive(Debug)]
+pub(crate) struct TurnTlsTransporter {
+    stream: BufferedIo<TlsStream>,
+    peer_addr: SocketAddr,
+    local_addr: SocketAddr,
+    encoder: TurnMessageEncoder,
+    decoder: TurnMessageDecoder,
+    outgoing_queue: VecDeque<TurnMessage>,
+}
+impl TurnTlsTransporter {
+    /// Makes a new `TurnTlsTransporter` instance from a stream of which handshake has been completed.
+    pub(crate) fn new(stream: TlsStream) -> Result<Self> {
+        let peer_addr = track!(stream.peer_addr().map_err(Error::from))?;
+        let local_addr = track!(stream.local_addr().map_err(Error::from))?;
+        Ok(TurnTlsTransporter {
+            stream: BufferedIo::new(stream, BUF_SIZE, BUF_SIZE),
+            peer_addr,
+            local_addr,
+            encoder: TurnMessageEncoder::default(),
+            decoder: TurnMessageDecoder::default(),
+            outgoing_queue: VecDeque::new(),
+        })
+    }
+
+    pub(crate) fn stream_ref(&self) -> &TlsStream {
+        self.stream.stream_ref()
+    }
+
+    pub(crate) fn decoder_mut(&mut self) -> &mut TurnMessageDecoder {
+        &mut self.decoder
+    }
+}
+impl Transport for TurnTlsTransporter {
+    type PeerAddr = ();
+    type SendItem = TurnMessage;
+    type RecvItem = TurnMessage;
+
+    fn start_send(
+        &mut self,
+        (): Self::PeerAddr,
+        item: Self::SendItem,
+    ) -> fibers_transport::Result<()> {
+        self.outgoing_queue.push_back(item);
+        track!(self.poll_send())?;
+        Ok(())
+    }
+
+    fn poll_send(&mut self) -> PollSend {
+        loop {
+            track!(self.stream.execute_io())?;
+            track!(self
+                .encoder
+                .encode_to_write_buf(self.stream.write_buf_mut()))?;
+            if self.encoder.is_idle() {
+                if let Some(item) = self.outgoing_queue.pop_front() {
+                    track!(self.encoder.start_encoding(item))?;
+                } else if self.stream.write_buf_ref().is_empty() {
+                    return Ok(Async::Ready(()));
+                }
+            }
+            if self.stream.would_block() || self.stream.is_eos() {
+                return Ok(Async::NotReady);
+            }
+        }
+    }
+
+    fn poll_recv(&mut self) -> PollRecv<(Self::PeerAddr, Self::RecvItem)> {
+        loop {
+            track!(self.stream.execute_io())?;
+            track!(self
+                .decoder
+                .decode_from_read_buf(self.stream.read_buf_mut()))?;
+            if self.decoder.is_idle() {
+                let item = track!(self.decoder.finish_decoding())?;
+                return Ok(Async::Ready(Some(((), item))));
+            }
+            if self.stream.is_eos() {
+                return Ok(Async::Ready(None));
+            }
+            if self.stream.would_block() {
+                return Ok(Async::NotReady);
+            }
+        }
+    }
+}
+impl TcpTransport for TurnTlsTransporter {
+    fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
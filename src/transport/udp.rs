@@ -0,0 +1,81 @@
+use super::virtual_network::VirtualSocket;
+use crate::turn_message::{TurnMessage, TurnMessageDecoder, TurnMessageEncoder};
+use bytecodec::{DecodeExt, EncodeExt};
+use fibers_transport::{PollRecv, PollSend, Result, Transport, UdpTransport, UdpTransporter};
+use futures::Async;
+use std::net::SocketAddr;
+
+/// UDP transporter for TURN messages that runs on either a real socket or a [`VirtualSocket`].
+#[derive(Debug)]
+pub(crate) enum TurnUdpTransporter {
+    Socket(UdpTransporter<TurnMessageEncoder, TurnMessageDecoder>),
+    Virtual {
+        socket: VirtualSocket,
+        encoder: TurnMessageEncoder,
+        decoder: TurnMessageDecoder,
+    },
+}
+impl TurnUdpTransporter {
+    pub(crate) fn new_virtual(socket: VirtualSocket) -> Self {
+        TurnUdpTransporter::Virtual {
+            socket,
+            encoder: TurnMessageEncoder::default(),
+            decoder: TurnMessageDecoder::default(),
+        }
+    }
+
+    pub(crate) fn socket_ref(&self) -> Option<&fibers::net::UdpSocket> {
+        match self {
+            TurnUdpTransporter::Socket(t) => Some(t.socket_ref()),
+            TurnUdpTransporter::Virtual { .. } => None,
+        }
+    }
+}
+impl Transport for TurnUdpTransporter {
+    type PeerAddr = SocketAddr;
+    type SendItem = TurnMessage;
+    type RecvItem = TurnMessage;
+
+    fn start_send(&mut self, peer: SocketAddr, item: TurnMessage) -> Result<()> {
+        match self {
+            TurnUdpTransporter::Socket(t) => track!(t.start_send(peer, item)),
+            TurnUdpTransporter::Virtual {
+                socket, encoder, ..
+            } => {
+                let bytes = track!(encoder.encode_into_bytes(item))?;
+                track!(socket.start_send(peer, bytes))
+            }
+        }
+    }
+
+    fn poll_send(&mut self) -> PollSend {
+        match self {
+            TurnUdpTransporter::Socket(t) => track!(t.poll_send()),
+            TurnUdpTransporter::Virtual { socket, .. } => track!(socket.poll_send()),
+        }
+    }
+
+    fn poll_recv(&mut self) -> PollRecv<(SocketAddr, TurnMessage)> {
+        match self {
+            TurnUdpTransporter::Socket(t) => track!(t.poll_recv()),
+            TurnUdpTransporter::Virtual {
+                socket, decoder, ..
+            } => match track!(socket.poll_recv())? {
+                Async::Ready(Some((peer, data))) => {
+                    let item = track!(decoder.decode_from_bytes(&data); peer)?;
+                    Ok(Async::Ready(Some((peer, item))))
+                }
+                Async::Ready(None) => Ok(Async::Ready(None)),
+                Async::NotReady => Ok(Async::NotReady),
+            },
+        }
+    }
+}
+impl UdpTransport for TurnUdpTransporter {
+    fn local_addr(&self) -> SocketAddr {
+        match self {
+            TurnUdpTransporter::Socket(t) => t.local_addr(),
+            TurnUdpTransporter::Virtual { socket, .. } => socket.local_addr(),
+        }
+    }
+}
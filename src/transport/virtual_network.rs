@@ -0,0 +1,288 @@
+use fibers::sync::mpsc;
+use fibers_transport::{ErrorKind, PollRecv, PollSend, Result, Transport, UdpTransport};
+use futures::{Async, Stream};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const MIN_EPHEMERAL_PORT: u16 = 49152;
+
+/// In-process simulated network between TURN servers and clients.
+///
+/// The network delays, drops and reorders datagrams according to its settings.
+/// The decisions are drawn from a random number generator initialized by the given seed,
+/// so a test that sends datagrams in the same order sees the same decisions every time.
+///
+/// Each [`VirtualSocket`] is behind a NAT whose mapping can be changed by [`VirtualNetwork::rebind`],
+/// so that the peers of the socket see a new source address (e.g., for testing mobility).
+///
+/// Latency and reordering are simulated by a virtual clock that only [`VirtualNetwork::advance`] moves,
+/// so the delayed datagrams are delivered exactly when the test decides (the others are delivered immediately).
+///
+/// The simulation is limited to the datagrams between virtual sockets:
+/// - The relayed transport addresses of servers are real UDP sockets, so peers are on the real network.
+/// - The timers of servers and clients (e.g., retransmissions and lifetimes) run on real time,
+///   so the order of the datagrams, and hence which of them are dropped, can vary between runs
+///   when datagrams are lost and retransmitted.
+#[derive(Debug, Clone)]
+pub struct VirtualNetwork(Arc<Mutex<NetworkState>>);
+impl VirtualNetwork {
+    /// Makes a new `VirtualNetwork` instance that delivers all datagrams immediately.
+    pub fn new(seed: u64) -> Self {
+        VirtualNetwork(Arc::new(Mutex::new(NetworkState {
+            rng: StdRng::seed_from_u64(seed),
+            latency: Duration::from_secs(0),
+            loss_rate: 0.0,
+            reorder_rate: 0.0,
+            reorder_delay: Duration::from_secs(0),
+            now: Duration::from_secs(0),
+            next_seqno: 0,
+            in_flight: BinaryHeap::new(),
+            sockets: HashMap::new(),
+            nat: HashMap::new(),
+        })))
+    }
+
+    /// Sets the one-way latency of the subsequent datagrams.
+    pub fn set_latency(&self, latency: Duration) {
+        self.state().latency = latency;
+    }
+
+    /// Sets the probability that a datagram is dropped.
+    ///
+    /// `rate` must be in the range of `0.0..=1.0`.
+    pub fn set_loss_rate(&self, rate: f64) -> Result<()> {
+        track_assert!(
+            (0.0..=1.0).contains(&rate),
+            ErrorKind::InvalidInput,
+            "Invalid loss rate: {}",
+            rate
+        );
+        self.state().loss_rate = rate;
+        Ok(())
+    }
+
+    /// Sets the probability that a datagram is delayed by `delay` in addition to the latency,
+    /// so that the datagrams sent after it overtake it.
+    ///
+    /// `rate` must be in the range of `0.0..=1.0`.
+    pub fn set_reordering(&self, rate: f64, delay: Duration) -> Result<()> {
+        track_assert!(
+            (0.0..=1.0).contains(&rate),
+            ErrorKind::InvalidInput,
+            "Invalid reordering rate: {}",
+            rate
+        );
+        let mut state = self.state();
+        state.reorder_rate = rate;
+        state.reorder_delay = delay;
+        Ok(())
+    }
+
+    /// Moves the virtual clock forward by `duration`
+    /// and delivers the datagrams that are due by then in the order of their delivery times.
+    pub fn advance(&self, duration: Duration) {
+        let mut state = self.state();
+        state.now += duration;
+        while state
+            .in_flight
+            .peek()
+            .is_some_and(|Reverse(p)| p.deliver_at <= state.now)
+        {
+            let Reverse(packet) = state.in_flight.pop().expect("never fails");
+            state.deliver(packet);
+        }
+    }
+
+    /// Returns the time elapsed on the virtual clock since the network was created.
+    pub fn elapsed(&self) -> Duration {
+        self.state().now
+    }
+
+    /// Binds a new socket to `addr`.
+    ///
+    /// If the port of `addr` is `0`, an ephemeral port is assigned.
+    /// Initially, the public address of the socket is the same as its local address.
+    pub fn bind(&self, addr: SocketAddr) -> Result<VirtualSocket> {
+        let mut state = self.state();
+        let mut addr = addr;
+        if addr.port() == 0 {
+            let port = (MIN_EPHEMERAL_PORT..=u16::MAX)
+                .find(|&p| !state.is_in_use(SocketAddr::new(addr.ip(), p)));
+            addr.set_port(track_assert_some!(port, ErrorKind::Other; addr));
+        }
+        track_assert!(
+            !state.is_in_use(addr),
+            ErrorKind::InvalidInput,
+            "Address in use: {}",
+            addr
+        );
+
+        let (tx, rx) = mpsc::channel();
+        state.sockets.insert(addr, SocketState::new(addr, tx));
+        state.nat.insert(addr, addr);
+        Ok(VirtualSocket {
+            network: self.clone(),
+            local_addr: addr,
+            incoming: rx,
+        })
+    }
+
+    /// Changes the public address of the socket bound to `local_addr` to `public_addr`
+    /// (i.e., simulates a NAT rebinding).
+    ///
+    /// The datagrams sent to the old public address are dropped after that.
+    pub fn rebind(&self, local_addr: SocketAddr, public_addr: SocketAddr) -> Result<()> {
+        let mut state = self.state();
+        track_assert!(!state.is_in_use(public_addr), ErrorKind::InvalidInput; public_addr);
+        let socket = track_assert_some!(
+            state.sockets.get_mut(&local_addr),
+            ErrorKind::InvalidInput;
+            local_addr
+        );
+        let old = std::mem::replace(&mut socket.public_addr, public_addr);
+        state.nat.remove(&old);
+        state.nat.insert(public_addr, local_addr);
+        Ok(())
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, NetworkState> {
+        self.0.lock().expect("never fails")
+    }
+}
+
+#[derive(Debug)]
+struct NetworkState {
+    rng: StdRng,
+    latency: Duration,
+    loss_rate: f64,
+    reorder_rate: f64,
+    reorder_delay: Duration,
+    now: Duration,
+    next_seqno: u64,
+    sockets: HashMap<SocketAddr, SocketState>,
+
+    // Datagrams that are not yet due
+    in_flight: BinaryHeap<Reverse<Packet>>,
+
+    // Public address to local address
+    nat: HashMap<SocketAddr, SocketAddr>,
+}
+impl NetworkState {
+    fn is_in_use(&self, addr: SocketAddr) -> bool {
+        self.sockets.contains_key(&addr) || self.nat.contains_key(&addr)
+    }
+
+    fn send(&mut self, from: SocketAddr, to: SocketAddr, data: Vec<u8>) {
+        if self.rng.gen_bool(self.loss_rate) {
+            return;
+        }
+        let mut delay = self.latency;
+        if self.rng.gen_bool(self.reorder_rate) {
+            delay += self.reorder_delay;
+        }
+        let source = self.sockets.get(&from).map_or(from, |s| s.public_addr);
+        let seqno = self.next_seqno;
+        self.next_seqno += 1;
+
+        let packet = Packet {
+            deliver_at: self.now + delay,
+            seqno,
+            source,
+            destination: to,
+            data,
+        };
+        if delay == Duration::from_secs(0) {
+            self.deliver(packet);
+        } else {
+            self.in_flight.push(Reverse(packet));
+        }
+    }
+
+    fn deliver(&self, packet: Packet) {
+        let destination = self
+            .nat
+            .get(&packet.destination)
+            .and_then(|to| self.sockets.get(to));
+        if let Some(destination) = destination {
+            let _ = destination.tx.send((packet.source, packet.data));
+        }
+    }
+}
+
+#[derive(Debug)]
+struct SocketState {
+    public_addr: SocketAddr,
+    tx: mpsc::Sender<(SocketAddr, Vec<u8>)>,
+}
+impl SocketState {
+    fn new(addr: SocketAddr, tx: mpsc::Sender<(SocketAddr, Vec<u8>)>) -> Self {
+        SocketState {
+            public_addr: addr,
+            tx,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct Packet {
+    deliver_at: Duration,
+    seqno: u64,
+    source: SocketAddr,
+    destination: SocketAddr,
+    data: Vec<u8>,
+}
+
+/// Datagram socket on a [`VirtualNetwork`].
+#[derive(Debug)]
+pub struct VirtualSocket {
+    network: VirtualNetwork,
+    local_addr: SocketAddr,
+    incoming: mpsc::Receiver<(SocketAddr, Vec<u8>)>,
+}
+impl VirtualSocket {
+    /// Returns the network to which the socket belongs.
+    pub fn network(&self) -> &VirtualNetwork {
+        &self.network
+    }
+
+    /// Returns the address by which the peers of the socket see it.
+    pub fn public_addr(&self) -> SocketAddr {
+        self.network.state().sockets[&self.local_addr].public_addr
+    }
+}
+impl Transport for VirtualSocket {
+    type PeerAddr = SocketAddr;
+    type SendItem = Vec<u8>;
+    type RecvItem = Vec<u8>;
+
+    fn start_send(&mut self, peer: SocketAddr, item: Vec<u8>) -> Result<()> {
+        self.network.state().send(self.local_addr, peer, item);
+        Ok(())
+    }
+
+    fn poll_send(&mut self) -> PollSend {
+        Ok(Async::Ready(()))
+    }
+
+    fn poll_recv(&mut self) -> PollRecv<(SocketAddr, Vec<u8>)> {
+        Ok(self.incoming.poll().expect("never fails"))
+    }
+}
+impl UdpTransport for VirtualSocket {
+    fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+impl Drop for VirtualSocket {
+    fn drop(&mut self) {
+        let mut state = self.network.state();
+        if let Some(socket) = state.sockets.remove(&self.local_addr) {
+            state.nat.remove(&socket.public_addr);
+        }
+    }
+}
@@ -17,8 +17,25 @@ pub enum TurnMessageDecoder {
     ChannelData(ChannelDataDecoder),
     #[default]
     None,
+
+    // Keeps the bytes as they are (e.g., those of a TCP data connection after ConnectionBind)
+    Raw(Vec<u8>),
 }
+impl TurnMessageDecoder {
+    /// Stops decoding and keeps the subsequent bytes as they are.
+    pub fn start_raw(&mut self) {
+        *self = TurnMessageDecoder::Raw(Vec::new());
+    }
 
+    /// Takes the bytes kept since `start_raw` was called.
+    pub fn take_raw_bytes(&mut self) -> Vec<u8> {
+        if let TurnMessageDecoder::Raw(bytes) = self {
+            std::mem::take(bytes)
+        } else {
+            Vec::new()
+        }
+    }
+}
 impl Decode for TurnMessageDecoder {
     type Item = TurnMessage;
 
@@ -39,6 +56,10 @@ impl Decode for TurnMessageDecoder {
                     }
                     return result;
                 }
+                TurnMessageDecoder::Raw(bytes) => {
+                    bytes.extend_from_slice(buf);
+                    return Ok(buf.len());
+                }
                 TurnMessageDecoder::None => match buf.first().map(|&b| b >> 6) {
                     None => return Ok(0),
                     Some(0b00) => TurnMessageDecoder::Stun(Default::default()),
@@ -65,7 +86,9 @@ impl Decode for TurnMessageDecoder {
             TurnMessageDecoder::ChannelData(x) => {
                 track!(x.finish_decoding().map(TurnMessage::ChannelData))?
             }
-            TurnMessageDecoder::None => track_panic!(ErrorKind::IncompleteDecoding),
+            TurnMessageDecoder::None | TurnMessageDecoder::Raw(_) => {
+                track_panic!(ErrorKind::IncompleteDecoding)
+            }
         };
         *self = TurnMessageDecoder::None;
         Ok(item)
@@ -75,7 +98,15 @@ impl Decode for TurnMessageDecoder {
         match self {
             TurnMessageDecoder::Stun(x) => x.requiring_bytes(),
             TurnMessageDecoder::ChannelData(x) => x.requiring_bytes(),
-            TurnMessageDecoder::None => ByteCount::Finite(0),
+            TurnMessageDecoder::None | TurnMessageDecoder::Raw(_) => ByteCount::Unknown,
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        match self {
+            TurnMessageDecoder::Stun(x) => x.is_idle(),
+            TurnMessageDecoder::ChannelData(x) => x.is_idle(),
+            TurnMessageDecoder::None | TurnMessageDecoder::Raw(_) => false,
         }
     }
 }